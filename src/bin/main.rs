@@ -1,87 +1,382 @@
 use async_channel::{unbounded, Receiver, Sender};
 use clap::{load_yaml, App};
+use log::{error, warn};
 use std::error::Error;
-use talkoxid::chats::RocketChat;
-use talkoxid::config::{load_config, ChatConfig};
-use talkoxid::core::{Channel, Chat, ChatEvent, UIEvent, UI};
-use talkoxid::notifications::DesktopNotifier;
-use talkoxid::ui::CursiveUI;
-
-use log::LevelFilter;
-use log4rs::append::console::ConsoleAppender;
-use log4rs::append::file::FileAppender;
-use log4rs::config::{Appender, Config, Root};
+use talkoxid::chatlog::ChatLogger;
+use talkoxid::chats::{backends, build_chat, ChatParams, RocketChat};
+use talkoxid::config::{check_config, config_path, load_config, masked, ChatConfig, Overrides};
+use talkoxid::core::{AccountId, Channel, Chat, ChatEvent, Notification, UIEvent, UI};
+use talkoxid::credentials::{self, prompt_passphrase, Credentials};
+use talkoxid::export::{parse_since, ExportOptions};
+use talkoxid::logging::{self, LogConfig};
+use talkoxid::notifications::{
+    build_notifier, CoalescingNotifier, DesktopNotifier, FocusNotifier, NotificationRules,
+    NotifierConfig, RuleNotifier, SoundConfig, SoundPlayer, TerminalFocus,
+};
+use talkoxid::session::{forward_ui_events, route_chat_events, SessionState};
+use talkoxid::ui::{setup_wizard, CursiveUI};
 
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
 use url::Url;
 
-async fn chat_loop(
-    rx_chat: Receiver<ChatEvent>,
-    tx_ui: Sender<UIEvent>,
+async fn export(
     config: ChatConfig,
+    channel_name: String,
+    options: ExportOptions,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    match RocketChat::new(
-        Url::parse(&config.hostname).unwrap_or_else(|err| panic!("Bad url :{:?}", err)),
-        config.username,
-        config.password,
-        config.ssl_verify,
-        tx_ui.clone(),
+    let (_tx_chat, rx_chat) = unbounded();
+    let (tx_ui, _rx_ui) = unbounded();
+    let params = ChatParams {
+        host: Url::parse(&config.hostname)?,
+        username: config.username,
+        password: config.password,
+        tls: config.tls,
+        proxy: config.proxy,
+        chat_log: None,
+        record: None,
+        options: config.options,
+        tx_ui,
         rx_chat,
-        Box::new(DesktopNotifier {}),
-    )
-    .await
-    {
-        Ok(chat_system) => {
-            chat_system
-                .init_view(Channel::Group("GENERAL".to_string()))
-                .await?;
-            chat_system.start_loop().await?;
+        notifier: Box::new(RuleNotifier::new(
+            config.notifications,
+            Box::new(DesktopNotifier::new(Arc::new(SoundPlayer::new(
+                SoundConfig {
+                    mute: true,
+                    ..SoundConfig::default()
+                },
+            )))),
+        )),
+    };
+    let chat_system = build_chat(&config.backend, params).await?;
+    let path = chat_system.export(channel_name, options).await?;
+    println!("Channel exported to {}", path.display());
+    Ok(())
+}
+
+/// Store a password in the encrypted credentials file.
+fn set_credentials(path: PathBuf, account: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let passphrase = if path.exists() {
+        prompt_passphrase(&format!("Passphrase of {}: ", path.display()))?
+    } else {
+        let passphrase = prompt_passphrase(&format!("New passphrase of {}: ", path.display()))?;
+        if prompt_passphrase("Repeat the passphrase: ")? != passphrase {
+            return Err("The passphrases don't match".into());
+        }
+        passphrase
+    };
+    let password = prompt_passphrase(&format!("Password of {}: ", account))?;
+    let prompt = |_: &std::path::Path| Ok(passphrase.clone());
+    Credentials::new(path.clone(), &prompt).set_password(account, &password, &passphrase)?;
+    println!("Password of {} stored in {}", account, path.display());
+    Ok(())
+}
+
+/// What the notifiers of every account share.
+#[derive(Clone)]
+struct Notifiers {
+    /// A single notification rate and hook limit.
+    notifier: Arc<CoalescingNotifier>,
+    /// The focus reported by the UI.
+    focus: Arc<TerminalFocus>,
+}
+
+impl Notifiers {
+    fn build(
+        &self,
+        account: &str,
+        rules: NotificationRules,
+    ) -> Box<dyn Notification + Send + Sync> {
+        Box::new(RuleNotifier::new(
+            rules,
+            Box::new(FocusNotifier::new(
+                account,
+                self.focus.clone(),
+                self.notifier.account(account),
+            )),
+        ))
+    }
+}
+
+/// Where the events of an account come from.
+enum ChatSource {
+    /// A chat server, the websocket session is recorded to the file if any.
+    Server(Box<ChatConfig>, Option<PathBuf>),
+    /// A recorded websocket session.
+    Replay(PathBuf),
+}
+
+async fn connect(
+    rx_chat: Receiver<ChatEvent>,
+    tx_ui: Sender<UIEvent>,
+    account: AccountId,
+    source: ChatSource,
+    notifiers: Notifiers,
+) -> Result<Box<dyn Chat + Send + Sync>, Box<dyn Error + Send + Sync>> {
+    let (config, record) = match source {
+        ChatSource::Server(config, record) => (*config, record),
+        ChatSource::Replay(path) => {
+            let notifier = notifiers.build(&account, NotificationRules::default());
+            let chat_system = RocketChat::replay(&path, tx_ui, rx_chat, notifier).await?;
+            return Ok(Box::new(chat_system));
         }
-        Err(err) => {
-            let err = format!("{}", err);
-            tx_ui.send(UIEvent::ShowFatalError(err)).await?;
+    };
+    // The hostname was checked when loading the configuration
+    let host = Url::parse(&config.hostname)?;
+    let chat_log = config.chat_log.map(|x| {
+        ChatLogger::new(
+            x,
+            &host[url::Position::BeforeHost..url::Position::AfterPort],
+        )
+    });
+    let params = ChatParams {
+        host,
+        username: config.username,
+        password: config.password,
+        tls: config.tls,
+        proxy: config.proxy,
+        chat_log,
+        record,
+        options: config.options,
+        tx_ui,
+        rx_chat,
+        notifier: notifiers.build(&account, config.notifications),
+    };
+    build_chat(&config.backend, params).await
+}
+
+async fn run_chat(
+    rx_chat: Receiver<ChatEvent>,
+    tx_ui: Sender<UIEvent>,
+    account: AccountId,
+    source: ChatSource,
+    channel: Option<Channel>,
+    notifiers: Notifiers,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let default_channel = match &source {
+        ChatSource::Server(config, _) => config.default_channel.clone(),
+        ChatSource::Replay(_) => None,
+    };
+    let chat_system = connect(rx_chat, tx_ui, account, source, notifiers).await?;
+    // The restored channel, then the default one, then the first one
+    let candidates = channel
+        .into_iter()
+        .chain(default_channel.map(Channel::Group));
+    let mut opened = false;
+    for channel in candidates {
+        match chat_system.init_view(Some(channel.clone())).await {
+            Ok(()) => {
+                opened = true;
+                break;
+            }
+            Err(err) => warn!("Can't open {}: {}", channel, err),
         }
     }
+    if !opened {
+        chat_system.init_view(None).await?;
+    }
+    chat_system.start_loop().await
+}
+
+/// Run the chat of an account, its failure is shown without stopping the
+/// other accounts.
+async fn chat_loop(
+    rx_chat: Receiver<ChatEvent>,
+    tx_ui: Sender<UIEvent>,
+    account: AccountId,
+    source: ChatSource,
+    channel: Option<Channel>,
+    notifiers: Notifiers,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let chat = run_chat(
+        rx_chat,
+        tx_ui.clone(),
+        account.clone(),
+        source,
+        channel,
+        notifiers,
+    );
+    if let Err(err) = chat.await {
+        let info = format!("The chat of {} stopped: {}", account, err);
+        error!("{}", info);
+        tx_ui.send(UIEvent::ShowInfo(info)).await?;
+    }
     Ok(())
 }
 
 fn ui_loop(
-    tx_chat: Sender<ChatEvent>,
-    rx_ui: Receiver<UIEvent>,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let ui = CursiveUI::new(tx_chat, rx_ui);
+    tx_chat: Sender<(AccountId, ChatEvent)>,
+    rx_ui: Receiver<(AccountId, UIEvent)>,
+    session: SessionState,
+    focus: Arc<TerminalFocus>,
+) -> Result<SessionState, Box<dyn Error + Send + Sync>> {
+    let ui = CursiveUI::new(tx_chat, rx_ui)
+        .with_session(session)
+        .with_focus(focus);
     ui.start_loop()?;
-    Ok(())
+    Ok(ui.session())
 }
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
-    let stdout = ConsoleAppender::builder().build();
-    let file = FileAppender::builder().build("/tmp/talkoxid.log").unwrap();
-    let config = Config::builder()
-        .appender(Appender::builder().build("stdout", Box::new(stdout)))
-        .appender(Appender::builder().build("file", Box::new(file)))
-        .build(Root::builder().appender("file").build(LevelFilter::Info))
-        .unwrap();
-    log4rs::init_config(config)?;
-    log::info!("Starting talkoxid");
     let yaml = load_yaml!("../../config/cli.yaml");
     let matches = App::from_yaml(yaml).get_matches();
+    let log_config = LogConfig {
+        file: matches.value_of("log_file").map(PathBuf::from),
+        level: matches.value_of("log_level").map(String::from),
+        config: matches.value_of("log_config").map(PathBuf::from),
+    };
+    if let Err(err) = logging::init(log_config, LogConfig::from_file(&config_path())) {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+    log::info!("Starting talkoxid");
 
-    let config = load_config(
-        matches.value_of("username"),
-        matches.value_of("password"),
-        matches.value_of("hostname"),
-        matches.is_present("disable_ssl_verify"),
-    );
+    if matches.subcommand_matches("backends").is_some() {
+        for backend in backends() {
+            println!("{:<12}{}", backend.name, backend.description);
+        }
+        return Ok(());
+    }
+
+    if let Some(matches) = matches
+        .subcommand_matches("credentials")
+        .and_then(|x| x.subcommand_matches("set"))
+    {
+        let path = matches
+            .value_of("file")
+            .map(PathBuf::from)
+            .unwrap_or_else(credentials::default_path);
+        return set_credentials(path, matches.value_of("account").unwrap_or_default());
+    }
+
+    let cli = Overrides {
+        profile: matches.value_of("profile").map(String::from),
+        username: matches.value_of("username").map(String::from),
+        password: matches.value_of("password").map(String::from),
+        hostname: matches.value_of("host").map(String::from),
+    };
+    let exit_on_error = |err| {
+        eprintln!("{}", err);
+        std::process::exit(1)
+    };
+    let load_configs = || {
+        load_config(cli.clone(), matches.is_present("disable_ssl_verify"))
+            .unwrap_or_else(exit_on_error)
+    };
 
-    // Channel used to communicate from ui to chat
+    if matches
+        .subcommand_matches("config")
+        .and_then(|x| x.subcommand_matches("check"))
+        .is_some()
+    {
+        // The secrets aren't resolved, the passwords are masked anyway
+        let configs = check_config(cli.clone(), matches.is_present("disable_ssl_verify"))
+            .unwrap_or_else(exit_on_error);
+        println!("# {}", config_path().display());
+        print!("{}", masked(&configs));
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("export") {
+        let mut configs = load_configs();
+        let options = ExportOptions {
+            format: matches.value_of("format").unwrap_or("md").parse()?,
+            since: matches.value_of("since").map(parse_since).transpose()?,
+            output: matches.value_of("output").map(PathBuf::from),
+        };
+        let channel_name = matches.value_of("channel").unwrap_or_default().to_string();
+        let index = match matches.value_of("account") {
+            Some(account) => configs
+                .iter()
+                .position(|x| x.account == account)
+                .ok_or_else(|| format!("Unknown account: {}", account))?,
+            None => 0,
+        };
+        return export(configs.swap_remove(index), channel_name, options).await;
+    }
+
+    // First run: no configuration file and nothing given at runtime
+    let runtime_config = ["username", "password", "host", "profile", "replay"]
+        .iter()
+        .any(|x| matches.is_present(x));
+    if !runtime_config && Overrides::from_env() == Overrides::default() && !config_path().exists() {
+        let handle = tokio::runtime::Handle::current();
+        let saved = tokio::task::spawn_blocking(|| setup_wizard(handle, config_path())).await?;
+        if !saved {
+            return Ok(());
+        }
+    }
+
+    // Channel used to communicate from ui to chats
     let (tx_chat, rx_chat) = unbounded();
-    // Channel used to communicate from chat to ui
+    // Channel used to communicate from chats to ui
     let (tx_ui, rx_ui) = unbounded();
 
-    let ui = tokio::task::spawn_blocking(|| ui_loop(tx_chat, rx_ui));
-    let chat = tokio::task::spawn(chat_loop(rx_chat, tx_ui, config));
+    // A replayed session isn't saved
+    let replay = matches.is_present("replay");
+    let session = match replay {
+        true => SessionState::default(),
+        false => SessionState::load(&SessionState::default_path()),
+    };
 
-    ui.await??;
-    chat.await??;
+    let sources = match matches.value_of("replay") {
+        Some(path) => vec![("replay".to_string(), ChatSource::Replay(path.into()))],
+        None => {
+            // Only the first account is recorded
+            let mut record = matches.value_of("record").map(PathBuf::from);
+            load_configs()
+                .into_iter()
+                .map(|x| {
+                    (
+                        x.account.clone(),
+                        ChatSource::Server(Box::new(x), record.take()),
+                    )
+                })
+                .collect()
+        }
+    };
+
+    let sounds = Arc::new(SoundPlayer::new(SoundConfig::from_file(&config_path())));
+    let notifiers = Notifiers {
+        notifier: build_notifier(&NotifierConfig::from_file(&config_path()), sounds),
+        focus: Arc::new(TerminalFocus::new()),
+    };
+    let mut routes = HashMap::new();
+    let mut chats = vec![];
+    for (account, source) in sources {
+        // Channels used to communicate with the chat of this account only
+        let (tx_account_chat, rx_account_chat) = unbounded();
+        let (tx_account_ui, rx_account_ui) = unbounded();
+        routes.insert(account.clone(), tx_account_chat);
+        let channel = session.channel(&account).cloned();
+        tokio::task::spawn(forward_ui_events(
+            account.clone(),
+            rx_account_ui,
+            tx_ui.clone(),
+        ));
+        chats.push(tokio::task::spawn(chat_loop(
+            rx_account_chat,
+            tx_account_ui,
+            account,
+            source,
+            channel,
+            notifiers.clone(),
+        )));
+    }
+    tokio::task::spawn(route_chat_events(rx_chat, routes));
+
+    let focus = notifiers.focus.clone();
+    let ui = tokio::task::spawn_blocking(|| ui_loop(tx_chat, rx_ui, session, focus));
+
+    let session = ui.await??;
+    if !replay {
+        if let Err(err) = session.save(&SessionState::default_path()) {
+            eprintln!("Can't save the session: {}", err);
+        }
+    }
+    for chat in chats {
+        chat.await??;
+    }
     Ok(())
 }
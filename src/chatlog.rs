@@ -0,0 +1,298 @@
+//! Chat log module.
+//!
+//! This module contains an IRC-style plaintext logger
+//! writing every displayed message to per-channel files.
+use super::core::Message;
+use chrono::{DateTime, Local};
+use log::error;
+use serde::{Deserialize, Serialize};
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Number of message ids remembered per channel to avoid duplicated lines.
+const SEEN_IDS: usize = 1000;
+
+fn default_timestamp_format() -> String {
+    String::from("%Y-%m-%d %H:%M:%S")
+}
+
+fn default_rotate_daily() -> bool {
+    true
+}
+
+/// Chat log configuration.
+///
+/// This type is the `[chat_log]` table of the configuration file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChatLogConfig {
+    /// Where the logs are written, default to `$XDG_DATA_HOME/talkoxid/logs`.
+    pub directory: Option<PathBuf>,
+    /// The strftime format of the timestamp starting each line.
+    #[serde(default = "default_timestamp_format")]
+    pub timestamp_format: String,
+    /// Whether the log of the previous day is moved aside.
+    #[serde(default = "default_rotate_daily")]
+    pub rotate_daily: bool,
+    /// Channels that are never logged.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+/// Message ids already written in a channel log.
+#[derive(Default)]
+struct SeenIds {
+    ids: HashSet<String>,
+    order: VecDeque<String>,
+}
+
+impl SeenIds {
+    fn insert(&mut self, id: String) {
+        if self.order.len() >= SEEN_IDS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        self.ids.insert(id.clone());
+        self.order.push_back(id);
+    }
+}
+
+/// Plaintext chat logger.
+///
+/// Messages are appended to `<directory>/<server>/<channel>.log`.
+pub struct ChatLogger {
+    directory: PathBuf,
+    config: ChatLogConfig,
+    seen: Mutex<HashMap<String, SeenIds>>,
+}
+
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '/' | '\\' | '\0' => '_',
+            c => c,
+        })
+        .collect::<String>()
+        .trim_start_matches('.')
+        .to_string()
+}
+
+impl ChatLogger {
+    pub fn new(config: ChatLogConfig, server: &str) -> Self {
+        let mut directory = config.directory.clone().unwrap_or_else(|| {
+            let mut directory = dirs_next::data_dir().unwrap_or_else(|| PathBuf::from("."));
+            directory.push("talkoxid");
+            directory.push("logs");
+            directory
+        });
+        directory.push(sanitize(server));
+        ChatLogger {
+            directory,
+            config,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Append a message to the log of a channel.
+    ///
+    /// Errors are reported in the application log and never returned
+    /// so a full disk can't stop the chat.
+    pub fn log(&self, channel_name: &str, message: &Message) {
+        if self.config.exclude.iter().any(|x| x == channel_name) {
+            return;
+        }
+        if let Err(err) = self.write(&sanitize(channel_name), message) {
+            error!("Can't write chat log of {}: {}", channel_name, err);
+        }
+    }
+
+    fn write(&self, channel: &str, message: &Message) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut seen = self.seen.lock().unwrap();
+        if !seen.contains_key(channel) {
+            seen.insert(channel.into(), self.load_seen(channel));
+        }
+        let seen_ids = seen.get_mut(channel).unwrap();
+        if !message.id.is_empty() && seen_ids.ids.contains(&message.id) {
+            return Ok(());
+        }
+        fs::create_dir_all(&self.directory)?;
+        let path = self.directory.join(format!("{}.log", channel));
+        if self.config.rotate_daily {
+            rotate(&path)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        file.write_all(self.format(message).as_bytes())?;
+        if !message.id.is_empty() {
+            let mut ids = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.ids_path(channel))?;
+            writeln!(ids, "{}", message.id)?;
+            seen_ids.insert(message.id.clone());
+        }
+        Ok(())
+    }
+
+    fn format(&self, message: &Message) -> String {
+        let timestamp = message
+            .datetime
+            .with_timezone(&Local)
+            .format(&self.config.timestamp_format)
+            .to_string();
+        let mut lines = message
+            .content
+            .lines()
+            .map(|line| format!("{} <{}> {}\n", timestamp, message.author, line))
+            .collect::<String>();
+        for attachment in message.attachments.iter() {
+            lines.push_str(&format!(
+                "{} <{}> {}: {}\n",
+                timestamp, message.author, attachment.title, attachment.link
+            ));
+        }
+        lines
+    }
+
+    fn ids_path(&self, channel: &str) -> PathBuf {
+        self.directory.join(format!(".{}.ids", channel))
+    }
+
+    /// Load the ids written by previous sessions and compact their file.
+    fn load_seen(&self, channel: &str) -> SeenIds {
+        let mut seen = SeenIds::default();
+        let path = self.ids_path(channel);
+        if let Ok(file) = File::open(&path) {
+            for id in BufReader::new(file).lines().map_while(Result::ok) {
+                seen.insert(id);
+            }
+            let compacted = seen
+                .order
+                .iter()
+                .map(|x| format!("{}\n", x))
+                .collect::<String>();
+            fs::write(&path, compacted)
+                .unwrap_or_else(|err| error!("Can't compact {}: {}", path.display(), err));
+        }
+        seen
+    }
+}
+
+/// Move the log aside if it was last written on a previous day.
+fn rotate(path: &Path) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let modified = match fs::metadata(path).and_then(|x| x.modified()) {
+        Ok(modified) => DateTime::<Local>::from(modified).date(),
+        Err(_) => return Ok(()),
+    };
+    if modified >= Local::today() {
+        return Ok(());
+    }
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{}", modified.format("%Y-%m-%d")));
+    let rotated = PathBuf::from(rotated);
+    if rotated.exists() {
+        let content = fs::read(path)?;
+        OpenOptions::new()
+            .append(true)
+            .open(&rotated)?
+            .write_all(&content)?;
+        fs::remove_file(path)?;
+    } else {
+        fs::rename(path, rotated)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+    use std::time::{Duration, SystemTime};
+
+    fn create_logger(name: &str, exclude: Vec<String>) -> (ChatLogger, PathBuf) {
+        let directory = std::env::temp_dir().join(format!("talkoxid_test_chatlog_{}", name));
+        fs::remove_dir_all(&directory).ok();
+        let config = ChatLogConfig {
+            directory: Some(directory.clone()),
+            timestamp_format: "%H:%M".into(),
+            rotate_daily: true,
+            exclude,
+        };
+        (ChatLogger::new(config, "chat.test"), directory)
+    }
+
+    fn message(id: &str, content: &str) -> Message {
+        Message {
+            id: id.into(),
+            author: "lou".into(),
+            content: content.into(),
+            datetime: Utc.timestamp_millis(1593589750164),
+            thread: None,
+            attachments: vec![],
+        }
+    }
+
+    #[test]
+    fn test_log_dedup() {
+        let (logger, directory) = create_logger("dedup", vec![]);
+        logger.log("general", &message("a", "hello\nworld"));
+        logger.log("general", &message("a", "hello\nworld"));
+        logger.log("general", &message("b", "bye"));
+        let expected = Local
+            .timestamp_millis(1593589750164)
+            .format("%H:%M")
+            .to_string();
+        let log = fs::read_to_string(directory.join("chat.test/general.log")).unwrap();
+        assert_eq!(
+            log,
+            format!(
+                "{0} <lou> hello\n{0} <lou> world\n{0} <lou> bye\n",
+                expected
+            )
+        );
+
+        let logger = ChatLogger::new(logger.config.clone(), "chat.test");
+        logger.log("general", &message("b", "bye"));
+        let log_again = fs::read_to_string(directory.join("chat.test/general.log")).unwrap();
+        assert_eq!(log, log_again);
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_log_exclude() {
+        let (logger, directory) = create_logger("exclude", vec!["random".into()]);
+        logger.log("random", &message("a", "hello"));
+        logger.log("general", &message("b", "hello"));
+        assert!(!directory.join("chat.test/random.log").exists());
+        assert!(directory.join("chat.test/general.log").exists());
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_log_rotate() {
+        let (logger, directory) = create_logger("rotate", vec![]);
+        logger.log("general", &message("a", "yesterday"));
+        let path = directory.join("chat.test/general.log");
+        let yesterday = SystemTime::now() - Duration::from_secs(2 * 24 * 3600);
+        File::options()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(yesterday)
+            .unwrap();
+        logger.log("general", &message("b", "today"));
+        let rotated = directory.join(format!(
+            "chat.test/general.log.{}",
+            DateTime::<Local>::from(yesterday).format("%Y-%m-%d")
+        ));
+        assert!(fs::read_to_string(rotated)
+            .unwrap()
+            .ends_with("yesterday\n"));
+        assert!(fs::read_to_string(&path).unwrap().ends_with("today\n"));
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,166 @@
+use async_channel::Sender;
+use std::error::Error;
+
+/// Longest IRC line, with its CR LF.
+const MAX_LINE: usize = 512;
+
+/// Room kept for the `:nick!user@host ` prefix the server adds when it
+/// relays a message, which must fit in the line too.
+const RELAY_PREFIX: usize = 100;
+
+/// The lines of a message, split at line breaks and shortened to `max`
+/// bytes without cutting characters.
+fn split_message(content: &str, max: usize) -> Vec<&str> {
+    let mut lines = vec![];
+    for mut line in content.split(['\r', '\n']) {
+        while !line.is_empty() {
+            let mut end = line.len().min(max);
+            while !line.is_char_boundary(end) {
+                end -= 1;
+            }
+            // A single character longer than max
+            if end == 0 {
+                end = line.chars().next().map_or(line.len(), |x| x.len_utf8());
+            }
+            lines.push(&line[..end]);
+            line = &line[end..];
+        }
+    }
+    lines
+}
+
+/// IRC command writer.
+///
+/// Every command is a line pushed to the task writing the socket.
+pub struct IrcWriter {
+    socket: Sender<String>,
+}
+
+impl IrcWriter {
+    pub fn new(socket: Sender<String>) -> Self {
+        IrcWriter { socket }
+    }
+
+    /// Send a line, which can't hold line breaks as they end IRC commands.
+    pub async fn send(&self, line: String) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if line.contains(['\r', '\n']) {
+            return Err(format!("Line break in the IRC command {:?}", line).into());
+        }
+        self.socket.send(format!("{}\r\n", line)).await?;
+        Ok(())
+    }
+
+    /// Open the registration, capabilities are negotiated before it completes.
+    pub async fn cap_ls(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.send("CAP LS 302".into()).await
+    }
+
+    /// Send the server password, before `user`.
+    pub async fn pass(&self, password: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.send(format!("PASS :{}", password)).await
+    }
+
+    /// Send the nick and the user name.
+    pub async fn user(&self, nick: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.send(format!("NICK {}", nick)).await?;
+        self.send(format!("USER {} 0 * :{}", nick, nick)).await
+    }
+
+    pub async fn cap_req(&self, caps: &[&str]) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.send(format!("CAP REQ :{}", caps.join(" "))).await
+    }
+
+    pub async fn cap_end(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.send("CAP END".into()).await
+    }
+
+    pub async fn authenticate(&self, payload: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.send(format!("AUTHENTICATE {}", payload)).await
+    }
+
+    pub async fn pong(&self, token: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.send(format!("PONG :{}", token)).await
+    }
+
+    pub async fn nick(&self, nick: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.send(format!("NICK {}", nick)).await
+    }
+
+    pub async fn join(&self, channels: &[String]) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.send(format!("JOIN {}", channels.join(","))).await
+    }
+
+    pub async fn part(&self, channel: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.send(format!("PART {}", channel)).await
+    }
+
+    pub async fn names(&self, channel: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.send(format!("NAMES {}", channel)).await
+    }
+
+    /// Send a message, one PRIVMSG per line as IRC has no multiline messages.
+    ///
+    /// Long lines are split so that each command fits in an IRC line once
+    /// relayed by the server, the lines sent are returned.
+    pub async fn privmsg<'a>(
+        &self,
+        target: &str,
+        content: &'a str,
+    ) -> Result<Vec<&'a str>, Box<dyn Error + Send + Sync>> {
+        let prefix = format!("PRIVMSG {} :", target);
+        let max = MAX_LINE
+            .checked_sub(RELAY_PREFIX + prefix.len() + 2)
+            .filter(|x| *x > 0)
+            .ok_or("The IRC target is too long")?;
+        let lines = split_message(content, max);
+        for line in &lines {
+            self.send(format!("{}{}", prefix, line)).await?;
+        }
+        Ok(lines)
+    }
+
+    pub async fn chathistory_latest(
+        &self,
+        target: &str,
+        count: usize,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.send(format!("CHATHISTORY LATEST {} * {}", target, count))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_channel::unbounded;
+
+    #[test]
+    fn test_split_message() {
+        assert_eq!(
+            split_message("hi\rQUIT :bye\r\n\nthere", 100),
+            vec!["hi", "QUIT :bye", "there"]
+        );
+        assert_eq!(split_message("abcdef", 4), vec!["abcd", "ef"]);
+        assert_eq!(split_message("aé€", 3), vec!["aé", "€"]);
+        assert_eq!(split_message("€", 1), vec!["€"]);
+    }
+
+    #[tokio::test]
+    async fn test_privmsg() {
+        let (tx, rx) = unbounded();
+        let writer = IrcWriter::new(tx);
+        writer.privmsg("#ops", "hi\rQUIT :bye").await.unwrap();
+        assert_eq!(rx.try_recv().unwrap(), "PRIVMSG #ops :hi\r\n");
+        assert_eq!(rx.try_recv().unwrap(), "PRIVMSG #ops :QUIT :bye\r\n");
+        let content = "a".repeat(1000);
+        let lines = writer.privmsg("#ops", &content).await.unwrap();
+        assert_eq!(lines.concat(), content);
+        for line in lines {
+            let sent = rx.try_recv().unwrap();
+            assert_eq!(sent, format!("PRIVMSG #ops :{}\r\n", line));
+            assert!(sent.len() + RELAY_PREFIX <= MAX_LINE);
+        }
+        assert!(writer.nick("lou\r\nQUIT").await.is_err());
+        assert!(rx.try_recv().is_err());
+    }
+}
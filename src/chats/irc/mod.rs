@@ -0,0 +1,1082 @@
+mod api;
+mod schema;
+
+use super::super::core::{Alert, Channel, Chat, ChatEvent, Message, Notification, UIEvent};
+use super::tls::{self, TlsConfig};
+use api::IrcWriter;
+use async_channel::{unbounded, Receiver, Sender};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::error;
+use schema::IrcMessage;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::convert::TryFrom;
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use url::Url;
+
+/// Capabilities requested when the server offers them.
+const WANTED_CAPS: [&str; 7] = [
+    "server-time",
+    "message-tags",
+    "batch",
+    "echo-message",
+    "multi-prefix",
+    "draft/chathistory",
+    "chathistory",
+];
+/// Number of messages kept per channel, IRC servers rarely keep history for us.
+const BACKLOG_SIZE: usize = 500;
+/// Number of messages requested with CHATHISTORY when opening a channel.
+const HISTORY_SIZE: usize = 100;
+
+fn is_channel(target: &str) -> bool {
+    target.starts_with(['#', '&', '+', '!'])
+}
+
+fn target_channel(target: &str) -> Channel {
+    if is_channel(target) {
+        Channel::Group(target.into())
+    } else {
+        Channel::User(target.into())
+    }
+}
+
+/// Spawn the tasks reading and writing lines on the connection.
+fn spawn_io<S>(stream: S) -> (Sender<String>, Receiver<String>)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (read, mut write) = tokio::io::split(stream);
+    let (tx_socket, rx_writer) = unbounded::<String>();
+    let (tx_reader, rx_socket) = unbounded();
+    tokio::spawn(async move {
+        while let Ok(line) = rx_writer.recv().await {
+            if let Err(err) = write.write_all(line.as_bytes()).await {
+                error!("Error when writing to the IRC server: {}", err);
+                break;
+            }
+        }
+    });
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(read).lines();
+        loop {
+            match lines.next_line().await {
+                Ok(Some(line)) => {
+                    if let Err(err) = tx_reader.send(line).await {
+                        error!("Error when sending to IRC receiver: {}", err);
+                        break;
+                    }
+                }
+                Ok(None) => {
+                    error!("IRC connection closed");
+                    break;
+                }
+                Err(err) => {
+                    error!("Error when reading from the IRC server: {}", err);
+                    break;
+                }
+            }
+        }
+    });
+    (tx_socket, rx_socket)
+}
+
+/// Negotiate the capabilities, authenticate and wait for the welcome.
+///
+/// Return the nickname given by the server and the enabled capabilities.
+async fn register(
+    writer: &IrcWriter,
+    rx_irc: &Receiver<String>,
+    username: &str,
+    password: &str,
+) -> Result<(String, HashSet<String>), Box<dyn Error + Send + Sync>> {
+    let mut nick = username.to_string();
+    let mut offered = vec![];
+    let mut enabled = HashSet::new();
+    // Without SASL, the password is sent with PASS before the nick
+    let identify = |sasl: bool| async move {
+        if !sasl && !password.is_empty() {
+            writer.pass(password).await?;
+        }
+        writer.user(username).await
+    };
+    let mut identified = false;
+    writer.cap_ls().await?;
+    loop {
+        let line = rx_irc.recv().await?;
+        let msg = match IrcMessage::parse(&line) {
+            Some(msg) => msg,
+            None => continue,
+        };
+        match &msg.command[..] {
+            "PING" => writer.pong(msg.param(0)).await?,
+            "CAP" if msg.param(1) == "LS" => {
+                let more = msg.params.len() > 3 && msg.param(2) == "*";
+                let caps = msg.params.last().cloned().unwrap_or_default();
+                offered.extend(
+                    caps.split(' ')
+                        .map(|x| x.split('=').next().unwrap_or(x).to_string()),
+                );
+                if more {
+                    continue;
+                }
+                let mut wanted = WANTED_CAPS
+                    .iter()
+                    .filter(|x| offered.iter().any(|y| y == *x))
+                    .copied()
+                    .collect::<Vec<&str>>();
+                let sasl = !password.is_empty() && offered.iter().any(|x| x == "sasl");
+                if sasl {
+                    wanted.push("sasl");
+                }
+                if !identified {
+                    identify(sasl).await?;
+                    identified = true;
+                }
+                if wanted.is_empty() {
+                    writer.cap_end().await?;
+                } else {
+                    writer.cap_req(&wanted).await?;
+                }
+            }
+            "CAP" if msg.param(1) == "ACK" => {
+                enabled.extend(msg.param(2).split(' ').map(String::from));
+                if enabled.contains("sasl") {
+                    writer.authenticate("PLAIN").await?;
+                } else {
+                    writer.cap_end().await?;
+                }
+            }
+            "CAP" if msg.param(1) == "NAK" => {
+                if !password.is_empty() && msg.param(2).split(' ').any(|x| x == "sasl") {
+                    return Err("The IRC server refused SASL, can't authenticate".into());
+                }
+                writer.cap_end().await?
+            }
+            // A server without capabilities
+            "421" | "451" if !identified => {
+                identify(false).await?;
+                identified = true;
+            }
+            "AUTHENTICATE" if msg.param(0) == "+" => {
+                let payload = format!("{}\0{}\0{}", username, username, password);
+                writer.authenticate(&base64::encode(payload)).await?;
+            }
+            "903" => writer.cap_end().await?,
+            "902" | "904" | "905" | "906" => {
+                return Err(format!("SASL authentication failed: {}", msg.param(1)).into());
+            }
+            "464" => return Err(format!("Bad password: {}", msg.param(1)).into()),
+            "433" => {
+                nick.push('_');
+                writer.nick(&nick).await?;
+            }
+            "001" => return Ok((msg.param(0).to_string(), enabled)),
+            "ERROR" => return Err(format!("IRC server error: {}", msg.param(0)).into()),
+            _ => {}
+        }
+    }
+}
+
+/// IRC chat system.
+///
+/// This type is a chat system implementation for IRC, channels are
+/// groups and queries are direct chats.
+pub struct Irc {
+    tx_ui: Sender<UIEvent>,
+    writer: IrcWriter,
+    notifier: Box<dyn Notification + Sync + Send>,
+    rx_irc: Receiver<String>,
+    rx_chat: Receiver<ChatEvent>,
+    nick: Mutex<String>,
+    caps: HashSet<String>,
+    autojoin: Vec<String>,
+    autojoined: AtomicBool,
+    current_channel: Mutex<Option<Channel>>,
+    joined: Mutex<BTreeSet<String>>,
+    queries: Mutex<BTreeSet<String>>,
+    members: Mutex<HashMap<String, BTreeSet<String>>>,
+    names: Mutex<HashMap<String, BTreeSet<String>>>,
+    backlog: Mutex<HashMap<String, Vec<Message>>>,
+    batches: Mutex<HashMap<String, (String, Vec<Message>)>>,
+}
+
+impl Irc {
+    pub async fn new(
+        host: Url,
+        username: String,
+        password: String,
+        tls: TlsConfig,
+        tx_ui: Sender<UIEvent>,
+        rx_chat: Receiver<ChatEvent>,
+        notifier: Box<dyn Notification + Sync + Send>,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let server = host
+            .host_str()
+            .ok_or("No IRC server in the url")?
+            .to_string();
+        let secure = host.scheme() == "ircs";
+        let port = host.port().unwrap_or(if secure { 6697 } else { 6667 });
+        let stream = TcpStream::connect((&server[..], port)).await?;
+        let (tx_irc, rx_irc) = if secure {
+            let name = rustls::ServerName::try_from(&server[..])?;
+            spawn_io(tls::connector(&tls)?.connect(name, stream).await?)
+        } else {
+            spawn_io(stream)
+        };
+        let writer = IrcWriter::new(tx_irc);
+        let (nick, caps) = register(&writer, &rx_irc, &username, &password).await?;
+        Ok(Irc {
+            tx_ui,
+            writer,
+            notifier,
+            rx_irc,
+            rx_chat,
+            nick: Mutex::new(nick),
+            caps,
+            autojoin: vec![],
+            autojoined: AtomicBool::new(false),
+            current_channel: Mutex::new(None),
+            joined: Mutex::new(BTreeSet::new()),
+            queries: Mutex::new(BTreeSet::new()),
+            members: Mutex::new(HashMap::new()),
+            names: Mutex::new(HashMap::new()),
+            backlog: Mutex::new(HashMap::new()),
+            batches: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Join these channels when the view is initialized.
+    pub fn with_channels(mut self, channels: Vec<String>) -> Self {
+        self.autojoin = channels;
+        self
+    }
+
+    fn nick(&self) -> String {
+        self.nick.lock().unwrap().clone()
+    }
+
+    fn has_history(&self) -> bool {
+        self.caps.contains("draft/chathistory") || self.caps.contains("chathistory")
+    }
+
+    fn is_current(&self, target: &str) -> bool {
+        self.current_channel
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|x| format!("{}", x) == target)
+            .unwrap_or(false)
+    }
+
+    fn channels(&self) -> Vec<(String, Channel)> {
+        let joined = self.joined.lock().unwrap().clone();
+        let queries = self.queries.lock().unwrap().clone();
+        joined
+            .into_iter()
+            .chain(queries)
+            .map(|x| (x.clone(), target_channel(&x)))
+            .collect()
+    }
+
+    fn to_message(&self, msg: &IrcMessage) -> Message {
+        let content = msg.param(1);
+        let content = match content
+            .strip_prefix("\x01ACTION ")
+            .map(|x| x.trim_end_matches('\x01'))
+        {
+            Some(action) => format!("* {}", action),
+            None => content.to_string(),
+        };
+        let datetime = msg
+            .tags
+            .get("time")
+            .and_then(|x| DateTime::parse_from_rfc3339(x).ok())
+            .map(|x| x.with_timezone(&Utc))
+            .unwrap_or_else(Utc::now);
+        Message {
+            id: msg.tags.get("msgid").cloned().unwrap_or_default(),
+            author: msg.nick().unwrap_or_default().to_string(),
+            content,
+            datetime,
+            thread: None,
+            attachments: vec![],
+        }
+    }
+
+    fn push_backlog(&self, target: &str, message: Message) {
+        let mut backlog = self.backlog.lock().unwrap();
+        let messages = backlog.entry(target.to_string()).or_default();
+        messages.push(message);
+        if messages.len() > BACKLOG_SIZE {
+            messages.remove(0);
+        }
+    }
+
+    fn render_backlog(&self, target: &str) -> String {
+        self.backlog
+            .lock()
+            .unwrap()
+            .get(target)
+            .map(|x| {
+                x.iter()
+                    .fold(String::from(""), |x, y| format!("{}{}\n", x, y))
+            })
+            .unwrap_or_default()
+    }
+
+    async fn update_channels(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.tx_ui
+            .send(UIEvent::UpdateChannels(self.channels()))
+            .await?;
+        Ok(())
+    }
+
+    async fn update_users(&self, target: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if !self.is_current(target) {
+            return Ok(());
+        }
+        let users = match is_channel(target) {
+            true => self
+                .members
+                .lock()
+                .unwrap()
+                .get(target)
+                .cloned()
+                .unwrap_or_default(),
+            false => vec![self.nick(), target.to_string()].into_iter().collect(),
+        };
+        self.tx_ui
+            .send(UIEvent::UpdateUsersInRoom(
+                users.into_iter().map(|x| (x.clone(), x)).collect(),
+            ))
+            .await?;
+        Ok(())
+    }
+
+    async fn open_query(&self, nick: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if self.queries.lock().unwrap().insert(nick.to_string()) {
+            self.update_channels().await?;
+        }
+        Ok(())
+    }
+
+    async fn recv_privmsg(&self, msg: IrcMessage) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let own_nick = self.nick();
+        let sender = msg.nick().unwrap_or_default().to_string();
+        let target = match msg.param(0) {
+            x if is_channel(x) || sender == own_nick => x.to_string(),
+            _ => sender.clone(),
+        };
+        let message = self.to_message(&msg);
+        if let Some(batch) = msg.tags.get("batch") {
+            if let Some((_, messages)) = self.batches.lock().unwrap().get_mut(batch) {
+                messages.push(message);
+                return Ok(());
+            }
+        }
+        if !is_channel(&target) {
+            self.open_query(&target).await?;
+        }
+        if sender != own_nick {
+            let alert = Alert::new(target_channel(&target), &target, &message, &own_nick);
+            if let Err(err) = self.notifier.notify_message(&alert) {
+                error!("Can't notify: {}", err);
+            }
+        }
+        self.push_backlog(&target, message.clone());
+        self.add_message(message, &target_channel(&target)).await
+    }
+
+    async fn recv_batch(&self, msg: IrcMessage) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let reference = msg.param(0);
+        if let Some(reference) = reference.strip_prefix('+') {
+            if msg.param(1).ends_with("chathistory") {
+                self.batches
+                    .lock()
+                    .unwrap()
+                    .insert(reference.into(), (msg.param(2).into(), vec![]));
+            }
+        } else if let Some(reference) = reference.strip_prefix('-') {
+            let batch = self.batches.lock().unwrap().remove(reference);
+            if let Some((target, messages)) = batch {
+                self.backlog
+                    .lock()
+                    .unwrap()
+                    .insert(target.clone(), messages);
+                if self.is_current(&target) {
+                    self.tx_ui
+                        .send(UIEvent::UpdateMessages(self.render_backlog(&target)))
+                        .await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn recv_membership(&self, msg: IrcMessage) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let nick = msg.nick().unwrap_or_default().to_string();
+        let (channel, who) = match &msg.command[..] {
+            "KICK" => (msg.param(0), msg.param(1).to_string()),
+            _ => (msg.param(0), nick),
+        };
+        let own = who == self.nick();
+        match (&msg.command[..], own) {
+            ("JOIN", true) => {
+                self.joined.lock().unwrap().insert(channel.into());
+                self.update_channels().await?;
+                if self.is_current(channel) && self.has_history() {
+                    self.writer
+                        .chathistory_latest(channel, HISTORY_SIZE)
+                        .await?;
+                }
+            }
+            ("JOIN", false) => {
+                self.members
+                    .lock()
+                    .unwrap()
+                    .entry(channel.into())
+                    .or_default()
+                    .insert(who);
+                self.update_users(channel).await?;
+            }
+            (_, true) => {
+                self.joined.lock().unwrap().remove(channel);
+                self.members.lock().unwrap().remove(channel);
+                self.update_channels().await?;
+            }
+            (_, false) => {
+                if let Some(members) = self.members.lock().unwrap().get_mut(channel) {
+                    members.remove(&who);
+                }
+                self.update_users(channel).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn recv_nick(&self, msg: IrcMessage) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let old = msg.nick().unwrap_or_default().to_string();
+        let new = msg.param(0).to_string();
+        if old == self.nick() {
+            *self.nick.lock().unwrap() = new.clone();
+        }
+        let mut renamed = vec![];
+        for (channel, members) in self.members.lock().unwrap().iter_mut() {
+            if members.remove(&old) {
+                members.insert(new.clone());
+                renamed.push(channel.clone());
+            }
+        }
+        for channel in renamed {
+            self.update_users(&channel).await?;
+        }
+        Ok(())
+    }
+
+    async fn recv_quit(&self, msg: IrcMessage) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let nick = msg.nick().unwrap_or_default().to_string();
+        let mut left = vec![];
+        for (channel, members) in self.members.lock().unwrap().iter_mut() {
+            if members.remove(&nick) {
+                left.push(channel.clone());
+            }
+        }
+        for channel in left {
+            self.update_users(&channel).await?;
+        }
+        Ok(())
+    }
+
+    async fn wait_messages_loop(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        loop {
+            let line = self.rx_irc.recv().await?;
+            let msg = match IrcMessage::parse(&line) {
+                Some(msg) => msg,
+                None => continue,
+            };
+            match &msg.command[..] {
+                "PING" => self.writer.pong(msg.param(0)).await?,
+                "PRIVMSG" => self.recv_privmsg(msg).await?,
+                "BATCH" => self.recv_batch(msg).await?,
+                "JOIN" | "PART" | "KICK" => self.recv_membership(msg).await?,
+                "NICK" => self.recv_nick(msg).await?,
+                "QUIT" => self.recv_quit(msg).await?,
+                // RPL_NAMREPLY
+                "353" => {
+                    let nicks = msg
+                        .param(3)
+                        .split(' ')
+                        .filter(|x| !x.is_empty())
+                        .map(|x| x.trim_start_matches(['~', '&', '@', '%', '+']).to_string());
+                    self.names
+                        .lock()
+                        .unwrap()
+                        .entry(msg.param(2).into())
+                        .or_default()
+                        .extend(nicks);
+                }
+                // RPL_ENDOFNAMES
+                "366" => {
+                    let channel = msg.param(1);
+                    let names = self.names.lock().unwrap().remove(channel);
+                    self.members
+                        .lock()
+                        .unwrap()
+                        .insert(channel.into(), names.unwrap_or_default());
+                    self.update_users(channel).await?;
+                }
+                "FAIL" => error!("IRC command failed: {}", line),
+                "ERROR" => {
+                    return Err(
+                        format!("IRC server closed the connection: {}", msg.param(0)).into(),
+                    )
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Handle an event of the UI, its errors are reported to the user.
+    async fn ui_event(&self, event: ChatEvent) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match event {
+            ChatEvent::SendMessage(message, channel) => {
+                let split = message.split(' ').collect::<Vec<&str>>();
+                match split[0] {
+                    "/join" if split.len() > 1 => {
+                        self.init_view(Some(Channel::Group(split[1].into()))).await
+                    }
+                    "/part" => {
+                        let target = format!("{}", channel);
+                        self.writer.part(split.get(1).unwrap_or(&&target[..])).await
+                    }
+                    "/direct" | "/query" if split.len() > 1 => {
+                        self.init_view(Some(Channel::User(split[1].into()))).await
+                    }
+                    "/notify" => {
+                        let target = format!("{}", channel);
+                        let args = message["/notify".len()..].trim();
+                        let info = self
+                            .notifier
+                            .configure(&channel, &target, args)
+                            .unwrap_or_else(|err| err);
+                        self.tx_ui.send(UIEvent::ShowInfo(info)).await?;
+                        Ok(())
+                    }
+                    _ => self.send_message(message, channel).await,
+                }
+            }
+            ChatEvent::Init(channel) => self.init_view(Some(channel)).await,
+            ChatEvent::DirectChat(user) => self.init_view(Some(Channel::User(user))).await,
+        }
+    }
+
+    async fn ui_event_loop(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        loop {
+            let event = self.rx_chat.recv().await?;
+            if let Err(err) = self.ui_event(event).await {
+                error!("{}", err);
+                self.tx_ui
+                    .send(UIEvent::ShowInfo(format!("{}", err)))
+                    .await?;
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Chat for Irc {
+    async fn init_view(
+        &self,
+        channel: Option<Channel>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if !self.autojoin.is_empty() && !self.autojoined.swap(true, Ordering::SeqCst) {
+            self.writer.join(&self.autojoin).await?;
+        }
+        let channel = match channel {
+            Some(channel) => channel,
+            None => {
+                let joined = self.joined.lock().unwrap().iter().next().cloned();
+                match joined.or_else(|| self.autojoin.first().cloned()) {
+                    Some(channel) => Channel::Group(channel),
+                    None => return self.update_channels().await,
+                }
+            }
+        };
+        let target = format!("{}", channel);
+        match channel {
+            Channel::User(_) => self.open_query(&target).await?,
+            _ if !is_channel(&target) => {
+                return Err(format!("{} isn't an IRC channel", target).into());
+            }
+            _ => {}
+        }
+        let joined = self.joined.lock().unwrap().contains(&target);
+        if is_channel(&target) && !joined && !self.autojoin.contains(&target) {
+            self.writer.join(std::slice::from_ref(&target)).await?;
+        }
+        let channel = target_channel(&target);
+        *self.current_channel.lock().unwrap() = Some(channel.clone());
+        self.update_channels().await?;
+        self.tx_ui
+            .send(UIEvent::UpdateMessages(self.render_backlog(&target)))
+            .await?;
+        self.update_users(&target).await?;
+        self.tx_ui.send(UIEvent::SelectChannel(channel)).await?;
+        if joined {
+            self.writer.names(&target).await?;
+        }
+        if (joined || !is_channel(&target)) && self.has_history() {
+            self.writer
+                .chathistory_latest(&target, HISTORY_SIZE)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn send_message(
+        &self,
+        content: String,
+        channel: Channel,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let target = format!("{}", channel);
+        let lines = self.writer.privmsg(&target, &content).await?;
+        if self.caps.contains("echo-message") {
+            return Ok(());
+        }
+        // Without echo-message the server never sends our messages back.
+        for line in lines {
+            let message = Message {
+                id: String::new(),
+                author: self.nick(),
+                content: line.to_string(),
+                datetime: Utc::now(),
+                thread: None,
+                attachments: vec![],
+            };
+            self.push_backlog(&target, message.clone());
+            self.add_message(message, &channel).await?;
+        }
+        Ok(())
+    }
+
+    async fn start_loop(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let read_loop = self.wait_messages_loop();
+        let ui_loop = self.ui_event_loop();
+        tokio::select! {
+            result = read_loop => result,
+            result = ui_loop => result,
+        }
+    }
+
+    async fn add_message(
+        &self,
+        message: Message,
+        channel: &Channel,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let current_channel = self.current_channel.lock().unwrap().clone();
+        if let Some(current) = current_channel.as_ref() {
+            if channel == current {
+                self.tx_ui.send(UIEvent::AddMessages(message)).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    type Script = Vec<(&'static str, Vec<&'static str>)>;
+
+    /// A scripted IRC server accepting a single client.
+    ///
+    /// Each line received is answered by the responses of the first
+    /// rule it starts with, tests can also push lines at any time.
+    struct ScriptedServer {
+        url: Url,
+        received: Receiver<String>,
+        tx_server: Sender<String>,
+    }
+
+    impl ScriptedServer {
+        async fn start(script: Script) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = Url::parse(&format!("irc://{}", listener.local_addr().unwrap())).unwrap();
+            let (tx_received, received) = unbounded();
+            let (tx_server, rx_server) = unbounded::<String>();
+            tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (read, mut write) = tokio::io::split(stream);
+                let mut lines = BufReader::new(read).lines();
+                loop {
+                    let responses = tokio::select! {
+                        line = lines.next_line() => match line {
+                            Ok(Some(line)) => {
+                                let responses = script
+                                    .iter()
+                                    .find(|(prefix, _)| line.starts_with(prefix))
+                                    .map(|(_, responses)| responses.iter().map(|x| x.to_string()).collect())
+                                    .unwrap_or_default();
+                                tx_received.send(line).await.unwrap();
+                                responses
+                            }
+                            _ => break,
+                        },
+                        line = rx_server.recv() => vec![line.unwrap()],
+                    };
+                    for response in responses {
+                        write
+                            .write_all(format!("{}\r\n", response).as_bytes())
+                            .await
+                            .unwrap();
+                    }
+                }
+            });
+            ScriptedServer {
+                url,
+                received,
+                tx_server,
+            }
+        }
+
+        async fn expect(&self, prefix: &str) -> String {
+            let wait = async {
+                loop {
+                    let line = self.received.recv().await.unwrap();
+                    if line.starts_with(prefix) {
+                        break line;
+                    }
+                }
+            };
+            tokio::time::timeout(Duration::from_secs(5), wait)
+                .await
+                .unwrap_or_else(|_| panic!("Client never sent {}", prefix))
+        }
+
+        async fn push(&self, line: &str) {
+            self.tx_server.send(line.into()).await.unwrap();
+        }
+    }
+
+    fn registration() -> Script {
+        vec![
+            (
+                "CAP LS",
+                vec![":irc.test CAP * LS :sasl server-time batch draft/chathistory message-tags"],
+            ),
+            (
+                "CAP REQ",
+                vec![":irc.test CAP * ACK :server-time message-tags batch draft/chathistory sasl"],
+            ),
+            ("AUTHENTICATE PLAIN", vec!["AUTHENTICATE +"]),
+            (
+                "AUTHENTICATE bG91AGxvdQBzZWNyZXQ=",
+                vec![
+                    ":irc.test 900 lou lou!lou@host lou :You are now logged in as lou",
+                    ":irc.test 903 lou :SASL authentication successful",
+                ],
+            ),
+            (
+                "CAP END",
+                vec![":irc.test 001 lou :Welcome to the test network lou"],
+            ),
+            (
+                "JOIN #ops",
+                vec![
+                    ":lou!lou@host JOIN #ops",
+                    ":irc.test 353 lou = #ops :@bob lou",
+                    ":irc.test 366 lou #ops :End of /NAMES list.",
+                ],
+            ),
+            (
+                "CHATHISTORY LATEST #ops",
+                vec![
+                    ":irc.test BATCH +h1 chathistory #ops",
+                    "@batch=h1;time=2020-07-01T07:49:10.164Z;msgid=m1 :bob!bob@host PRIVMSG #ops :is the deploy done?",
+                    "@batch=h1;time=2020-07-01T07:49:20.164Z;msgid=m2 :lou!lou@host PRIVMSG #ops :yes",
+                    ":irc.test BATCH -h1",
+                ],
+            ),
+        ]
+    }
+
+    struct FakeNotifier;
+    impl Notification for FakeNotifier {
+        fn notify(&self, _title: &str, _content: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+            Ok(())
+        }
+    }
+
+    async fn create_chat_system(
+        script: Script,
+    ) -> Result<
+        (ScriptedServer, Irc, Receiver<UIEvent>, Sender<ChatEvent>),
+        Box<dyn Error + Send + Sync>,
+    > {
+        let server = ScriptedServer::start(script).await;
+        let (tx_ui, rx_ui) = unbounded();
+        let (tx_chat, rx_chat) = unbounded();
+        let chat = Irc::new(
+            server.url.clone(),
+            "lou".into(),
+            "secret".into(),
+            TlsConfig::default(),
+            tx_ui,
+            rx_chat,
+            Box::new(FakeNotifier {}),
+        )
+        .await?;
+        Ok((server, chat, rx_ui, tx_chat))
+    }
+
+    async fn recv_until<F>(rx_ui: &Receiver<UIEvent>, filter: F) -> UIEvent
+    where
+        F: Fn(&UIEvent) -> bool,
+    {
+        let wait = async {
+            loop {
+                let event = rx_ui.recv().await.unwrap();
+                if filter(&event) {
+                    break event;
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), wait)
+            .await
+            .expect("Expected UI event never received")
+    }
+
+    #[tokio::test]
+    async fn test_register_sasl() {
+        let (server, chat, _, _) = create_chat_system(registration()).await.unwrap();
+        server.expect("AUTHENTICATE bG91AGxvdQBzZWNyZXQ=").await;
+        assert_eq!(chat.nick(), "lou");
+        assert!(chat.has_history());
+        assert!(!chat.caps.contains("echo-message"));
+    }
+
+    #[tokio::test]
+    async fn test_register_sasl_failure() {
+        let mut script = registration();
+        script[3].1 = vec![":irc.test 904 lou :SASL authentication failed"];
+        let err = create_chat_system(script).await.err().unwrap();
+        assert_eq!(
+            format!("{}", err),
+            "SASL authentication failed: SASL authentication failed"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_register_pass() {
+        let mut script = registration();
+        script[0].1 = vec![":irc.test CAP * LS :server-time"];
+        script[1].1 = vec![":irc.test CAP * ACK :server-time"];
+        let (server, _, _, _) = create_chat_system(script).await.unwrap();
+        assert_eq!(server.expect("PASS").await, "PASS :secret");
+        server.expect("NICK lou").await;
+        server.expect("CAP END").await;
+
+        // A server without capabilities
+        let script = vec![
+            ("CAP LS", vec![":irc.test 421 * CAP :Unknown command"]),
+            (
+                "USER",
+                vec![":irc.test 001 lou :Welcome to the test network lou"],
+            ),
+        ];
+        let (server, _, _, _) = create_chat_system(script).await.unwrap();
+        server.expect("PASS :secret").await;
+        server.expect("NICK lou").await;
+    }
+
+    #[tokio::test]
+    async fn test_register_sasl_refused() {
+        let mut script = registration();
+        script[1].1 =
+            vec![":irc.test CAP * NAK :server-time message-tags batch draft/chathistory sasl"];
+        let err = create_chat_system(script).await.err().unwrap();
+        assert_eq!(
+            format!("{}", err),
+            "The IRC server refused SASL, can't authenticate"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_init_view() {
+        let (server, chat, rx_ui, _) = create_chat_system(registration()).await.unwrap();
+        let chat = chat.with_channels(vec!["#ops".into()]);
+        assert!(chat
+            .init_view(Some(Channel::Group("GENERAL".into())))
+            .await
+            .is_err());
+        chat.init_view(None).await.unwrap();
+        server.expect("JOIN #ops").await;
+        let events = async {
+            let channels = recv_until(
+                &rx_ui,
+                |x| matches!(x, UIEvent::UpdateChannels(channels) if !channels.is_empty()),
+            )
+            .await;
+            assert_eq!(
+                channels,
+                UIEvent::UpdateChannels(vec![("#ops".into(), Channel::Group("#ops".into()))])
+            );
+            let users = recv_until(
+                &rx_ui,
+                |x| matches!(x, UIEvent::UpdateUsersInRoom(users) if !users.is_empty()),
+            )
+            .await;
+            assert_eq!(
+                users,
+                UIEvent::UpdateUsersInRoom(vec![
+                    ("bob".into(), "bob".into()),
+                    ("lou".into(), "lou".into())
+                ])
+            );
+            let history = recv_until(
+                &rx_ui,
+                |x| matches!(x, UIEvent::UpdateMessages(messages) if !messages.is_empty()),
+            )
+            .await;
+            let expected = [
+                Message {
+                    id: "m1".into(),
+                    author: "bob".into(),
+                    content: "is the deploy done?".into(),
+                    datetime: Utc.timestamp_millis(1593589750164),
+                    thread: None,
+                    attachments: vec![],
+                },
+                Message {
+                    id: "m2".into(),
+                    author: "lou".into(),
+                    content: "yes".into(),
+                    datetime: Utc.timestamp_millis(1593589760164),
+                    thread: None,
+                    attachments: vec![],
+                },
+            ];
+            assert_eq!(
+                history,
+                UIEvent::UpdateMessages(format!("{}\n{}\n", expected[0], expected[1]))
+            );
+        };
+        tokio::select! {
+            _ = chat.wait_messages_loop() => panic!("Abnormal"),
+            _ = events => {},
+        }
+    }
+
+    #[tokio::test]
+    async fn test_recv_privmsg() {
+        let (server, chat, rx_ui, _) = create_chat_system(registration()).await.unwrap();
+        *chat.current_channel.lock().unwrap() = Some(Channel::Group("#ops".into()));
+        server
+            .push(":bob!bob@host PRIVMSG #ops :\x01ACTION waves\x01")
+            .await;
+        server.push(":carol!carol@host PRIVMSG lou :hi lou").await;
+        let events = async {
+            let message = recv_until(&rx_ui, |x| matches!(x, UIEvent::AddMessages(_))).await;
+            match message {
+                UIEvent::AddMessages(message) => {
+                    assert_eq!(message.author, "bob");
+                    assert_eq!(message.content, "* waves");
+                }
+                _ => unreachable!(),
+            }
+            let channels = recv_until(&rx_ui, |x| matches!(x, UIEvent::UpdateChannels(_))).await;
+            assert_eq!(
+                channels,
+                UIEvent::UpdateChannels(vec![("carol".into(), Channel::User("carol".into()))])
+            );
+        };
+        tokio::select! {
+            _ = chat.wait_messages_loop() => panic!("Abnormal"),
+            _ = events => {},
+        }
+        assert_eq!(chat.render_backlog("carol").lines().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_send_message() {
+        let (server, chat, rx_ui, _) = create_chat_system(registration()).await.unwrap();
+        *chat.current_channel.lock().unwrap() = Some(Channel::Group("#ops".into()));
+        chat.send_message("hello\nworld".into(), Channel::Group("#ops".into()))
+            .await
+            .unwrap();
+        assert_eq!(server.expect("PRIVMSG").await, "PRIVMSG #ops :hello");
+        assert_eq!(server.expect("PRIVMSG").await, "PRIVMSG #ops :world");
+        match rx_ui.recv().await.unwrap() {
+            UIEvent::AddMessages(message) => {
+                assert_eq!(message.author, "lou");
+                assert_eq!(message.content, "hello");
+            }
+            event => panic!("Unexpected event {:?}", event),
+        }
+        rx_ui.recv().await.unwrap();
+        // Long lines are echoed as the chunks sent to the server
+        chat.send_message("a".repeat(1000), Channel::Group("#ops".into()))
+            .await
+            .unwrap();
+        let mut echoed = String::new();
+        while echoed.len() < 1000 {
+            let line = server.expect("PRIVMSG").await;
+            match rx_ui.recv().await.unwrap() {
+                UIEvent::AddMessages(message) => {
+                    assert_eq!(line, format!("PRIVMSG #ops :{}", message.content));
+                    echoed.push_str(&message.content);
+                }
+                event => panic!("Unexpected event {:?}", event),
+            }
+        }
+        assert_eq!(echoed, "a".repeat(1000));
+    }
+
+    #[tokio::test]
+    async fn test_ui_event_error() {
+        let (server, chat, rx_ui, tx_chat) = create_chat_system(registration()).await.unwrap();
+        tx_chat
+            .send(ChatEvent::Init(Channel::Group("GENERAL".into())))
+            .await
+            .unwrap();
+        tx_chat
+            .send(ChatEvent::SendMessage(
+                "hello".into(),
+                Channel::Group("#ops".into()),
+            ))
+            .await
+            .unwrap();
+        let events = async {
+            let info = recv_until(&rx_ui, |x| matches!(x, UIEvent::ShowInfo(_))).await;
+            assert_eq!(
+                info,
+                UIEvent::ShowInfo("GENERAL isn't an IRC channel".into())
+            );
+            // The loop goes on after the error
+            assert_eq!(server.expect("PRIVMSG").await, "PRIVMSG #ops :hello");
+        };
+        tokio::select! {
+            _ = chat.ui_event_loop() => panic!("Abnormal"),
+            _ = events => {},
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ping() {
+        let (server, chat, _, _) = create_chat_system(registration()).await.unwrap();
+        server.push("PING :irc.test").await;
+        tokio::select! {
+            _ = chat.wait_messages_loop() => panic!("Abnormal"),
+            line = server.expect("PONG") => assert_eq!(line, "PONG :irc.test"),
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+
+/// A parsed IRC line.
+///
+/// See the message format of RFC 1459 extended with IRCv3 message tags.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IrcMessage {
+    pub tags: HashMap<String, String>,
+    pub prefix: Option<String>,
+    pub command: String,
+    pub params: Vec<String>,
+}
+
+fn unescape_tag(value: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(':') => unescaped.push(';'),
+            Some('s') => unescaped.push(' '),
+            Some('r') => unescaped.push('\r'),
+            Some('n') => unescaped.push('\n'),
+            Some(c) => unescaped.push(c),
+            None => {}
+        }
+    }
+    unescaped
+}
+
+impl IrcMessage {
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut rest = line.trim_end_matches(['\r', '\n']);
+        let mut tags = HashMap::new();
+        if let Some(tagged) = rest.strip_prefix('@') {
+            let (raw_tags, remaining) = tagged.split_once(' ')?;
+            for tag in raw_tags.split(';') {
+                let (key, value) = tag.split_once('=').unwrap_or((tag, ""));
+                tags.insert(key.to_string(), unescape_tag(value));
+            }
+            rest = remaining.trim_start();
+        }
+        let mut prefix = None;
+        if let Some(prefixed) = rest.strip_prefix(':') {
+            let (source, remaining) = prefixed.split_once(' ')?;
+            prefix = Some(source.to_string());
+            rest = remaining.trim_start();
+        }
+        let (middle, trailing) = match rest.split_once(" :") {
+            Some((middle, trailing)) => (middle, Some(trailing)),
+            None => (rest, None),
+        };
+        let mut words = middle.split(' ').filter(|x| !x.is_empty());
+        let command = words.next()?.to_uppercase();
+        let mut params = words.map(String::from).collect::<Vec<String>>();
+        if let Some(trailing) = trailing {
+            params.push(trailing.to_string());
+        }
+        Some(IrcMessage {
+            tags,
+            prefix,
+            command,
+            params,
+        })
+    }
+
+    /// The nickname of the source, `lou` for `lou!user@host`.
+    pub fn nick(&self) -> Option<&str> {
+        self.prefix
+            .as_deref()
+            .map(|x| x.split('!').next().unwrap_or(x))
+    }
+
+    pub fn param(&self, index: usize) -> &str {
+        self.params.get(index).map(|x| &x[..]).unwrap_or("")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_privmsg() {
+        let message = IrcMessage::parse(
+            "@time=2020-07-01T07:49:10.164Z;msgid=abc\\s1 :bob!bob@host PRIVMSG #ops :hello :)\r\n",
+        )
+        .unwrap();
+        assert_eq!(message.tags["time"], "2020-07-01T07:49:10.164Z");
+        assert_eq!(message.tags["msgid"], "abc 1");
+        assert_eq!(message.nick(), Some("bob"));
+        assert_eq!(message.command, "PRIVMSG");
+        assert_eq!(message.params, vec!["#ops", "hello :)"]);
+    }
+
+    #[test]
+    fn test_parse_without_prefix() {
+        let message = IrcMessage::parse("PING :irc.test").unwrap();
+        assert_eq!(message.prefix, None);
+        assert_eq!(message.command, "PING");
+        assert_eq!(message.params, vec!["irc.test"]);
+        let message = IrcMessage::parse(":irc.test 353 lou = #ops :@bob lou").unwrap();
+        assert_eq!(message.params, vec!["lou", "=", "#ops", "@bob lou"]);
+        assert_eq!(IrcMessage::parse(""), None);
+    }
+}
@@ -0,0 +1,219 @@
+use super::super::tls::TlsConfig;
+use super::schema::*;
+use reqwest::{Client, Method, RequestBuilder, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use std::error::Error;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use url::Url;
+
+/// Filter used for every sync, it keeps the initial sync small.
+const SYNC_FILTER: &str =
+    r#"{"room":{"timeline":{"limit":50},"state":{"lazy_load_members":true}}}"#;
+
+/// Read the answer of a request, or its error.
+async fn read<T: DeserializeOwned>(response: Response) -> Result<T, Box<dyn Error + Send + Sync>> {
+    if !response.status().is_success() {
+        let status = response.status();
+        let err = match response.json::<ErrorResponseMx>().await {
+            Ok(err) => format!("{}: {}", err.errcode, err.error.unwrap_or_default()),
+            Err(_) => format!("Matrix request failed: {}", status),
+        };
+        return Err(err.into());
+    }
+    Ok(response.json::<T>().await?)
+}
+
+/// Matrix client-server API client.
+pub struct MatrixClient {
+    http: Client,
+    homeserver: Url,
+    access_token: String,
+    pub user_id: String,
+    transaction: AtomicU64,
+}
+
+impl MatrixClient {
+    /// Log in with a password and return an authenticated client.
+    pub async fn login(
+        homeserver: Url,
+        username: &str,
+        password: &str,
+        tls: &TlsConfig,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let http = tls
+            .http_client(Client::builder())?
+            .timeout(Duration::from_secs(90))
+            .build()?;
+        let mut client = MatrixClient {
+            http,
+            homeserver,
+            access_token: String::new(),
+            user_id: String::new(),
+            transaction: AtomicU64::new(0),
+        };
+        let login = LoginMx {
+            kind: "m.login.password".into(),
+            identifier: IdentifierMx {
+                kind: "m.id.user".into(),
+                user: username.into(),
+            },
+            password: password.into(),
+            initial_device_display_name: "talkoxid".into(),
+        };
+        let response: LoginResponseMx = client
+            .send(client.request(Method::POST, &["login"]).json(&login))
+            .await?;
+        client.access_token = response.access_token;
+        client.user_id = response.user_id;
+        Ok(client)
+    }
+
+    fn endpoint(&self, segments: &[&str]) -> Url {
+        let mut url = self.homeserver.clone();
+        url.path_segments_mut()
+            .map(|mut path| {
+                path.pop_if_empty()
+                    .extend(&["_matrix", "client", "v3"])
+                    .extend(segments);
+            })
+            .ok();
+        url
+    }
+
+    fn request(&self, method: Method, segments: &[&str]) -> RequestBuilder {
+        let request = self.http.request(method, self.endpoint(segments));
+        if self.access_token.is_empty() {
+            request
+        } else {
+            request.bearer_auth(&self.access_token)
+        }
+    }
+
+    async fn send<T: DeserializeOwned>(
+        &self,
+        request: RequestBuilder,
+    ) -> Result<T, Box<dyn Error + Send + Sync>> {
+        read(request.send().await?).await
+    }
+
+    /// Long poll the events that happened since the `since` token.
+    pub async fn sync(
+        &self,
+        since: Option<&str>,
+        timeout: Duration,
+    ) -> Result<SyncResponseMx, Box<dyn Error + Send + Sync>> {
+        let mut query = vec![
+            ("filter", SYNC_FILTER.to_string()),
+            ("timeout", timeout.as_millis().to_string()),
+        ];
+        if let Some(since) = since {
+            query.push(("since", since.into()));
+        }
+        self.send(self.request(Method::GET, &["sync"]).query(&query))
+            .await
+    }
+
+    /// Fetch the last messages of a room, newest first.
+    pub async fn messages(
+        &self,
+        room_id: &str,
+        limit: usize,
+    ) -> Result<MessagesResponseMx, Box<dyn Error + Send + Sync>> {
+        self.send(
+            self.request(Method::GET, &["rooms", room_id, "messages"])
+                .query(&[("dir", "b"), ("limit", &limit.to_string())]),
+        )
+        .await
+    }
+
+    pub async fn joined_members(
+        &self,
+        room_id: &str,
+    ) -> Result<JoinedMembersResponseMx, Box<dyn Error + Send + Sync>> {
+        self.send(self.request(Method::GET, &["rooms", room_id, "joined_members"]))
+            .await
+    }
+
+    /// Send a `m.room.message` text event and return its id.
+    pub async fn send_message(
+        &self,
+        room_id: &str,
+        body: String,
+    ) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let transaction = format!(
+            "talkoxid-{}-{}",
+            chrono::Utc::now().timestamp_millis(),
+            self.transaction.fetch_add(1, Ordering::SeqCst)
+        );
+        let message = TextMessageMx {
+            msgtype: "m.text".into(),
+            body,
+        };
+        let response: EventIdResponseMx = self
+            .send(
+                self.request(
+                    Method::PUT,
+                    &["rooms", room_id, "send", "m.room.message", &transaction],
+                )
+                .json(&message),
+            )
+            .await?;
+        Ok(response.event_id)
+    }
+
+    /// Create a direct chat with an user and return the room id.
+    pub async fn create_direct_chat(
+        &self,
+        user_id: String,
+    ) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let room = CreateRoomMx {
+            is_direct: true,
+            invite: vec![user_id],
+            preset: "trusted_private_chat".into(),
+        };
+        let response: RoomIdResponseMx = self
+            .send(self.request(Method::POST, &["createRoom"]).json(&room))
+            .await?;
+        Ok(response.room_id)
+    }
+
+    /// Add `room_id` to the direct chats with `user_id` in the `m.direct`
+    /// account data, so that every client lists it as a direct chat.
+    pub async fn add_direct_room(
+        &self,
+        user_id: &str,
+        room_id: &str,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let path = ["user", &self.user_id, "account_data", "m.direct"];
+        let response = self.request(Method::GET, &path).send().await?;
+        let mut direct = match response.status() {
+            // Nobody started a direct chat yet
+            StatusCode::NOT_FOUND => serde_json::Map::new(),
+            _ => read::<serde_json::Map<String, serde_json::Value>>(response).await?,
+        };
+        let rooms = direct
+            .entry(user_id)
+            .or_insert_with(|| serde_json::Value::Array(vec![]));
+        match rooms.as_array_mut() {
+            Some(rooms) if rooms.iter().any(|x| x == room_id) => return Ok(()),
+            Some(rooms) => rooms.push(room_id.into()),
+            None => *rooms = serde_json::json!([room_id]),
+        }
+        self.send::<serde_json::Value>(self.request(Method::PUT, &path).json(&direct))
+            .await?;
+        Ok(())
+    }
+
+    /// Resolve a `mxc://` content uri to a download link.
+    pub fn media_url(&self, mxc: &str) -> Option<String> {
+        let media = mxc.strip_prefix("mxc://")?;
+        let mut url = self.homeserver.clone();
+        url.path_segments_mut()
+            .ok()?
+            .pop_if_empty()
+            .extend(&["_matrix", "media", "v3", "download"])
+            .extend(media.split('/'));
+        Some(url.to_string())
+    }
+}
@@ -0,0 +1,803 @@
+mod api;
+mod schema;
+
+use super::super::core::{
+    Alert, Attachment, Channel, Chat, ChatEvent, Message, Notification, UIEvent,
+};
+use super::tls::TlsConfig;
+use api::MatrixClient;
+use async_channel::{Receiver, Sender};
+use async_trait::async_trait;
+use chrono::{TimeZone, Utc};
+use log::{error, warn};
+use schema::*;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::sync::Mutex;
+use std::time::Duration;
+use url::Url;
+
+/// How long the homeserver holds a sync request when nothing happens.
+const SYNC_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long we wait before syncing again after an error.
+const SYNC_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Room metadata gathered from state events across syncs.
+#[derive(Default, Clone, Debug)]
+struct RoomInfo {
+    name: Option<String>,
+    alias: Option<String>,
+    heroes: Vec<String>,
+    public: bool,
+}
+
+/// The local part of an user id or room alias, `lou` for `@lou:matrix.org`.
+fn localpart(user_id: &str) -> &str {
+    user_id
+        .trim_start_matches(['@', '#'])
+        .split(':')
+        .next()
+        .unwrap_or(user_id)
+}
+
+impl RoomInfo {
+    fn update(&mut self, room: &JoinedRoomMx) {
+        if !room.summary.heroes.is_empty() {
+            self.heroes = room.summary.heroes.clone();
+        }
+        let state_events = room
+            .state
+            .events
+            .iter()
+            .chain(room.timeline.events.iter())
+            .filter(|x| x.state_key.is_some());
+        for event in state_events {
+            match &event.kind[..] {
+                "m.room.name" => self.name = event.content["name"].as_str().map(String::from),
+                "m.room.canonical_alias" => {
+                    self.alias = event.content["alias"].as_str().map(String::from)
+                }
+                "m.room.join_rules" => self.public = event.content["join_rule"] == "public",
+                _ => {}
+            }
+        }
+    }
+
+    fn display_name(&self, room_id: &str, user_id: &str) -> String {
+        if let Some(name) = self.name.as_ref().filter(|x| !x.is_empty()) {
+            return name.clone();
+        }
+        if let Some(alias) = self.alias.as_ref() {
+            return localpart(alias).to_string();
+        }
+        let heroes = self
+            .heroes
+            .iter()
+            .filter(|x| *x != user_id)
+            .map(|x| localpart(x))
+            .collect::<Vec<&str>>();
+        if heroes.is_empty() {
+            room_id.to_string()
+        } else {
+            heroes.join(",")
+        }
+    }
+}
+
+/// Matrix chat system.
+///
+/// This type is a chat system implementation for the Matrix client-server API.
+pub struct Matrix {
+    tx_ui: Sender<UIEvent>,
+    client: MatrixClient,
+    notifier: Box<dyn Notification + Sync + Send>,
+    rx_chat: Receiver<ChatEvent>,
+    current_channel: Mutex<Option<Channel>>,
+    rooms: Mutex<HashMap<String, RoomInfo>>,
+    direct_rooms: Mutex<HashSet<String>>,
+    next_batch: Mutex<Option<String>>,
+}
+
+impl Matrix {
+    pub async fn new(
+        host: Url,
+        username: String,
+        password: String,
+        tls: TlsConfig,
+        tx_ui: Sender<UIEvent>,
+        rx_chat: Receiver<ChatEvent>,
+        notifier: Box<dyn Notification + Sync + Send>,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let client = MatrixClient::login(host, &username, &password, &tls).await?;
+        let chat = Matrix {
+            tx_ui,
+            client,
+            notifier,
+            rx_chat,
+            current_channel: Mutex::new(None),
+            rooms: Mutex::new(HashMap::new()),
+            direct_rooms: Mutex::new(HashSet::new()),
+            next_batch: Mutex::new(None),
+        };
+        let initial = chat.client.sync(None, Duration::from_secs(0)).await?;
+        chat.update_rooms(&initial);
+        *chat.next_batch.lock().unwrap() = Some(initial.next_batch);
+        Ok(chat)
+    }
+
+    fn channel(&self, room_id: &str) -> Channel {
+        let public = self
+            .rooms
+            .lock()
+            .unwrap()
+            .get(room_id)
+            .map(|x| x.public)
+            .unwrap_or(false);
+        if self.direct_rooms.lock().unwrap().contains(room_id) {
+            Channel::User(room_id.into())
+        } else if public {
+            Channel::Group(room_id.into())
+        } else {
+            Channel::Private(room_id.into())
+        }
+    }
+
+    fn channel_name(&self, room_id: &str) -> String {
+        self.rooms
+            .lock()
+            .unwrap()
+            .get(room_id)
+            .map(|x| x.display_name(room_id, &self.client.user_id))
+            .unwrap_or_else(|| room_id.to_string())
+    }
+
+    fn channels(&self) -> Vec<(String, Channel)> {
+        let rooms = self.rooms.lock().unwrap().clone();
+        rooms
+            .iter()
+            .map(|(room_id, info)| {
+                (
+                    info.display_name(room_id, &self.client.user_id),
+                    self.channel(room_id),
+                )
+            })
+            .collect()
+    }
+
+    /// Merge the rooms of a sync response, return whether the room list changed.
+    fn update_rooms(&self, sync: &SyncResponseMx) -> bool {
+        let mut changed = false;
+        for event in sync.account_data.events.iter() {
+            if event.kind == "m.direct" {
+                if let Some(direct) = event.content.as_object() {
+                    let mut direct_rooms = self.direct_rooms.lock().unwrap();
+                    direct_rooms.clear();
+                    direct_rooms.extend(
+                        direct
+                            .values()
+                            .filter_map(|x| x.as_array())
+                            .flatten()
+                            .filter_map(|x| x.as_str().map(String::from)),
+                    );
+                    changed = true;
+                }
+            }
+        }
+        let mut rooms = self.rooms.lock().unwrap();
+        for (room_id, room) in sync.rooms.join.iter() {
+            let info = rooms.entry(room_id.clone()).or_insert_with(|| {
+                changed = true;
+                RoomInfo::default()
+            });
+            let before = format!("{:?}", info);
+            info.update(room);
+            changed |= before != format!("{:?}", info);
+        }
+        for room_id in sync.rooms.leave.keys() {
+            changed |= rooms.remove(room_id).is_some();
+        }
+        changed
+    }
+
+    fn to_message(&self, event: &EventMx) -> Option<Message> {
+        if event.kind != "m.room.message" {
+            return None;
+        }
+        let content = &event.content;
+        let body = content["body"].as_str()?.to_string();
+        let attachments = match (content["msgtype"].as_str(), content["url"].as_str()) {
+            (Some("m.file"), Some(url))
+            | (Some("m.image"), Some(url))
+            | (Some("m.video"), Some(url))
+            | (Some("m.audio"), Some(url)) => self
+                .client
+                .media_url(url)
+                .map(|link| {
+                    vec![Attachment {
+                        title: body.clone(),
+                        link,
+                    }]
+                })
+                .unwrap_or_default(),
+            _ => vec![],
+        };
+        let relation = &content["m.relates_to"];
+        let thread = match relation["rel_type"].as_str() {
+            Some("m.thread") => relation["event_id"].as_str().map(String::from),
+            _ => None,
+        };
+        Some(Message {
+            id: event.event_id.clone(),
+            author: localpart(&event.sender).to_string(),
+            content: body,
+            datetime: Utc.timestamp_millis(event.origin_server_ts),
+            thread,
+            attachments,
+        })
+    }
+
+    async fn handle_sync(&self, sync: SyncResponseMx) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if self.update_rooms(&sync) {
+            self.tx_ui
+                .send(UIEvent::UpdateChannels(self.channels()))
+                .await?;
+        }
+        let own_name = localpart(&self.client.user_id).to_string();
+        for (room_id, room) in sync.rooms.join.iter() {
+            let channel = self.channel(room_id);
+            for event in room.timeline.events.iter() {
+                let message = match self.to_message(event) {
+                    Some(message) => message,
+                    None => continue,
+                };
+                if event.sender != self.client.user_id {
+                    let channel_name = self.channel_name(room_id);
+                    let alert = Alert::new(channel.clone(), &channel_name, &message, &own_name);
+                    if let Err(err) = self.notifier.notify_message(&alert) {
+                        error!("Can't notify: {}", err);
+                    }
+                }
+                self.add_message(message, &channel).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn sync_loop(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        loop {
+            let since = self.next_batch.lock().unwrap().clone();
+            match self.client.sync(since.as_deref(), SYNC_TIMEOUT).await {
+                Ok(sync) => {
+                    *self.next_batch.lock().unwrap() = Some(sync.next_batch.clone());
+                    self.handle_sync(sync).await?;
+                }
+                Err(err) => {
+                    error!("Error when syncing with the homeserver: {}", err);
+                    tokio::time::sleep(SYNC_RETRY_DELAY).await;
+                }
+            }
+        }
+    }
+
+    async fn direct_chat(&self, user_id: String) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let room_id = self.client.create_direct_chat(user_id.clone()).await?;
+        if let Err(err) = self.client.add_direct_room(&user_id, &room_id).await {
+            warn!("Can't list {} as a direct chat: {}", room_id, err);
+        }
+        self.direct_rooms.lock().unwrap().insert(room_id.clone());
+        self.rooms
+            .lock()
+            .unwrap()
+            .entry(room_id.clone())
+            .or_default();
+        self.init_view(Some(Channel::User(room_id))).await
+    }
+
+    /// Handle an event of the UI, its errors are reported to the user.
+    async fn ui_event(&self, event: ChatEvent) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match event {
+            ChatEvent::SendMessage(message, channel) => {
+                let split = message.split(' ').collect::<Vec<&str>>();
+                if message.starts_with("/direct") && split.len() > 1 {
+                    self.direct_chat(split[1].into()).await
+                } else if split[0] == "/notify" {
+                    let channel_name = self.channel_name(&format!("{}", channel));
+                    let args = message["/notify".len()..].trim();
+                    let info = self
+                        .notifier
+                        .configure(&channel, &channel_name, args)
+                        .unwrap_or_else(|err| err);
+                    self.tx_ui.send(UIEvent::ShowInfo(info)).await?;
+                    Ok(())
+                } else {
+                    self.send_message(message, channel).await
+                }
+            }
+            ChatEvent::Init(channel) => self.init_view(Some(channel)).await,
+            ChatEvent::DirectChat(user) => self.direct_chat(user).await,
+        }
+    }
+
+    async fn ui_event_loop(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        loop {
+            let event = self.rx_chat.recv().await?;
+            if let Err(err) = self.ui_event(event).await {
+                error!("{}", err);
+                self.tx_ui
+                    .send(UIEvent::ShowInfo(format!("{}", err)))
+                    .await?;
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Chat for Matrix {
+    async fn init_view(
+        &self,
+        channel: Option<Channel>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let channels = self.channels();
+        self.tx_ui
+            .send(UIEvent::UpdateChannels(channels.clone()))
+            .await?;
+        let mut channels = channels;
+        channels.sort();
+        // The first public room
+        let first = channels
+            .iter()
+            .find(|x| matches!(x.1, Channel::Group(_)))
+            .or_else(|| channels.first())
+            .map(|(_, channel)| channel.clone());
+        let channel = match channel {
+            Some(channel) => {
+                let room_id = format!("{}", channel);
+                let known = self.rooms.lock().unwrap().contains_key(&room_id);
+                match Channel::find(&channels, &room_id) {
+                    _ if known => self.channel(&room_id),
+                    Some(channel) => channel.clone(),
+                    None => return Err(format!("Unknown Matrix room {}", room_id).into()),
+                }
+            }
+            None => match first {
+                Some(channel) => channel,
+                None => return Ok(()),
+            },
+        };
+        let room_id = format!("{}", channel);
+        let history = self.client.messages(&room_id, 100).await?;
+        let messages = history
+            .chunk
+            .iter()
+            .rev()
+            .filter_map(|x| self.to_message(x))
+            .fold(String::from(""), |x, y| format!("{}{}\n", x, y));
+        self.tx_ui.send(UIEvent::UpdateMessages(messages)).await?;
+        let members = self.client.joined_members(&room_id).await?;
+        let mut users = members
+            .joined
+            .into_iter()
+            .map(|(user_id, member)| {
+                (
+                    member
+                        .display_name
+                        .unwrap_or_else(|| localpart(&user_id).to_string()),
+                    user_id,
+                )
+            })
+            .collect::<Vec<(String, String)>>();
+        users.sort();
+        self.tx_ui.send(UIEvent::UpdateUsersInRoom(users)).await?;
+        self.tx_ui
+            .send(UIEvent::SelectChannel(channel.clone()))
+            .await?;
+        *self.current_channel.lock().unwrap() = Some(channel);
+        Ok(())
+    }
+
+    async fn send_message(
+        &self,
+        content: String,
+        channel: Channel,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.client
+            .send_message(&format!("{}", channel), content)
+            .await?;
+        Ok(())
+    }
+
+    async fn start_loop(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let sync_loop = self.sync_loop();
+        let ui_loop = self.ui_event_loop();
+        tokio::select! {
+            result = sync_loop => result,
+            result = ui_loop => result,
+        }
+    }
+
+    async fn add_message(
+        &self,
+        message: Message,
+        channel: &Channel,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let current_channel = self.current_channel.lock().unwrap().clone();
+        if let Some(current) = current_channel.as_ref() {
+            if channel == current {
+                self.tx_ui.send(UIEvent::AddMessages(message)).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chats::mock_server::{MockResponse, MockServer};
+    use async_channel::unbounded;
+
+    struct FakeNotifier;
+    impl Notification for FakeNotifier {
+        fn notify(&self, _title: &str, _content: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+            Ok(())
+        }
+    }
+
+    fn fixture(name: &str) -> String {
+        match name {
+            "login" => include_str!("../../../tests/data/matrix_login.json"),
+            "sync_initial" => include_str!("../../../tests/data/matrix_sync_initial.json"),
+            "sync_message" => include_str!("../../../tests/data/matrix_sync_message.json"),
+            "messages" => include_str!("../../../tests/data/matrix_messages.json"),
+            "joined_members" => include_str!("../../../tests/data/matrix_joined_members.json"),
+            _ => panic!("Unknown fixture {}", name),
+        }
+        .to_string()
+    }
+
+    async fn create_chat_system(
+        sync: Vec<MockResponse>,
+    ) -> (MockServer, Matrix, Receiver<UIEvent>, Sender<ChatEvent>) {
+        let server = MockServer::start(vec![
+            (
+                "POST",
+                "/_matrix/client/v3/login",
+                vec![MockResponse::json(200, fixture("login"))],
+            ),
+            ("GET", "/_matrix/client/v3/sync", sync),
+            (
+                "GET",
+                "/_matrix/client/v3/rooms/!ops:matrix.test/messages",
+                vec![MockResponse::json(200, fixture("messages"))],
+            ),
+            (
+                "GET",
+                "/_matrix/client/v3/rooms/!ops:matrix.test/joined_members",
+                vec![MockResponse::json(200, fixture("joined_members"))],
+            ),
+            (
+                "PUT",
+                "/_matrix/client/v3/rooms/!ops:matrix.test/send/m.room.message/",
+                vec![MockResponse::json(200, r#"{"event_id": "$sent"}"#.into())],
+            ),
+            (
+                "GET",
+                "/_matrix/client/v3/rooms/!new:matrix.test/messages",
+                vec![MockResponse::json(200, r#"{"chunk": []}"#.into())],
+            ),
+            (
+                "GET",
+                "/_matrix/client/v3/rooms/!new:matrix.test/joined_members",
+                vec![MockResponse::json(200, r#"{"joined": {}}"#.into())],
+            ),
+            (
+                "POST",
+                "/_matrix/client/v3/createRoom",
+                vec![MockResponse::json(
+                    200,
+                    r#"{"room_id": "!new:matrix.test"}"#.into(),
+                )],
+            ),
+            (
+                "GET",
+                "/_matrix/client/v3/user/@lou:matrix.test/account_data/m.direct",
+                vec![MockResponse::json(
+                    200,
+                    r#"{"@bob:matrix.test": ["!direct:matrix.test"]}"#.into(),
+                )],
+            ),
+            (
+                "PUT",
+                "/_matrix/client/v3/user/@lou:matrix.test/account_data/m.direct",
+                vec![MockResponse::json(200, "{}".into())],
+            ),
+        ])
+        .await;
+        let (tx_ui, rx_ui) = unbounded();
+        let (tx_chat, rx_chat) = unbounded();
+        let chat = Matrix::new(
+            server.url.clone(),
+            "lou".into(),
+            "secret".into(),
+            TlsConfig::default(),
+            tx_ui,
+            rx_chat,
+            Box::new(FakeNotifier {}),
+        )
+        .await
+        .unwrap();
+        (server, chat, rx_ui, tx_chat)
+    }
+
+    #[tokio::test]
+    async fn test_login() {
+        let (server, chat, _, _) =
+            create_chat_system(vec![MockResponse::json(200, fixture("sync_initial"))]).await;
+        let login = serde_json::from_str::<serde_json::Value>(
+            &server.requests("POST", "/_matrix/client/v3/login")[0].1,
+        )
+        .unwrap();
+        assert_eq!(login["identifier"]["user"], "lou");
+        assert_eq!(login["password"], "secret");
+        assert_eq!(chat.client.user_id, "@lou:matrix.test");
+    }
+
+    #[tokio::test]
+    async fn test_login_error() {
+        let server = MockServer::start(vec![(
+            "POST",
+            "/_matrix/client/v3/login",
+            vec![MockResponse::json(
+                403,
+                r#"{"errcode": "M_FORBIDDEN", "error": "Invalid password"}"#.into(),
+            )],
+        )])
+        .await;
+        let (tx_ui, _) = unbounded();
+        let (_, rx_chat) = unbounded();
+        let err = Matrix::new(
+            server.url.clone(),
+            "lou".into(),
+            "wrong".into(),
+            TlsConfig::default(),
+            tx_ui,
+            rx_chat,
+            Box::new(FakeNotifier {}),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert_eq!(format!("{}", err), "M_FORBIDDEN: Invalid password");
+    }
+
+    #[tokio::test]
+    async fn test_rooms() {
+        let (_, chat, _, _) =
+            create_chat_system(vec![MockResponse::json(200, fixture("sync_initial"))]).await;
+        let mut channels = chat.channels();
+        channels.sort();
+        assert_eq!(
+            channels,
+            vec![
+                (
+                    "bob".to_string(),
+                    Channel::User("!direct:matrix.test".into())
+                ),
+                ("ops".to_string(), Channel::Group("!ops:matrix.test".into())),
+                (
+                    "secret-plans".to_string(),
+                    Channel::Private("!plans:matrix.test".into())
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_init_view() {
+        let (_, chat, rx_ui, _) =
+            create_chat_system(vec![MockResponse::json(200, fixture("sync_initial"))]).await;
+        chat.init_view(Some(Channel::Group("!ops:matrix.test".into())))
+            .await
+            .unwrap();
+        assert!(matches!(
+            rx_ui.recv().await.unwrap(),
+            UIEvent::UpdateChannels(_)
+        ));
+        let expected = [
+            Message {
+                id: "$first".into(),
+                author: "bob".into(),
+                content: "is the deploy done?".into(),
+                datetime: Utc.timestamp_millis(1593589750164),
+                thread: None,
+                attachments: vec![],
+            },
+            Message {
+                id: "$second".into(),
+                author: "lou".into(),
+                content: "yes".into(),
+                datetime: Utc.timestamp_millis(1593589760164),
+                thread: Some("$first".into()),
+                attachments: vec![],
+            },
+        ];
+        assert_eq!(
+            rx_ui.recv().await.unwrap(),
+            UIEvent::UpdateMessages(format!("{}\n{}\n", expected[0], expected[1]))
+        );
+        assert_eq!(
+            rx_ui.recv().await.unwrap(),
+            UIEvent::UpdateUsersInRoom(vec![
+                ("Bob".to_string(), "@bob:matrix.test".to_string()),
+                ("lou".to_string(), "@lou:matrix.test".to_string()),
+            ])
+        );
+        assert_eq!(
+            rx_ui.recv().await.unwrap(),
+            UIEvent::SelectChannel(Channel::Group("!ops:matrix.test".into()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_init_view_unknown_room() {
+        let (_, chat, rx_ui, _) =
+            create_chat_system(vec![MockResponse::json(200, fixture("sync_initial"))]).await;
+        assert!(chat
+            .init_view(Some(Channel::Group("GENERAL".into())))
+            .await
+            .is_err());
+        let selected = || async {
+            loop {
+                if let UIEvent::SelectChannel(channel) = rx_ui.recv().await.unwrap() {
+                    break channel;
+                }
+            }
+        };
+        chat.init_view(None).await.unwrap();
+        assert_eq!(selected().await, Channel::Group("!ops:matrix.test".into()));
+        chat.init_view(Some(Channel::Group("#ops".into())))
+            .await
+            .unwrap();
+        assert_eq!(selected().await, Channel::Group("!ops:matrix.test".into()));
+    }
+
+    #[tokio::test]
+    async fn test_direct_chat() {
+        let (server, chat, _rx_ui, _) =
+            create_chat_system(vec![MockResponse::json(200, fixture("sync_initial"))]).await;
+        chat.direct_chat("@bob:matrix.test".into()).await.unwrap();
+        let created = server.requests("POST", "/_matrix/client/v3/createRoom");
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&created[0].1).unwrap()["is_direct"],
+            true
+        );
+        let direct = server.requests(
+            "PUT",
+            "/_matrix/client/v3/user/@lou:matrix.test/account_data/m.direct",
+        );
+        assert_eq!(direct.len(), 1);
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&direct[0].1).unwrap(),
+            serde_json::json!({"@bob:matrix.test": ["!direct:matrix.test", "!new:matrix.test"]})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_first_direct_room() {
+        // No m.direct account data yet, its GET answers 404
+        let server = MockServer::start(vec![
+            (
+                "POST",
+                "/_matrix/client/v3/login",
+                vec![MockResponse::json(200, fixture("login"))],
+            ),
+            (
+                "PUT",
+                "/_matrix/client/v3/user/@lou:matrix.test/account_data/m.direct",
+                vec![MockResponse::json(200, "{}".into())],
+            ),
+        ])
+        .await;
+        let client =
+            MatrixClient::login(server.url.clone(), "lou", "secret", &TlsConfig::default())
+                .await
+                .unwrap();
+        client
+            .add_direct_room("@bob:matrix.test", "!new:matrix.test")
+            .await
+            .unwrap();
+        let direct = server.requests(
+            "PUT",
+            "/_matrix/client/v3/user/@lou:matrix.test/account_data/m.direct",
+        );
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&direct[0].1).unwrap(),
+            serde_json::json!({"@bob:matrix.test": ["!new:matrix.test"]})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ui_event_error() {
+        let (server, chat, rx_ui, tx_chat) =
+            create_chat_system(vec![MockResponse::json(200, fixture("sync_initial"))]).await;
+        tx_chat
+            .send(ChatEvent::Init(Channel::Group("GENERAL".into())))
+            .await
+            .unwrap();
+        tx_chat
+            .send(ChatEvent::SendMessage(
+                "hello".into(),
+                Channel::Group("!ops:matrix.test".into()),
+            ))
+            .await
+            .unwrap();
+        let events = async {
+            loop {
+                if let UIEvent::ShowInfo(info) = rx_ui.recv().await.unwrap() {
+                    assert_eq!(info, "Unknown Matrix room GENERAL");
+                    break;
+                }
+            }
+            // The loop goes on after the error
+            while server
+                .requests(
+                    "PUT",
+                    "/_matrix/client/v3/rooms/!ops:matrix.test/send/m.room.message/",
+                )
+                .is_empty()
+            {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::select! {
+            _ = chat.ui_event_loop() => panic!("Abnormal"),
+            _ = events => {},
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_message() {
+        let (server, chat, _, _) =
+            create_chat_system(vec![MockResponse::json(200, fixture("sync_initial"))]).await;
+        chat.send_message("hello".into(), Channel::Group("!ops:matrix.test".into()))
+            .await
+            .unwrap();
+        let sent = server.requests(
+            "PUT",
+            "/_matrix/client/v3/rooms/!ops:matrix.test/send/m.room.message/talkoxid-",
+        );
+        assert_eq!(sent.len(), 1);
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&sent[0].1).unwrap(),
+            serde_json::json!({"msgtype": "m.text", "body": "hello"})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sync_new_message() {
+        let (server, chat, rx_ui, _tx_chat) = create_chat_system(vec![
+            MockResponse::json(200, fixture("sync_initial")),
+            MockResponse::json(200, fixture("sync_message")),
+        ])
+        .await;
+        *chat.current_channel.lock().unwrap() = Some(Channel::Group("!ops:matrix.test".into()));
+        let sync_loop = chat.sync_loop();
+        let msg = async {
+            loop {
+                if let UIEvent::AddMessages(message) = rx_ui.recv().await.unwrap() {
+                    break message;
+                }
+            }
+        };
+        tokio::select! {
+            _ = sync_loop => {panic!("Abnormal")},
+            message = msg => {
+                assert_eq!(message.author, "bob");
+                assert_eq!(message.content, "lou: ping");
+                assert_eq!(message.attachments, vec![]);
+            },
+        };
+        let syncs = server.requests("GET", "/_matrix/client/v3/sync");
+        assert!(syncs[1].0.contains("since=s1_initial"));
+    }
+}
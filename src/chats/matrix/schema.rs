@@ -0,0 +1,129 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Serialize, Debug)]
+pub struct IdentifierMx {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub user: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct LoginMx {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub identifier: IdentifierMx,
+    pub password: String,
+    pub initial_device_display_name: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct LoginResponseMx {
+    pub access_token: String,
+    pub user_id: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TextMessageMx {
+    pub msgtype: String,
+    pub body: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CreateRoomMx {
+    pub is_direct: bool,
+    pub invite: Vec<String>,
+    pub preset: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RoomIdResponseMx {
+    pub room_id: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct EventIdResponseMx {
+    pub event_id: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct EventMx {
+    #[serde(default)]
+    pub event_id: String,
+    #[serde(default)]
+    pub sender: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default)]
+    pub origin_server_ts: i64,
+    pub state_key: Option<String>,
+    #[serde(default)]
+    pub content: serde_json::Value,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct EventsMx {
+    #[serde(default)]
+    pub events: Vec<EventMx>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct TimelineMx {
+    #[serde(default)]
+    pub events: Vec<EventMx>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct RoomSummaryMx {
+    #[serde(rename = "m.heroes", default)]
+    pub heroes: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct JoinedRoomMx {
+    #[serde(default)]
+    pub summary: RoomSummaryMx,
+    #[serde(default)]
+    pub state: EventsMx,
+    #[serde(default)]
+    pub timeline: TimelineMx,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct RoomsMx {
+    #[serde(default)]
+    pub join: HashMap<String, JoinedRoomMx>,
+    #[serde(default)]
+    pub leave: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SyncResponseMx {
+    pub next_batch: String,
+    #[serde(default)]
+    pub rooms: RoomsMx,
+    #[serde(default)]
+    pub account_data: EventsMx,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MessagesResponseMx {
+    #[serde(default)]
+    pub chunk: Vec<EventMx>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MemberMx {
+    pub display_name: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct JoinedMembersResponseMx {
+    pub joined: HashMap<String, MemberMx>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ErrorResponseMx {
+    pub errcode: String,
+    pub error: Option<String>,
+}
@@ -0,0 +1,189 @@
+use super::super::tls::TlsConfig;
+use super::schema::*;
+use reqwest::{Client, Method, RequestBuilder, Response};
+use serde::de::DeserializeOwned;
+use std::error::Error;
+use std::time::Duration;
+use url::Url;
+
+/// Mattermost REST API v4 client.
+pub struct MattermostClient {
+    http: Client,
+    host: Url,
+    token: String,
+    pub user: UserMm,
+}
+
+impl MattermostClient {
+    /// Log in with a password and return an authenticated client.
+    pub async fn login(
+        host: Url,
+        username: &str,
+        password: &str,
+        tls: &TlsConfig,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let http = tls
+            .http_client(Client::builder())?
+            .timeout(Duration::from_secs(30))
+            .build()?;
+        let login = LoginMm {
+            login_id: username.into(),
+            password: password.into(),
+        };
+        let response = check(
+            http.post(endpoint(&host, &["users", "login"]))
+                .json(&login)
+                .send()
+                .await?,
+        )
+        .await?;
+        let token = response
+            .headers()
+            .get("Token")
+            .and_then(|x| x.to_str().ok())
+            .ok_or("No session token in the login response")?
+            .to_string();
+        let user = response.json::<UserMm>().await?;
+        Ok(MattermostClient {
+            http,
+            host,
+            token,
+            user,
+        })
+    }
+
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    /// The websocket url of the server, on the same host as the API.
+    pub fn websocket_url(&self) -> Result<Url, Box<dyn Error + Send + Sync>> {
+        let mut url = endpoint(&self.host, &["websocket"]);
+        let scheme = match url.scheme() {
+            "https" => "wss",
+            _ => "ws",
+        };
+        url.set_scheme(scheme)
+            .map_err(|_| "Can't build the websocket url")?;
+        Ok(url)
+    }
+
+    pub fn file_url(&self, file_id: &str) -> String {
+        endpoint(&self.host, &["files", file_id]).to_string()
+    }
+
+    fn request(&self, method: Method, segments: &[&str]) -> RequestBuilder {
+        self.http
+            .request(method, endpoint(&self.host, segments))
+            .bearer_auth(&self.token)
+    }
+
+    async fn send<T: DeserializeOwned>(
+        &self,
+        request: RequestBuilder,
+    ) -> Result<T, Box<dyn Error + Send + Sync>> {
+        Ok(check(request.send().await?).await?.json::<T>().await?)
+    }
+
+    pub async fn teams(&self) -> Result<Vec<TeamMm>, Box<dyn Error + Send + Sync>> {
+        self.send(self.request(Method::GET, &["users", "me", "teams"]))
+            .await
+    }
+
+    pub async fn channels(
+        &self,
+        team_id: &str,
+    ) -> Result<Vec<ChannelMm>, Box<dyn Error + Send + Sync>> {
+        self.send(self.request(Method::GET, &["users", "me", "teams", team_id, "channels"]))
+            .await
+    }
+
+    /// Fetch the last posts of a channel, `order` is newest first.
+    pub async fn posts(
+        &self,
+        channel_id: &str,
+        count: usize,
+    ) -> Result<PostListMm, Box<dyn Error + Send + Sync>> {
+        self.send(
+            self.request(Method::GET, &["channels", channel_id, "posts"])
+                .query(&[("per_page", count)]),
+        )
+        .await
+    }
+
+    pub async fn users_by_ids(
+        &self,
+        ids: &[String],
+    ) -> Result<Vec<UserMm>, Box<dyn Error + Send + Sync>> {
+        self.send(self.request(Method::POST, &["users", "ids"]).json(ids))
+            .await
+    }
+
+    pub async fn user_by_username(
+        &self,
+        username: &str,
+    ) -> Result<UserMm, Box<dyn Error + Send + Sync>> {
+        self.send(self.request(Method::GET, &["users", "username", username]))
+            .await
+    }
+
+    pub async fn users_in_channel(
+        &self,
+        channel_id: &str,
+    ) -> Result<Vec<UserMm>, Box<dyn Error + Send + Sync>> {
+        self.send(
+            self.request(Method::GET, &["users"])
+                .query(&[("in_channel", channel_id), ("per_page", "200")]),
+        )
+        .await
+    }
+
+    pub async fn create_post(
+        &self,
+        channel_id: String,
+        message: String,
+    ) -> Result<PostMm, Box<dyn Error + Send + Sync>> {
+        let post = CreatePostMm {
+            channel_id,
+            message,
+        };
+        self.send(self.request(Method::POST, &["posts"]).json(&post))
+            .await
+    }
+
+    /// Create, or get if it exists, the direct channel with an user.
+    pub async fn create_direct_channel(
+        &self,
+        user_id: &str,
+    ) -> Result<ChannelMm, Box<dyn Error + Send + Sync>> {
+        let members = [self.user.id.clone(), user_id.to_string()];
+        self.send(
+            self.request(Method::POST, &["channels", "direct"])
+                .json(&members),
+        )
+        .await
+    }
+}
+
+fn endpoint(host: &Url, segments: &[&str]) -> Url {
+    let mut url = host.clone();
+    url.path_segments_mut()
+        .map(|mut path| {
+            path.pop_if_empty().extend(&["api", "v4"]).extend(segments);
+        })
+        .ok();
+    url
+}
+
+/// Turn an error status into the message of the Mattermost error.
+async fn check(response: Response) -> Result<Response, Box<dyn Error + Send + Sync>> {
+    if response.status().is_success() {
+        return Ok(response);
+    }
+    let status = response.status();
+    let err = match response.json::<ErrorResponseMm>().await {
+        Ok(err) => err.message,
+        Err(_) => format!("Mattermost request failed: {}", status),
+    };
+    Err(err.into())
+}
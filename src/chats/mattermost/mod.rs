@@ -0,0 +1,735 @@
+mod api;
+mod schema;
+
+use super::super::core::{
+    Alert, Attachment, Channel, Chat, ChatEvent, Message, Notification, UIEvent,
+};
+use super::tls::{self, TlsConfig};
+use api::MattermostClient;
+use async_channel::{unbounded, Receiver, Sender};
+use async_trait::async_trait;
+use async_tungstenite::tungstenite;
+use chrono::{TimeZone, Utc};
+use futures_util::StreamExt;
+use log::error;
+use schema::*;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::sync::Mutex;
+use url::Url;
+
+/// Name of the channel every team member is in.
+const DEFAULT_CHANNEL: &str = "town-square";
+
+/// Mattermost chat system.
+///
+/// This type is a chat system implementation for Mattermost, using the
+/// REST API for requests and the websocket for events.
+pub struct Mattermost {
+    tx_ui: Sender<UIEvent>,
+    client: MattermostClient,
+    notifier: Box<dyn Notification + Sync + Send>,
+    /// The websocket closes when every sender is dropped.
+    _ws: Sender<tungstenite::Message>,
+    rx_ws: Receiver<tungstenite::Message>,
+    rx_chat: Receiver<ChatEvent>,
+    current_channel: Mutex<Option<Channel>>,
+    channels: Mutex<BTreeMap<String, ChannelMm>>,
+    usernames: Mutex<HashMap<String, String>>,
+}
+
+impl Mattermost {
+    pub async fn new(
+        host: Url,
+        username: String,
+        password: String,
+        tls: TlsConfig,
+        tx_ui: Sender<UIEvent>,
+        rx_chat: Receiver<ChatEvent>,
+        notifier: Box<dyn Notification + Sync + Send>,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let client = MattermostClient::login(host, &username, &password, &tls).await?;
+        let ws_url = client.websocket_url()?;
+        let tls_config = match ws_url.scheme() {
+            "wss" => Some(tls::connector(&tls)?),
+            _ => None,
+        };
+        let (socket, _) =
+            async_tungstenite::tokio::connect_async_with_tls_connector(ws_url, tls_config).await?;
+        let (tx_ws, rx_forwarder_ws) = unbounded();
+        let (tx_forwarder_ws, rx_ws) = unbounded();
+        let (write, mut read) = socket.split();
+        tokio::spawn(rx_forwarder_ws.map(Ok).forward(write));
+        tokio::spawn(async move {
+            while let Some(msg) = read.next().await {
+                match msg {
+                    Ok(msg) => {
+                        if let Err(err) = tx_forwarder_ws.send(msg).await {
+                            error!("Error when sending to ws sender: {}", err);
+                            break;
+                        }
+                    }
+                    Err(err) => {
+                        error!("Error when reading websocket: {}", err);
+                        break;
+                    }
+                }
+            }
+            error!("Mattermost websocket closed");
+        });
+        Mattermost::from_parts(client, tx_ws, rx_ws, tx_ui, rx_chat, notifier).await
+    }
+
+    async fn from_parts(
+        client: MattermostClient,
+        ws: Sender<tungstenite::Message>,
+        rx_ws: Receiver<tungstenite::Message>,
+        tx_ui: Sender<UIEvent>,
+        rx_chat: Receiver<ChatEvent>,
+        notifier: Box<dyn Notification + Sync + Send>,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let challenge = AuthenticationChallengeMm {
+            seq: 1,
+            action: "authentication_challenge".into(),
+            data: TokenMm {
+                token: client.token().into(),
+            },
+        };
+        ws.send(tungstenite::Message::Text(serde_json::to_string(
+            &challenge,
+        )?))
+        .await?;
+        let mut usernames = HashMap::new();
+        usernames.insert(client.user.id.clone(), client.user.username.clone());
+        Ok(Mattermost {
+            tx_ui,
+            client,
+            notifier,
+            _ws: ws,
+            rx_ws,
+            rx_chat,
+            current_channel: Mutex::new(None),
+            channels: Mutex::new(BTreeMap::new()),
+            usernames: Mutex::new(usernames),
+        })
+    }
+
+    fn channel(&self, kind: &str, channel_id: &str) -> Channel {
+        match kind {
+            "O" => Channel::Group(channel_id.into()),
+            "P" => Channel::Private(channel_id.into()),
+            _ => Channel::User(channel_id.into()),
+        }
+    }
+
+    fn username(&self, user_id: &str) -> String {
+        self.usernames
+            .lock()
+            .unwrap()
+            .get(user_id)
+            .cloned()
+            .unwrap_or_else(|| user_id.to_string())
+    }
+
+    /// The other member of a direct channel, named `<user id>__<user id>`.
+    fn direct_user(&self, channel: &ChannelMm) -> Option<String> {
+        channel
+            .name
+            .split("__")
+            .find(|x| *x != self.client.user.id)
+            .or(Some(&self.client.user.id[..]))
+            .map(String::from)
+    }
+
+    /// Fetch the usernames we don't know yet.
+    async fn load_usernames(&self, ids: Vec<String>) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let unknown = {
+            let usernames = self.usernames.lock().unwrap();
+            ids.into_iter()
+                .filter(|x| !usernames.contains_key(x))
+                .collect::<HashSet<String>>()
+                .into_iter()
+                .collect::<Vec<String>>()
+        };
+        if unknown.is_empty() {
+            return Ok(());
+        }
+        let users = self.client.users_by_ids(&unknown).await?;
+        let mut usernames = self.usernames.lock().unwrap();
+        usernames.extend(users.into_iter().map(|x| (x.id, x.username)));
+        Ok(())
+    }
+
+    async fn load_channels(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut channels = BTreeMap::new();
+        for team in self.client.teams().await? {
+            for channel in self.client.channels(&team.id).await? {
+                channels.insert(channel.id.clone(), channel);
+            }
+        }
+        let direct_users = channels
+            .values()
+            .filter(|x| x.kind == "D")
+            .filter_map(|x| self.direct_user(x))
+            .collect();
+        self.load_usernames(direct_users).await?;
+        *self.channels.lock().unwrap() = channels;
+        Ok(())
+    }
+
+    fn channel_list(&self) -> Vec<(String, Channel)> {
+        self.channels
+            .lock()
+            .unwrap()
+            .values()
+            .map(|x| {
+                let name = match &x.kind[..] {
+                    "D" => self
+                        .direct_user(x)
+                        .map(|x| self.username(&x))
+                        .unwrap_or_default(),
+                    _ if x.display_name.is_empty() => x.name.clone(),
+                    _ => x.display_name.clone(),
+                };
+                (name, self.channel(&x.kind, &x.id))
+            })
+            .collect()
+    }
+
+    fn channel_name(&self, channel: &Channel) -> String {
+        self.channel_list()
+            .into_iter()
+            .find(|(_, x)| x == channel)
+            .map(|(name, _)| name)
+            .unwrap_or_else(|| format!("{}", channel))
+    }
+
+    async fn update_channels(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.load_channels().await?;
+        self.tx_ui
+            .send(UIEvent::UpdateChannels(self.channel_list()))
+            .await?;
+        Ok(())
+    }
+
+    fn to_message(&self, post: &PostMm) -> Message {
+        Message {
+            id: post.id.clone(),
+            author: self.username(&post.user_id),
+            content: post.message.clone(),
+            datetime: Utc.timestamp_millis(post.create_at),
+            thread: Some(post.root_id.clone()).filter(|x| !x.is_empty()),
+            attachments: post
+                .metadata
+                .files
+                .iter()
+                .map(|x| Attachment {
+                    title: x.name.clone(),
+                    link: self.client.file_url(&x.id),
+                })
+                .collect(),
+        }
+    }
+
+    async fn recv_posted(&self, data: PostedDataMm) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let post = serde_json::from_str::<PostMm>(&data.post)?;
+        let sender_name = data.sender_name.trim_start_matches('@');
+        if !sender_name.is_empty() {
+            self.usernames
+                .lock()
+                .unwrap()
+                .insert(post.user_id.clone(), sender_name.to_string());
+        }
+        let channel = self.channel(&data.channel_type, &post.channel_id);
+        let message = self.to_message(&post);
+        if post.user_id != self.client.user.id {
+            let alert = Alert::new(
+                channel.clone(),
+                &self.channel_name(&channel),
+                &message,
+                &self.client.user.username,
+            );
+            if let Err(err) = self.notifier.notify_message(&alert) {
+                error!("Can't notify: {}", err);
+            }
+        }
+        self.add_message(message, &channel).await
+    }
+
+    async fn wait_messages_loop(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        loop {
+            let msg = self.rx_ws.recv().await?;
+            let event = match serde_json::from_str::<WsEventMm>(&format!("{}", msg)) {
+                Ok(event) => event,
+                // Replies to our requests have no event.
+                Err(_) => continue,
+            };
+            match &event.event[..] {
+                "posted" => match serde_json::from_value::<PostedDataMm>(event.data) {
+                    Ok(data) => self.recv_posted(data).await?,
+                    Err(err) => error!("Invalid posted event: {}", err),
+                },
+                "channel_created" | "direct_added" | "group_added" | "user_added" => {
+                    self.update_channels().await?
+                }
+                _ => {}
+            }
+        }
+    }
+
+    async fn direct_chat(&self, username: String) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let user = self.client.user_by_username(&username).await?;
+        self.usernames
+            .lock()
+            .unwrap()
+            .insert(user.id.clone(), user.username);
+        let channel = self.client.create_direct_channel(&user.id).await?;
+        self.init_view(Some(Channel::User(channel.id))).await
+    }
+
+    /// Handle an event of the UI, its errors are reported to the user.
+    async fn ui_event(&self, event: ChatEvent) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match event {
+            ChatEvent::SendMessage(message, channel) => {
+                let split = message.split(' ').collect::<Vec<&str>>();
+                if message.starts_with("/direct") && split.len() > 1 {
+                    self.direct_chat(split[1].into()).await
+                } else if split[0] == "/notify" {
+                    let args = message["/notify".len()..].trim();
+                    let info = self
+                        .notifier
+                        .configure(&channel, &self.channel_name(&channel), args)
+                        .unwrap_or_else(|err| err);
+                    self.tx_ui.send(UIEvent::ShowInfo(info)).await?;
+                    Ok(())
+                } else {
+                    self.send_message(message, channel).await
+                }
+            }
+            ChatEvent::Init(channel) => self.init_view(Some(channel)).await,
+            ChatEvent::DirectChat(user) => self.direct_chat(user).await,
+        }
+    }
+
+    async fn ui_event_loop(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        loop {
+            let event = self.rx_chat.recv().await?;
+            if let Err(err) = self.ui_event(event).await {
+                error!("{}", err);
+                self.tx_ui
+                    .send(UIEvent::ShowInfo(format!("{}", err)))
+                    .await?;
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Chat for Mattermost {
+    async fn init_view(
+        &self,
+        channel: Option<Channel>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.update_channels().await?;
+        let channel_id = channel.map(|x| format!("{}", x));
+        let channel = {
+            let channels = self.channels.lock().unwrap();
+            // The town square of the first team
+            let first = || {
+                channels
+                    .values()
+                    .find(|x| x.name == DEFAULT_CHANNEL)
+                    .or_else(|| channels.values().find(|x| x.kind == "O"))
+            };
+            let found = match channel_id.as_deref() {
+                Some(channel_id) => {
+                    let name = channel_id.trim_start_matches('#');
+                    channels.get(channel_id).or_else(|| {
+                        channels
+                            .values()
+                            .find(|x| x.name == name || x.display_name == name)
+                    })
+                }
+                None => first(),
+            };
+            match (found, channel_id) {
+                (Some(found), _) => self.channel(&found.kind, &found.id),
+                (None, Some(channel_id)) => {
+                    return Err(format!("Unknown Mattermost channel {}", channel_id).into())
+                }
+                (None, None) => return Ok(()),
+            }
+        };
+        let channel_id = format!("{}", channel);
+        let posts = self.client.posts(&channel_id, 100).await?;
+        self.load_usernames(posts.posts.values().map(|x| x.user_id.clone()).collect())
+            .await?;
+        let messages = posts
+            .order
+            .iter()
+            .rev()
+            .filter_map(|x| posts.posts.get(x))
+            .map(|x| self.to_message(x))
+            .fold(String::from(""), |x, y| format!("{}{}\n", x, y));
+        self.tx_ui.send(UIEvent::UpdateMessages(messages)).await?;
+        let users = self.client.users_in_channel(&channel_id).await?;
+        let mut users_in_room = users
+            .into_iter()
+            .map(|x| (x.username.clone(), x.username))
+            .collect::<Vec<(String, String)>>();
+        users_in_room.sort();
+        self.tx_ui
+            .send(UIEvent::UpdateUsersInRoom(users_in_room))
+            .await?;
+        self.tx_ui
+            .send(UIEvent::SelectChannel(channel.clone()))
+            .await?;
+        *self.current_channel.lock().unwrap() = Some(channel);
+        Ok(())
+    }
+
+    async fn send_message(
+        &self,
+        content: String,
+        channel: Channel,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.client
+            .create_post(format!("{}", channel), content)
+            .await?;
+        Ok(())
+    }
+
+    async fn start_loop(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let read_loop = self.wait_messages_loop();
+        let ui_loop = self.ui_event_loop();
+        tokio::select! {
+            result = read_loop => result,
+            result = ui_loop => result,
+        }
+    }
+
+    async fn add_message(
+        &self,
+        message: Message,
+        channel: &Channel,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let current_channel = self.current_channel.lock().unwrap().clone();
+        if let Some(current) = current_channel.as_ref() {
+            if channel == current {
+                self.tx_ui.send(UIEvent::AddMessages(message)).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chats::mock_server::{MockResponse, MockServer, Route};
+
+    struct FakeNotifier;
+    impl Notification for FakeNotifier {
+        fn notify(&self, _title: &str, _content: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+            Ok(())
+        }
+    }
+
+    fn fixture(name: &str) -> MockResponse {
+        let body = match name {
+            "login" => include_str!("../../../tests/data/mattermost_login.json"),
+            "teams" => include_str!("../../../tests/data/mattermost_teams.json"),
+            "channels" => include_str!("../../../tests/data/mattermost_channels.json"),
+            "users_ids" => include_str!("../../../tests/data/mattermost_users_ids.json"),
+            "posts" => include_str!("../../../tests/data/mattermost_posts.json"),
+            "users_in_channel" => {
+                include_str!("../../../tests/data/mattermost_users_in_channel.json")
+            }
+            "create_post" => include_str!("../../../tests/data/mattermost_create_post.json"),
+            _ => panic!("Unknown fixture {}", name),
+        };
+        MockResponse::json(200, body.into())
+    }
+
+    fn routes() -> Vec<Route> {
+        vec![
+            (
+                "POST",
+                "/api/v4/users/login",
+                vec![fixture("login").with_header("Token", "session-token")],
+            ),
+            (
+                "GET",
+                "/api/v4/users/me/teams/t_dev/channels",
+                vec![fixture("channels")],
+            ),
+            ("GET", "/api/v4/users/me/teams", vec![fixture("teams")]),
+            ("POST", "/api/v4/users/ids", vec![fixture("users_ids")]),
+            (
+                "GET",
+                "/api/v4/channels/c_town/posts",
+                vec![fixture("posts")],
+            ),
+            (
+                "GET",
+                "/api/v4/users?in_channel=c_town",
+                vec![fixture("users_in_channel")],
+            ),
+            ("POST", "/api/v4/posts", vec![fixture("create_post")]),
+            (
+                "GET",
+                "/api/v4/users/username/ghost",
+                vec![MockResponse::json(
+                    404,
+                    r#"{"id": "app.user.missing_account.const", "message": "Unable to find the user.", "status_code": 404}"#.into(),
+                )],
+            ),
+        ]
+    }
+
+    async fn create_chat_system() -> (
+        MockServer,
+        Mattermost,
+        Receiver<UIEvent>,
+        Sender<tungstenite::Message>,
+        Receiver<tungstenite::Message>,
+        Sender<ChatEvent>,
+    ) {
+        let server = MockServer::start(routes()).await;
+        let (tx_ui, rx_ui) = unbounded();
+        let (tx_chat, rx_chat) = unbounded();
+        let (tx_ws, rx_sent) = unbounded();
+        let (tx_forwarder_ws, rx_ws) = unbounded();
+        let client =
+            MattermostClient::login(server.url.clone(), "lou", "secret", &TlsConfig::default())
+                .await
+                .unwrap();
+        let chat = Mattermost::from_parts(
+            client,
+            tx_ws,
+            rx_ws,
+            tx_ui,
+            rx_chat,
+            Box::new(FakeNotifier {}),
+        )
+        .await
+        .unwrap();
+        (server, chat, rx_ui, tx_forwarder_ws, rx_sent, tx_chat)
+    }
+
+    #[tokio::test]
+    async fn test_login() {
+        let (server, chat, _, _, rx_sent, _) = create_chat_system().await;
+        let login = serde_json::from_str::<serde_json::Value>(
+            &server.requests("POST", "/api/v4/users/login")[0].1,
+        )
+        .unwrap();
+        assert_eq!(
+            login,
+            serde_json::json!({"login_id": "lou", "password": "secret"})
+        );
+        assert_eq!(chat.client.user.id, "u_lou");
+        let challenge = rx_sent.recv().await.unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&challenge.to_string()).unwrap(),
+            serde_json::json!({
+                "seq": 1,
+                "action": "authentication_challenge",
+                "data": {"token": "session-token"}
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_login_error() {
+        let server = MockServer::start(vec![(
+            "POST",
+            "/api/v4/users/login",
+            vec![MockResponse::json(
+                401,
+                r#"{"id": "api.user.login.invalid_credentials_email_username", "message": "Enter a valid email or username and/or password.", "status_code": 401}"#.into(),
+            )],
+        )])
+        .await;
+        let err =
+            MattermostClient::login(server.url.clone(), "lou", "wrong", &TlsConfig::default())
+                .await
+                .err()
+                .unwrap();
+        assert_eq!(
+            format!("{}", err),
+            "Enter a valid email or username and/or password."
+        );
+    }
+
+    #[tokio::test]
+    async fn test_init_view() {
+        let (_, chat, rx_ui, _, _, _) = create_chat_system().await;
+        chat.init_view(None).await.unwrap();
+        let mut channels = match rx_ui.recv().await.unwrap() {
+            UIEvent::UpdateChannels(channels) => channels,
+            event => panic!("Unexpected event {:?}", event),
+        };
+        channels.sort();
+        assert_eq!(
+            channels,
+            vec![
+                ("Town Square".to_string(), Channel::Group("c_town".into())),
+                ("bob".to_string(), Channel::User("c_direct".into())),
+                ("secret".to_string(), Channel::Private("c_secret".into())),
+            ]
+        );
+        let expected = [
+            Message {
+                id: "p1".into(),
+                author: "bob".into(),
+                content: "here are the logs".into(),
+                datetime: Utc.timestamp_millis(1593589750164),
+                thread: None,
+                attachments: vec![Attachment {
+                    title: "deploy.log".into(),
+                    link: chat.client.file_url("f_log"),
+                }],
+            },
+            Message {
+                id: "p2".into(),
+                author: "lou".into(),
+                content: "thanks".into(),
+                datetime: Utc.timestamp_millis(1593589760164),
+                thread: Some("p1".into()),
+                attachments: vec![],
+            },
+        ];
+        assert_eq!(
+            rx_ui.recv().await.unwrap(),
+            UIEvent::UpdateMessages(format!("{}\n{}\n", expected[0], expected[1]))
+        );
+        assert_eq!(
+            rx_ui.recv().await.unwrap(),
+            UIEvent::UpdateUsersInRoom(vec![
+                ("bob".to_string(), "bob".to_string()),
+                ("lou".to_string(), "lou".to_string()),
+            ])
+        );
+        assert_eq!(
+            rx_ui.recv().await.unwrap(),
+            UIEvent::SelectChannel(Channel::Group("c_town".into()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_init_view_by_name() {
+        let (_, chat, rx_ui, _, _, _) = create_chat_system().await;
+        assert!(chat
+            .init_view(Some(Channel::Group("GENERAL".into())))
+            .await
+            .is_err());
+        chat.init_view(Some(Channel::Group("Town Square".into())))
+            .await
+            .unwrap();
+        loop {
+            if let UIEvent::SelectChannel(channel) = rx_ui.recv().await.unwrap() {
+                assert_eq!(channel, Channel::Group("c_town".into()));
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ui_event_error() {
+        let (server, chat, rx_ui, _, _, tx_chat) = create_chat_system().await;
+        tx_chat
+            .send(ChatEvent::DirectChat("ghost".into()))
+            .await
+            .unwrap();
+        tx_chat
+            .send(ChatEvent::SendMessage(
+                "hello".into(),
+                Channel::Group("c_town".into()),
+            ))
+            .await
+            .unwrap();
+        let events = async {
+            loop {
+                if let UIEvent::ShowInfo(info) = rx_ui.recv().await.unwrap() {
+                    assert_eq!(info, "Unable to find the user.");
+                    break;
+                }
+            }
+            // The loop goes on after the error
+            while server.requests("POST", "/api/v4/posts").is_empty() {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        };
+        tokio::select! {
+            _ = chat.ui_event_loop() => panic!("Abnormal"),
+            _ = events => {},
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_message() {
+        let (server, chat, _, _, _, _) = create_chat_system().await;
+        chat.send_message("hello".into(), Channel::Group("c_town".into()))
+            .await
+            .unwrap();
+        let post = serde_json::from_str::<serde_json::Value>(
+            &server.requests("POST", "/api/v4/posts")[0].1,
+        )
+        .unwrap();
+        assert_eq!(
+            post,
+            serde_json::json!({"channel_id": "c_town", "message": "hello"})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_recv_posted() {
+        let (_, chat, rx_ui, tx_forwarder_ws, _, _) = create_chat_system().await;
+        *chat.current_channel.lock().unwrap() = Some(Channel::Group("c_town".into()));
+        tx_forwarder_ws
+            .send(tungstenite::Message::Text(
+                r#"{"status": "OK", "seq_reply": 1}"#.into(),
+            ))
+            .await
+            .unwrap();
+        tx_forwarder_ws
+            .send(tungstenite::Message::Text(
+                include_str!("../../../tests/data/mattermost_posted.json").into(),
+            ))
+            .await
+            .unwrap();
+        tokio::select! {
+            _ = chat.wait_messages_loop() => panic!("Abnormal"),
+            event = rx_ui.recv() => assert_eq!(
+                event.unwrap(),
+                UIEvent::AddMessages(Message {
+                    id: "p3".into(),
+                    author: "carol".into(),
+                    content: "lou: deploy is green".into(),
+                    datetime: Utc.timestamp_millis(1593589770164),
+                    thread: None,
+                    attachments: vec![],
+                })
+            ),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_recv_posted_not_current_channel() {
+        let (_, chat, rx_ui, tx_forwarder_ws, _, _) = create_chat_system().await;
+        *chat.current_channel.lock().unwrap() = Some(Channel::Private("c_secret".into()));
+        tx_forwarder_ws
+            .send(tungstenite::Message::Text(
+                include_str!("../../../tests/data/mattermost_posted.json").into(),
+            ))
+            .await
+            .unwrap();
+        tokio::select! {
+            _ = chat.wait_messages_loop() => panic!("Abnormal"),
+            _ = tokio::time::sleep(std::time::Duration::from_millis(100)) => {},
+        }
+        assert!(rx_ui.is_empty());
+        assert_eq!(chat.username("u_carol"), "carol");
+    }
+}
@@ -0,0 +1,99 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Serialize, Debug)]
+pub struct LoginMm {
+    pub login_id: String,
+    pub password: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct UserMm {
+    pub id: String,
+    pub username: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TeamMm {
+    pub id: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ChannelMm {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub name: String,
+    #[serde(default)]
+    pub display_name: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct FileInfoMm {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct PostMetadataMm {
+    #[serde(default)]
+    pub files: Vec<FileInfoMm>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PostMm {
+    pub id: String,
+    pub user_id: String,
+    pub channel_id: String,
+    pub message: String,
+    pub create_at: i64,
+    #[serde(default)]
+    pub root_id: String,
+    #[serde(default)]
+    pub metadata: PostMetadataMm,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PostListMm {
+    pub order: Vec<String>,
+    pub posts: HashMap<String, PostMm>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CreatePostMm {
+    pub channel_id: String,
+    pub message: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct AuthenticationChallengeMm {
+    pub seq: u64,
+    pub action: String,
+    pub data: TokenMm,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TokenMm {
+    pub token: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct WsEventMm {
+    pub event: String,
+    #[serde(default)]
+    pub data: serde_json::Value,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PostedDataMm {
+    pub channel_type: String,
+    #[serde(default)]
+    pub sender_name: String,
+    /// The post, serialized as a JSON string.
+    pub post: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ErrorResponseMm {
+    pub message: String,
+}
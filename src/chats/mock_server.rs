@@ -0,0 +1,150 @@
+//! Mock HTTP server module.
+//!
+//! This module contains a scripted HTTP server used to test
+//! the chats relying on REST APIs.
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use url::Url;
+
+type Requests = Arc<Mutex<Vec<(String, String, String)>>>;
+pub type Route = (&'static str, &'static str, Vec<MockResponse>);
+
+/// A response of the mock server.
+#[derive(Clone, Debug)]
+pub struct MockResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl MockResponse {
+    pub fn json(status: u16, body: String) -> Self {
+        MockResponse {
+            status,
+            headers: vec![],
+            body,
+        }
+    }
+
+    #[cfg(feature = "mattermost")]
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+}
+
+/// A scripted server answering requests with fixtures.
+///
+/// Routes are matched on the method and the start of the path. Each route
+/// answers its responses in order, the last one is repeated. A route without
+/// response holds the request like an idle long poll.
+pub struct MockServer {
+    pub url: Url,
+    requests: Requests,
+}
+
+async fn read_request(stream: &mut TcpStream) -> Option<(String, String, String)> {
+    let mut buffer = vec![];
+    let mut chunk = [0; 4096];
+    let header_end = loop {
+        let read = stream.read(&mut chunk).await.ok()?;
+        if read == 0 {
+            return None;
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+        if let Some(end) = buffer.windows(4).position(|x| x == b"\r\n\r\n") {
+            break end + 4;
+        }
+    };
+    let head = String::from_utf8_lossy(&buffer[..header_end]).to_string();
+    let length = head
+        .lines()
+        .filter_map(|x| x.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+    while buffer.len() < header_end + length {
+        let read = stream.read(&mut chunk).await.ok()?;
+        if read == 0 {
+            break;
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    }
+    let mut request_line = head.lines().next()?.split(' ');
+    let method = request_line.next()?.to_string();
+    let path = request_line.next()?.to_string();
+    let body = String::from_utf8_lossy(&buffer[header_end..]).to_string();
+    Some((method, path, body))
+}
+
+impl MockServer {
+    pub async fn start(routes: Vec<Route>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+        let requests: Requests = Arc::new(Mutex::new(vec![]));
+        let routes = Arc::new(Mutex::new(routes));
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let routes = routes.clone();
+                let recorded = recorded.clone();
+                tokio::spawn(async move {
+                    let (method, path, body) = match read_request(&mut stream).await {
+                        Some(request) => request,
+                        None => return,
+                    };
+                    recorded
+                        .lock()
+                        .unwrap()
+                        .push((method.clone(), path.clone(), body));
+                    let response = routes
+                        .lock()
+                        .unwrap()
+                        .iter_mut()
+                        .find(|(m, p, _)| *m == method && path.starts_with(p))
+                        .map(|(_, _, responses)| match responses.len() {
+                            0 => None,
+                            1 => Some(responses[0].clone()),
+                            _ => Some(responses.remove(0)),
+                        })
+                        .unwrap_or_else(|| Some(MockResponse::json(404, "{}".into())));
+                    let response = match response {
+                        Some(response) => response,
+                        None => {
+                            tokio::time::sleep(Duration::from_secs(3600)).await;
+                            return;
+                        }
+                    };
+                    let headers = response
+                        .headers
+                        .iter()
+                        .map(|(name, value)| format!("{}: {}\r\n", name, value))
+                        .collect::<String>();
+                    let response = format!(
+                        "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n{}\r\n{}",
+                        response.status,
+                        response.body.len(),
+                        headers,
+                        response.body
+                    );
+                    stream.write_all(response.as_bytes()).await.ok();
+                });
+            }
+        });
+        MockServer { url, requests }
+    }
+
+    /// The path and body of the requests received for a route.
+    pub fn requests(&self, method: &str, path: &str) -> Vec<(String, String)> {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(m, p, _)| m == method && p.starts_with(path))
+            .map(|(_, p, body)| (p.clone(), body.clone()))
+            .collect()
+    }
+}
@@ -1,6 +1,32 @@
 //! Chat system module.
 //!
 //! This module contains the various chats implementations.
+#[cfg(feature = "irc")]
+mod irc;
+#[cfg(feature = "matrix")]
+mod matrix;
+#[cfg(feature = "mattermost")]
+mod mattermost;
+#[cfg(all(test, any(feature = "matrix", feature = "mattermost")))]
+mod mock_server;
+mod proxy;
+mod registry;
 mod rocketchat;
+mod tls;
+#[cfg(feature = "xmpp")]
+mod xmpp;
 
+#[cfg(feature = "irc")]
+pub use irc::Irc;
+#[cfg(feature = "matrix")]
+pub use matrix::Matrix;
+#[cfg(feature = "mattermost")]
+pub use mattermost::Mattermost;
+pub use proxy::parse as parse_proxy;
+pub use registry::{backends, build_chat, Backend, ChatParams};
+#[cfg(test)]
+pub(crate) use rocketchat::fake_server;
 pub use rocketchat::RocketChat;
+pub use tls::TlsConfig;
+#[cfg(feature = "xmpp")]
+pub use xmpp::{Xmpp, XmppTls};
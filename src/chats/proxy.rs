@@ -0,0 +1,477 @@
+//! Proxy module.
+//!
+//! This module opens TCP connections through an HTTP proxy, with the
+//! CONNECT method, or through a SOCKS5 proxy. The TLS and websocket
+//! handshakes then happen in the tunnel.
+use percent_encoding::percent_decode_str;
+use std::error::Error;
+use std::net::{IpAddr, SocketAddr};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpStream};
+use url::Url;
+
+/// Longest proxy response header we accept.
+const MAX_RESPONSE_SIZE: usize = 8192;
+
+/// The proxy schemes we support.
+const SCHEMES: &[&str] = &["http", "socks5", "socks5h"];
+
+/// Check a proxy URL from the configuration or the environment.
+pub fn parse(proxy: &str) -> Result<Url, String> {
+    let url = Url::parse(proxy).map_err(|err| err.to_string())?;
+    if !SCHEMES.contains(&url.scheme()) {
+        return Err(format!(
+            "unsupported proxy scheme {}, expected one of {}",
+            url.scheme(),
+            SCHEMES.join(", ")
+        ));
+    }
+    if url.host_str().is_none() {
+        return Err("the proxy host is missing".into());
+    }
+    Ok(url)
+}
+
+/// Whether `host` matches the `no_proxy` list.
+///
+/// The list is separated by commas, an entry matches the host and its
+/// subdomains, `*` matches every host.
+fn no_proxy(list: &str, host: &str) -> bool {
+    list.split(',')
+        .map(|x| x.trim().trim_start_matches('.').to_lowercase())
+        .filter(|x| !x.is_empty())
+        .any(|x| x == "*" || host == x || host.ends_with(&format!(".{}", x)))
+}
+
+/// The proxy of `target` in the environment variables given by `var`.
+///
+/// Secure targets use `https_proxy`, the others `http_proxy`, both fall
+/// back to `all_proxy`. Lowercase variables win like in curl.
+fn from_vars(
+    target: &Url,
+    var: impl Fn(&str) -> Option<String>,
+) -> Result<Option<Url>, Box<dyn Error + Send + Sync>> {
+    let var = |name: &str| {
+        var(name)
+            .or_else(|| var(&name.to_uppercase()))
+            .filter(|x| !x.is_empty())
+    };
+    let host = target.host_str().unwrap_or_default().to_lowercase();
+    if var("no_proxy").is_some_and(|x| no_proxy(&x, &host)) {
+        return Ok(None);
+    }
+    let scheme_proxy = match target.scheme() {
+        "https" | "wss" => "https_proxy",
+        _ => "http_proxy",
+    };
+    match var(scheme_proxy).or_else(|| var("all_proxy")) {
+        Some(proxy) => {
+            Ok(Some(parse(&proxy).map_err(|err| {
+                format!("Invalid proxy {}: {}", proxy, err)
+            })?))
+        }
+        None => Ok(None),
+    }
+}
+
+/// The proxy used to reach `target`.
+///
+/// The `proxy` configuration key wins over the environment, an empty
+/// key connects directly.
+pub fn resolve(
+    configured: Option<&str>,
+    target: &Url,
+) -> Result<Option<Url>, Box<dyn Error + Send + Sync>> {
+    match configured {
+        Some("") => Ok(None),
+        Some(proxy) => Ok(Some(parse(proxy)?)),
+        None => from_vars(target, |name| std::env::var(name).ok()),
+    }
+}
+
+/// The decoded user and password of a proxy URL.
+fn credentials(proxy: &Url) -> Option<(String, String)> {
+    if proxy.username().is_empty() {
+        return None;
+    }
+    let decode = |x: &str| percent_decode_str(x).decode_utf8_lossy().into_owned();
+    Some((
+        decode(proxy.username()),
+        decode(proxy.password().unwrap_or_default()),
+    ))
+}
+
+/// Connect to `host` and `port`, through `proxy` if any.
+pub async fn connect(
+    host: &str,
+    port: u16,
+    proxy: Option<&Url>,
+) -> Result<TcpStream, Box<dyn Error + Send + Sync>> {
+    let proxy = match proxy {
+        Some(proxy) => proxy,
+        None => return Ok(TcpStream::connect((host, port)).await?),
+    };
+    let proxy_host = proxy.host_str().ok_or("The proxy host is missing")?;
+    // SOCKS5 proxies listen on 1080 by default
+    let proxy_port = proxy.port_or_known_default().unwrap_or(1080);
+    let mut stream = TcpStream::connect((proxy_host, proxy_port))
+        .await
+        .map_err(|err| format!("Can't connect to the proxy {}: {}", proxy_host, err))?;
+    match proxy.scheme() {
+        "http" => http_connect(&mut stream, host, port, credentials(proxy)).await?,
+        "socks5" => {
+            let address = lookup_host((host, port))
+                .await?
+                .next()
+                .ok_or_else(|| format!("Can't resolve {}", host))?;
+            socks5_connect(&mut stream, Target::Address(address), credentials(proxy)).await?
+        }
+        "socks5h" => {
+            socks5_connect(&mut stream, Target::Domain(host, port), credentials(proxy)).await?
+        }
+        scheme => return Err(format!("Unsupported proxy scheme {}", scheme).into()),
+    }
+    Ok(stream)
+}
+
+/// Open a tunnel with the HTTP CONNECT method.
+async fn http_connect(
+    stream: &mut TcpStream,
+    host: &str,
+    port: u16,
+    credentials: Option<(String, String)>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let authority = match host.parse::<IpAddr>() {
+        Ok(IpAddr::V6(_)) => format!("[{}]:{}", host, port),
+        _ => format!("{}:{}", host, port),
+    };
+    let mut request = format!(
+        "CONNECT {0} HTTP/1.1\r\nHost: {0}\r\nProxy-Connection: Keep-Alive\r\n",
+        authority
+    );
+    if let Some((user, password)) = credentials {
+        request.push_str(&format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            base64::encode(format!("{}:{}", user, password))
+        ));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+    // Read byte by byte, nothing after the header may be consumed
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_RESPONSE_SIZE {
+            return Err("The proxy response is too long".into());
+        }
+        let byte = stream
+            .read_u8()
+            .await
+            .map_err(|_| "The proxy closed the connection")?;
+        response.push(byte);
+    }
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or_default();
+    let mut parts = status_line.splitn(3, ' ');
+    let version = parts.next().unwrap_or_default();
+    let status = parts.next().unwrap_or_default();
+    if !version.starts_with("HTTP/") || status.len() != 3 {
+        return Err(format!("Invalid proxy response: {}", status_line).into());
+    }
+    if !status.starts_with('2') {
+        return Err(format!(
+            "The proxy refused to connect to {}: {}",
+            authority, status_line
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// The destination sent to a SOCKS5 proxy.
+enum Target<'a> {
+    /// Resolved by us.
+    Address(SocketAddr),
+    /// Resolved by the proxy.
+    Domain(&'a str, u16),
+}
+
+/// The meaning of a SOCKS5 reply code.
+fn socks5_error(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+/// Open a tunnel with the SOCKS5 CONNECT command, see RFC 1928 and 1929.
+async fn socks5_connect(
+    stream: &mut TcpStream,
+    target: Target<'_>,
+    credentials: Option<(String, String)>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let method = if credentials.is_some() { 2 } else { 0 };
+    stream.write_all(&[5, 1, method]).await?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != 5 {
+        return Err("Not a SOCKS5 proxy".into());
+    }
+    if reply[1] != method {
+        return Err("The SOCKS5 proxy refused the authentication method".into());
+    }
+    if let Some((user, password)) = credentials {
+        if user.len() > 255 || password.len() > 255 {
+            return Err("The SOCKS5 user and password are limited to 255 bytes".into());
+        }
+        let mut request = vec![1, user.len() as u8];
+        request.extend_from_slice(user.as_bytes());
+        request.push(password.len() as u8);
+        request.extend_from_slice(password.as_bytes());
+        stream.write_all(&request).await?;
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != 0 {
+            return Err("The SOCKS5 proxy refused the user and password".into());
+        }
+    }
+    let mut request = vec![5, 1, 0];
+    let port = match target {
+        Target::Address(SocketAddr::V4(address)) => {
+            request.push(1);
+            request.extend_from_slice(&address.ip().octets());
+            address.port()
+        }
+        Target::Address(SocketAddr::V6(address)) => {
+            request.push(4);
+            request.extend_from_slice(&address.ip().octets());
+            address.port()
+        }
+        Target::Domain(host, port) => {
+            if host.len() > 255 {
+                return Err("The host name is too long for SOCKS5".into());
+            }
+            request.push(3);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+            port
+        }
+    };
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        return Err(format!("The SOCKS5 proxy can't connect: {}", socks5_error(reply[1])).into());
+    }
+    // Skip the bound address
+    let address_size = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => stream.read_u8().await? as usize,
+        _ => return Err("Invalid SOCKS5 reply".into()),
+    };
+    let mut bound = vec![0u8; address_size + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use tokio::net::TcpListener;
+
+    /// Answer "pong" to "ping", the server behind the proxies.
+    async fn echo_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut ping = [0u8; 4];
+            stream.read_exact(&mut ping).await.unwrap();
+            assert_eq!(&ping, b"ping");
+            stream.write_all(b"pong").await.unwrap();
+        });
+        address
+    }
+
+    async fn ping(mut stream: TcpStream) {
+        stream.write_all(b"ping").await.unwrap();
+        let mut pong = [0u8; 4];
+        stream.read_exact(&mut pong).await.unwrap();
+        assert_eq!(&pong, b"pong");
+    }
+
+    /// Serve one client, then pipe it to the requested address.
+    async fn fake_proxy<F, R>(handshake: F) -> SocketAddr
+    where
+        F: FnOnce(TcpStream) -> R + Send + 'static,
+        R: std::future::Future<Output = Option<(TcpStream, String)>> + Send,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            if let Some((mut client, target)) = handshake(stream).await {
+                let mut server = TcpStream::connect(target).await.unwrap();
+                tokio::io::copy_bidirectional(&mut client, &mut server)
+                    .await
+                    .ok();
+            }
+        });
+        address
+    }
+
+    async fn read_header(stream: &mut TcpStream) -> String {
+        let mut header = Vec::new();
+        while !header.ends_with(b"\r\n\r\n") {
+            header.push(stream.read_u8().await.unwrap());
+        }
+        String::from_utf8(header).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_http_connect() {
+        let server = echo_server().await;
+        let proxy = fake_proxy(move |mut stream| async move {
+            let header = read_header(&mut stream).await;
+            let request_line = format!("CONNECT 127.0.0.1:{} HTTP/1.1\r\n", server.port());
+            assert!(header.starts_with(&request_line));
+            // lou:p@ss
+            assert!(header.contains("Proxy-Authorization: Basic bG91OnBAc3M=\r\n"));
+            let target = header.split(' ').nth(1).unwrap().to_string();
+            stream
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .await
+                .unwrap();
+            Some((stream, target))
+        })
+        .await;
+        let url = Url::parse(&format!("http://lou:p%40ss@{}", proxy)).unwrap();
+        let stream = connect("127.0.0.1", server.port(), Some(&url))
+            .await
+            .unwrap();
+        ping(stream).await;
+
+        let proxy = fake_proxy(|mut stream| async move {
+            read_header(&mut stream).await;
+            stream
+                .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+                .await
+                .unwrap();
+            None
+        })
+        .await;
+        let url = Url::parse(&format!("http://{}", proxy)).unwrap();
+        let err = connect("chat.test", 443, Some(&url)).await.err().unwrap();
+        assert_eq!(
+            format!("{}", err),
+            "The proxy refused to connect to chat.test:443: HTTP/1.1 407 Proxy Authentication Required"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_socks5_connect() {
+        let server = echo_server().await;
+        let proxy = fake_proxy(move |mut stream| async move {
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [5, 1, 2]);
+            stream.write_all(&[5, 2]).await.unwrap();
+            let mut auth = [0u8; 12];
+            stream.read_exact(&mut auth).await.unwrap();
+            assert_eq!(&auth, b"\x01\x03lou\x06secret");
+            stream.write_all(&[1, 0]).await.unwrap();
+            let mut request = [0u8; 5];
+            stream.read_exact(&mut request).await.unwrap();
+            assert_eq!(request[..4], [5, 1, 0, 3]);
+            let mut host = vec![0u8; request[4] as usize];
+            stream.read_exact(&mut host).await.unwrap();
+            assert_eq!(&host, b"localhost");
+            let port = stream.read_u16().await.unwrap();
+            stream
+                .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0])
+                .await
+                .unwrap();
+            Some((stream, format!("127.0.0.1:{}", port)))
+        })
+        .await;
+        let url = Url::parse(&format!("socks5h://lou:secret@{}", proxy)).unwrap();
+        let stream = connect("localhost", server.port(), Some(&url))
+            .await
+            .unwrap();
+        ping(stream).await;
+
+        let proxy = fake_proxy(|mut stream| async move {
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [5, 1, 0]);
+            stream.write_all(&[5, 0]).await.unwrap();
+            let mut request = [0u8; 10];
+            stream.read_exact(&mut request).await.unwrap();
+            assert_eq!(request[..8], [5, 1, 0, 1, 127, 0, 0, 1]);
+            stream
+                .write_all(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+            None
+        })
+        .await;
+        let url = Url::parse(&format!("socks5://{}", proxy)).unwrap();
+        let err = connect("127.0.0.1", 4000, Some(&url)).await.err().unwrap();
+        assert_eq!(
+            format!("{}", err),
+            "The SOCKS5 proxy can't connect: connection refused"
+        );
+    }
+
+    #[test]
+    fn test_from_vars() {
+        let vars: HashMap<&str, &str> = vec![
+            ("HTTPS_PROXY", "http://proxy.corp:3128"),
+            ("all_proxy", "socks5h://socks.corp"),
+            ("no_proxy", "localhost, .internal.corp"),
+        ]
+        .into_iter()
+        .collect();
+        let proxy = |target: &str| {
+            from_vars(&Url::parse(target).unwrap(), |name| {
+                vars.get(name).map(|x| x.to_string())
+            })
+            .unwrap()
+            .map(|x| x.to_string())
+        };
+        assert_eq!(
+            proxy("https://chat.test"),
+            Some("http://proxy.corp:3128/".into())
+        );
+        assert_eq!(
+            proxy("http://chat.test"),
+            Some("socks5h://socks.corp".into())
+        );
+        assert_eq!(proxy("https://chat.internal.corp"), None);
+        assert_eq!(proxy("https://internal.corp"), None);
+        assert_eq!(proxy("http://localhost:3000"), None);
+        let err = from_vars(&Url::parse("https://chat.test").unwrap(), |name| {
+            Some("ftp://proxy.corp")
+                .filter(|_| name == "https_proxy")
+                .map(String::from)
+        })
+        .err()
+        .unwrap();
+        assert_eq!(
+            format!("{}", err),
+            "Invalid proxy ftp://proxy.corp: unsupported proxy scheme ftp, expected one of http, socks5, socks5h"
+        );
+        assert_eq!(
+            resolve(Some(""), &Url::parse("https://chat.test").unwrap()).unwrap(),
+            None
+        );
+    }
+}
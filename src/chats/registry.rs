@@ -0,0 +1,345 @@
+use super::super::chatlog::ChatLogger;
+use super::super::core::{Chat, ChatEvent, Notification, UIEvent};
+use super::proxy;
+use super::rocketchat::{self, Recorder, RocketChat};
+use super::tls::TlsConfig;
+use async_channel::{Receiver, Sender};
+#[cfg(any(
+    feature = "matrix",
+    feature = "irc",
+    feature = "mattermost",
+    feature = "xmpp"
+))]
+use log::warn;
+use serde::{de::DeserializeOwned, Deserialize};
+use std::error::Error;
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+use url::Url;
+
+type ChatResult = Result<Box<dyn Chat + Send + Sync>, Box<dyn Error + Send + Sync>>;
+type ChatFuture = Pin<Box<dyn Future<Output = ChatResult> + Send>>;
+
+/// Chat parameters.
+///
+/// This type contains what every backend needs to build its chat system,
+/// `options` is the table of the backend in the configuration.
+pub struct ChatParams {
+    pub host: Url,
+    pub username: String,
+    pub password: String,
+    pub tls: TlsConfig,
+    /// The `proxy` configuration key, see [ChatConfig](../config/struct.ChatConfig.html).
+    pub proxy: Option<String>,
+    pub chat_log: Option<ChatLogger>,
+    /// Record the websocket session to this file.
+    pub record: Option<PathBuf>,
+    pub options: toml::value::Table,
+    pub tx_ui: Sender<UIEvent>,
+    pub rx_chat: Receiver<ChatEvent>,
+    pub notifier: Box<dyn Notification + Sync + Send>,
+}
+
+/// A chat backend compiled in the binary.
+pub struct Backend {
+    /// The name used by the `backend` configuration key.
+    pub name: &'static str,
+    pub description: &'static str,
+    build: fn(ChatParams) -> ChatFuture,
+}
+
+const BACKENDS: &[Backend] = &[
+    Backend {
+        name: "rocketchat",
+        description: "Rocket.Chat realtime API",
+        build: rocketchat,
+    },
+    #[cfg(feature = "matrix")]
+    Backend {
+        name: "matrix",
+        description: "Matrix client-server API",
+        build: matrix,
+    },
+    #[cfg(feature = "irc")]
+    Backend {
+        name: "irc",
+        description: "IRC with SASL and CHATHISTORY",
+        build: irc,
+    },
+    #[cfg(feature = "mattermost")]
+    Backend {
+        name: "mattermost",
+        description: "Mattermost API v4",
+        build: mattermost,
+    },
+    #[cfg(feature = "xmpp")]
+    Backend {
+        name: "xmpp",
+        description: "XMPP with multi-user chat",
+        build: xmpp,
+    },
+];
+
+/// The backends compiled in, Rocket.Chat is always available.
+pub fn backends() -> &'static [Backend] {
+    BACKENDS
+}
+
+/// Build the chat system of a backend.
+pub async fn build_chat(backend: &str, params: ChatParams) -> ChatResult {
+    match BACKENDS.iter().find(|x| x.name == backend) {
+        Some(backend) => (backend.build)(params).await,
+        None => Err(format!(
+            "Unknown backend {}, available backends: {}",
+            backend,
+            BACKENDS
+                .iter()
+                .map(|x| x.name)
+                .collect::<Vec<&str>>()
+                .join(", ")
+        )
+        .into()),
+    }
+}
+
+/// Read the backend table into its typed options.
+fn options<T: DeserializeOwned>(
+    backend: &str,
+    options: toml::value::Table,
+) -> Result<T, Box<dyn Error + Send + Sync>> {
+    toml::Value::Table(options)
+        .try_into()
+        .map_err(|err| format!("Invalid [{}] options: {}", backend, err).into())
+}
+
+/// Warn about the parameters only Rocket.Chat supports.
+#[cfg(any(
+    feature = "matrix",
+    feature = "irc",
+    feature = "mattermost",
+    feature = "xmpp"
+))]
+fn warn_unsupported(backend: &str, params: &ChatParams) {
+    if params.chat_log.is_some() {
+        warn!("Chat logging is not supported by the {} backend", backend);
+    }
+    if params.record.is_some() {
+        warn!("Recording is not supported by the {} backend", backend);
+    }
+    if params.proxy.as_ref().is_some_and(|x| !x.is_empty()) {
+        warn!("The proxy key is not supported by the {} backend", backend);
+    }
+}
+
+/// Options of the `[rocketchat]` table.
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+struct RocketChatOptions {
+    /// The websocket, when a reverse proxy doesn't serve it next to the API.
+    websocket_url: Option<String>,
+}
+
+fn rocketchat(params: ChatParams) -> ChatFuture {
+    Box::pin(async move {
+        let options: RocketChatOptions = options("rocketchat", params.options)?;
+        let recorder = params.record.map(|x| Recorder::create(&x)).transpose()?;
+        let proxy = proxy::resolve(params.proxy.as_deref(), &params.host)?;
+        let ws_url = rocketchat::discover(
+            &params.host,
+            options.websocket_url.as_deref(),
+            &params.tls,
+            proxy.as_ref(),
+        )
+        .await?;
+        let socket =
+            rocketchat::connect_socket(&ws_url, &params.tls, proxy.as_ref(), recorder).await?;
+        let mut chat = RocketChat::with_socket(
+            socket,
+            params.host,
+            params.username,
+            params.password,
+            params.tx_ui,
+            params.rx_chat,
+            params.notifier,
+        )
+        .await?;
+        if let Some(chat_log) = params.chat_log {
+            chat = chat.with_chat_log(chat_log);
+        }
+        Ok(Box::new(chat) as Box<dyn Chat + Send + Sync>)
+    })
+}
+
+#[cfg(feature = "matrix")]
+fn matrix(params: ChatParams) -> ChatFuture {
+    Box::pin(async move {
+        warn_unsupported("matrix", &params);
+        let chat = super::Matrix::new(
+            params.host,
+            params.username,
+            params.password,
+            params.tls,
+            params.tx_ui,
+            params.rx_chat,
+            params.notifier,
+        )
+        .await?;
+        Ok(Box::new(chat) as Box<dyn Chat + Send + Sync>)
+    })
+}
+
+/// Options of the `[irc]` table.
+#[cfg(feature = "irc")]
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+struct IrcOptions {
+    /// Channels joined on startup.
+    channels: Vec<String>,
+}
+
+#[cfg(feature = "irc")]
+fn irc(params: ChatParams) -> ChatFuture {
+    Box::pin(async move {
+        warn_unsupported("irc", &params);
+        let options: IrcOptions = options("irc", params.options)?;
+        let chat = super::Irc::new(
+            params.host,
+            params.username,
+            params.password,
+            params.tls,
+            params.tx_ui,
+            params.rx_chat,
+            params.notifier,
+        )
+        .await?
+        .with_channels(options.channels);
+        Ok(Box::new(chat) as Box<dyn Chat + Send + Sync>)
+    })
+}
+
+#[cfg(feature = "mattermost")]
+fn mattermost(params: ChatParams) -> ChatFuture {
+    Box::pin(async move {
+        warn_unsupported("mattermost", &params);
+        let chat = super::Mattermost::new(
+            params.host,
+            params.username,
+            params.password,
+            params.tls,
+            params.tx_ui,
+            params.rx_chat,
+            params.notifier,
+        )
+        .await?;
+        Ok(Box::new(chat) as Box<dyn Chat + Send + Sync>)
+    })
+}
+
+/// Options of the `[xmpp]` table.
+#[cfg(feature = "xmpp")]
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+struct XmppOptions {
+    /// Multi-user chat rooms joined on startup.
+    rooms: Vec<String>,
+    /// Log in without TLS when the server doesn't offer STARTTLS.
+    allow_plaintext: bool,
+}
+
+#[cfg(feature = "xmpp")]
+fn xmpp(params: ChatParams) -> ChatFuture {
+    Box::pin(async move {
+        warn_unsupported("xmpp", &params);
+        let options: XmppOptions = options("xmpp", params.options)?;
+        let chat = super::Xmpp::new(
+            params.host,
+            params.username,
+            params.password,
+            super::XmppTls {
+                config: params.tls,
+                allow_plaintext: options.allow_plaintext,
+            },
+            params.tx_ui,
+            params.rx_chat,
+            params.notifier,
+        )
+        .await?
+        .with_rooms(options.rooms);
+        Ok(Box::new(chat) as Box<dyn Chat + Send + Sync>)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_channel::unbounded;
+
+    struct FakeNotifier;
+    impl Notification for FakeNotifier {
+        fn notify(&self, _title: &str, _content: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_backends() {
+        let names = backends().iter().map(|x| x.name).collect::<Vec<&str>>();
+        assert_eq!(names[0], "rocketchat");
+        assert_eq!(cfg!(feature = "irc"), names.contains(&"irc"));
+        assert_eq!(cfg!(feature = "xmpp"), names.contains(&"xmpp"));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_backend() {
+        let (tx_ui, _rx_ui) = unbounded();
+        let (_tx_chat, rx_chat) = unbounded();
+        let params = ChatParams {
+            host: Url::parse("https://chat.test").unwrap(),
+            username: "lou".into(),
+            password: "secret".into(),
+            tls: TlsConfig::default(),
+            proxy: None,
+            chat_log: None,
+            record: None,
+            options: toml::value::Table::new(),
+            tx_ui,
+            rx_chat,
+            notifier: Box::new(FakeNotifier {}),
+        };
+        let err = build_chat("slack", params).await.err().unwrap();
+        assert!(
+            format!("{}", err).starts_with("Unknown backend slack, available backends: rocketchat")
+        );
+    }
+
+    #[test]
+    fn test_rocketchat_options() {
+        let table: toml::value::Table =
+            toml::from_str(r#"websocket_url = "wss://ws.chat.test/websocket""#).unwrap();
+        let rocketchat: RocketChatOptions = options("rocketchat", table).unwrap();
+        assert_eq!(
+            rocketchat.websocket_url,
+            Some("wss://ws.chat.test/websocket".into())
+        );
+    }
+
+    #[cfg(feature = "irc")]
+    #[test]
+    fn test_irc_options() {
+        let table: toml::value::Table =
+            toml::from_str(r##"channels = ["#rust", "#ops"]"##).unwrap();
+        let irc: IrcOptions = options("irc", table).unwrap();
+        assert_eq!(irc.channels, vec!["#rust", "#ops"]);
+    }
+
+    #[cfg(feature = "xmpp")]
+    #[test]
+    fn test_xmpp_options() {
+        let xmpp: XmppOptions = options("xmpp", toml::value::Table::new()).unwrap();
+        assert_eq!(xmpp, XmppOptions::default());
+        let table: toml::value::Table = toml::from_str(r#"room = "ops""#).unwrap();
+        let err = options::<XmppOptions>("xmpp", table).err().unwrap();
+        assert!(format!("{}", err).starts_with("Invalid [xmpp] options: unknown field `room`"));
+    }
+}
@@ -2,6 +2,7 @@ use super::schema::*;
 use async_channel::{Receiver, Sender};
 use async_trait::async_trait;
 use async_tungstenite::tungstenite;
+use chrono::{DateTime, Utc};
 use sha2::{Digest, Sha256};
 use std::error::Error;
 
@@ -30,7 +31,14 @@ pub trait WebSocketWriter {
         room_id: String,
         count: usize,
     ) -> Result<(), Box<dyn Error + Send + Sync>>;
+    async fn load_history_before(
+        &self,
+        room_id: String,
+        end: Option<DateTime<Utc>>,
+        count: usize,
+    ) -> Result<(), Box<dyn Error + Send + Sync>>;
     async fn load_rooms(&self) -> Result<(), Box<dyn Error + Send + Sync>>;
+    async fn get_room_id(&self, name: String) -> Result<(), Box<dyn Error + Send + Sync>>;
     async fn create_direct_chat(
         &self,
         username: String,
@@ -38,6 +46,13 @@ pub trait WebSocketWriter {
     async fn subscribe_user(&self) -> Result<(), Box<dyn Error + Send + Sync>>;
     async fn subscribe_messages(&self) -> Result<(), Box<dyn Error + Send + Sync>>;
     async fn get_users_room(&self, room_id: String) -> Result<(), Box<dyn Error + Send + Sync>>;
+    async fn load_subscriptions(&self) -> Result<(), Box<dyn Error + Send + Sync>>;
+    /// Set the desktop notifications of a room, `default` removes the setting.
+    async fn save_notification_settings(
+        &self,
+        room_id: String,
+        value: String,
+    ) -> Result<(), Box<dyn Error + Send + Sync>>;
 }
 
 pub struct RocketChatWsWriter {
@@ -61,8 +76,21 @@ impl RocketChatWsWriter {
         RocketChatWsWriter::connect(&websocket).await?;
         reader.recv().await?;
         RocketChatWsWriter::init(&username, &password_digest, &websocket).await?;
-        let msg = reader.recv().await?;
-        let user_id = serde_json::from_str::<UserIdResponse>(&msg.to_string()[..])?.id;
+        let user_id = loop {
+            let msg = reader.recv().await?;
+            if let Ok(login) = serde_json::from_str::<LoginResponseWs>(&msg.to_string()[..]) {
+                if login.id == "1" {
+                    match (login.result, login.error) {
+                        (Some(user), _) => break user.id,
+                        (None, error) => {
+                            let error = error.unwrap_or_default();
+                            let reason = error["reason"].as_str().unwrap_or("unknown error");
+                            return Err(format!("Login failed: {}", reason).into());
+                        }
+                    }
+                }
+            }
+        };
         Ok(RocketChatWsWriter {
             username,
             password_digest,
@@ -182,6 +210,30 @@ impl WebSocketWriter for RocketChatWsWriter {
         Ok(())
     }
 
+    async fn load_history_before(
+        &self,
+        room_id: String,
+        end: Option<DateTime<Utc>>,
+        count: usize,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let end = end
+            .map(|x| format!(r#"{{ "$date": {} }}"#, x.timestamp_millis()))
+            .unwrap_or_else(|| "null".into());
+        let msg = format!(
+            r#"
+            {{
+                "msg": "method",
+                "method": "loadHistory",
+                "id": "9",
+                "params": [ "{}", {}, {}, null ]
+            }}
+        "#,
+            room_id, end, count
+        );
+        self.websocket.send(tungstenite::Message::Text(msg)).await?;
+        Ok(())
+    }
+
     async fn load_rooms(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
         let msg = r#"
             {
@@ -197,6 +249,22 @@ impl WebSocketWriter for RocketChatWsWriter {
         Ok(())
     }
 
+    async fn get_room_id(&self, name: String) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let msg = format!(
+            r#"
+            {{
+                "msg": "method",
+                "method": "getRoomIdByNameOrId",
+                "id": "10",
+                "params": ["{}"]
+            }}
+        "#,
+            name
+        );
+        self.websocket.send(tungstenite::Message::Text(msg)).await?;
+        Ok(())
+    }
+
     async fn create_direct_chat(
         &self,
         username: String,
@@ -271,6 +339,38 @@ impl WebSocketWriter for RocketChatWsWriter {
         self.websocket.send(tungstenite::Message::Text(msg)).await?;
         Ok(())
     }
+
+    async fn load_subscriptions(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let msg = r#"
+            {
+                "msg": "method",
+                "method": "subscriptions/get",
+                "id": "11",
+                "params": []
+            }
+        "#;
+        self.websocket
+            .send(tungstenite::Message::Text(msg.into()))
+            .await?;
+        Ok(())
+    }
+
+    async fn save_notification_settings(
+        &self,
+        room_id: String,
+        value: String,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let msg = serde_json::json!({
+            "msg": "method",
+            "method": "saveNotificationSettings",
+            "id": "12",
+            "params": [room_id, "desktopNotifications", value]
+        });
+        self.websocket
+            .send(tungstenite::Message::Text(msg.to_string()))
+            .await?;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -294,9 +394,11 @@ mod tests {
         tx.send(tungstenite::Message::Text("connect".into()))
             .await
             .unwrap();
-        tx.send(tungstenite::Message::Text(r#"{"id": "idtest"}"#.into()))
-            .await
-            .unwrap();
+        tx.send(tungstenite::Message::Text(
+            r#"{"msg": "result", "id": "1", "result": {"id": "idtest"}}"#.into(),
+        ))
+        .await
+        .unwrap();
         let ws = RocketChatWsWriter::new("usertest".into(), "passtest".into(), tx, &rx)
             .await
             .unwrap();
@@ -416,6 +518,60 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_load_history_before() {
+        let (ws, rx) = create_fake_websocket().await;
+        ws.load_history_before("roomtest".into(), None, 100)
+            .await
+            .unwrap();
+        compare_json(
+            &rx.recv().await.unwrap().to_string(),
+            r#"
+            {
+                "msg": "method",
+                "method": "loadHistory",
+                "id": "9",
+                "params": [ "roomtest", null, 100, null ]
+            }
+            "#,
+        );
+        ws.load_history_before(
+            "roomtest".into(),
+            Some(chrono::TimeZone::timestamp_millis(&Utc, 1593589750164)),
+            100,
+        )
+        .await
+        .unwrap();
+        compare_json(
+            &rx.recv().await.unwrap().to_string(),
+            r#"
+            {
+                "msg": "method",
+                "method": "loadHistory",
+                "id": "9",
+                "params": [ "roomtest", { "$date": 1593589750164 }, 100, null ]
+            }
+            "#,
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_room_id() {
+        let (ws, rx) = create_fake_websocket().await;
+        ws.get_room_id("general".into()).await.unwrap();
+        compare_json(
+            &rx.recv().await.unwrap().to_string(),
+            r#"
+            {
+                "msg": "method",
+                "method": "getRoomIdByNameOrId",
+                "id": "10",
+                "params": ["general"]
+            }
+            "#,
+        );
+    }
+
     #[tokio::test]
     async fn test_load_rooms() {
         let (ws, rx) = create_fake_websocket().await;
@@ -433,6 +589,37 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_load_subscriptions() {
+        let (ws, rx) = create_fake_websocket().await;
+        ws.load_subscriptions().await.unwrap();
+        compare_json(
+            &rx.recv().await.unwrap().to_string(),
+            r#"
+            {
+                "msg": "method",
+                "method": "subscriptions/get",
+                "id": "11",
+                "params": []
+            }
+            "#,
+        );
+        ws.save_notification_settings("GENERAL".into(), "nothing".into())
+            .await
+            .unwrap();
+        compare_json(
+            &rx.recv().await.unwrap().to_string(),
+            r#"
+            {
+                "msg": "method",
+                "method": "saveNotificationSettings",
+                "id": "12",
+                "params": ["GENERAL", "desktopNotifications", "nothing"]
+            }
+            "#,
+        );
+    }
+
     #[tokio::test]
     async fn test_create_direct_chat() {
         let (ws, rx) = create_fake_websocket().await;
@@ -0,0 +1,297 @@
+//! Server discovery.
+//!
+//! This module finds the websocket of a Rocket.Chat server from its URL,
+//! keeping the path it may be served under, and asks `/api/info` whether
+//! the URL really points to a Rocket.Chat instance, following redirects.
+use super::super::proxy;
+use super::super::tls::{self, TlsConfig};
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::error::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use url::Url;
+
+/// Largest `/api/info` response we read.
+const MAX_RESPONSE_SIZE: u64 = 64 * 1024;
+/// Redirects followed before giving up.
+const MAX_REDIRECTS: usize = 5;
+
+/// The answer of `/api/info`.
+///
+/// Recent servers only give the version to logged in administrators.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct ServerInfo {
+    #[serde(default)]
+    pub success: bool,
+    pub version: Option<String>,
+}
+
+/// What `/api/info` tells about a server.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerCheck {
+    /// A Rocket.Chat server, at the given URL after redirects.
+    RocketChat(ServerInfo, Url),
+    /// The server answered, but not as Rocket.Chat does.
+    Other(String),
+}
+
+/// An HTTP response.
+struct Response {
+    status: u16,
+    location: Option<String>,
+    body: Vec<u8>,
+}
+
+/// The server URL with a trailing slash, so that joining keeps its path.
+fn base_url(host: &Url) -> Url {
+    let mut url = host.clone();
+    if !url.path().ends_with('/') {
+        url.set_path(&format!("{}/", url.path()));
+    }
+    url.set_query(None);
+    url.set_fragment(None);
+    url
+}
+
+/// The websocket of a server, `https://corp.example/chat` has its
+/// websocket at `wss://corp.example/chat/websocket`.
+pub fn websocket_url(host: &Url) -> Result<Url, Box<dyn Error + Send + Sync>> {
+    let mut url = base_url(host).join("websocket")?;
+    let scheme = match url.scheme() {
+        "https" | "wss" => "wss",
+        _ => "ws",
+    };
+    url.set_scheme(scheme).map_err(|err| format!("{:?}", err))?;
+    Ok(url)
+}
+
+/// Check a `websocket_url` given in the configuration.
+pub fn parse_websocket_url(websocket_url: &str) -> Result<Url, Box<dyn Error + Send + Sync>> {
+    let url = Url::parse(websocket_url)
+        .map_err(|err| format!("Invalid websocket_url {}: {}", websocket_url, err))?;
+    match url.scheme() {
+        "ws" | "wss" if url.host_str().is_some() => Ok(url),
+        _ => Err(format!(
+            "Invalid websocket_url {}: expected a ws:// or wss:// URL",
+            websocket_url
+        )
+        .into()),
+    }
+}
+
+/// Send a GET request and read the status, the location and the body of
+/// the answer.
+///
+/// HTTP/1.0 is used so that the body is neither chunked nor kept alive.
+async fn get<S>(mut stream: S, url: &Url) -> Result<Response, Box<dyn Error + Send + Sync>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nAccept: application/json\r\nUser-Agent: talkoxid\r\n\r\n",
+        &url[url::Position::BeforePath..url::Position::AfterQuery],
+        &url[url::Position::BeforeHost..url::Position::AfterPort],
+    );
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = Vec::new();
+    stream
+        .take(MAX_RESPONSE_SIZE)
+        .read_to_end(&mut response)
+        .await?;
+    let header_end = response
+        .windows(4)
+        .position(|x| x == b"\r\n\r\n")
+        .ok_or("Incomplete HTTP response")?;
+    let header = String::from_utf8_lossy(&response[..header_end]);
+    let status = header
+        .split(' ')
+        .nth(1)
+        .and_then(|x| x.parse::<u16>().ok())
+        .ok_or_else(|| {
+            format!(
+                "Invalid HTTP response: {}",
+                header.lines().next().unwrap_or_default()
+            )
+        })?;
+    let location = header.lines().skip(1).find_map(|x| {
+        let (name, value) = x.split_once(':')?;
+        match name.trim().eq_ignore_ascii_case("location") {
+            true => Some(value.trim().to_string()),
+            false => None,
+        }
+    });
+    Ok(Response {
+        status,
+        location,
+        body: response[header_end + 4..].to_vec(),
+    })
+}
+
+/// Send a GET request to `url` through `proxy`.
+async fn get_url(
+    url: &Url,
+    tls: &TlsConfig,
+    proxy: Option<&Url>,
+) -> Result<Response, Box<dyn Error + Send + Sync>> {
+    let server = url.host_str().ok_or("The hostname has no host")?;
+    let stream = proxy::connect(server, url.port_or_known_default().unwrap_or(443), proxy).await?;
+    match url.scheme() {
+        "https" => {
+            let name = rustls::ServerName::try_from(server)?;
+            get(tls::connector(tls)?.connect(name, stream).await?, url).await
+        }
+        _ => get(stream, url).await,
+    }
+}
+
+/// Ask `/api/info` whether `host` is a Rocket.Chat server and its version.
+///
+/// An error means that the server couldn't tell, it may still be one.
+pub async fn server_info(
+    host: &Url,
+    tls: &TlsConfig,
+    proxy: Option<&Url>,
+) -> Result<ServerCheck, Box<dyn Error + Send + Sync>> {
+    let mut url = base_url(host).join("api/info")?;
+    let mut response = get_url(&url, tls, proxy).await?;
+    let mut redirects = 0;
+    while let (301 | 302 | 303 | 307 | 308, Some(location)) = (response.status, &response.location)
+    {
+        if redirects == MAX_REDIRECTS {
+            return Err(format!("{} redirected too many times", url).into());
+        }
+        url = url.join(location)?;
+        response = get_url(&url, tls, proxy).await?;
+        redirects += 1;
+    }
+    if response.status != 200 {
+        return Err(format!("{} answered {}", url, response.status).into());
+    }
+    let info = match serde_json::from_slice::<ServerInfo>(&response.body) {
+        Ok(info) if info.success => info,
+        _ => {
+            return Ok(ServerCheck::Other(format!(
+            "{} doesn't look like a Rocket.Chat server, {} didn't answer Rocket.Chat information",
+            host, url
+        )))
+        }
+    };
+    // The server may have moved, to HTTPS or another path
+    let path = url
+        .path()
+        .strip_suffix("api/info")
+        .unwrap_or("/")
+        .to_string();
+    url.set_path(&path);
+    url.set_query(None);
+    Ok(ServerCheck::RocketChat(info, url))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::fake_server::FakeDdpServer;
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_websocket_url() {
+        let websocket = |host: &str| {
+            websocket_url(&Url::parse(host).unwrap())
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(
+            websocket("https://corp.example/chat"),
+            "wss://corp.example/chat/websocket"
+        );
+        assert_eq!(
+            websocket("https://corp.example/chat/?lang=fr"),
+            "wss://corp.example/chat/websocket"
+        );
+        assert_eq!(
+            websocket("http://localhost:3000"),
+            "ws://localhost:3000/websocket"
+        );
+        assert!(parse_websocket_url("wss://corp.example/ws").is_ok());
+        assert_eq!(
+            format!(
+                "{}",
+                parse_websocket_url("https://corp.example/ws")
+                    .err()
+                    .unwrap()
+            ),
+            "Invalid websocket_url https://corp.example/ws: expected a ws:// or wss:// URL"
+        );
+    }
+
+    /// Answer one request under `/chat` with `response`.
+    async fn serve_once(response: String) -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host = Url::parse(&format!("http://{}/chat", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                request.push(stream.read_u8().await.unwrap());
+            }
+            assert!(request.starts_with(b"GET /chat/api/info HTTP/1.0\r\n"));
+            stream.write_all(response.as_bytes()).await.unwrap();
+        });
+        host
+    }
+
+    #[tokio::test]
+    async fn test_server_info() {
+        let server = FakeDdpServer::start("lou", "secret", vec![]).await;
+        let check = server_info(&server.url, &TlsConfig::default(), None)
+            .await
+            .unwrap();
+        match check {
+            ServerCheck::RocketChat(info, url) => {
+                assert_eq!(info.version, Some("6.3.0".into()));
+                assert_eq!(url, base_url(&server.url));
+            }
+            check => panic!("Unexpected check {:?}", check),
+        }
+
+        let host = serve_once("HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".into()).await;
+        let err = server_info(&host, &TlsConfig::default(), None)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            format!("{}", err),
+            format!("{}api/info answered 404", base_url(&host))
+        );
+
+        let host = serve_once("HTTP/1.1 200 OK\r\n\r\n<html></html>".into()).await;
+        assert_eq!(
+            server_info(&host, &TlsConfig::default(), None)
+                .await
+                .unwrap(),
+            ServerCheck::Other(format!(
+                "{} doesn't look like a Rocket.Chat server, {}api/info didn't answer Rocket.Chat information",
+                host,
+                base_url(&host)
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_server_info_redirect() {
+        let server = FakeDdpServer::start("lou", "secret", vec![]).await;
+        let moved = base_url(&server.url).join("api/info").unwrap();
+        let host = serve_once(format!(
+            "HTTP/1.1 301 Moved Permanently\r\nlocation: {}\r\n\r\n",
+            moved
+        ))
+        .await;
+        match server_info(&host, &TlsConfig::default(), None)
+            .await
+            .unwrap()
+        {
+            ServerCheck::RocketChat(_, url) => assert_eq!(url, base_url(&server.url)),
+            check => panic!("Unexpected check {:?}", check),
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use async_channel::{unbounded, Receiver, Sender};
+use async_tungstenite::tungstenite;
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use url::Url;
+
+/// The reply of the fake server to a method call.
+#[derive(Debug, Clone)]
+pub enum Reply {
+    /// A `result` frame with this result.
+    Result(Value),
+    /// A Meteor error with this reason.
+    Error(&'static str),
+    /// Close the connection without replying.
+    Disconnect,
+}
+
+/// The replies to the calls of a method, the last one repeats.
+pub type Method = (&'static str, Vec<Reply>);
+
+enum Command {
+    Push(Value),
+    Disconnect,
+}
+
+/// A fake Rocket.Chat server speaking DDP over a websocket.
+///
+/// This type accepts a single client on a local port. It answers the
+/// connection, the login of one user, subscriptions and pings, method
+/// calls get the scripted replies or a "method not found" error.
+/// Requests to `/api/info` are answered before the websocket opens.
+pub struct FakeDdpServer {
+    pub url: Url,
+    received: Receiver<Value>,
+    tx_server: Sender<Command>,
+}
+
+pub const USER_ID: &str = "fake-user-id";
+pub const TOKEN: &str = "fake-token";
+
+fn error(id: &Value, code: u16, reason: &str) -> Value {
+    json!({
+        "msg": "result",
+        "id": id,
+        "error": {
+            "isClientSafe": true,
+            "error": code,
+            "reason": reason,
+            "message": format!("{} [{}]", reason, code),
+            "errorType": "Meteor.Error"
+        }
+    })
+}
+
+fn login(frame: &Value, username: &str, digest: &str) -> Value {
+    let params = &frame["params"][0];
+    if params["user"]["username"] == username && params["password"]["digest"] == digest {
+        json!({
+            "msg": "result",
+            "id": frame["id"],
+            "result": {
+                "id": USER_ID,
+                "token": TOKEN,
+                "tokenExpires": { "$date": 1596027867123u64 },
+                "type": "password"
+            }
+        })
+    } else {
+        error(&frame["id"], 403, "User not found")
+    }
+}
+
+/// The first client connection which isn't an `/api/info` request.
+async fn accept_websocket(listener: &TcpListener) -> TcpStream {
+    loop {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut start = [0u8; 64];
+        let size = loop {
+            let size = stream.peek(&mut start).await.unwrap();
+            if size == start.len() || start[..size].contains(&b'\n') {
+                break size;
+            }
+            tokio::task::yield_now().await;
+        };
+        let request_line = String::from_utf8_lossy(&start[..size]);
+        if !request_line.starts_with("GET ")
+            || !request_line
+                .split(' ')
+                .nth(1)
+                .unwrap_or_default()
+                .ends_with("/api/info")
+        {
+            return stream;
+        }
+        let mut request = Vec::new();
+        while !request.ends_with(b"\r\n\r\n") {
+            request.push(stream.read_u8().await.unwrap());
+        }
+        let body = json!({ "version": "6.3.0", "success": true }).to_string();
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        stream.write_all(response.as_bytes()).await.unwrap();
+    }
+}
+
+impl FakeDdpServer {
+    pub async fn start(username: &str, password: &str, mut methods: Vec<Method>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+        let username = username.to_string();
+        let digest = format!("{:x}", Sha256::digest(password.as_bytes()));
+        let (tx_received, received) = unbounded();
+        let (tx_server, rx_server) = unbounded();
+        tokio::spawn(async move {
+            let stream = accept_websocket(&listener).await;
+            let mut socket = async_tungstenite::tokio::accept_async(stream)
+                .await
+                .unwrap();
+            let hello = json!({ "server_id": "0" });
+            socket
+                .send(tungstenite::Message::Text(hello.to_string()))
+                .await
+                .unwrap();
+            loop {
+                let replies = tokio::select! {
+                    frame = socket.next() => match frame {
+                        Some(Ok(tungstenite::Message::Text(frame))) => {
+                            let frame: Value = serde_json::from_str(&frame).unwrap();
+                            let replies = match frame["msg"].as_str().unwrap_or_default() {
+                                "connect" => vec![json!({ "msg": "connected", "session": "fake-session" })],
+                                "ping" => vec![json!({ "msg": "pong" })],
+                                "sub" => vec![json!({ "msg": "ready", "subs": [frame["id"]] })],
+                                "unsub" => vec![json!({ "msg": "nosub", "id": frame["id"] })],
+                                "method" if frame["method"] == "login" => {
+                                    vec![login(&frame, &username, &digest)]
+                                }
+                                "method" => {
+                                    let method = frame["method"].as_str().unwrap_or_default();
+                                    let reply = match methods.iter_mut().find(|(x, _)| *x == method) {
+                                        Some((_, replies)) if replies.len() > 1 => Some(replies.remove(0)),
+                                        Some((_, replies)) => replies.first().cloned(),
+                                        None => None,
+                                    };
+                                    match reply {
+                                        Some(Reply::Result(result)) => {
+                                            vec![json!({ "msg": "result", "id": frame["id"], "result": result })]
+                                        }
+                                        Some(Reply::Error(reason)) => vec![error(&frame["id"], 400, reason)],
+                                        Some(Reply::Disconnect) => {
+                                            tx_received.send(frame).await.unwrap();
+                                            break;
+                                        }
+                                        None => vec![error(
+                                            &frame["id"],
+                                            404,
+                                            &format!("Method '{}' not found", method),
+                                        )],
+                                    }
+                                }
+                                _ => vec![],
+                            };
+                            tx_received.send(frame).await.unwrap();
+                            replies
+                        }
+                        Some(Ok(_)) => vec![],
+                        _ => break,
+                    },
+                    command = rx_server.recv() => match command {
+                        Ok(Command::Push(frame)) => vec![frame],
+                        _ => break,
+                    },
+                };
+                for reply in replies {
+                    socket
+                        .send(tungstenite::Message::Text(reply.to_string()))
+                        .await
+                        .unwrap();
+                }
+            }
+            socket.close(None).await.ok();
+        });
+        FakeDdpServer {
+            url,
+            received,
+            tx_server,
+        }
+    }
+
+    /// Wait for the client to send a frame, like a method call, matching `filter`.
+    pub async fn expect<F>(&self, filter: F) -> Value
+    where
+        F: Fn(&Value) -> bool,
+    {
+        let wait = async {
+            loop {
+                let frame = self.received.recv().await.unwrap();
+                if filter(&frame) {
+                    break frame;
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), wait)
+            .await
+            .expect("Expected frame never received")
+    }
+
+    /// Wait for the client to call `method`.
+    pub async fn expect_method(&self, method: &str) -> Value {
+        self.expect(|x| x["msg"] == "method" && x["method"] == method)
+            .await
+    }
+
+    /// Send a frame to the client, like a stream event.
+    pub async fn push(&self, frame: Value) {
+        self.tx_server.send(Command::Push(frame)).await.unwrap();
+    }
+
+    pub async fn ping(&self) {
+        self.push(json!({ "msg": "ping" })).await
+    }
+
+    /// Close the connection with the client.
+    pub async fn disconnect(&self) {
+        self.tx_server.send(Command::Disconnect).await.unwrap();
+    }
+}
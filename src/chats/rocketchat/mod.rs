@@ -1,75 +1,79 @@
 mod api;
+mod discovery;
+#[cfg(test)]
+pub(crate) mod fake_server;
+mod recording;
 mod schema;
 
-use super::super::core::{Channel, Chat, ChatEvent, Message, Notification, UIEvent};
-use api::{RocketChatWsWriter, WebSocketWriter};
+use super::super::chatlog::ChatLogger;
+use super::super::core::{
+    Alert, Attachment, Channel, Chat, ChatEvent, Message, Notification, NotificationLevel, UIEvent,
+};
+use super::super::export::{self, ExportFormat, ExportOptions};
+use super::proxy;
+use super::tls::{self, TlsConfig};
+use api::RocketChatWsWriter;
+use api::WebSocketWriter;
 use async_channel::{unbounded, Receiver, Sender};
 use async_trait::async_trait;
 use async_tungstenite::tungstenite;
+use discovery::ServerCheck;
 use futures_util::StreamExt;
-use log::error;
+use log::{error, info, warn};
+pub(super) use recording::Recorder;
+use recording::{Direction, ReplayWsWriter};
 use schema::*;
+use std::collections::HashMap;
 use std::error::Error;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
-use tokio_rustls::TlsConnector;
 use url::Url;
-use webpki_roots;
 
-struct NoCertificateVerification {}
+/// Number of messages requested per page when exporting a channel.
+const EXPORT_PAGE_SIZE: usize = 100;
+/// The room every Rocket.Chat server creates.
+const DEFAULT_CHANNEL: &str = "GENERAL";
 
-impl rustls::client::ServerCertVerifier for NoCertificateVerification {
-    fn verify_server_cert(
-        &self,
-        _end_entity: &rustls::Certificate,
-        _intermediates: &[rustls::Certificate],
-        _server_name: &rustls::ServerName,
-        _scts: &mut dyn Iterator<Item = &[u8]>,
-        _ocsp_response: &[u8],
-        _now: std::time::SystemTime,
-    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
-        Ok(rustls::client::ServerCertVerified::assertion())
+/// The level of a subscription, unless it follows the user preferences.
+fn server_level(subscription: &SubscriptionResponseWs) -> Option<NotificationLevel> {
+    if subscription.disable_notifications {
+        return Some(NotificationLevel::None);
+    }
+    match subscription.desktop_notifications.as_deref() {
+        Some("all") => Some(NotificationLevel::All),
+        Some("mentions") => Some(NotificationLevel::Mentions),
+        Some("nothing") => Some(NotificationLevel::None),
+        _ => None,
     }
 }
-fn resolve_ws_url(
-    mut url: Url,
-    ssl_verify: bool,
-) -> Result<(Url, Option<TlsConnector>), Box<dyn Error + Send + Sync>> {
-    let (scheme, tls_config) = match url.scheme() {
-        "https" => {
-            let mut root_store = rustls::RootCertStore::empty();
-            root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(
-                |ta| {
-                    rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
-                        ta.subject,
-                        ta.spki,
-                        ta.name_constraints,
-                    )
-                },
-            ));
-            let mut tls_builder = rustls::ClientConfig::builder()
-                .with_safe_defaults()
-                .with_root_certificates(root_store)
-                .with_no_client_auth();
-            if !ssl_verify {
-                tls_builder = rustls::ClientConfig::builder()
-                    .with_safe_defaults()
-                    .with_custom_certificate_verifier(Arc::new(NoCertificateVerification {}))
-                    .with_no_client_auth();
-            }
-            let connector = TlsConnector::from(Arc::new(tls_builder));
-            ("wss", Some(connector.into()))
-        }
-        _ => ("ws", None),
-    };
-    url.set_scheme(scheme).map_err(|err| format!("{:?}", err))?;
-    url.set_path("/websocket");
-    Ok((url, tls_config))
+
+/// The `desktopNotifications` value saved for a `/notify` command, if any.
+fn server_setting(args: &str) -> Option<&'static str> {
+    match args {
+        "all" => Some("all"),
+        "mentions" => Some("mentions"),
+        "none" => Some("nothing"),
+        "default" => Some("default"),
+        _ => None,
+    }
+}
+
+/// An export in progress.
+///
+/// History pages are accumulated until the requested range is covered.
+struct ExportJob {
+    channel_name: String,
+    room_id: Option<String>,
+    options: ExportOptions,
+    messages: Vec<Message>,
+    done: Option<Sender<Result<PathBuf, String>>>,
 }
 
 /// RocketChat chat system.
 ///
 /// This type is a chat system implementation for RocketChat.
 pub struct RocketChat<U: WebSocketWriter + Send + Sync> {
+    host: Url,
     tx_ui: Sender<UIEvent>,
     ws: U,
     notifier: Box<dyn Notification + Sync + Send>,
@@ -78,12 +82,187 @@ pub struct RocketChat<U: WebSocketWriter + Send + Sync> {
     rx_chat: Receiver<ChatEvent>,
     username: String,
     current_channel: Mutex<Option<Channel>>,
+    room_names: Mutex<HashMap<String, String>>,
+    /// A channel opened before the rooms were known, maybe by name.
+    pending_channel: Mutex<Option<String>>,
+    /// The notification levels chosen on the server, by room id.
+    notification_levels: Mutex<HashMap<String, NotificationLevel>>,
+    export_job: Mutex<Option<ExportJob>>,
+    chat_log: Option<ChatLogger>,
 }
 
 impl<U> RocketChat<U>
 where
     U: WebSocketWriter + Send + Sync,
 {
+    /// Write every displayed message to a plaintext log.
+    pub fn with_chat_log(mut self, chat_log: ChatLogger) -> Self {
+        self.chat_log = Some(chat_log);
+        self
+    }
+
+    fn channel_name(&self, room_id: &str) -> String {
+        self.room_names
+            .lock()
+            .unwrap()
+            .get(room_id)
+            .cloned()
+            .unwrap_or_else(|| room_id.to_string())
+    }
+
+    fn log_message(&self, room_id: &str, message: &Message) {
+        if let Some(chat_log) = self.chat_log.as_ref() {
+            chat_log.log(&self.channel_name(room_id), message);
+        }
+    }
+
+    fn to_message(&self, message: &MessageResponseWs) -> Message {
+        let attachments = message
+            .attachments
+            .iter()
+            .filter_map(|x| {
+                let link = x.title_link.as_ref().or(x.image_url.as_ref())?;
+                let link = self
+                    .host
+                    .join(link)
+                    .map(|x| x.to_string())
+                    .unwrap_or_else(|_| link.clone());
+                Some(Attachment {
+                    title: x.title.clone().unwrap_or_else(|| link.clone()),
+                    link,
+                })
+            })
+            .collect();
+        Message {
+            id: message._id.clone(),
+            author: message.u.username.clone(),
+            content: message.msg.clone(),
+            datetime: message.ts.date,
+            thread: message.tmid.clone(),
+            attachments,
+        }
+    }
+
+    async fn start_export(
+        &self,
+        channel_name: String,
+        room_id: Option<String>,
+        options: ExportOptions,
+        done: Option<Sender<Result<PathBuf, String>>>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        {
+            let mut job = self.export_job.lock().unwrap();
+            // A single export at a time, its pages are told by their request id
+            if let Some(job) = job.as_ref() {
+                return Err(format!("The export of {} is still running", job.channel_name).into());
+            }
+            *job = Some(ExportJob {
+                channel_name: channel_name.clone(),
+                room_id: room_id.clone(),
+                options,
+                messages: vec![],
+                done,
+            });
+        }
+        match room_id {
+            Some(room_id) => {
+                self.ws
+                    .load_history_before(room_id, None, EXPORT_PAGE_SIZE)
+                    .await?
+            }
+            None => self.ws.get_room_id(channel_name).await?,
+        }
+        Ok(())
+    }
+
+    async fn export_room_found(&self, room_id: String) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if let Some(job) = self.export_job.lock().unwrap().as_mut() {
+            job.room_id = Some(room_id.clone());
+        } else {
+            return Ok(());
+        }
+        self.ws
+            .load_history_before(room_id, None, EXPORT_PAGE_SIZE)
+            .await?;
+        Ok(())
+    }
+
+    async fn export_page(
+        &self,
+        page: &[MessageResponseWs],
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let next_page = {
+            let mut job = self.export_job.lock().unwrap();
+            let job = match job.as_mut() {
+                Some(job) => job,
+                None => return Ok(()),
+            };
+            let oldest = page.iter().map(|x| x.ts.date).min();
+            job.messages.extend(page.iter().map(|x| self.to_message(x)));
+            match (oldest, job.options.since, job.room_id.clone()) {
+                (Some(oldest), Some(since), _) if oldest < since => None,
+                (Some(oldest), _, Some(room_id)) if page.len() >= EXPORT_PAGE_SIZE => {
+                    Some((room_id, oldest))
+                }
+                _ => None,
+            }
+        };
+        match next_page {
+            Some((room_id, oldest)) => {
+                self.ws
+                    .load_history_before(room_id, Some(oldest), EXPORT_PAGE_SIZE)
+                    .await?
+            }
+            None => self.finish_export(Ok(())).await?,
+        }
+        Ok(())
+    }
+
+    async fn finish_export(
+        &self,
+        status: Result<(), String>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let job = match self.export_job.lock().unwrap().take() {
+            Some(job) => job,
+            None => return Ok(()),
+        };
+        let ExportJob {
+            channel_name,
+            options,
+            mut messages,
+            done,
+            ..
+        } = job;
+        let result = match status {
+            Ok(()) => {
+                messages.sort_by_key(|x| x.datetime);
+                messages.dedup_by(|a, b| a.id == b.id);
+                let name = channel_name.clone();
+                // Rendering and writing a long history would block the executor
+                let written =
+                    tokio::task::spawn_blocking(move || export::write(&name, &messages, &options))
+                        .await;
+                match written {
+                    Ok(Ok(path)) => Ok(path),
+                    Ok(Err(err)) => Err(format!("Can't export {}: {}", channel_name, err)),
+                    Err(err) => Err(format!("Can't export {}: {}", channel_name, err)),
+                }
+            }
+            Err(err) => Err(err),
+        };
+        match done {
+            Some(done) => done.send(result).await?,
+            None => {
+                let info = match result {
+                    Ok(path) => format!("Channel exported to {}", path.display()),
+                    Err(err) => err,
+                };
+                self.tx_ui.send(UIEvent::ShowInfo(info)).await?
+            }
+        }
+        Ok(())
+    }
+
     async fn wait_messages_loop(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
         loop {
             let msg = self.rx_ws.recv().await?;
@@ -92,7 +271,7 @@ where
                     WsResponse::NewMessage(SocketMessageWs {
                         fields:
                             SocketArgsWs {
-                                args: SocketEventResponseWs(_, EventResponseWs { last_message, t }),
+                                args: (_, EventResponseWs { last_message, t }),
                                 ..
                             },
                         ..
@@ -102,40 +281,52 @@ where
                             x if x == "p" => Channel::Private(last_message.rid.clone()),
                             _ => Channel::Group(last_message.rid.clone()),
                         };
+                        let message = self.to_message(&last_message);
                         if last_message.u.username != self.username {
-                            if let Channel::User(_) = channel {
-                                self.notifier
-                                    .notify(&last_message.u.username[..], &last_message.msg[..])?;
-                            } else if last_message.msg.contains(&self.username[..]) {
-                                self.notifier
-                                    .notify(&last_message.u.username[..], &last_message.msg[..])?;
+                            let mut alert = Alert::new(
+                                channel.clone(),
+                                &self.channel_name(&last_message.rid),
+                                &message,
+                                &self.username,
+                            );
+                            alert.server_level = self
+                                .notification_levels
+                                .lock()
+                                .unwrap()
+                                .get(&last_message.rid)
+                                .copied();
+                            if let Err(err) = self.notifier.notify_message(&alert) {
+                                error!("Can't notify: {}", err);
                             }
                         }
-                        self.add_message(
-                            Message {
-                                author: last_message.u.username.clone(),
-                                content: last_message.msg.clone(),
-                                datetime: last_message.ts.date,
-                            },
-                            &channel,
-                        )
-                        .await?;
+                        self.add_message(message, &channel).await?;
                     }
                     WsResponse::History { id, result, .. } if id == "3" => {
                         let messages =
                             result.messages.iter().rev().fold(String::from(""), |x, y| {
-                                format!(
-                                    "{}{}\n",
-                                    x,
-                                    Message {
-                                        content: y.msg.clone(),
-                                        author: y.u.username.clone(),
-                                        datetime: y.ts.date
-                                    }
-                                )
+                                let message = self.to_message(y);
+                                self.log_message(&y.rid, &message);
+                                format!("{}{}\n", x, message)
                             });
                         self.tx_ui.send(UIEvent::UpdateMessages(messages)).await?;
                     }
+                    WsResponse::History { id, result, .. } if id == "9" => {
+                        self.export_page(&result.messages).await?;
+                    }
+                    WsResponse::Subscriptions { id, result, .. } if id == "11" => {
+                        *self.notification_levels.lock().unwrap() = result
+                            .iter()
+                            .filter_map(|x| Some((x.rid.clone(), server_level(x)?)))
+                            .collect();
+                    }
+                    WsResponse::RoomId { id, result, .. } if id == "10" => {
+                        self.export_room_found(result).await?;
+                    }
+                    WsResponse::MethodError { id, error, .. } if id == "10" => {
+                        let reason = error["reason"].as_str().unwrap_or("unknown channel");
+                        self.finish_export(Err(format!("Can't export: {}", reason)))
+                            .await?;
+                    }
 
                     WsResponse::Rooms { id, result, .. } if id == "4" => {
                         let channels = result
@@ -145,8 +336,8 @@ where
                                 RoomResponseWs::Direct(DirectChatResponseWs { _id, usernames }) => {
                                     let all_usernames = usernames
                                         .iter()
+                                        .filter(|x| *x != &self.username || usernames.len() == 1)
                                         .cloned()
-                                        .filter(|x| x != &self.username || usernames.len() == 1)
                                         .collect::<Vec<String>>()
                                         .join(",");
                                     (all_usernames, Channel::User(_id.clone()))
@@ -159,17 +350,38 @@ where
                                 }
                             })
                             .collect::<Vec<(String, Channel)>>();
-                        self.tx_ui.send(UIEvent::UpdateChannels(channels)).await?;
+                        self.room_names.lock().unwrap().extend(
+                            channels
+                                .iter()
+                                .map(|(name, channel)| (format!("{}", channel), name.clone())),
+                        );
+                        self.tx_ui
+                            .send(UIEvent::UpdateChannels(channels.clone()))
+                            .await?;
+                        // Open a channel given by name
+                        let pending = self.pending_channel.lock().unwrap().take();
+                        if let Some(name) = pending {
+                            let is_id = channels.iter().any(|x| format!("{}", x.1) == name);
+                            match Channel::find(&channels, &name) {
+                                Some(channel) if !is_id => {
+                                    self.init_view(Some(channel.clone())).await?
+                                }
+                                _ => {}
+                            }
+                        }
                     }
                     WsResponse::JoinedRoom { id, result, .. } if id == "5" => match result {
                         JoinedRoomResponseWs::Direct(result) => {
-                            self.init_view(Channel::User(result.rid.clone())).await?;
+                            self.init_view(Some(Channel::User(result.rid.clone())))
+                                .await?;
                         }
                         JoinedRoomResponseWs::Chat(result) => {
-                            self.init_view(Channel::Group(result.rid.clone())).await?;
+                            self.init_view(Some(Channel::Group(result.rid.clone())))
+                                .await?;
                         }
                         JoinedRoomResponseWs::Private(result) => {
-                            self.init_view(Channel::Private(result.rid.clone())).await?;
+                            self.init_view(Some(Channel::Private(result.rid.clone())))
+                                .await?;
                         }
                     },
                     WsResponse::UsersInRoom { id, result, .. } if id == "8" => {
@@ -197,84 +409,292 @@ where
                     let split = message.split(' ').collect::<Vec<&str>>();
                     if message.starts_with("/direct") && split.len() > 1 {
                         self.ws.create_direct_chat(split[1].into()).await?;
+                    } else if split[0] == "/export" {
+                        let format = match split.get(1).map(|x| x.parse::<ExportFormat>()) {
+                            Some(Ok(format)) => format,
+                            Some(Err(err)) => {
+                                self.tx_ui.send(UIEvent::ShowInfo(err)).await?;
+                                continue;
+                            }
+                            None => ExportFormat::Markdown,
+                        };
+                        let room_id = format!("{}", channel);
+                        let channel_name = self.channel_name(&room_id);
+                        let options = ExportOptions {
+                            format,
+                            since: None,
+                            output: None,
+                        };
+                        if let Err(err) = self
+                            .start_export(channel_name, Some(room_id), options, None)
+                            .await
+                        {
+                            self.tx_ui
+                                .send(UIEvent::ShowInfo(format!("{}", err)))
+                                .await?;
+                        }
+                    } else if split[0] == "/notify" {
+                        let args = message["/notify".len()..].trim();
+                        let room_id = format!("{}", channel);
+                        if let Some(value) = server_setting(args) {
+                            self.ws
+                                .save_notification_settings(room_id.clone(), value.into())
+                                .await?;
+                            let mut levels = self.notification_levels.lock().unwrap();
+                            match args.parse::<NotificationLevel>() {
+                                Ok(level) => levels.insert(room_id.clone(), level),
+                                Err(_) => levels.remove(&room_id),
+                            };
+                        }
+                        let info = self
+                            .notifier
+                            .configure(&channel, &self.channel_name(&room_id), args)
+                            .unwrap_or_else(|err| err);
+                        self.tx_ui.send(UIEvent::ShowInfo(info)).await?;
                     } else {
                         self.send_message(message, channel).await?;
                     }
                 }
                 ChatEvent::Init(channel) => {
-                    self.init_view(channel).await?;
+                    self.init_view(Some(channel)).await?;
                 }
                 ChatEvent::DirectChat(user) => {
-                    self.ws.create_direct_chat(user.into()).await?;
+                    self.ws.create_direct_chat(user).await?;
                 }
             };
         }
     }
 }
 
+/// The frames sent to and received from the websocket.
+pub(super) type Socket = (Sender<tungstenite::Message>, Receiver<tungstenite::Message>);
+
+/// Check the server with `/api/info` and find its websocket.
+///
+/// The websocket is next to the API, after redirects, unless
+/// `websocket_url` is given. Only a server answering something else than
+/// Rocket.Chat without a `websocket_url` is an error, a failed check is
+/// a warning.
+pub(super) async fn discover(
+    host: &Url,
+    websocket_url: Option<&str>,
+    tls: &TlsConfig,
+    proxy: Option<&Url>,
+) -> Result<Url, Box<dyn Error + Send + Sync>> {
+    let websocket_url = websocket_url
+        .map(discovery::parse_websocket_url)
+        .transpose()?;
+    let host = match discovery::server_info(host, tls, proxy).await {
+        Ok(ServerCheck::RocketChat(info, found)) => {
+            info!(
+                "Rocket.Chat {} at {}",
+                info.version.as_deref().unwrap_or("(version hidden)"),
+                found
+            );
+            found
+        }
+        Ok(ServerCheck::Other(reason)) if websocket_url.is_none() => return Err(reason.into()),
+        Ok(ServerCheck::Other(reason)) => {
+            warn!("{}", reason);
+            host.clone()
+        }
+        Err(err) => {
+            warn!("Can't check that {} is a Rocket.Chat server: {}", host, err);
+            host.clone()
+        }
+    };
+    match websocket_url {
+        Some(websocket_url) => Ok(websocket_url),
+        None => discovery::websocket_url(&host),
+    }
+}
+
+/// Open the websocket of the server, frames are recorded if a recorder is given.
+///
+/// The TCP stream goes through `proxy` if any, the TLS and websocket
+/// handshakes happen in the tunnel.
+pub(super) async fn connect_socket(
+    ws_url: &Url,
+    tls: &TlsConfig,
+    proxy: Option<&Url>,
+    recorder: Option<Recorder>,
+) -> Result<Socket, Box<dyn Error + Send + Sync>> {
+    let tls_config = match ws_url.scheme() {
+        "wss" => Some(tls::connector(tls)?),
+        _ => None,
+    };
+    let stream = proxy::connect(
+        ws_url.host_str().ok_or("The websocket URL has no host")?,
+        ws_url.port_or_known_default().unwrap_or(443),
+        proxy,
+    )
+    .await?;
+    let (socket, _) = async_tungstenite::tokio::client_async_tls_with_connector(
+        ws_url.clone(),
+        stream,
+        tls_config,
+    )
+    .await?;
+    let recorder = recorder.map(Arc::new);
+    let out_recorder = recorder.clone();
+    let (tx_ws, rx_forwarder_ws) = unbounded();
+    let (tx_forwarder_ws, rx_ws) = unbounded();
+    let (write, mut read) = socket.split();
+    tokio::spawn(
+        rx_forwarder_ws
+            .inspect(move |msg| {
+                if let Some(recorder) = out_recorder.as_ref() {
+                    recorder.record(Direction::Out, msg);
+                }
+            })
+            .map(Ok)
+            .forward(write),
+    );
+    tokio::spawn(async move {
+        loop {
+            let msg = read.next().await;
+            match msg {
+                Some(Ok(msg)) => {
+                    if let Some(recorder) = recorder.as_ref() {
+                        recorder.record(Direction::In, &msg);
+                    }
+                    if let Err(err) = tx_forwarder_ws.send(msg).await {
+                        error!("Error when sending to ws sender: {}", err);
+                        break;
+                    }
+                }
+                Some(Err(err)) => {
+                    error!("Error when reading websocket: {}", err);
+                    break;
+                }
+                None => {
+                    error!("No message when reading websocket");
+                    break;
+                }
+            }
+        }
+    });
+    Ok((tx_ws, rx_ws))
+}
+
 impl RocketChat<RocketChatWsWriter> {
     pub async fn new(
         host: Url,
         username: String,
         password: String,
-        ssl_verify: bool,
+        tls: TlsConfig,
+        tx_ui: Sender<UIEvent>,
+        rx_chat: Receiver<ChatEvent>,
+        notifier: Box<dyn Notification + Sync + Send>,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let proxy = proxy::resolve(None, &host)?;
+        let ws_url = discover(&host, None, &tls, proxy.as_ref()).await?;
+        let socket = connect_socket(&ws_url, &tls, proxy.as_ref(), None).await?;
+        Self::with_socket(socket, host, username, password, tx_ui, rx_chat, notifier).await
+    }
+
+    /// Log in through an open websocket.
+    pub(super) async fn with_socket(
+        (tx_ws, rx_ws): Socket,
+        host: Url,
+        username: String,
+        password: String,
         tx_ui: Sender<UIEvent>,
         rx_chat: Receiver<ChatEvent>,
         notifier: Box<dyn Notification + Sync + Send>,
     ) -> Result<Self, Box<dyn Error + Send + Sync>> {
-        let (ws_host, tls_config) = resolve_ws_url(host.clone(), ssl_verify)?;
-        let (socket, _) =
-            async_tungstenite::tokio::connect_async_with_tls_connector(ws_host, tls_config).await?;
-        let (tx_ws, rx_forwarder_ws) = unbounded();
         let ponger = tx_ws.clone();
-        let (tx_forwarder_ws, rx_ws) = unbounded();
-        let (write, mut read) = socket.split();
-        tokio::spawn(rx_forwarder_ws.map(Ok).forward(write));
+        let ws = RocketChatWsWriter::new(username.clone(), password, tx_ws, &rx_ws).await?;
+        Ok(RocketChat {
+            host,
+            tx_ui,
+            ws,
+            notifier,
+            rx_ws,
+            ponger,
+            rx_chat,
+            username,
+            current_channel: Mutex::new(None),
+            room_names: Mutex::new(HashMap::new()),
+            pending_channel: Mutex::new(None),
+            notification_levels: Mutex::new(HashMap::new()),
+            export_job: Mutex::new(None),
+            chat_log: None,
+        })
+    }
+}
+
+impl RocketChat<ReplayWsWriter> {
+    /// Replay a recorded session, the frames received are fed to the chat
+    /// and nothing is sent.
+    pub async fn replay(
+        path: &Path,
+        tx_ui: Sender<UIEvent>,
+        rx_chat: Receiver<ChatEvent>,
+        notifier: Box<dyn Notification + Sync + Send>,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let frames = recording::read_recording(path)?;
+        let username = recording::recorded_username(&frames).unwrap_or_default();
+        let (ponger, rx_pongs) = unbounded();
+        let (tx_ws, rx_ws) = unbounded();
+        let tx_info = tx_ui.clone();
         tokio::spawn(async move {
-            loop {
-                let msg = read.next().await;
-                match msg {
-                    Some(Ok(msg)) => {
-                        if let Err(err) = tx_forwarder_ws.send(msg).await {
-                            error!("Error when sending to ws sender: {}", err);
-                            break;
-                        }
-                    }
-                    Some(Err(err)) => {
-                        error!("Error when reading websocket: {}", err);
-                        break;
-                    }
-                    None => {
-                        error!("No message when reading websocket");
-                        break;
-                    }
+            for frame in frames.into_iter().filter(|x| x.direction == Direction::In) {
+                if tx_ws
+                    .send(tungstenite::Message::Text(frame.frame))
+                    .await
+                    .is_err()
+                {
+                    return;
                 }
             }
+            tx_info
+                .send(UIEvent::ShowInfo("End of the replay".into()))
+                .await
+                .ok();
+            // Keep the replayed chat open, pongs go nowhere.
+            while rx_pongs.recv().await.is_ok() {}
+            drop(tx_ws);
         });
-        let ws = RocketChatWsWriter::new(username.clone(), password, tx_ws, &rx_ws).await?;
         Ok(RocketChat {
+            host: Url::parse("http://replay.invalid")?,
             tx_ui,
-            ws,
+            ws: ReplayWsWriter,
             notifier,
             rx_ws,
             ponger,
             rx_chat,
             username,
             current_channel: Mutex::new(None),
+            room_names: Mutex::new(HashMap::new()),
+            pending_channel: Mutex::new(None),
+            notification_levels: Mutex::new(HashMap::new()),
+            export_job: Mutex::new(None),
+            chat_log: None,
         })
     }
 }
+
 #[async_trait]
 impl<U> Chat for RocketChat<U>
 where
     U: WebSocketWriter + Send + Sync,
 {
-    async fn init_view(&self, channel: Channel) -> Result<(), Box<dyn Error + Send + Sync>> {
+    async fn init_view(
+        &self,
+        channel: Option<Channel>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let channel = channel.unwrap_or_else(|| Channel::Group(DEFAULT_CHANNEL.into()));
+        let room_id = format!("{}", channel);
+        if !self.room_names.lock().unwrap().contains_key(&room_id) {
+            *self.pending_channel.lock().unwrap() = Some(room_id);
+        }
         let channel_to_switch = channel.clone();
+        self.ws.load_rooms().await?;
+        self.ws.load_subscriptions().await?;
         self.ws
             .load_history(format!("{}", channel_to_switch), 100)
             .await?;
-        self.ws.load_rooms().await?;
         self.ws.subscribe_user().await?;
         self.ws
             .get_users_room(format!("{}", channel_to_switch))
@@ -316,18 +736,38 @@ where
         let current_channel = self.current_channel.lock().unwrap().clone();
         if let Some(current) = current_channel.as_ref() {
             if channel == current {
+                self.log_message(&format!("{}", channel), &message);
                 self.tx_ui.send(UIEvent::AddMessages(message)).await?;
             }
         }
         Ok(())
     }
+
+    async fn export(
+        &self,
+        channel_name: String,
+        options: ExportOptions,
+    ) -> Result<PathBuf, Box<dyn Error + Send + Sync>> {
+        let (tx_done, rx_done) = unbounded();
+        self.start_export(channel_name, None, options, Some(tx_done))
+            .await?;
+        tokio::select! {
+            result = rx_done.recv() => Ok(result??),
+            result = self.wait_messages_loop() => {
+                result?;
+                Err("Connection closed before the end of the export".into())
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::notifications::{NotificationRules, RuleNotifier};
     use async_trait::async_trait;
-    use chrono::{TimeZone, Utc};
+    use chrono::{DateTime, TimeZone, Utc};
+    use fake_server::{FakeDdpServer, Reply};
     use std::collections::HashMap;
     use std::sync::{Arc, Mutex};
 
@@ -347,7 +787,7 @@ mod tests {
 
         async fn login(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
             let mut call_map = self.call_map.lock().unwrap();
-            let mut current_vec = call_map.get("login").or(Some(&vec![])).unwrap().clone();
+            let mut current_vec = call_map.get("login").cloned().unwrap_or_default();
             current_vec.push(vec![]);
             call_map.insert("login".into(), current_vec);
             Ok(())
@@ -361,7 +801,7 @@ mod tests {
 
         async fn pong(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
             let mut call_map = self.call_map.lock().unwrap();
-            let mut current_vec = call_map.get("pong").or(Some(&vec![])).unwrap().clone();
+            let mut current_vec = call_map.get("pong").cloned().unwrap_or_default();
             current_vec.push(vec![]);
             call_map.insert("pong".into(), current_vec);
             Ok(())
@@ -372,11 +812,7 @@ mod tests {
             content: String,
         ) -> Result<(), Box<dyn Error + Send + Sync>> {
             let mut call_map = self.call_map.lock().unwrap();
-            let mut current_vec = call_map
-                .get("send_message")
-                .or(Some(&vec![]))
-                .unwrap()
-                .clone();
+            let mut current_vec = call_map.get("send_message").cloned().unwrap_or_default();
             current_vec.push(vec![room_id, content]);
             call_map.insert("send_message".into(), current_vec);
             Ok(())
@@ -387,22 +823,40 @@ mod tests {
             count: usize,
         ) -> Result<(), Box<dyn Error + Send + Sync>> {
             let mut call_map = self.call_map.lock().unwrap();
-            let mut current_vec = call_map
-                .get("load_history")
-                .or(Some(&vec![]))
-                .unwrap()
-                .clone();
+            let mut current_vec = call_map.get("load_history").cloned().unwrap_or_default();
             current_vec.push(vec![room_id, format!("{}", count)]);
             call_map.insert("load_history".into(), current_vec);
             Ok(())
         }
-        async fn load_rooms(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        async fn load_history_before(
+            &self,
+            room_id: String,
+            end: Option<DateTime<Utc>>,
+            count: usize,
+        ) -> Result<(), Box<dyn Error + Send + Sync>> {
             let mut call_map = self.call_map.lock().unwrap();
             let mut current_vec = call_map
-                .get("load_rooms")
-                .or(Some(&vec![]))
-                .unwrap()
-                .clone();
+                .get("load_history_before")
+                .cloned()
+                .unwrap_or_default();
+            current_vec.push(vec![
+                room_id,
+                format!("{:?}", end.map(|x| x.timestamp_millis())),
+                format!("{}", count),
+            ]);
+            call_map.insert("load_history_before".into(), current_vec);
+            Ok(())
+        }
+        async fn get_room_id(&self, name: String) -> Result<(), Box<dyn Error + Send + Sync>> {
+            let mut call_map = self.call_map.lock().unwrap();
+            let mut current_vec = call_map.get("get_room_id").cloned().unwrap_or_default();
+            current_vec.push(vec![name]);
+            call_map.insert("get_room_id".into(), current_vec);
+            Ok(())
+        }
+        async fn load_rooms(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+            let mut call_map = self.call_map.lock().unwrap();
+            let mut current_vec = call_map.get("load_rooms").cloned().unwrap_or_default();
             current_vec.push(vec![]);
             call_map.insert("load_rooms".into(), current_vec);
             Ok(())
@@ -414,20 +868,15 @@ mod tests {
             let mut call_map = self.call_map.lock().unwrap();
             let mut current_vec = call_map
                 .get("create_direct_chat")
-                .or(Some(&vec![]))
-                .unwrap()
-                .clone();
+                .cloned()
+                .unwrap_or_default();
             current_vec.push(vec![username]);
             call_map.insert("create_direct_chat".into(), current_vec);
             Ok(())
         }
         async fn subscribe_user(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
             let mut call_map = self.call_map.lock().unwrap();
-            let mut current_vec = call_map
-                .get("subscribe_user")
-                .or(Some(&vec![]))
-                .unwrap()
-                .clone();
+            let mut current_vec = call_map.get("subscribe_user").cloned().unwrap_or_default();
             current_vec.push(vec![]);
             call_map.insert("subscribe_user".into(), current_vec);
             Ok(())
@@ -436,9 +885,8 @@ mod tests {
             let mut call_map = self.call_map.lock().unwrap();
             let mut current_vec = call_map
                 .get("subscribe_messages")
-                .or(Some(&vec![]))
-                .unwrap()
-                .clone();
+                .cloned()
+                .unwrap_or_default();
             current_vec.push(vec![]);
             call_map.insert("subscribe_messages".into(), current_vec);
             Ok(())
@@ -448,15 +896,35 @@ mod tests {
             _room_id: String,
         ) -> Result<(), Box<dyn Error + Send + Sync>> {
             let mut call_map = self.call_map.lock().unwrap();
-            let mut current_vec = call_map
-                .get("get_users_room")
-                .or(Some(&vec![]))
-                .unwrap()
-                .clone();
+            let mut current_vec = call_map.get("get_users_room").cloned().unwrap_or_default();
             current_vec.push(vec![_room_id]);
             call_map.insert("get_users_room".into(), current_vec);
             Ok(())
         }
+        async fn load_subscriptions(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+            let mut call_map = self.call_map.lock().unwrap();
+            let mut current_vec = call_map
+                .get("load_subscriptions")
+                .cloned()
+                .unwrap_or_default();
+            current_vec.push(vec![]);
+            call_map.insert("load_subscriptions".into(), current_vec);
+            Ok(())
+        }
+        async fn save_notification_settings(
+            &self,
+            room_id: String,
+            value: String,
+        ) -> Result<(), Box<dyn Error + Send + Sync>> {
+            let mut call_map = self.call_map.lock().unwrap();
+            let mut current_vec = call_map
+                .get("save_notification_settings")
+                .cloned()
+                .unwrap_or_default();
+            current_vec.push(vec![room_id, value]);
+            call_map.insert("save_notification_settings".into(), current_vec);
+            Ok(())
+        }
     }
 
     impl RocketChat<FakeWsWriter> {
@@ -478,6 +946,7 @@ mod tests {
             let (tx_forwarder_ws, rx_ws) = unbounded();
             Ok((
                 RocketChat {
+                    host,
                     tx_ui,
                     ws,
                     rx_ws,
@@ -485,6 +954,11 @@ mod tests {
                     rx_chat: rx_ui,
                     username,
                     current_channel: Mutex::new(Some(Channel::Group("test_channel".to_string()))),
+                    room_names: Mutex::new(HashMap::new()),
+                    pending_channel: Mutex::new(None),
+                    notification_levels: Mutex::new(HashMap::new()),
+                    export_job: Mutex::new(None),
+                    chat_log: None,
                     notifier,
                 },
                 tx_forwarder_ws,
@@ -533,11 +1007,7 @@ mod tests {
         .await
         .unwrap();
         assert_eq!(
-            ws.call_map
-                .lock()
-                .unwrap()
-                .get("send_message".into())
-                .unwrap()[0],
+            ws.call_map.lock().unwrap().get("send_message").unwrap()[0],
             vec!["test_channel".to_string(), "test".to_string()]
         );
     }
@@ -547,9 +1017,12 @@ mod tests {
         let (_, rx_ui, chat, _) = create_chat_system();
         chat.add_message(
             Message {
+                id: "testid".into(),
                 author: "testauthor".into(),
                 content: "testcontent".into(),
                 datetime: Utc.timestamp_millis(1593435867123),
+                thread: None,
+                attachments: vec![],
             },
             &Channel::Group("test_channel".to_string()),
         )
@@ -559,9 +1032,12 @@ mod tests {
             assert_eq!(
                 msg,
                 Message {
+                    id: "testid".into(),
                     author: "testauthor".into(),
                     content: "testcontent".into(),
                     datetime: Utc.timestamp_millis(1593435867123),
+                    thread: None,
+                    attachments: vec![],
                 }
             );
         } else {
@@ -574,9 +1050,12 @@ mod tests {
         let (_, rx_ui, chat, _) = create_chat_system();
         chat.add_message(
             Message {
+                id: "testid".into(),
                 author: "testauthor".into(),
                 content: "testcontent".into(),
                 datetime: Utc.timestamp_millis(1593435867123),
+                thread: None,
+                attachments: vec![],
             },
             &Channel::Group("other_channel".to_string()),
         )
@@ -588,24 +1067,24 @@ mod tests {
     #[tokio::test]
     async fn test_init() {
         let (ws, _rx_ui, chat, _) = create_chat_system();
-        chat.init_view(Channel::Group("test_channel".to_string()))
+        chat.init_view(Some(Channel::Group("test_channel".to_string())))
             .await
             .unwrap();
         let ws_call_map = ws.call_map.lock().unwrap();
         assert_eq!(
-            ws_call_map.get("load_history".into()).unwrap()[0],
+            ws_call_map.get("load_history").unwrap()[0],
             vec!["test_channel".to_string(), "100".to_string()]
         );
         assert_eq!(
-            ws_call_map.get("load_rooms".into()).unwrap()[0],
+            ws_call_map.get("load_rooms").unwrap()[0],
             Vec::<String>::new()
         );
         assert_eq!(
-            ws_call_map.get("subscribe_user".into()).unwrap()[0],
+            ws_call_map.get("subscribe_user").unwrap()[0],
             Vec::<String>::new()
         );
         assert_eq!(
-            ws_call_map.get("get_users_room".into()).unwrap()[0],
+            ws_call_map.get("get_users_room").unwrap()[0],
             vec!["test_channel".to_string()]
         );
     }
@@ -626,12 +1105,120 @@ mod tests {
             Ok(UIEvent::AddMessages(message)) = msg => {
                 assert_eq!(
                     message,
-                    Message { author: "testauthor".into(), content: "testcontent".into(), datetime: Utc.timestamp_millis(1593435867123) }
+                    Message {
+                        id: "nFJCiS76ZRAZQiD4E".into(),
+                        author: "testauthor".into(),
+                        content: "testcontent".into(),
+                        datetime: Utc.timestamp_millis(1593435867123),
+                        thread: None,
+                        attachments: vec![],
+                    }
                 );
             },
         };
     }
 
+    /// Notifier keeping the notified titles and contents.
+    struct RecordingNotifier(Arc<Mutex<Vec<(String, String)>>>);
+    impl Notification for RecordingNotifier {
+        fn notify(&self, title: &str, content: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+            self.0.lock().unwrap().push((title.into(), content.into()));
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_recv_subscriptions() {
+        let notified = Arc::new(Mutex::new(vec![]));
+        let ws = FakeWsWriter {
+            call_map: Arc::new(Mutex::new(HashMap::new())),
+        };
+        let (_, rx_chat) = unbounded();
+        let (tx_ui, rx_ui) = unbounded();
+        let (chat, tx_forwarder_ws) = RocketChat::<FakeWsWriter>::new(
+            Url::parse("http://localhost").unwrap(),
+            "usertest".into(),
+            tx_ui,
+            rx_chat,
+            ws,
+            Box::new(RuleNotifier::new(
+                NotificationRules::default(),
+                Box::new(RecordingNotifier(notified.clone())),
+            )),
+        )
+        .unwrap();
+        let message_loop = chat.wait_messages_loop();
+        for frame in [
+            std::include_str!("../../../tests/data/test_recv_subscriptions.json"),
+            std::include_str!("../../../tests/data/test_recv_message.json"),
+        ] {
+            tx_forwarder_ws
+                .send(tungstenite::Message::Text(frame.into()))
+                .await
+                .unwrap();
+        }
+        tokio::select! {
+            Ok(UIEvent::AddMessages(_)) = rx_ui.recv() => {},
+            _ = message_loop => panic!("Abnormal"),
+        };
+        assert_eq!(
+            *chat.notification_levels.lock().unwrap(),
+            vec![
+                ("test_channel".to_string(), NotificationLevel::All),
+                ("GENERAL".to_string(), NotificationLevel::None),
+            ]
+            .into_iter()
+            .collect::<HashMap<String, NotificationLevel>>()
+        );
+        // The room notifies every message on the server, not only mentions
+        assert_eq!(
+            *notified.lock().unwrap(),
+            vec![("testauthor".to_string(), "testcontent".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_notify_command() {
+        let ws = FakeWsWriter {
+            call_map: Arc::new(Mutex::new(HashMap::new())),
+        };
+        let (tx_chat, rx_chat) = unbounded();
+        let (tx_ui, rx_ui) = unbounded();
+        let (chat, _) = RocketChat::<FakeWsWriter>::new(
+            Url::parse("http://localhost").unwrap(),
+            "usertest".into(),
+            tx_ui,
+            rx_chat,
+            ws.clone(),
+            Box::new(RuleNotifier::new(
+                NotificationRules::default(),
+                Box::new(FakeNotifier {}),
+            )),
+        )
+        .unwrap();
+        let channel = Channel::Group("test_channel".into());
+        tx_chat
+            .send(ChatEvent::SendMessage("/notify none".into(), channel))
+            .await
+            .unwrap();
+        tokio::select! {
+            Ok(UIEvent::ShowInfo(info)) = rx_ui.recv() => assert!(
+                info.starts_with("Notifications in test_channel: none."),
+                "{}",
+                info
+            ),
+            _ = chat.ui_event_loop() => panic!("Abnormal"),
+        };
+        assert_eq!(
+            ws.call_map.lock().unwrap()["save_notification_settings"],
+            vec![vec!["test_channel".to_string(), "nothing".to_string()]]
+        );
+        assert_eq!(
+            chat.notification_levels.lock().unwrap()["test_channel"],
+            NotificationLevel::None
+        );
+    }
+
     #[tokio::test]
     async fn test_recv_history() {
         let (_, rx_ui, chat, tx_forwarder_ws) = create_chat_system();
@@ -648,7 +1235,7 @@ mod tests {
         tokio::select! {
             Ok(UIEvent::UpdateMessages(messages)) = msg => {
                 assert_eq!(
-                    format!("{}", messages).trim(),
+                    messages.trim(),
                     expected_str.to_string().trim()
                 );
             },
@@ -679,6 +1266,33 @@ mod tests {
         };
     }
 
+    #[tokio::test]
+    async fn test_recv_rooms_pending_channel() {
+        let (_, rx_ui, chat, tx_forwarder_ws) = create_chat_system();
+        *chat.pending_channel.lock().unwrap() = Some("#test".into());
+        let message_str = std::include_str!("../../../tests/data/test_recv_rooms.json").to_string();
+        let message_loop = chat.wait_messages_loop();
+        tx_forwarder_ws
+            .send(tungstenite::Message::Text(message_str))
+            .await
+            .unwrap();
+
+        let selected = async {
+            loop {
+                if let UIEvent::SelectChannel(channel) = rx_ui.recv().await.unwrap() {
+                    return channel;
+                }
+            }
+        };
+        tokio::select! {
+            channel = selected => {
+                assert_eq!(channel, Channel::Private("hncFhRCpRrcB7Dodx".into()));
+            },
+            _ = message_loop => {panic!("Abnormal")},
+        };
+        assert_eq!(*chat.pending_channel.lock().unwrap(), None);
+    }
+
     #[tokio::test]
     async fn test_recv_users_in_room() {
         let (_, rx_ui, chat, tx_forwarder_ws) = create_chat_system();
@@ -747,4 +1361,428 @@ mod tests {
             _ = message_loop => {panic!("Abnormal")},
         };
     }
+
+    #[tokio::test]
+    async fn test_export_lookup_room() {
+        let (ws, _rx_ui, chat, tx_forwarder_ws) = create_chat_system();
+        let options = ExportOptions {
+            format: ExportFormat::Json,
+            since: None,
+            output: None,
+        };
+        chat.start_export("general".into(), None, options, None)
+            .await
+            .unwrap();
+        tx_forwarder_ws
+            .send(tungstenite::Message::Text(
+                r#"{"msg": "result", "id": "10", "result": "GENERAL"}"#.into(),
+            ))
+            .await
+            .unwrap();
+        let message_loop = chat.wait_messages_loop();
+        let wait_request = async {
+            loop {
+                if ws
+                    .call_map
+                    .lock()
+                    .unwrap()
+                    .contains_key("load_history_before")
+                {
+                    break;
+                }
+                tokio::task::yield_now().await;
+            }
+        };
+        tokio::select! {
+            _ = message_loop => {panic!("Abnormal")},
+            _ = wait_request => {},
+        };
+        let options = ExportOptions {
+            format: ExportFormat::Json,
+            since: None,
+            output: None,
+        };
+        let err = chat
+            .start_export("random".into(), None, options, None)
+            .await
+            .unwrap_err();
+        assert_eq!(format!("{}", err), "The export of general is still running");
+        let call_map = ws.call_map.lock().unwrap();
+        assert_eq!(call_map.get("get_room_id").unwrap().len(), 1);
+        assert_eq!(call_map.get("get_room_id").unwrap()[0], vec!["general"]);
+        assert_eq!(
+            call_map.get("load_history_before").unwrap()[0],
+            vec!["GENERAL", "None", "100"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_export_unknown_room() {
+        let (_, _rx_ui, chat, tx_forwarder_ws) = create_chat_system();
+        let options = ExportOptions {
+            format: ExportFormat::Json,
+            since: None,
+            output: None,
+        };
+        tx_forwarder_ws
+            .send(tungstenite::Message::Text(
+                r#"{"msg": "result", "id": "10", "error": {"reason": "error-not-allowed"}}"#.into(),
+            ))
+            .await
+            .unwrap();
+        let err = chat.export("nope".into(), options).await.unwrap_err();
+        assert_eq!(format!("{}", err), "Can't export: error-not-allowed");
+    }
+
+    #[tokio::test]
+    async fn test_export_history() {
+        let (_, rx_ui, chat, tx_forwarder_ws) = create_chat_system();
+        let message_str = std::include_str!("../../../tests/data/test_recv_history.json")
+            .replace(r#""id": "3""#, r#""id": "9""#);
+        let output = std::env::temp_dir().join("talkoxid_test_export_history.md");
+        let options = ExportOptions {
+            format: ExportFormat::Markdown,
+            since: Some(Utc.timestamp_millis(1593589665563)),
+            output: Some(output.clone()),
+        };
+        chat.start_export("general".into(), Some("GENERAL".into()), options, None)
+            .await
+            .unwrap();
+        tx_forwarder_ws
+            .send(tungstenite::Message::Text(message_str))
+            .await
+            .unwrap();
+        let message_loop = chat.wait_messages_loop();
+        let msg = rx_ui.recv();
+        tokio::select! {
+            Ok(UIEvent::ShowInfo(info)) = msg => {
+                assert_eq!(info, format!("Channel exported to {}", output.display()));
+            },
+            _ = message_loop => {panic!("Abnormal")},
+        };
+        let exported = std::fs::read_to_string(&output).unwrap();
+        std::fs::remove_file(&output).unwrap();
+        assert_eq!(
+            exported,
+            "# general\n\n\
+             - **lou** _2020-07-01 07:47:45 UTC_: la\n\
+             - **lou** _2020-07-01 07:47:47 UTC_: nice\n\
+             - **lou** _2020-07-01 07:49:10 UTC_: la\n"
+        );
+    }
+
+    fn fixture_result(fixture: &str) -> serde_json::Value {
+        serde_json::from_str::<serde_json::Value>(fixture).unwrap()["result"].clone()
+    }
+
+    async fn connect_chat_system(
+        server: &FakeDdpServer,
+        password: &str,
+    ) -> Result<(RocketChat<RocketChatWsWriter>, Receiver<UIEvent>), Box<dyn Error + Send + Sync>>
+    {
+        let (tx_ui, rx_ui) = unbounded();
+        let (_, rx_chat) = unbounded();
+        let chat = RocketChat::<RocketChatWsWriter>::new(
+            server.url.clone(),
+            "usertest".into(),
+            password.into(),
+            TlsConfig::default(),
+            tx_ui,
+            rx_chat,
+            Box::new(FakeNotifier {}),
+        )
+        .await?;
+        Ok((chat, rx_ui))
+    }
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let server = FakeDdpServer::start(
+            "usertest",
+            "secret",
+            vec![
+                (
+                    "rooms/get",
+                    vec![Reply::Result(fixture_result(std::include_str!(
+                        "../../../tests/data/test_recv_rooms.json"
+                    )))],
+                ),
+                (
+                    "getUsersOfRoom",
+                    vec![Reply::Result(fixture_result(std::include_str!(
+                        "../../../tests/data/test_recv_users_in_room.json"
+                    )))],
+                ),
+            ],
+        )
+        .await;
+        let (chat, rx_ui) = connect_chat_system(&server, "secret").await.unwrap();
+        server.expect(|x| x["msg"] == "connect").await;
+        let login = server.expect_method("login").await;
+        assert_eq!(
+            login["params"][0]["password"]["digest"],
+            "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b"
+        );
+        chat.init_view(Some(Channel::Group("GENERAL".into())))
+            .await
+            .unwrap();
+        let subscription = server
+            .expect(|x| x["msg"] == "sub" && x["name"] == "stream-notify-user")
+            .await;
+        assert_eq!(
+            subscription["params"][0],
+            format!("{}/rooms-changed", fake_server::USER_ID)
+        );
+        let events = async {
+            loop {
+                match rx_ui.recv().await.unwrap() {
+                    UIEvent::UpdateChannels(channels) => {
+                        assert_eq!(
+                            format!("{:?}", channels),
+                            std::include_str!("../../../tests/data/test_recv_rooms.txt").trim()
+                        );
+                        break;
+                    }
+                    _ => continue,
+                }
+            }
+        };
+        tokio::select! {
+            _ = chat.wait_messages_loop() => panic!("Abnormal"),
+            _ = events => {},
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handshake_wrong_password() {
+        let server = FakeDdpServer::start("usertest", "secret", vec![]).await;
+        let err = connect_chat_system(&server, "wrong").await.err().unwrap();
+        assert_eq!(format!("{}", err), "Login failed: User not found");
+    }
+
+    #[tokio::test]
+    async fn test_server_ping() {
+        let server = FakeDdpServer::start("usertest", "secret", vec![]).await;
+        let (chat, _rx_ui) = connect_chat_system(&server, "secret").await.unwrap();
+        server.ping().await;
+        tokio::select! {
+            _ = chat.wait_messages_loop() => panic!("Abnormal"),
+            _ = server.expect(|x| x["msg"] == "pong") => {},
+        }
+    }
+
+    #[tokio::test]
+    async fn test_server_disconnect() {
+        let server = FakeDdpServer::start("usertest", "secret", vec![]).await;
+        let (chat, _rx_ui) = connect_chat_system(&server, "secret").await.unwrap();
+        server.disconnect().await;
+        let result =
+            tokio::time::timeout(std::time::Duration::from_secs(5), chat.wait_messages_loop())
+                .await
+                .expect("The loop should end when the server disconnects");
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_method_error() {
+        let server = FakeDdpServer::start(
+            "usertest",
+            "secret",
+            vec![(
+                "getRoomIdByNameOrId",
+                vec![Reply::Error("error-not-allowed")],
+            )],
+        )
+        .await;
+        let (chat, _rx_ui) = connect_chat_system(&server, "secret").await.unwrap();
+        let options = ExportOptions {
+            format: ExportFormat::Markdown,
+            since: None,
+            output: None,
+        };
+        let err = chat.export("secret".into(), options).await.err().unwrap();
+        assert_eq!(format!("{}", err), "Can't export: error-not-allowed");
+    }
+
+    #[tokio::test]
+    async fn test_method_disconnect() {
+        let server = FakeDdpServer::start(
+            "usertest",
+            "secret",
+            vec![("getRoomIdByNameOrId", vec![Reply::Disconnect])],
+        )
+        .await;
+        let (chat, _rx_ui) = connect_chat_system(&server, "secret").await.unwrap();
+        let options = ExportOptions {
+            format: ExportFormat::Markdown,
+            since: None,
+            output: None,
+        };
+        assert!(chat.export("general".into(), options).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pushed_message() {
+        let server = FakeDdpServer::start("usertest", "secret", vec![]).await;
+        let (chat, rx_ui) = connect_chat_system(&server, "secret").await.unwrap();
+        *chat.current_channel.lock().unwrap() = Some(Channel::Group("test_channel".into()));
+        let frame = std::include_str!("../../../tests/data/test_recv_message.json");
+        server.push(serde_json::from_str(frame).unwrap()).await;
+        tokio::select! {
+            _ = chat.wait_messages_loop() => panic!("Abnormal"),
+            event = rx_ui.recv() => match event.unwrap() {
+                UIEvent::AddMessages(message) => assert_eq!(message.content, "testcontent"),
+                event => panic!("Unexpected event {:?}", event),
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_discover_subpath() {
+        let server = FakeDdpServer::start("usertest", "secret", vec![]).await;
+        let host = server.url.join("/chat").unwrap();
+        let ws_url = discover(&host, None, &TlsConfig::default(), None)
+            .await
+            .unwrap();
+        let authority = &server.url[url::Position::BeforeHost..url::Position::AfterPort];
+        assert_eq!(
+            ws_url.as_str(),
+            format!("ws://{}/chat/websocket", authority)
+        );
+        let socket = connect_socket(&ws_url, &TlsConfig::default(), None, None)
+            .await
+            .unwrap();
+        let (tx_ui, _rx_ui) = unbounded();
+        let (_, rx_chat) = unbounded();
+        RocketChat::with_socket(
+            socket,
+            host,
+            "usertest".into(),
+            "secret".into(),
+            tx_ui,
+            rx_chat,
+            Box::new(FakeNotifier {}),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_record_session() {
+        let server = FakeDdpServer::start("usertest", "secret", vec![]).await;
+        let path = std::env::temp_dir().join("talkoxid_test_record_session.jsonl");
+        let recorder = Recorder::create(&path).unwrap();
+        let socket = connect_socket(
+            &discovery::websocket_url(&server.url).unwrap(),
+            &TlsConfig::default(),
+            None,
+            Some(recorder),
+        )
+        .await
+        .unwrap();
+        let (tx_ui, _rx_ui) = unbounded();
+        let (_, rx_chat) = unbounded();
+        let chat = RocketChat::with_socket(
+            socket,
+            server.url.clone(),
+            "usertest".into(),
+            "secret".into(),
+            tx_ui,
+            rx_chat,
+            Box::new(FakeNotifier {}),
+        )
+        .await
+        .unwrap();
+        server.ping().await;
+        tokio::select! {
+            _ = chat.wait_messages_loop() => panic!("Abnormal"),
+            _ = server.expect(|x| x["msg"] == "pong") => {},
+        }
+        let frames = recording::read_recording(&path).unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(!content.contains(fake_server::TOKEN));
+        assert!(
+            !content.contains("2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b")
+        );
+        let directions = frames
+            .iter()
+            .map(|x| {
+                (
+                    x.direction,
+                    serde_json::from_str::<serde_json::Value>(&x.frame).unwrap(),
+                )
+            })
+            .map(|(direction, frame)| {
+                (
+                    direction,
+                    frame["msg"].as_str().unwrap_or_default().to_string(),
+                )
+            })
+            .collect::<Vec<(Direction, String)>>();
+        assert_eq!(
+            directions,
+            vec![
+                (Direction::In, "".into()),
+                (Direction::Out, "connect".into()),
+                (Direction::In, "connected".into()),
+                (Direction::Out, "method".into()),
+                (Direction::In, "result".into()),
+                (Direction::In, "ping".into()),
+                (Direction::Out, "pong".into()),
+            ]
+        );
+        assert_eq!(
+            recording::recorded_username(&frames),
+            Some("usertest".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_replay() {
+        let path = std::env::temp_dir().join("talkoxid_test_replay.jsonl");
+        let frames = [
+            (Direction::Out, r#"{"msg":"method","method":"login","id":"1","params":[{"user":{"username":"lou"},"password":"<redacted>"}]}"#.to_string()),
+            (Direction::In, std::include_str!("../../../tests/data/test_recv_rooms.json").to_string()),
+        ];
+        let recording = frames
+            .iter()
+            .map(|(direction, frame)| {
+                let frame = recording::RecordedFrame {
+                    timestamp: Utc.timestamp_millis(1593589750164),
+                    direction: *direction,
+                    frame: frame.clone(),
+                };
+                format!("{}\n", serde_json::to_string(&frame).unwrap())
+            })
+            .collect::<String>();
+        std::fs::write(&path, recording).unwrap();
+        let (tx_ui, rx_ui) = unbounded();
+        let (_, rx_chat) = unbounded();
+        let chat = RocketChat::replay(&path, tx_ui, rx_chat, Box::new(FakeNotifier {}))
+            .await
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(chat.username, "lou");
+        chat.init_view(None).await.unwrap();
+        let events = async {
+            let (mut channels, mut info) = (None, None);
+            while channels.is_none() || info.is_none() {
+                match rx_ui.recv().await.unwrap() {
+                    UIEvent::UpdateChannels(update) => channels = Some(format!("{:?}", update)),
+                    UIEvent::ShowInfo(update) => info = Some(update),
+                    _ => continue,
+                }
+            }
+            // The recorded user is left out of the direct chat names
+            assert!(channels
+                .unwrap()
+                .starts_with(r#"[("general", Group("GENERAL")), ("collkid", User("#));
+            info.unwrap()
+        };
+        tokio::select! {
+            _ = chat.wait_messages_loop() => panic!("Abnormal"),
+            info = events => assert_eq!(info, "End of the replay"),
+        }
+    }
 }
@@ -0,0 +1,281 @@
+use super::api::WebSocketWriter;
+use async_channel::Sender;
+use async_trait::async_trait;
+use async_tungstenite::tungstenite;
+use chrono::{DateTime, Utc};
+use log::error;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Keys whose values never end up in a recording.
+const SECRET_KEYS: &[&str] = &["password", "digest", "token", "resume", "authToken"];
+const REDACTED: &str = "<redacted>";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// From the server.
+    In,
+    /// To the server.
+    Out,
+}
+
+/// A DDP frame of a recording, one per line of the file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RecordedFrame {
+    pub timestamp: DateTime<Utc>,
+    pub direction: Direction,
+    pub frame: String,
+}
+
+fn redact_value(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if SECRET_KEYS.contains(&&key[..]) && !value.is_null() {
+                    *value = Value::String(REDACTED.into());
+                } else {
+                    redact_value(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact_value),
+        _ => {}
+    }
+}
+
+/// Replace passwords and tokens of a frame, frames that aren't JSON are kept.
+pub fn redact(frame: &str) -> String {
+    match serde_json::from_str::<Value>(frame) {
+        Ok(mut value) => {
+            redact_value(&mut value);
+            value.to_string()
+        }
+        Err(_) => frame.to_string(),
+    }
+}
+
+/// Websocket session recorder.
+///
+/// Text frames are appended to a JSONL file as they are sent and received.
+pub struct Recorder {
+    file: Mutex<File>,
+}
+
+impl Recorder {
+    /// Create the recording, only the user can read it.
+    pub fn create(path: &Path) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let file = options
+            .open(path)
+            .map_err(|err| format!("Can't create {}: {}", path.display(), err))?;
+        // The mode only applies to a new file, not to a recording replaced
+        #[cfg(unix)]
+        file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+        Ok(Recorder {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn record(&self, direction: Direction, message: &tungstenite::Message) {
+        let frame = match message {
+            tungstenite::Message::Text(frame) => frame,
+            _ => return,
+        };
+        let recorded = RecordedFrame {
+            timestamp: Utc::now(),
+            direction,
+            frame: redact(frame),
+        };
+        let written = serde_json::to_string(&recorded)
+            .map_err(|err| err.to_string())
+            .and_then(|line| {
+                writeln!(self.file.lock().unwrap(), "{}", line).map_err(|err| err.to_string())
+            });
+        if let Err(err) = written {
+            error!("Can't record websocket frame: {}", err);
+        }
+    }
+}
+
+/// Read the frames of a recording.
+pub fn read_recording(path: &Path) -> Result<Vec<RecordedFrame>, Box<dyn Error + Send + Sync>> {
+    let file = File::open(path).map_err(|err| format!("Can't open {}: {}", path.display(), err))?;
+    BufReader::new(file)
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !matches!(line, Ok(line) if line.trim().is_empty()))
+        .map(|(number, line)| {
+            serde_json::from_str(&line?)
+                .map_err(|err| format!("Invalid recording line {}: {}", number + 1, err).into())
+        })
+        .collect()
+}
+
+/// The username of a recording, from its login frame.
+pub fn recorded_username(frames: &[RecordedFrame]) -> Option<String> {
+    frames
+        .iter()
+        .filter(|x| x.direction == Direction::Out)
+        .filter_map(|x| serde_json::from_str::<Value>(&x.frame).ok())
+        .find(|x| x["method"] == "login")
+        .and_then(|x| {
+            x["params"][0]["user"]["username"]
+                .as_str()
+                .map(String::from)
+        })
+}
+
+/// Websocket writer of a replay, nothing is sent.
+pub struct ReplayWsWriter;
+
+#[async_trait]
+impl WebSocketWriter for ReplayWsWriter {
+    async fn init(
+        _username: &str,
+        _password_digest: &str,
+        _websocket: &Sender<tungstenite::Message>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        Ok(())
+    }
+
+    async fn login(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        Ok(())
+    }
+
+    async fn connect(
+        _writer: &Sender<tungstenite::Message>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        Ok(())
+    }
+
+    async fn pong(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        Ok(())
+    }
+
+    async fn send_message(
+        &self,
+        _room_id: String,
+        _content: String,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        Ok(())
+    }
+
+    async fn load_history(
+        &self,
+        _room_id: String,
+        _count: usize,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        Ok(())
+    }
+
+    async fn load_history_before(
+        &self,
+        _room_id: String,
+        _end: Option<DateTime<Utc>>,
+        _count: usize,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        Ok(())
+    }
+
+    async fn load_rooms(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        Ok(())
+    }
+
+    async fn get_room_id(&self, _name: String) -> Result<(), Box<dyn Error + Send + Sync>> {
+        Ok(())
+    }
+
+    async fn create_direct_chat(
+        &self,
+        _username: String,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        Ok(())
+    }
+
+    async fn subscribe_user(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        Ok(())
+    }
+
+    async fn subscribe_messages(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        Ok(())
+    }
+
+    async fn get_users_room(&self, _room_id: String) -> Result<(), Box<dyn Error + Send + Sync>> {
+        Ok(())
+    }
+
+    async fn load_subscriptions(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        Ok(())
+    }
+
+    async fn save_notification_settings(
+        &self,
+        _room_id: String,
+        _value: String,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        let login = r#"{"msg":"method","method":"login","id":"1","params":[{"user":{"username":"lou"},"password":{"digest":"2bb80d53","algorithm":"sha-256"}}]}"#;
+        let redacted: Value = serde_json::from_str(&redact(login)).unwrap();
+        assert_eq!(redacted["params"][0]["user"]["username"], "lou");
+        assert_eq!(redacted["params"][0]["password"], REDACTED);
+        let result = r#"{"msg":"result","id":"1","result":{"id":"u1","token":"secret-token","tokenExpires":null}}"#;
+        let redacted: Value = serde_json::from_str(&redact(result)).unwrap();
+        assert_eq!(redacted["result"]["id"], "u1");
+        assert_eq!(redacted["result"]["token"], REDACTED);
+        assert_eq!(redact("not json"), "not json");
+    }
+
+    #[test]
+    fn test_record_and_read() {
+        let path = std::env::temp_dir().join("talkoxid_test_record_and_read.jsonl");
+        std::fs::write(&path, "").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        }
+        let recorder = Recorder::create(&path).unwrap();
+        recorder.record(
+            Direction::Out,
+            &tungstenite::Message::Text(
+                r#"{"msg":"method","method":"login","params":[{"user":{"username":"lou"},"password":{"digest":"2bb80d53"}}]}"#.into(),
+            ),
+        );
+        recorder.record(Direction::In, &tungstenite::Message::Ping(vec![]));
+        recorder.record(
+            Direction::In,
+            &tungstenite::Message::Text(r#"{"msg":"ping"}"#.into()),
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode, 0o600);
+        }
+        let frames = read_recording(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[1].direction, Direction::In);
+        assert_eq!(frames[1].frame, r#"{"msg":"ping"}"#);
+        assert!(!frames[0].frame.contains("2bb80d53"));
+        assert_eq!(recorded_username(&frames), Some("lou".into()));
+    }
+}
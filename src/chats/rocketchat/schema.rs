@@ -1,6 +1,7 @@
 use chrono::prelude::*;
 use chrono::serde::ts_milliseconds;
 
+use serde::de::IgnoredAny;
 use serde::{Deserialize, Serialize};
 #[derive(Deserialize, Debug, Clone)]
 pub struct AuthorResponseWs {
@@ -73,12 +74,24 @@ pub struct DateResponseWs {
     pub date: DateTime<Utc>,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct AttachmentResponseWs {
+    pub title: Option<String>,
+    pub title_link: Option<String>,
+    pub image_url: Option<String>,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct MessageResponseWs {
+    #[serde(default)]
+    pub _id: String,
     pub u: AuthorResponseWs,
     pub rid: String,
     pub msg: String,
     pub ts: DateResponseWs,
+    pub tmid: Option<String>,
+    #[serde(default)]
+    pub attachments: Vec<AttachmentResponseWs>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -92,18 +105,15 @@ pub struct EventResponseWs {
     pub last_message: MessageResponseWs,
     pub t: String,
 }
-#[derive(Deserialize, Debug)]
-pub struct SocketEventResponseWs(pub String, pub EventResponseWs);
 
+/// The arguments of a stream event: the kind of change and the event.
 #[derive(Deserialize, Debug)]
 pub struct SocketArgsWs {
-    pub args: SocketEventResponseWs,
+    pub args: (IgnoredAny, EventResponseWs),
 }
 
 #[derive(Deserialize, Debug)]
 pub struct SocketMessageWs {
-    pub id: String,
-    pub msg: String,
     pub fields: SocketArgsWs,
 }
 
@@ -112,6 +122,13 @@ pub struct UserIdResponse {
     pub id: String,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct LoginResponseWs {
+    pub id: String,
+    pub result: Option<UserIdResponse>,
+    pub error: Option<serde_json::value::Value>,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct DirectChatResponseWs {
     pub _id: String,
@@ -138,7 +155,6 @@ pub enum RoomResponseWs {
 #[derive(Deserialize, Debug)]
 pub struct RoomsResponseWs {
     pub update: Vec<RoomResponseWs>,
-    pub remove: Vec<serde_json::value::Value>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -157,15 +173,19 @@ pub enum JoinedRoomResponseWs {
     Private(ResultRoomResponseWs),
 }
 
+/// The subscription of the user to a room, with its notification settings.
 #[derive(Deserialize, Debug)]
-pub struct UserInRoomResponseWs {
-    pub total: usize,
-    pub record: Vec<serde_json::value::Value>,
+#[serde(rename_all = "camelCase")]
+pub struct SubscriptionResponseWs {
+    pub rid: String,
+    /// `all`, `mentions`, `nothing` or `default`.
+    pub desktop_notifications: Option<String>,
+    #[serde(default)]
+    pub disable_notifications: bool,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct UsersInRoomResponseWs {
-    pub total: usize,
     pub records: Vec<AuthorResponseWs>,
 }
 
@@ -174,25 +194,33 @@ pub struct UsersInRoomResponseWs {
 pub enum WsResponse {
     NewMessage(SocketMessageWs),
     History {
-        msg: String,
         id: String,
         result: ChannelHistoryResponseWs,
     },
     Rooms {
-        msg: String,
         id: String,
         result: RoomsResponseWs,
     },
     JoinedRoom {
-        msg: String,
         id: String,
         result: JoinedRoomResponseWs,
     },
     UsersInRoom {
-        msg: String,
         id: String,
         result: UsersInRoomResponseWs,
     },
+    RoomId {
+        id: String,
+        result: String,
+    },
+    Subscriptions {
+        id: String,
+        result: Vec<SubscriptionResponseWs>,
+    },
+    MethodError {
+        id: String,
+        error: serde_json::value::Value,
+    },
     Ping {
         msg: String,
     },
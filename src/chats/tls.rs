@@ -0,0 +1,544 @@
+//! TLS module.
+//!
+//! This module builds the TLS configuration shared by the chats.
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerName};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::convert::{TryFrom, TryInto};
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio_rustls::TlsConnector;
+
+/// TLS configuration.
+///
+/// This type is the `[tls]` table of an account, `verify` comes from
+/// `ssl_verify` and the `-k` option.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// Whether the server certificate chain is checked.
+    #[serde(skip)]
+    pub verify: bool,
+    /// PEM file of CA certificates trusted on top of the bundled roots.
+    pub ca_file: Option<PathBuf>,
+    /// PEM certificate chain sent to the server, `client_key` is its key.
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+    /// SHA-256 fingerprints of the server public keys (SPKI), one must
+    /// match the server certificate or a CA certificate of its verified
+    /// path, sent by the server or in `ca_file`. Either `sha256//<base64>`
+    /// or hexadecimal.
+    pub pins: Vec<String>,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            verify: true,
+            ca_file: None,
+            client_cert: None,
+            client_key: None,
+            pins: vec![],
+        }
+    }
+}
+
+type Pin = [u8; 32];
+
+/// Signature algorithms tried when following a certificate path, a
+/// signature only verifies with the key type and hash it was made with.
+static SIGNATURE_ALGORITHMS: &[&webpki::SignatureAlgorithm] = &[
+    &webpki::ECDSA_P256_SHA256,
+    &webpki::ECDSA_P256_SHA384,
+    &webpki::ECDSA_P384_SHA256,
+    &webpki::ECDSA_P384_SHA384,
+    &webpki::ED25519,
+    &webpki::RSA_PKCS1_2048_8192_SHA256,
+    &webpki::RSA_PKCS1_2048_8192_SHA384,
+    &webpki::RSA_PKCS1_2048_8192_SHA512,
+    &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA384_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA512_LEGACY_KEY,
+];
+/// A client certificate chain and its private key.
+type Identity = (Vec<Certificate>, PrivateKey);
+
+fn parse_pin(pin: &str) -> Result<Pin, Box<dyn Error + Send + Sync>> {
+    let bytes = match pin.strip_prefix("sha256//") {
+        Some(encoded) => base64::decode(encoded).ok(),
+        None => {
+            let hex = pin.replace(':', "");
+            (0..hex.len())
+                .step_by(2)
+                .map(|i| {
+                    hex.get(i..i + 2)
+                        .and_then(|x| u8::from_str_radix(x, 16).ok())
+                })
+                .collect::<Option<Vec<u8>>>()
+        }
+    };
+    bytes.and_then(|x| x.try_into().ok()).ok_or_else(|| {
+        format!(
+            "Invalid pin {}, expected sha256//<base64> or 64 hex digits",
+            pin
+        )
+        .into()
+    })
+}
+
+/// A DER element and what follows it.
+struct Der<'a> {
+    tag: u8,
+    encoding: &'a [u8],
+    content: &'a [u8],
+    rest: &'a [u8],
+}
+
+fn der_element(data: &[u8]) -> Option<Der<'_>> {
+    let tag = *data.first()?;
+    let first = *data.get(1)? as usize;
+    let (length, header) = if first < 0x80 {
+        (first, 2)
+    } else {
+        let count = first & 0x7f;
+        if count == 0 || count > 4 {
+            return None;
+        }
+        let length = data
+            .get(2..2 + count)?
+            .iter()
+            .fold(0usize, |length, byte| length << 8 | *byte as usize);
+        (length, 2 + count)
+    };
+    let encoding = data.get(..header.checked_add(length)?)?;
+    Some(Der {
+        tag,
+        encoding,
+        content: &encoding[header..],
+        rest: &data[encoding.len()..],
+    })
+}
+
+/// The SubjectPublicKeyInfo of a DER certificate.
+fn spki(certificate: &[u8]) -> Option<&[u8]> {
+    let certificate = der_element(certificate)?.content;
+    let mut tbs = der_element(certificate)?.content;
+    // The explicit version is optional
+    let version = der_element(tbs)?;
+    if version.tag == 0xa0 {
+        tbs = version.rest;
+    }
+    // Serial number, signature algorithm, issuer, validity and subject
+    for _ in 0..5 {
+        tbs = der_element(tbs)?.rest;
+    }
+    Some(der_element(tbs)?.encoding)
+}
+
+/// The signed part and the signature of a DER certificate.
+fn signed_data(certificate: &[u8]) -> Option<(&[u8], &[u8])> {
+    let certificate = der_element(certificate)?.content;
+    let tbs = der_element(certificate)?;
+    let signature = der_element(der_element(tbs.rest)?.rest)?;
+    if signature.tag != 0x03 {
+        return None;
+    }
+    // The bit string starts with its count of unused bits
+    match signature.content.split_first() {
+        Some((0, signature)) => Some((tbs.encoding, signature)),
+        _ => None,
+    }
+}
+
+/// Whether `issuer` holds the key which signed `certificate`.
+fn signed_by(certificate: &[u8], issuer: &[u8]) -> bool {
+    let (tbs, signature) = match signed_data(certificate) {
+        Some(signed) => signed,
+        None => return false,
+    };
+    let issuer = match webpki::EndEntityCert::try_from(issuer) {
+        Ok(issuer) => issuer,
+        Err(_) => return false,
+    };
+    SIGNATURE_ALGORITHMS
+        .iter()
+        .any(|x| issuer.verify_signature(x, tbs, signature).is_ok())
+}
+
+/// The certificates of the path from `end_entity` to its root.
+///
+/// Each certificate of the path signed the previous one, so a certificate
+/// which was only appended to the handshake is never part of it.
+fn issuer_path<'a>(end_entity: &'a [u8], candidates: &[&'a [u8]]) -> Vec<&'a [u8]> {
+    let mut path = vec![end_entity];
+    let mut current = end_entity;
+    while path.len() <= candidates.len() {
+        let issuer = candidates
+            .iter()
+            .find(|x| !path.contains(x) && signed_by(current, x));
+        match issuer {
+            Some(issuer) => {
+                path.push(issuer);
+                current = issuer;
+            }
+            None => break,
+        }
+    }
+    path
+}
+
+fn read_certificates(path: &Path) -> Result<Vec<Vec<u8>>, Box<dyn Error + Send + Sync>> {
+    let file = File::open(path).map_err(|err| format!("Can't open {}: {}", path.display(), err))?;
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(file))
+        .map_err(|err| format!("Invalid PEM file {}: {}", path.display(), err))?;
+    if certificates.is_empty() {
+        return Err(format!("No certificate in {}", path.display()).into());
+    }
+    Ok(certificates)
+}
+
+fn read_private_key(path: &Path) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let file = File::open(path).map_err(|err| format!("Can't open {}: {}", path.display(), err))?;
+    rustls_pemfile::read_all(&mut BufReader::new(file))
+        .map_err(|err| format!("Invalid PEM file {}: {}", path.display(), err))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(key),
+            _ => None,
+        })
+        .ok_or_else(|| format!("No private key in {}", path.display()).into())
+}
+
+struct NoCertificateVerification {}
+
+impl ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// Accept a server whose certificate path contains a pinned public key.
+///
+/// The chain is checked first, unless certificates aren't verified: only
+/// the key of the server certificate is compared then.
+struct PinnedVerifier {
+    chain: Option<WebPkiVerifier>,
+    /// The certificates of `ca_file`, the path may end with one of them.
+    anchors: Vec<Vec<u8>>,
+    pins: Vec<Pin>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(chain) = &self.chain {
+            chain.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                scts,
+                ocsp_response,
+                now,
+            )?;
+        }
+        // Without a verified chain, only the key of the handshake is proven
+        let candidates: Vec<&[u8]> = match self.chain {
+            Some(_) => intermediates
+                .iter()
+                .map(|x| x.0.as_slice())
+                .chain(self.anchors.iter().map(|x| x.as_slice()))
+                .collect(),
+            None => vec![],
+        };
+        let pinned = issuer_path(&end_entity.0, &candidates)
+            .into_iter()
+            .filter_map(spki)
+            .any(|x| self.pins.contains(&Sha256::digest(x).into()));
+        if pinned {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "No pinned public key in the server certificates".into(),
+            ))
+        }
+    }
+}
+
+impl TlsConfig {
+    /// The trusted roots, the webpki ones and those of `ca_file`.
+    fn root_store(&self) -> Result<RootCertStore, Box<dyn Error + Send + Sync>> {
+        let mut root_store = RootCertStore::empty();
+        root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+            rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+                ta.subject,
+                ta.spki,
+                ta.name_constraints,
+            )
+        }));
+        if let Some(ca_file) = &self.ca_file {
+            let (_, ignored) = root_store.add_parsable_certificates(&read_certificates(ca_file)?);
+            if ignored > 0 {
+                return Err(format!("Invalid CA certificate in {}", ca_file.display()).into());
+            }
+        }
+        Ok(root_store)
+    }
+
+    /// The client certificate chain and key, if any.
+    fn client_identity(&self) -> Result<Option<Identity>, Box<dyn Error + Send + Sync>> {
+        match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => Ok(Some((
+                read_certificates(cert)?
+                    .into_iter()
+                    .map(Certificate)
+                    .collect(),
+                PrivateKey(read_private_key(key)?),
+            ))),
+            (None, None) => Ok(None),
+            _ => Err("client_cert and client_key go together".into()),
+        }
+    }
+
+    /// Build the rustls configuration, the files are read now.
+    pub fn client_config(&self) -> Result<rustls::ClientConfig, Box<dyn Error + Send + Sync>> {
+        let pins = self
+            .pins
+            .iter()
+            .map(|x| parse_pin(x))
+            .collect::<Result<Vec<Pin>, _>>()?;
+        let root_store = self.root_store()?;
+        let builder = rustls::ClientConfig::builder().with_safe_defaults();
+        let verifier: Arc<dyn ServerCertVerifier> = match (self.verify, pins.is_empty()) {
+            (true, true) => Arc::new(WebPkiVerifier::new(root_store, None)),
+            (false, true) => Arc::new(NoCertificateVerification {}),
+            (verify, false) => Arc::new(PinnedVerifier {
+                chain: verify.then(|| WebPkiVerifier::new(root_store, None)),
+                anchors: match &self.ca_file {
+                    Some(ca_file) => read_certificates(ca_file)?,
+                    None => vec![],
+                },
+                pins,
+            }),
+        };
+        let builder = builder.with_custom_certificate_verifier(verifier);
+        Ok(match self.client_identity()? {
+            Some((chain, key)) => builder
+                .with_single_cert(chain, key)
+                .map_err(|err| format!("Invalid client certificate: {}", err))?,
+            None => builder.with_no_client_auth(),
+        })
+    }
+
+    /// Configure an HTTP client like the websockets.
+    ///
+    /// The HTTP client can't pin certificates, pins are refused instead
+    /// of ignored.
+    #[cfg(any(feature = "matrix", feature = "mattermost"))]
+    pub fn http_client(
+        &self,
+        builder: reqwest::ClientBuilder,
+    ) -> Result<reqwest::ClientBuilder, Box<dyn Error + Send + Sync>> {
+        if !self.pins.is_empty() {
+            return Err("Certificate pinning is not supported by HTTP backends".into());
+        }
+        let mut builder = builder.danger_accept_invalid_certs(!self.verify);
+        if let Some(ca_file) = &self.ca_file {
+            for certificate in read_certificates(ca_file)? {
+                builder =
+                    builder.add_root_certificate(reqwest::Certificate::from_der(&certificate)?);
+            }
+        }
+        if let (Some(cert), Some(key)) = (&self.client_cert, &self.client_key) {
+            let mut pem = std::fs::read(cert)?;
+            pem.extend(std::fs::read(key)?);
+            builder = builder.identity(reqwest::Identity::from_pem(&pem)?);
+        }
+        Ok(builder)
+    }
+}
+
+/// Build a TLS connector trusting the webpki roots and the `[tls]` table,
+/// or anything if certificates aren't verified nor pinned.
+pub fn connector(tls: &TlsConfig) -> Result<TlsConnector, Box<dyn Error + Send + Sync>> {
+    Ok(TlsConnector::from(Arc::new(tls.client_config()?)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERVER_PIN: &str = "sha256//AeR1ft5AkorG3AfhpeWAQ9n/n3Gs0T4nFmg18oiWU1A=";
+    const CA_PIN: &str = "sha256//SBk9zL9j1aWBlFKU0lS1VSl7lr7I50elixUtzXarZGk=";
+
+    fn data(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/data")
+            .join(name)
+    }
+
+    fn chain() -> (Certificate, Vec<Certificate>) {
+        let server = read_certificates(&data("tls_server.pem")).unwrap();
+        let ca = read_certificates(&data("tls_ca.pem")).unwrap();
+        (
+            Certificate(server[0].clone()),
+            vec![Certificate(ca[0].clone())],
+        )
+    }
+
+    fn verify(verifier: &dyn ServerCertVerifier, name: &str) -> Result<(), rustls::Error> {
+        let (end_entity, intermediates) = chain();
+        verify_chain(verifier, name, &end_entity, &intermediates)
+    }
+
+    fn verify_chain(
+        verifier: &dyn ServerCertVerifier,
+        name: &str,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+    ) -> Result<(), rustls::Error> {
+        verifier
+            .verify_server_cert(
+                end_entity,
+                intermediates,
+                &ServerName::try_from(name).unwrap(),
+                &mut std::iter::empty(),
+                &[],
+                SystemTime::now(),
+            )
+            .map(|_| ())
+    }
+
+    #[test]
+    fn test_parse_pin() {
+        let hex = "01e4757ede40928ac6dc07e1a5e58043d9ff9f71acd13e27166835f288965350";
+        assert_eq!(parse_pin(SERVER_PIN).unwrap(), parse_pin(hex).unwrap());
+        assert_eq!(parse_pin(&hex.to_uppercase()).unwrap()[0], 0x01);
+        assert!(parse_pin("sha256//AeR1").is_err());
+        assert!(parse_pin(&hex[1..]).is_err());
+    }
+
+    #[test]
+    fn test_spki() {
+        let (end_entity, _) = chain();
+        let spki = spki(&end_entity.0).unwrap();
+        assert_eq!(
+            <[u8; 32]>::from(Sha256::digest(spki)),
+            parse_pin(SERVER_PIN).unwrap()
+        );
+        assert_eq!(super::spki(&end_entity.0[..40]), None);
+    }
+
+    #[test]
+    fn test_pinned_verifier() {
+        let tls = TlsConfig {
+            ca_file: Some(data("tls_ca.pem")),
+            ..TlsConfig::default()
+        };
+        let chain = WebPkiVerifier::new(tls.root_store().unwrap(), None);
+        let pinned = |pin: &str, chain: Option<WebPkiVerifier>| PinnedVerifier {
+            chain,
+            anchors: vec![],
+            pins: vec![parse_pin(pin).unwrap()],
+        };
+        assert!(verify(&chain, "chat.test").is_ok());
+        assert!(verify(&chain, "other.test").is_err());
+        assert!(verify(&pinned(SERVER_PIN, None), "other.test").is_ok());
+        assert!(verify(&pinned(CA_PIN, None), "chat.test").is_err());
+        let chain = WebPkiVerifier::new(tls.root_store().unwrap(), None);
+        assert!(verify(&pinned(CA_PIN, Some(chain)), "chat.test").is_ok());
+        let chain = WebPkiVerifier::new(tls.root_store().unwrap(), None);
+        assert!(verify(&pinned(SERVER_PIN, Some(chain)), "other.test").is_err());
+        let unknown = "00".repeat(32);
+        assert!(verify(&pinned(&unknown, None), "chat.test").is_err());
+        let webpki_only = WebPkiVerifier::new(TlsConfig::default().root_store().unwrap(), None);
+        assert!(verify(&webpki_only, "chat.test").is_err());
+    }
+
+    #[test]
+    fn test_pinned_path() {
+        let tls = TlsConfig {
+            ca_file: Some(data("tls_ca.pem")),
+            ..TlsConfig::default()
+        };
+        let client = Certificate(read_certificates(&data("tls_client.pem")).unwrap()[0].clone());
+        let client_pin = Sha256::digest(spki(&client.0).unwrap()).into();
+        let pinned = |pin: Pin, anchors: Vec<Vec<u8>>| PinnedVerifier {
+            chain: Some(WebPkiVerifier::new(tls.root_store().unwrap(), None)),
+            anchors,
+            pins: vec![pin],
+        };
+        let (end_entity, mut intermediates) = chain();
+        // A pinned key appended to a valid chain isn't on its path
+        intermediates.push(client);
+        assert!(verify_chain(
+            &pinned(client_pin, vec![]),
+            "chat.test",
+            &end_entity,
+            &intermediates
+        )
+        .is_err());
+        assert!(verify_chain(
+            &pinned(parse_pin(CA_PIN).unwrap(), vec![]),
+            "chat.test",
+            &end_entity,
+            &intermediates
+        )
+        .is_ok());
+        // The CA of ca_file ends the path when the server doesn't send it
+        let anchors = read_certificates(&data("tls_ca.pem")).unwrap();
+        let ca_pin = parse_pin(CA_PIN).unwrap();
+        assert!(verify_chain(&pinned(ca_pin, anchors), "chat.test", &end_entity, &[]).is_ok());
+        assert!(verify_chain(&pinned(ca_pin, vec![]), "chat.test", &end_entity, &[]).is_err());
+    }
+
+    #[test]
+    fn test_client_config() {
+        let tls = TlsConfig {
+            ca_file: Some(data("tls_ca.pem")),
+            client_cert: Some(data("tls_client.pem")),
+            client_key: Some(data("tls_client.key")),
+            pins: vec![SERVER_PIN.into()],
+            ..TlsConfig::default()
+        };
+        let config = tls.client_config().unwrap();
+        assert!(config.client_auth_cert_resolver.has_certs());
+        let err = TlsConfig {
+            client_key: None,
+            ..tls.clone()
+        }
+        .client_config()
+        .err()
+        .unwrap();
+        assert_eq!(format!("{}", err), "client_cert and client_key go together");
+        let err = TlsConfig {
+            ca_file: Some(data("tls_client.key")),
+            ..tls
+        }
+        .client_config()
+        .err()
+        .unwrap();
+        assert!(format!("{}", err).starts_with("No certificate in"));
+    }
+}
@@ -0,0 +1,169 @@
+use super::xml::Element;
+use async_channel::Sender;
+use std::error::Error;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub const NS_ROSTER: &str = "jabber:iq:roster";
+pub const NS_MUC: &str = "http://jabber.org/protocol/muc";
+pub const NS_MUC_USER: &str = "http://jabber.org/protocol/muc#user";
+pub const NS_MAM: &str = "urn:xmpp:mam:2";
+pub const NS_PING: &str = "urn:xmpp:ping";
+/// Number of messages requested from the archive when opening a chat.
+const HISTORY_SIZE: usize = 100;
+
+/// XMPP stanza writer.
+///
+/// Every stanza is serialized and pushed to the task writing the socket.
+pub struct XmppWriter {
+    socket: Sender<String>,
+    id: AtomicU64,
+}
+
+impl XmppWriter {
+    pub fn new(socket: Sender<String>) -> Self {
+        XmppWriter {
+            socket,
+            id: AtomicU64::new(0),
+        }
+    }
+
+    fn next_id(&self, prefix: &str) -> String {
+        format!("{}{}", prefix, self.id.fetch_add(1, Ordering::SeqCst))
+    }
+
+    pub async fn send(&self, stanza: Element) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.socket.send(format!("{}", stanza)).await?;
+        Ok(())
+    }
+
+    pub async fn roster(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.send(
+            Element::new("iq")
+                .with_attr("type", "get")
+                .with_attr("id", "roster")
+                .with_child(Element::new("query").with_attr("xmlns", NS_ROSTER)),
+        )
+        .await
+    }
+
+    pub async fn presence(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.send(Element::new("presence")).await
+    }
+
+    /// Join a room, the history comes from the archive instead of the room.
+    pub async fn join(&self, room: &str, nick: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.send(
+            Element::new("presence")
+                .with_attr("to", &format!("{}/{}", room, nick))
+                .with_child(
+                    Element::new("x")
+                        .with_attr("xmlns", NS_MUC)
+                        .with_child(Element::new("history").with_attr("maxstanzas", "0")),
+                ),
+        )
+        .await
+    }
+
+    pub async fn part(&self, room: &str, nick: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.send(
+            Element::new("presence")
+                .with_attr("to", &format!("{}/{}", room, nick))
+                .with_attr("type", "unavailable"),
+        )
+        .await
+    }
+
+    pub async fn message(
+        &self,
+        to: &str,
+        kind: &str,
+        body: &str,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.send(
+            Element::new("message")
+                .with_attr("to", to)
+                .with_attr("type", kind)
+                .with_attr("id", &self.next_id("msg"))
+                .with_child(Element::new("body").with_text(body)),
+        )
+        .await
+    }
+
+    /// Query the last messages of a room archive, or of our archive with a contact.
+    ///
+    /// Return the query id, results are tagged with it.
+    pub async fn mam_query(
+        &self,
+        room: Option<&str>,
+        with: Option<&str>,
+    ) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let query_id = self.next_id("mam");
+        let mut form = Element::new("x")
+            .with_attr("xmlns", "jabber:x:data")
+            .with_attr("type", "submit")
+            .with_child(
+                Element::new("field")
+                    .with_attr("var", "FORM_TYPE")
+                    .with_attr("type", "hidden")
+                    .with_child(Element::new("value").with_text(NS_MAM)),
+            );
+        if let Some(with) = with {
+            form = form.with_child(
+                Element::new("field")
+                    .with_attr("var", "with")
+                    .with_child(Element::new("value").with_text(with)),
+            );
+        }
+        let mut iq = Element::new("iq")
+            .with_attr("type", "set")
+            .with_attr("id", &query_id);
+        if let Some(room) = room {
+            iq = iq.with_attr("to", room);
+        }
+        self.send(
+            iq.with_child(
+                Element::new("query")
+                    .with_attr("xmlns", NS_MAM)
+                    .with_attr("queryid", &query_id)
+                    .with_child(form)
+                    .with_child(
+                        Element::new("set")
+                            .with_attr("xmlns", "http://jabber.org/protocol/rsm")
+                            .with_child(Element::new("max").with_text(&HISTORY_SIZE.to_string()))
+                            .with_child(Element::new("before")),
+                    ),
+            ),
+        )
+        .await?;
+        Ok(query_id)
+    }
+
+    pub async fn iq_result(&self, to: &str, id: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.send(
+            Element::new("iq")
+                .with_attr("to", to)
+                .with_attr("id", id)
+                .with_attr("type", "result"),
+        )
+        .await
+    }
+
+    /// Refuse a request we don't support.
+    pub async fn iq_error(&self, to: &str, id: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.send(
+            Element::new("iq")
+                .with_attr("to", to)
+                .with_attr("id", id)
+                .with_attr("type", "error")
+                .with_child(
+                    Element::new("error")
+                        .with_attr("type", "cancel")
+                        .with_child(
+                            Element::new("service-unavailable")
+                                .with_attr("xmlns", "urn:ietf:params:xml:ns:xmpp-stanzas"),
+                        ),
+                ),
+        )
+        .await
+    }
+}
@@ -0,0 +1,1225 @@
+mod api;
+mod xml;
+
+use super::super::core::{Alert, Channel, Chat, ChatEvent, Message, Notification, UIEvent};
+use super::tls::{self, TlsConfig};
+use api::*;
+use async_channel::{unbounded, Receiver, Sender};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::{error, warn};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::convert::TryFrom;
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use url::Url;
+use xml::{Element, XmlEvent, XmlParser};
+
+const NS_TLS: &str = "urn:ietf:params:xml:ns:xmpp-tls";
+const NS_SASL: &str = "urn:ietf:params:xml:ns:xmpp-sasl";
+const NS_BIND: &str = "urn:ietf:params:xml:ns:xmpp-bind";
+const NS_SESSION: &str = "urn:ietf:params:xml:ns:xmpp-session";
+/// Number of messages kept per chat when the server has no archive.
+const BACKLOG_SIZE: usize = 500;
+
+fn bare(jid: &str) -> &str {
+    jid.split('/').next().unwrap_or(jid)
+}
+
+fn resource(jid: &str) -> &str {
+    jid.split_once('/').map(|x| x.1).unwrap_or("")
+}
+
+fn localpart(jid: &str) -> &str {
+    jid.split('@').next().unwrap_or(jid)
+}
+
+fn domainpart(jid: &str) -> &str {
+    bare(jid).rsplit('@').next().unwrap_or(jid)
+}
+
+trait XmppStream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> XmppStream for T {}
+
+/// An XML stream being negotiated, before the stanzas are handed to the chat.
+struct Connection<S> {
+    stream: S,
+    parser: XmlParser,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Connection<S> {
+    fn new(stream: S) -> Self {
+        Connection {
+            stream,
+            parser: XmlParser::default(),
+        }
+    }
+
+    async fn send(&mut self, data: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.stream.write_all(data.as_bytes()).await?;
+        Ok(())
+    }
+
+    async fn next(&mut self) -> Result<XmlEvent, Box<dyn Error + Send + Sync>> {
+        let mut buffer = [0; 4096];
+        loop {
+            if let Some(event) = self.parser.next_event()? {
+                return Ok(event);
+            }
+            let read = self.stream.read(&mut buffer).await?;
+            if read == 0 {
+                return Err("XMPP connection closed".into());
+            }
+            self.parser.feed(&buffer[..read]);
+        }
+    }
+
+    async fn stanza(&mut self) -> Result<Element, Box<dyn Error + Send + Sync>> {
+        loop {
+            match self.next().await? {
+                XmlEvent::Stanza(stanza) => return Ok(stanza),
+                XmlEvent::StreamClose => return Err("XMPP stream closed by the server".into()),
+                XmlEvent::StreamOpen(_) => {}
+            }
+        }
+    }
+
+    /// Open a stream and return the features offered by the server.
+    async fn open(&mut self, domain: &str) -> Result<Element, Box<dyn Error + Send + Sync>> {
+        self.send(&format!(
+            "<?xml version='1.0'?><stream:stream to='{}' version='1.0' xmlns='jabber:client' xmlns:stream='http://etherx.jabber.org/streams'>",
+            domain
+        ))
+        .await?;
+        let features = self.stanza().await?;
+        if !features.is("features") {
+            return Err(format!("Unexpected XMPP stanza {}", features).into());
+        }
+        Ok(features)
+    }
+
+    async fn iq(&mut self, iq: Element) -> Result<Element, Box<dyn Error + Send + Sync>> {
+        let id = iq.attr("id").unwrap_or_default().to_string();
+        self.send(&format!("{}", iq)).await?;
+        loop {
+            let stanza = self.stanza().await?;
+            if stanza.is("iq") && stanza.attr("id") == Some(&id[..]) {
+                return match stanza.attr("type") {
+                    Some("result") => Ok(stanza),
+                    _ => Err(format!("XMPP request {} failed", id).into()),
+                };
+            }
+        }
+    }
+
+    /// Authenticate with SASL PLAIN and bind a resource, return the full jid.
+    async fn login(
+        &mut self,
+        features: Element,
+        jid: &str,
+        password: &str,
+    ) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let plain = features
+            .child_ns("mechanisms", NS_SASL)
+            .map(|x| x.children().any(|x| x.text() == "PLAIN"))
+            .unwrap_or(false);
+        if !plain {
+            return Err("The XMPP server doesn't offer SASL PLAIN".into());
+        }
+        let payload = format!("\0{}\0{}", localpart(jid), password);
+        let auth = Element::new("auth")
+            .with_attr("xmlns", NS_SASL)
+            .with_attr("mechanism", "PLAIN")
+            .with_text(&base64::encode(payload));
+        self.send(&format!("{}", auth)).await?;
+        let result = self.stanza().await?;
+        if !result.is("success") {
+            let condition = result
+                .children()
+                .find(|x| !x.is("text"))
+                .map(|x| x.name.clone())
+                .unwrap_or_default();
+            return Err(format!("SASL authentication failed: {}", condition).into());
+        }
+        let features = self.open(domainpart(jid)).await?;
+        let bind = Element::new("iq")
+            .with_attr("type", "set")
+            .with_attr("id", "bind")
+            .with_child(
+                Element::new("bind")
+                    .with_attr("xmlns", NS_BIND)
+                    .with_child(Element::new("resource").with_text("talkoxid")),
+            );
+        let bound = self.iq(bind).await?;
+        let full_jid = bound
+            .child("bind")
+            .and_then(|x| x.child("jid"))
+            .map(|x| x.text())
+            .ok_or("No jid in the XMPP bind result")?;
+        let session = features.child_ns("session", NS_SESSION);
+        if session
+            .map(|x| x.child("optional").is_none())
+            .unwrap_or(false)
+        {
+            let session = Element::new("iq")
+                .with_attr("type", "set")
+                .with_attr("id", "session")
+                .with_child(Element::new("session").with_attr("xmlns", NS_SESSION));
+            self.iq(session).await?;
+        }
+        Ok(full_jid)
+    }
+}
+
+/// Spawn the tasks writing stanzas and reading them from the connection.
+fn spawn_io(connection: Connection<Box<dyn XmppStream>>) -> (Sender<String>, Receiver<Element>) {
+    let Connection { stream, mut parser } = connection;
+    let (mut read, mut write) = tokio::io::split(stream);
+    let (tx_socket, rx_writer) = unbounded::<String>();
+    let (tx_reader, rx_socket) = unbounded();
+    tokio::spawn(async move {
+        while let Ok(stanza) = rx_writer.recv().await {
+            if let Err(err) = write.write_all(stanza.as_bytes()).await {
+                error!("Error when writing to the XMPP server: {}", err);
+                break;
+            }
+        }
+    });
+    tokio::spawn(async move {
+        let mut buffer = [0; 4096];
+        loop {
+            match parser.next_event() {
+                Ok(Some(XmlEvent::Stanza(stanza))) => {
+                    if let Err(err) = tx_reader.send(stanza).await {
+                        error!("Error when sending to XMPP receiver: {}", err);
+                        break;
+                    }
+                    continue;
+                }
+                Ok(Some(XmlEvent::StreamClose)) => {
+                    error!("XMPP stream closed by the server");
+                    break;
+                }
+                Ok(Some(XmlEvent::StreamOpen(_))) => continue,
+                Ok(None) => {}
+                Err(err) => {
+                    error!("Invalid XMPP stream: {}", err);
+                    break;
+                }
+            }
+            match read.read(&mut buffer).await {
+                Ok(0) => {
+                    error!("XMPP connection closed");
+                    break;
+                }
+                Ok(read) => parser.feed(&buffer[..read]),
+                Err(err) => {
+                    error!("Error when reading from the XMPP server: {}", err);
+                    break;
+                }
+            }
+        }
+    });
+    (tx_socket, rx_socket)
+}
+
+/// XMPP chat system.
+///
+/// This type is a chat system implementation for XMPP, multi-user chat
+/// rooms are groups and roster contacts are direct chats.
+pub struct Xmpp {
+    tx_ui: Sender<UIEvent>,
+    writer: XmppWriter,
+    notifier: Box<dyn Notification + Sync + Send>,
+    rx_xmpp: Receiver<Element>,
+    rx_chat: Receiver<ChatEvent>,
+    jid: String,
+    nick: String,
+    autojoin: Vec<String>,
+    autojoined: AtomicBool,
+    current_channel: Mutex<Option<Channel>>,
+    roster: Mutex<BTreeMap<String, String>>,
+    rooms: Mutex<BTreeSet<String>>,
+    joined: Mutex<BTreeSet<String>>,
+    occupants: Mutex<HashMap<String, BTreeSet<String>>>,
+    presences: Mutex<HashMap<String, String>>,
+    backlog: Mutex<HashMap<String, Vec<Message>>>,
+    archives: Mutex<HashMap<String, (String, Vec<Message>)>>,
+}
+
+/// The TLS settings of an XMPP connection.
+pub struct XmppTls {
+    pub config: TlsConfig,
+    /// Log in without TLS when the server doesn't offer STARTTLS.
+    pub allow_plaintext: bool,
+}
+
+impl Xmpp {
+    pub async fn new(
+        host: Url,
+        username: String,
+        password: String,
+        tls: XmppTls,
+        tx_ui: Sender<UIEvent>,
+        rx_chat: Receiver<ChatEvent>,
+        notifier: Box<dyn Notification + Sync + Send>,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let server = host
+            .host_str()
+            .ok_or("No XMPP server in the url")?
+            .to_string();
+        let jid = match username.contains('@') {
+            true => username,
+            false => format!("{}@{}", username, server),
+        };
+        let domain = domainpart(&jid).to_string();
+        let XmppTls {
+            config: tls,
+            allow_plaintext,
+        } = tls;
+        let direct_tls = host.scheme() == "xmpps";
+        let port = host.port().unwrap_or(if direct_tls { 5223 } else { 5222 });
+        let stream = TcpStream::connect((&server[..], port)).await?;
+        let name = rustls::ServerName::try_from(&server[..]).ok();
+        let (mut connection, features) = if direct_tls {
+            let name = name.ok_or("Invalid XMPP server name")?;
+            let stream = tls::connector(&tls)?.connect(name, stream).await?;
+            let mut connection = Connection::new(Box::new(stream) as Box<dyn XmppStream>);
+            let features = connection.open(&domain).await?;
+            (connection, features)
+        } else {
+            let mut plain = Connection::new(stream);
+            let features = plain.open(&domain).await?;
+            if features.child_ns("starttls", NS_TLS).is_some() {
+                plain
+                    .send(&format!(
+                        "{}",
+                        Element::new("starttls").with_attr("xmlns", NS_TLS)
+                    ))
+                    .await?;
+                if !plain.stanza().await?.is("proceed") {
+                    return Err("The XMPP server refused STARTTLS".into());
+                }
+                let name = name.ok_or("Invalid XMPP server name")?;
+                let stream = tls::connector(&tls)?.connect(name, plain.stream).await?;
+                let mut connection = Connection::new(Box::new(stream) as Box<dyn XmppStream>);
+                let features = connection.open(&domain).await?;
+                (connection, features)
+            } else if !allow_plaintext {
+                return Err(
+                    "The XMPP server doesn't offer STARTTLS, set allow_plaintext to log in anyway"
+                        .into(),
+                );
+            } else {
+                warn!("The XMPP server doesn't offer STARTTLS, the password is sent in clear");
+                let connection = Connection {
+                    stream: Box::new(plain.stream) as Box<dyn XmppStream>,
+                    parser: plain.parser,
+                };
+                (connection, features)
+            }
+        };
+        let full_jid = connection.login(features, &jid, &password).await?;
+        let (tx_xmpp, rx_xmpp) = spawn_io(connection);
+        let writer = XmppWriter::new(tx_xmpp);
+        writer.roster().await?;
+        writer.presence().await?;
+        Ok(Xmpp {
+            tx_ui,
+            writer,
+            notifier,
+            rx_xmpp,
+            rx_chat,
+            nick: localpart(&full_jid).to_string(),
+            jid: full_jid,
+            autojoin: vec![],
+            autojoined: AtomicBool::new(false),
+            current_channel: Mutex::new(None),
+            roster: Mutex::new(BTreeMap::new()),
+            rooms: Mutex::new(BTreeSet::new()),
+            joined: Mutex::new(BTreeSet::new()),
+            occupants: Mutex::new(HashMap::new()),
+            presences: Mutex::new(HashMap::new()),
+            backlog: Mutex::new(HashMap::new()),
+            archives: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Join these rooms when the view is initialized.
+    pub fn with_rooms(mut self, rooms: Vec<String>) -> Self {
+        self.autojoin = rooms;
+        self
+    }
+
+    fn is_current(&self, target: &str) -> bool {
+        self.current_channel
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|x| format!("{}", x) == target)
+            .unwrap_or(false)
+    }
+
+    fn is_room(&self, jid: &str) -> bool {
+        self.rooms.lock().unwrap().contains(bare(jid))
+    }
+
+    fn contact_name(&self, jid: &str) -> String {
+        let jid = bare(jid);
+        if jid == bare(&self.jid) {
+            return self.nick.clone();
+        }
+        self.roster
+            .lock()
+            .unwrap()
+            .get(jid)
+            .filter(|x| !x.is_empty())
+            .cloned()
+            .unwrap_or_else(|| localpart(jid).to_string())
+    }
+
+    fn channels(&self) -> Vec<(String, Channel)> {
+        let rooms = self.rooms.lock().unwrap().clone();
+        let contacts = self.roster.lock().unwrap().clone();
+        rooms
+            .into_iter()
+            .map(|x| (localpart(&x).to_string(), Channel::Group(x)))
+            .chain(
+                contacts
+                    .into_keys()
+                    .map(|x| (self.contact_name(&x), Channel::User(x))),
+            )
+            .collect()
+    }
+
+    fn to_message(&self, stanza: &Element, id: Option<&str>, stamp: Option<&str>) -> Message {
+        let from = stanza.attr("from").unwrap_or_default();
+        let author = match stanza.attr("type") {
+            Some("groupchat") => resource(from).to_string(),
+            _ => self.contact_name(from),
+        };
+        let stamp = stamp.or_else(|| stanza.child("delay").and_then(|x| x.attr("stamp")));
+        Message {
+            id: id
+                .or_else(|| stanza.child("stanza-id").and_then(|x| x.attr("id")))
+                .or_else(|| stanza.attr("id"))
+                .unwrap_or_default()
+                .to_string(),
+            author,
+            content: stanza.child("body").map(|x| x.text()).unwrap_or_default(),
+            datetime: stamp
+                .and_then(|x| DateTime::parse_from_rfc3339(x).ok())
+                .map(|x| x.with_timezone(&Utc))
+                .unwrap_or_else(Utc::now),
+            thread: None,
+            attachments: vec![],
+        }
+    }
+
+    fn push_backlog(&self, target: &str, message: Message) {
+        let mut backlog = self.backlog.lock().unwrap();
+        let messages = backlog.entry(target.to_string()).or_default();
+        messages.push(message);
+        if messages.len() > BACKLOG_SIZE {
+            messages.remove(0);
+        }
+    }
+
+    fn render_backlog(&self, target: &str) -> String {
+        self.backlog
+            .lock()
+            .unwrap()
+            .get(target)
+            .map(|x| {
+                x.iter()
+                    .fold(String::from(""), |x, y| format!("{}{}\n", x, y))
+            })
+            .unwrap_or_default()
+    }
+
+    async fn update_channels(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.tx_ui
+            .send(UIEvent::UpdateChannels(self.channels()))
+            .await?;
+        Ok(())
+    }
+
+    async fn update_users(&self, target: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if !self.is_current(target) {
+            return Ok(());
+        }
+        let users = if self.is_room(target) {
+            self.occupants
+                .lock()
+                .unwrap()
+                .get(target)
+                .cloned()
+                .unwrap_or_default()
+                .into_iter()
+                .map(|x| (x.clone(), x))
+                .collect()
+        } else {
+            let presence = self
+                .presences
+                .lock()
+                .unwrap()
+                .get(target)
+                .cloned()
+                .unwrap_or_else(|| String::from("offline"));
+            vec![(
+                format!("{} ({})", self.contact_name(target), presence),
+                target.to_string(),
+            )]
+        };
+        self.tx_ui.send(UIEvent::UpdateUsersInRoom(users)).await?;
+        Ok(())
+    }
+
+    async fn load_history(&self, target: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let query_id = match self.is_room(target) {
+            true => self.writer.mam_query(Some(target), None).await?,
+            false => self.writer.mam_query(None, Some(target)).await?,
+        };
+        self.archives
+            .lock()
+            .unwrap()
+            .insert(query_id, (target.to_string(), vec![]));
+        Ok(())
+    }
+
+    async fn recv_message(&self, stanza: Element) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if let Some(result) = stanza.child_ns("result", NS_MAM) {
+            let query_id = result.attr("queryid").unwrap_or_default();
+            let forwarded = result.child("forwarded");
+            let archived = forwarded.and_then(|x| x.child("message"));
+            let stamp = forwarded
+                .and_then(|x| x.child("delay"))
+                .and_then(|x| x.attr("stamp"));
+            if let Some(archived) = archived.filter(|x| x.child("body").is_some()) {
+                let message = self.to_message(archived, result.attr("id"), stamp);
+                if let Some((_, messages)) = self.archives.lock().unwrap().get_mut(query_id) {
+                    messages.push(message);
+                }
+            }
+            return Ok(());
+        }
+        if stanza.child("body").is_none() {
+            return Ok(());
+        }
+        let from = stanza.attr("from").unwrap_or_default();
+        match stanza.attr("type") {
+            Some("error") => error!("XMPP message error: {}", stanza),
+            Some("groupchat") => {
+                let room = bare(from).to_string();
+                let message = self.to_message(&stanza, None, None);
+                if message.author != self.nick {
+                    let channel = Channel::Group(room.clone());
+                    let alert = Alert::new(channel, &room, &message, &self.nick);
+                    if let Err(err) = self.notifier.notify_message(&alert) {
+                        error!("Can't notify: {}", err);
+                    }
+                }
+                self.push_backlog(&room, message.clone());
+                self.add_message(message, &Channel::Group(room)).await?;
+            }
+            _ => {
+                let contact = bare(from).to_string();
+                let new_contact = !self.roster.lock().unwrap().contains_key(&contact);
+                if new_contact {
+                    self.roster
+                        .lock()
+                        .unwrap()
+                        .insert(contact.clone(), String::new());
+                    self.update_channels().await?;
+                }
+                let message = self.to_message(&stanza, None, None);
+                let channel = Channel::User(contact.clone());
+                let alert = Alert::new(channel, &contact, &message, &self.nick);
+                if let Err(err) = self.notifier.notify_message(&alert) {
+                    error!("Can't notify: {}", err);
+                }
+                self.push_backlog(&contact, message.clone());
+                self.add_message(message, &Channel::User(contact)).await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn update_roster(&self, query: &Element) {
+        let mut roster = self.roster.lock().unwrap();
+        for item in query.children().filter(|x| x.is("item")) {
+            let jid = item.attr("jid").unwrap_or_default().to_string();
+            if item.attr("subscription") == Some("remove") {
+                roster.remove(&jid);
+            } else {
+                roster.insert(jid, item.attr("name").unwrap_or_default().to_string());
+            }
+        }
+    }
+
+    async fn recv_iq(&self, stanza: Element) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let from = stanza.attr("from").unwrap_or_default();
+        let id = stanza.attr("id").unwrap_or_default();
+        let archive = self.archives.lock().unwrap().remove(id);
+        match (stanza.attr("type").unwrap_or_default(), archive) {
+            ("result", Some((target, messages))) => {
+                self.backlog
+                    .lock()
+                    .unwrap()
+                    .insert(target.clone(), messages);
+                if self.is_current(&target) {
+                    self.tx_ui
+                        .send(UIEvent::UpdateMessages(self.render_backlog(&target)))
+                        .await?;
+                }
+            }
+            ("error", Some((target, _))) => {
+                error!("Can't load the archive of {}: {}", target, stanza)
+            }
+            ("result", None) => {
+                if let Some(query) = stanza.child_ns("query", NS_ROSTER) {
+                    self.update_roster(query);
+                    self.update_channels().await?;
+                }
+            }
+            ("set", None) if stanza.child_ns("query", NS_ROSTER).is_some() => {
+                if let Some(query) = stanza.child_ns("query", NS_ROSTER) {
+                    self.update_roster(query);
+                }
+                self.writer.iq_result(from, id).await?;
+                self.update_channels().await?;
+            }
+            ("get", None) if stanza.child_ns("ping", NS_PING).is_some() => {
+                self.writer.iq_result(from, id).await?;
+            }
+            ("get", None) | ("set", None) => self.writer.iq_error(from, id).await?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    async fn recv_room_presence(
+        &self,
+        stanza: Element,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let from = stanza.attr("from").unwrap_or_default();
+        let room = bare(from).to_string();
+        let nick = resource(from).to_string();
+        let own = stanza
+            .child_ns("x", NS_MUC_USER)
+            .map(|x| {
+                x.children()
+                    .any(|x| x.is("status") && x.attr("code") == Some("110"))
+            })
+            .unwrap_or(false);
+        match (stanza.attr("type"), own) {
+            (Some("error"), _) => {
+                error!("Can't join {}: {}", room, stanza);
+                self.rooms.lock().unwrap().remove(&room);
+                self.update_channels().await?;
+            }
+            (Some("unavailable"), true) => {
+                self.rooms.lock().unwrap().remove(&room);
+                self.joined.lock().unwrap().remove(&room);
+                self.occupants.lock().unwrap().remove(&room);
+                self.update_channels().await?;
+            }
+            (Some("unavailable"), false) => {
+                if let Some(occupants) = self.occupants.lock().unwrap().get_mut(&room) {
+                    occupants.remove(&nick);
+                }
+                self.update_users(&room).await?;
+            }
+            (_, own) => {
+                self.occupants
+                    .lock()
+                    .unwrap()
+                    .entry(room.clone())
+                    .or_default()
+                    .insert(nick);
+                let joined = own && self.joined.lock().unwrap().insert(room.clone());
+                if joined && self.is_current(&room) {
+                    self.load_history(&room).await?;
+                }
+                self.update_users(&room).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn recv_presence(&self, stanza: Element) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let from = stanza.attr("from").unwrap_or_default();
+        if self.is_room(from) {
+            return self.recv_room_presence(stanza).await;
+        }
+        let contact = bare(from).to_string();
+        match stanza.attr("type") {
+            None => {
+                let show = stanza
+                    .child("show")
+                    .map(|x| x.text())
+                    .unwrap_or_else(|| String::from("online"));
+                self.presences.lock().unwrap().insert(contact.clone(), show);
+            }
+            Some("unavailable") => {
+                self.presences.lock().unwrap().remove(&contact);
+            }
+            _ => return Ok(()),
+        }
+        self.update_users(&contact).await
+    }
+
+    async fn wait_messages_loop(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        loop {
+            let stanza = self.rx_xmpp.recv().await?;
+            match &stanza.name[..] {
+                "message" => self.recv_message(stanza).await?,
+                "iq" => self.recv_iq(stanza).await?,
+                "presence" => self.recv_presence(stanza).await?,
+                "stream:error" => return Err(format!("XMPP stream error: {}", stanza).into()),
+                _ => {}
+            }
+        }
+    }
+
+    async fn ui_event_loop(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        loop {
+            match self.rx_chat.recv().await? {
+                ChatEvent::SendMessage(message, channel) => {
+                    let split = message.split(' ').collect::<Vec<&str>>();
+                    match split[0] {
+                        "/join" if split.len() > 1 => {
+                            let channel = Channel::Group(split[1].into());
+                            if let Err(err) = self.init_view(Some(channel)).await {
+                                self.tx_ui
+                                    .send(UIEvent::ShowInfo(format!("{}", err)))
+                                    .await?
+                            }
+                        }
+                        "/part" => {
+                            let target = format!("{}", channel);
+                            let room = split.get(1).unwrap_or(&&target[..]).to_string();
+                            self.writer.part(&room, &self.nick).await?
+                        }
+                        "/direct" if split.len() > 1 => {
+                            self.init_view(Some(Channel::User(split[1].into()))).await?
+                        }
+                        "/notify" => {
+                            let target = format!("{}", channel);
+                            let args = message["/notify".len()..].trim();
+                            let info = self
+                                .notifier
+                                .configure(&channel, &target, args)
+                                .unwrap_or_else(|err| err);
+                            self.tx_ui.send(UIEvent::ShowInfo(info)).await?
+                        }
+                        _ => self.send_message(message, channel).await?,
+                    }
+                }
+                ChatEvent::Init(channel) => {
+                    if let Err(err) = self.init_view(Some(channel)).await {
+                        self.tx_ui
+                            .send(UIEvent::ShowInfo(format!("{}", err)))
+                            .await?;
+                    }
+                }
+                ChatEvent::DirectChat(user) => {
+                    self.init_view(Some(Channel::User(user))).await?;
+                }
+            };
+        }
+    }
+}
+
+#[async_trait]
+impl Chat for Xmpp {
+    async fn init_view(
+        &self,
+        channel: Option<Channel>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if !self.autojoined.swap(true, Ordering::SeqCst) {
+            for room in self.autojoin.iter() {
+                self.rooms.lock().unwrap().insert(room.clone());
+                self.writer.join(room, &self.nick).await?;
+            }
+        }
+        let channel = match channel.or_else(|| self.channels().first().map(|x| x.1.clone())) {
+            Some(channel) => channel,
+            None => return self.update_channels().await,
+        };
+        let mut target = format!("{}", channel);
+        let is_group = !matches!(channel, Channel::User(_));
+        if !target.contains('@') {
+            // Not a jid, but maybe a room or contact name
+            match Channel::find(&self.channels(), &target) {
+                Some(channel) => target = format!("{}", channel),
+                None => return Err(format!("Unknown XMPP room or contact {}", target).into()),
+            }
+        } else if is_group && !self.is_room(&target) {
+            self.rooms.lock().unwrap().insert(target.clone());
+            self.writer.join(&target, &self.nick).await?;
+        } else if !is_group {
+            let known = self.roster.lock().unwrap().contains_key(&target);
+            if !known {
+                self.roster
+                    .lock()
+                    .unwrap()
+                    .insert(target.clone(), String::new());
+            }
+        }
+        let channel = match self.is_room(&target) {
+            true => Channel::Group(target.clone()),
+            false => Channel::User(target.clone()),
+        };
+        *self.current_channel.lock().unwrap() = Some(channel.clone());
+        self.update_channels().await?;
+        self.tx_ui
+            .send(UIEvent::UpdateMessages(self.render_backlog(&target)))
+            .await?;
+        self.update_users(&target).await?;
+        self.tx_ui.send(UIEvent::SelectChannel(channel)).await?;
+        let joined = self.joined.lock().unwrap().contains(&target);
+        if joined || !self.is_room(&target) {
+            self.load_history(&target).await?;
+        }
+        Ok(())
+    }
+
+    async fn send_message(
+        &self,
+        content: String,
+        channel: Channel,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let target = format!("{}", channel);
+        if self.is_room(&target) {
+            // The room sends our messages back like everyone else's.
+            return self.writer.message(&target, "groupchat", &content).await;
+        }
+        self.writer.message(&target, "chat", &content).await?;
+        let message = Message {
+            id: String::new(),
+            author: self.nick.clone(),
+            content,
+            datetime: Utc::now(),
+            thread: None,
+            attachments: vec![],
+        };
+        self.push_backlog(&target, message.clone());
+        self.add_message(message, &channel).await
+    }
+
+    async fn start_loop(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let read_loop = self.wait_messages_loop();
+        let ui_loop = self.ui_event_loop();
+        tokio::select! {
+            result = read_loop => result?,
+            _ = ui_loop => {},
+        }
+        Ok(())
+    }
+
+    async fn add_message(
+        &self,
+        message: Message,
+        channel: &Channel,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let current_channel = self.current_channel.lock().unwrap().clone();
+        if let Some(current) = current_channel.as_ref() {
+            if channel == current {
+                self.tx_ui.send(UIEvent::AddMessages(message)).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    type Script = Vec<(&'static str, Vec<String>)>;
+
+    const HEADER: &str = "<?xml version='1.0'?><stream:stream xmlns='jabber:client' xmlns:stream='http://etherx.jabber.org/streams' id='s1' from='xmpp.test' version='1.0'>";
+
+    /// A scripted XMPP server accepting a single client.
+    ///
+    /// Each stanza received is answered by the responses of the first rule
+    /// it contains, rules are used once and `{id}` is replaced by the id of
+    /// the stanza. Tests can also push stanzas at any time.
+    struct ScriptedServer {
+        url: Url,
+        received: Receiver<String>,
+        tx_server: Sender<String>,
+    }
+
+    impl ScriptedServer {
+        async fn start(mut script: Script) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = Url::parse(&format!("xmpp://{}", listener.local_addr().unwrap())).unwrap();
+            let (tx_received, received) = unbounded();
+            let (tx_server, rx_server) = unbounded::<String>();
+            tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (mut read, mut write) = tokio::io::split(stream);
+                let mut parser = XmlParser::default();
+                let mut buffer = [0; 4096];
+                loop {
+                    let responses = match parser.next_event().unwrap() {
+                        Some(event) => {
+                            let (stanza, id) = match event {
+                                XmlEvent::StreamOpen(open) => (format!("{}", open), None),
+                                XmlEvent::Stanza(stanza) => {
+                                    (format!("{}", stanza), stanza.attr("id").map(String::from))
+                                }
+                                XmlEvent::StreamClose => break,
+                            };
+                            let responses =
+                                match script.iter().position(|(x, _)| stanza.contains(x)) {
+                                    Some(rule) => script.remove(rule).1,
+                                    None => vec![],
+                                };
+                            tx_received.send(stanza).await.unwrap();
+                            responses
+                                .iter()
+                                .map(|x| x.replace("{id}", id.as_deref().unwrap_or_default()))
+                                .collect()
+                        }
+                        None => tokio::select! {
+                            read = read.read(&mut buffer) => match read {
+                                Ok(0) | Err(_) => break,
+                                Ok(read) => {
+                                    parser.feed(&buffer[..read]);
+                                    vec![]
+                                }
+                            },
+                            stanza = rx_server.recv() => vec![stanza.unwrap()],
+                        },
+                    };
+                    for response in responses {
+                        write.write_all(response.as_bytes()).await.unwrap();
+                    }
+                }
+            });
+            ScriptedServer {
+                url,
+                received,
+                tx_server,
+            }
+        }
+
+        async fn expect(&self, pattern: &str) -> String {
+            let wait = async {
+                loop {
+                    let stanza = self.received.recv().await.unwrap();
+                    if stanza.contains(pattern) {
+                        break stanza;
+                    }
+                }
+            };
+            tokio::time::timeout(Duration::from_secs(5), wait)
+                .await
+                .unwrap_or_else(|_| panic!("Client never sent {}", pattern))
+        }
+
+        async fn push(&self, stanza: &str) {
+            self.tx_server.send(stanza.into()).await.unwrap();
+        }
+    }
+
+    fn login() -> Script {
+        vec![
+            (
+                "<stream:stream",
+                vec![format!(
+                    "{}<stream:features><mechanisms xmlns='urn:ietf:params:xml:ns:xmpp-sasl'><mechanism>SCRAM-SHA-1</mechanism><mechanism>PLAIN</mechanism></mechanisms></stream:features>",
+                    HEADER
+                )],
+            ),
+            (
+                "AGxvdQBzZWNyZXQ=",
+                vec!["<success xmlns='urn:ietf:params:xml:ns:xmpp-sasl'/>".into()],
+            ),
+            (
+                "<stream:stream",
+                vec![format!(
+                    "{}<stream:features><bind xmlns='urn:ietf:params:xml:ns:xmpp-bind'/></stream:features>",
+                    HEADER
+                )],
+            ),
+            (
+                "urn:ietf:params:xml:ns:xmpp-bind",
+                vec!["<iq type='result' id='{id}'><bind xmlns='urn:ietf:params:xml:ns:xmpp-bind'><jid>lou@xmpp.test/talkoxid</jid></bind></iq>".into()],
+            ),
+            (
+                "jabber:iq:roster",
+                vec!["<iq type='result' id='{id}'><query xmlns='jabber:iq:roster'><item jid='alice@xmpp.test' name='Alice' subscription='both'/></query></iq>".into()],
+            ),
+            (
+                "to='ops@conference.xmpp.test/lou'",
+                vec![
+                    "<presence from='ops@conference.xmpp.test/bob'><x xmlns='http://jabber.org/protocol/muc#user'><item affiliation='owner' role='moderator'/></x></presence>".into(),
+                    "<presence from='ops@conference.xmpp.test/lou'><x xmlns='http://jabber.org/protocol/muc#user'><item affiliation='member' role='participant'/><status code='110'/></x></presence>".into(),
+                ],
+            ),
+            (
+                "urn:xmpp:mam:2",
+                vec![
+                    "<message to='lou@xmpp.test/talkoxid'><result xmlns='urn:xmpp:mam:2' queryid='{id}' id='a1'><forwarded xmlns='urn:xmpp:forward:0'><delay xmlns='urn:xmpp:delay' stamp='2020-07-01T07:49:10.164Z'/><message from='ops@conference.xmpp.test/bob' type='groupchat'><body>is the deploy done?</body></message></forwarded></result></message>".into(),
+                    "<message to='lou@xmpp.test/talkoxid'><result xmlns='urn:xmpp:mam:2' queryid='{id}' id='a2'><forwarded xmlns='urn:xmpp:forward:0'><delay xmlns='urn:xmpp:delay' stamp='2020-07-01T07:49:20.164Z'/><message from='ops@conference.xmpp.test/lou' type='groupchat'><body>yes</body></message></forwarded></result></message>".into(),
+                    "<iq type='result' id='{id}'><fin xmlns='urn:xmpp:mam:2' complete='true'/></iq>".into(),
+                ],
+            ),
+        ]
+    }
+
+    struct FakeNotifier;
+    impl Notification for FakeNotifier {
+        fn notify(&self, _title: &str, _content: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+            Ok(())
+        }
+    }
+
+    async fn create_chat_system(
+        script: Script,
+    ) -> Result<
+        (ScriptedServer, Xmpp, Receiver<UIEvent>, Sender<ChatEvent>),
+        Box<dyn Error + Send + Sync>,
+    > {
+        connect_chat_system(script, true).await
+    }
+
+    async fn connect_chat_system(
+        script: Script,
+        allow_plaintext: bool,
+    ) -> Result<
+        (ScriptedServer, Xmpp, Receiver<UIEvent>, Sender<ChatEvent>),
+        Box<dyn Error + Send + Sync>,
+    > {
+        let server = ScriptedServer::start(script).await;
+        let (tx_ui, rx_ui) = unbounded();
+        let (tx_chat, rx_chat) = unbounded();
+        let chat = Xmpp::new(
+            server.url.clone(),
+            "lou@xmpp.test".into(),
+            "secret".into(),
+            XmppTls {
+                config: TlsConfig::default(),
+                allow_plaintext,
+            },
+            tx_ui,
+            rx_chat,
+            Box::new(FakeNotifier {}),
+        )
+        .await?;
+        Ok((server, chat, rx_ui, tx_chat))
+    }
+
+    async fn recv_until<F>(rx_ui: &Receiver<UIEvent>, filter: F) -> UIEvent
+    where
+        F: Fn(&UIEvent) -> bool,
+    {
+        let wait = async {
+            loop {
+                let event = rx_ui.recv().await.unwrap();
+                if filter(&event) {
+                    break event;
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), wait)
+            .await
+            .expect("Expected UI event never received")
+    }
+
+    #[tokio::test]
+    async fn test_login() {
+        let (server, chat, _, _) = create_chat_system(login()).await.unwrap();
+        server.expect("AGxvdQBzZWNyZXQ=").await;
+        server.expect("jabber:iq:roster").await;
+        assert_eq!(chat.jid, "lou@xmpp.test/talkoxid");
+        assert_eq!(chat.nick, "lou");
+    }
+
+    #[tokio::test]
+    async fn test_login_without_starttls() {
+        let err = connect_chat_system(login(), false).await.err().unwrap();
+        assert_eq!(
+            format!("{}", err),
+            "The XMPP server doesn't offer STARTTLS, set allow_plaintext to log in anyway"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_login_failure() {
+        let mut script = login();
+        script[1].1 =
+            vec!["<failure xmlns='urn:ietf:params:xml:ns:xmpp-sasl'><not-authorized/><text>Wrong password</text></failure>".into()];
+        let err = create_chat_system(script).await.err().unwrap();
+        assert_eq!(
+            format!("{}", err),
+            "SASL authentication failed: not-authorized"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_init_view() {
+        let (server, chat, rx_ui, _) = create_chat_system(login()).await.unwrap();
+        let chat = chat.with_rooms(vec!["ops@conference.xmpp.test".into()]);
+        assert!(chat
+            .init_view(Some(Channel::Group("GENERAL".into())))
+            .await
+            .is_err());
+        chat.init_view(Some(Channel::Group("#ops".into())))
+            .await
+            .unwrap();
+        server.expect("to='ops@conference.xmpp.test/lou'").await;
+        let events = async {
+            let channels = recv_until(
+                &rx_ui,
+                |x| matches!(x, UIEvent::UpdateChannels(channels) if channels.len() == 2),
+            )
+            .await;
+            assert_eq!(
+                channels,
+                UIEvent::UpdateChannels(vec![
+                    (
+                        "ops".into(),
+                        Channel::Group("ops@conference.xmpp.test".into())
+                    ),
+                    ("Alice".into(), Channel::User("alice@xmpp.test".into())),
+                ])
+            );
+            let users = recv_until(
+                &rx_ui,
+                |x| matches!(x, UIEvent::UpdateUsersInRoom(users) if users.len() == 2),
+            )
+            .await;
+            assert_eq!(
+                users,
+                UIEvent::UpdateUsersInRoom(vec![
+                    ("bob".into(), "bob".into()),
+                    ("lou".into(), "lou".into())
+                ])
+            );
+            let history = recv_until(
+                &rx_ui,
+                |x| matches!(x, UIEvent::UpdateMessages(messages) if !messages.is_empty()),
+            )
+            .await;
+            let expected = [
+                Message {
+                    id: "a1".into(),
+                    author: "bob".into(),
+                    content: "is the deploy done?".into(),
+                    datetime: Utc.timestamp_millis(1593589750164),
+                    thread: None,
+                    attachments: vec![],
+                },
+                Message {
+                    id: "a2".into(),
+                    author: "lou".into(),
+                    content: "yes".into(),
+                    datetime: Utc.timestamp_millis(1593589760164),
+                    thread: None,
+                    attachments: vec![],
+                },
+            ];
+            assert_eq!(
+                history,
+                UIEvent::UpdateMessages(format!("{}\n{}\n", expected[0], expected[1]))
+            );
+        };
+        tokio::select! {
+            _ = chat.wait_messages_loop() => panic!("Abnormal"),
+            _ = events => {},
+        }
+    }
+
+    #[tokio::test]
+    async fn test_recv_message() {
+        let (server, chat, rx_ui, _) = create_chat_system(login()).await.unwrap();
+        chat.rooms
+            .lock()
+            .unwrap()
+            .insert("ops@conference.xmpp.test".into());
+        *chat.current_channel.lock().unwrap() =
+            Some(Channel::Group("ops@conference.xmpp.test".into()));
+        server
+            .push("<message from='ops@conference.xmpp.test/bob' type='groupchat' id='g1'><body>lou: ping</body></message>")
+            .await;
+        server
+            .push("<message from='carol@xmpp.test/phone' type='chat' id='c1'><active xmlns='http://jabber.org/protocol/chatstates'/><body>hi lou</body></message>")
+            .await;
+        let events = async {
+            let message = recv_until(&rx_ui, |x| matches!(x, UIEvent::AddMessages(_))).await;
+            match message {
+                UIEvent::AddMessages(message) => {
+                    assert_eq!(message.author, "bob");
+                    assert_eq!(message.content, "lou: ping");
+                }
+                _ => unreachable!(),
+            }
+            let channels = recv_until(
+                &rx_ui,
+                |x| matches!(x, UIEvent::UpdateChannels(channels) if channels.len() == 3),
+            )
+            .await;
+            assert_eq!(
+                channels,
+                UIEvent::UpdateChannels(vec![
+                    (
+                        "ops".into(),
+                        Channel::Group("ops@conference.xmpp.test".into())
+                    ),
+                    ("Alice".into(), Channel::User("alice@xmpp.test".into())),
+                    ("carol".into(), Channel::User("carol@xmpp.test".into())),
+                ])
+            );
+        };
+        tokio::select! {
+            _ = chat.wait_messages_loop() => panic!("Abnormal"),
+            _ = events => {},
+        }
+        assert_eq!(chat.render_backlog("carol@xmpp.test").lines().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_send_message() {
+        let (server, chat, rx_ui, _) = create_chat_system(login()).await.unwrap();
+        *chat.current_channel.lock().unwrap() = Some(Channel::User("alice@xmpp.test".into()));
+        chat.send_message("hello".into(), Channel::User("alice@xmpp.test".into()))
+            .await
+            .unwrap();
+        assert_eq!(
+            server.expect("<message").await,
+            "<message to='alice@xmpp.test' type='chat' id='msg0'><body>hello</body></message>"
+        );
+        match rx_ui.recv().await.unwrap() {
+            UIEvent::AddMessages(message) => {
+                assert_eq!(message.author, "lou");
+                assert_eq!(message.content, "hello");
+            }
+            event => panic!("Unexpected event {:?}", event),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ping() {
+        let (server, chat, _rx_ui, _) = create_chat_system(login()).await.unwrap();
+        server
+            .push("<iq from='xmpp.test' id='p1' type='get'><ping xmlns='urn:xmpp:ping'/></iq>")
+            .await;
+        tokio::select! {
+            _ = chat.wait_messages_loop() => panic!("Abnormal"),
+            stanza = server.expect("id='p1'") => {
+                assert_eq!(stanza, "<iq to='xmpp.test' id='p1' type='result'/>")
+            }
+        }
+    }
+}
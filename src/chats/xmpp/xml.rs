@@ -0,0 +1,377 @@
+use std::error::Error;
+use std::fmt;
+
+/// A node of an XML element.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node {
+    Element(Element),
+    Text(String),
+}
+
+/// An XML element, names keep their prefix like `stream:features`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Element {
+    pub name: String,
+    pub attrs: Vec<(String, String)>,
+    pub nodes: Vec<Node>,
+}
+
+fn local_name(name: &str) -> &str {
+    name.rsplit(':').next().unwrap_or(name)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('\'', "&apos;")
+        .replace('"', "&quot;")
+}
+
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        unescaped.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = match rest.find(';') {
+            Some(end) => end,
+            None => break,
+        };
+        let entity = &rest[1..end];
+        let c = match entity {
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "amp" => Some('&'),
+            "apos" => Some('\''),
+            "quot" => Some('"'),
+            _ if entity.starts_with("#x") => u32::from_str_radix(&entity[2..], 16)
+                .ok()
+                .and_then(char::from_u32),
+            _ if entity.starts_with('#') => entity[1..].parse().ok().and_then(char::from_u32),
+            _ => None,
+        };
+        match c {
+            Some(c) => unescaped.push(c),
+            None => unescaped.push_str(&rest[..=end]),
+        }
+        rest = &rest[end + 1..];
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+impl Element {
+    pub fn new(name: &str) -> Self {
+        Element {
+            name: name.into(),
+            attrs: vec![],
+            nodes: vec![],
+        }
+    }
+
+    pub fn with_attr(mut self, name: &str, value: &str) -> Self {
+        self.attrs.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn with_child(mut self, child: Element) -> Self {
+        self.nodes.push(Node::Element(child));
+        self
+    }
+
+    pub fn with_text(mut self, text: &str) -> Self {
+        self.nodes.push(Node::Text(text.into()));
+        self
+    }
+
+    pub fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs
+            .iter()
+            .find(|(x, _)| x == name)
+            .map(|(_, value)| &value[..])
+    }
+
+    /// Whether the local name is `name`, prefixes are ignored.
+    pub fn is(&self, name: &str) -> bool {
+        local_name(&self.name) == name
+    }
+
+    pub fn children(&self) -> impl Iterator<Item = &Element> {
+        self.nodes.iter().filter_map(|x| match x {
+            Node::Element(element) => Some(element),
+            Node::Text(_) => None,
+        })
+    }
+
+    pub fn child(&self, name: &str) -> Option<&Element> {
+        self.children().find(|x| x.is(name))
+    }
+
+    /// The first child with this name and namespace.
+    pub fn child_ns(&self, name: &str, xmlns: &str) -> Option<&Element> {
+        self.children()
+            .find(|x| x.is(name) && x.attr("xmlns") == Some(xmlns))
+    }
+
+    pub fn text(&self) -> String {
+        self.nodes
+            .iter()
+            .map(|x| match x {
+                Node::Text(text) => text.clone(),
+                Node::Element(element) => element.text(),
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for Element {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<{}", self.name)?;
+        for (name, value) in self.attrs.iter() {
+            write!(f, " {}='{}'", name, escape(value))?;
+        }
+        if self.nodes.is_empty() {
+            return write!(f, "/>");
+        }
+        write!(f, ">")?;
+        for node in self.nodes.iter() {
+            match node {
+                Node::Element(element) => write!(f, "{}", element)?,
+                Node::Text(text) => write!(f, "{}", escape(text))?,
+            }
+        }
+        write!(f, "</{}>", self.name)
+    }
+}
+
+/// An event of an XMPP stream.
+#[derive(Debug, Clone, PartialEq)]
+pub enum XmlEvent {
+    /// The `<stream:stream>` header, sent again when the stream restarts.
+    StreamOpen(Element),
+    /// A complete top level element.
+    Stanza(Element),
+    /// The `</stream:stream>` footer.
+    StreamClose,
+}
+
+type ParseResult<T> = Result<Option<(T, usize)>, Box<dyn Error + Send + Sync>>;
+
+fn find(buffer: &[u8], pos: usize, pattern: &[u8]) -> Option<usize> {
+    buffer[pos..]
+        .windows(pattern.len())
+        .position(|x| x == pattern)
+        .map(|x| x + pos)
+}
+
+fn text(buffer: &[u8]) -> Result<String, Box<dyn Error + Send + Sync>> {
+    Ok(unescape(std::str::from_utf8(buffer)?))
+}
+
+/// Parse a start tag at `pos`, return the element and whether it is self-closing.
+fn parse_start_tag(buffer: &[u8], pos: usize) -> ParseResult<(Element, bool)> {
+    let end = match find(buffer, pos, b">") {
+        Some(end) => end,
+        None => return Ok(None),
+    };
+    let mut tag = &buffer[pos + 1..end];
+    let self_closing = tag.ends_with(b"/");
+    if self_closing {
+        tag = &tag[..tag.len() - 1];
+    }
+    let tag = std::str::from_utf8(tag)?;
+    let name_end = tag.find(char::is_whitespace).unwrap_or(tag.len());
+    let mut element = Element::new(&tag[..name_end]);
+    let mut rest = tag[name_end..].trim_start();
+    while !rest.is_empty() {
+        let (name, value) = rest.split_once('=').ok_or("Invalid XML attribute")?;
+        let value = value.trim_start();
+        let quote = value.chars().next().ok_or("Invalid XML attribute")?;
+        let value_end = value[1..].find(quote).ok_or("Invalid XML attribute")? + 1;
+        element
+            .attrs
+            .push((name.trim().into(), unescape(&value[1..value_end])));
+        rest = value[value_end + 1..].trim_start();
+    }
+    Ok(Some(((element, self_closing), end + 1)))
+}
+
+fn parse_element(buffer: &[u8], pos: usize) -> ParseResult<Element> {
+    let ((mut element, self_closing), mut pos) = match parse_start_tag(buffer, pos)? {
+        Some(tag) => tag,
+        None => return Ok(None),
+    };
+    if self_closing {
+        return Ok(Some((element, pos)));
+    }
+    loop {
+        let rest = &buffer[pos..];
+        if rest.is_empty() {
+            return Ok(None);
+        } else if rest.starts_with(b"</") {
+            return match find(buffer, pos, b">") {
+                Some(end) => Ok(Some((element, end + 1))),
+                None => Ok(None),
+            };
+        } else if rest.starts_with(b"<!--") {
+            match find(buffer, pos, b"-->") {
+                Some(end) => pos = end + 3,
+                None => return Ok(None),
+            }
+        } else if rest.starts_with(b"<![CDATA[") {
+            match find(buffer, pos, b"]]>") {
+                Some(end) => {
+                    let cdata = std::str::from_utf8(&buffer[pos + 9..end])?;
+                    element.nodes.push(Node::Text(cdata.into()));
+                    pos = end + 3;
+                }
+                None => return Ok(None),
+            }
+        } else if rest.starts_with(b"<") {
+            match parse_element(buffer, pos)? {
+                Some((child, end)) => {
+                    element.nodes.push(Node::Element(child));
+                    pos = end;
+                }
+                None => return Ok(None),
+            }
+        } else {
+            let end = match find(buffer, pos, b"<") {
+                Some(end) => end,
+                None => return Ok(None),
+            };
+            element.nodes.push(Node::Text(text(&buffer[pos..end])?));
+            pos = end;
+        }
+    }
+}
+
+/// Incremental parser of an XMPP stream.
+///
+/// Bytes are fed as they are read, events come out once complete.
+#[derive(Default)]
+pub struct XmlParser {
+    buffer: Vec<u8>,
+}
+
+impl XmlParser {
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    pub fn next_event(&mut self) -> Result<Option<XmlEvent>, Box<dyn Error + Send + Sync>> {
+        loop {
+            let start = match self.buffer.iter().position(|x| !x.is_ascii_whitespace()) {
+                Some(start) => start,
+                None => {
+                    self.buffer.clear();
+                    return Ok(None);
+                }
+            };
+            let rest = &self.buffer[start..];
+            let (event, end) = if rest.starts_with(b"<?") || rest.starts_with(b"<!--") {
+                let pattern: &[u8] = if rest.starts_with(b"<?") {
+                    b"?>"
+                } else {
+                    b"-->"
+                };
+                match find(&self.buffer, start, pattern) {
+                    Some(end) => (None, end + pattern.len()),
+                    None => return Ok(None),
+                }
+            } else if rest.starts_with(b"</") {
+                match find(&self.buffer, start, b">") {
+                    Some(end) => (Some(XmlEvent::StreamClose), end + 1),
+                    None => return Ok(None),
+                }
+            } else if rest.starts_with(b"<") {
+                match parse_start_tag(&self.buffer, start)? {
+                    Some(((element, false), end)) if element.is("stream") => {
+                        (Some(XmlEvent::StreamOpen(element)), end)
+                    }
+                    Some(_) => match parse_element(&self.buffer, start)? {
+                        Some((element, end)) => (Some(XmlEvent::Stanza(element)), end),
+                        None => return Ok(None),
+                    },
+                    None => return Ok(None),
+                }
+            } else {
+                return Err("Unexpected text in the XMPP stream".into());
+            };
+            self.buffer.drain(..end);
+            if event.is_some() {
+                return Ok(event);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_stream() {
+        let mut parser = XmlParser::default();
+        parser.feed(b"<?xml version='1.0'?><stream:stream xmlns='jabber:client' from='xmpp.test'>");
+        parser.feed(b"<stream:features><mechanisms xmlns='urn:ietf:params:xml:ns:xmpp-sasl'>");
+        match parser.next_event().unwrap() {
+            Some(XmlEvent::StreamOpen(open)) => assert_eq!(open.attr("from"), Some("xmpp.test")),
+            event => panic!("Unexpected event {:?}", event),
+        }
+        assert_eq!(parser.next_event().unwrap(), None);
+        parser.feed("<mechanism>PLAIN</mechanism></mechanisms></stream:features> ".as_bytes());
+        match parser.next_event().unwrap() {
+            Some(XmlEvent::Stanza(features)) => {
+                assert!(features.is("features"));
+                let mechanisms = features
+                    .child_ns("mechanisms", "urn:ietf:params:xml:ns:xmpp-sasl")
+                    .unwrap();
+                assert_eq!(mechanisms.child("mechanism").unwrap().text(), "PLAIN");
+            }
+            event => panic!("Unexpected event {:?}", event),
+        }
+        parser.feed(b"</stream:stream>");
+        assert_eq!(parser.next_event().unwrap(), Some(XmlEvent::StreamClose));
+    }
+
+    #[test]
+    fn test_parse_split_utf8() {
+        let stanza =
+            "<message from='bob@xmpp.test'><body>caf\u{e9} &amp; &lt;3 &#x2615;</body></message>";
+        let bytes = stanza.as_bytes();
+        let split = stanza.find('\u{e9}').unwrap() + 1;
+        let mut parser = XmlParser::default();
+        parser.feed(&bytes[..split]);
+        assert_eq!(parser.next_event().unwrap(), None);
+        parser.feed(&bytes[split..]);
+        match parser.next_event().unwrap() {
+            Some(XmlEvent::Stanza(message)) => {
+                assert_eq!(
+                    message.child("body").unwrap().text(),
+                    "caf\u{e9} & <3 \u{2615}"
+                )
+            }
+            event => panic!("Unexpected event {:?}", event),
+        }
+    }
+
+    #[test]
+    fn test_serialize() {
+        let message = Element::new("message")
+            .with_attr("to", "ops@conference.xmpp.test")
+            .with_attr("type", "groupchat")
+            .with_child(Element::new("body").with_text("it's <done> & ok"));
+        assert_eq!(
+            format!("{}", message),
+            "<message to='ops@conference.xmpp.test' type='groupchat'><body>it&apos;s &lt;done&gt; &amp; ok</body></message>"
+        );
+        let mut parser = XmlParser::default();
+        parser.feed(format!("{}", message).as_bytes());
+        assert_eq!(
+            parser.next_event().unwrap(),
+            Some(XmlEvent::Stanza(message))
+        );
+    }
+}
@@ -4,21 +4,48 @@
 use async_trait::async_trait;
 
 use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::export::ExportOptions;
 
 use std::error::Error;
 use std::fmt;
+use std::path::PathBuf;
+
+/// Account identifier.
+///
+/// Each configured account runs its own chat system, events
+/// exchanged with the UI are tagged with this identifier.
+pub type AccountId = String;
+
+/// Attachment representation.
+///
+/// This type represent a file or a link attached to a message.
+#[derive(Eq, PartialEq, PartialOrd, Clone, Debug)]
+pub struct Attachment {
+    /// The attachment's title.
+    pub title: String,
+    /// The absolute link to the attachment.
+    pub link: String,
+}
 
 /// Message representation.
 ///
 /// This type represent a message in a chat.
 #[derive(Eq, PartialEq, PartialOrd, Clone, Debug)]
 pub struct Message {
+    /// The message's unique id in the chat.
+    pub id: String,
     /// The message's author.
     pub author: String,
     /// The content of the message.
     pub content: String,
     /// The date and time of when the message was sent.
     pub datetime: DateTime<Utc>,
+    /// The id of the thread's first message if this message is a reply.
+    pub thread: Option<String>,
+    /// The files and links attached to the message.
+    pub attachments: Vec<Attachment>,
 }
 
 impl fmt::Display for Message {
@@ -46,8 +73,8 @@ impl fmt::Display for Message {
 }
 
 #[derive(Debug)]
-struct UIError {
-    source: String,
+pub(crate) struct UIError {
+    pub(crate) source: String,
 }
 
 impl fmt::Display for UIError {
@@ -63,7 +90,8 @@ impl Error for UIError {}
 /// This type represent a channel in a chat.
 ///
 /// A channel is a place where user can send message to.
-#[derive(Eq, PartialEq, PartialOrd, Clone, Debug)]
+#[derive(Eq, PartialEq, Clone, Debug, Hash, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "id", rename_all = "lowercase")]
 pub enum Channel {
     /// A public group channel.
     Group(String),
@@ -99,6 +127,23 @@ impl Ord for Channel {
     }
 }
 
+impl PartialOrd for Channel {
+    fn partial_cmp(&self, b: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(b))
+    }
+}
+
+impl Channel {
+    /// The channel named `name`, `#name` or with the id `name`.
+    pub fn find<'a>(channels: &'a [(String, Channel)], name: &str) -> Option<&'a Channel> {
+        let name = name.trim_start_matches('#');
+        channels
+            .iter()
+            .find(|(repr, channel)| repr == name || format!("{}", channel) == name)
+            .map(|(_, channel)| channel)
+    }
+}
+
 /// Events sent to the chat system.
 ///
 /// This enum represent all the events that a chat system
@@ -175,6 +220,8 @@ pub enum UIEvent {
     SelectChannel(Channel),
     /// Used when a fatal error occurred and need to be displayed.
     ShowFatalError(String),
+    /// Used when an information need to be displayed to the User.
+    ShowInfo(String),
 }
 
 /// Chat system trait
@@ -182,7 +229,10 @@ pub enum UIEvent {
 /// All chat backends should implement this trait.
 #[async_trait]
 pub trait Chat {
-    async fn init_view(&self, channel: Channel) -> Result<(), Box<dyn Error + Send + Sync>>;
+    /// Open `channel`, a channel id or name, or the first channel of the
+    /// chat when none is given.
+    async fn init_view(&self, channel: Option<Channel>)
+        -> Result<(), Box<dyn Error + Send + Sync>>;
     async fn send_message(
         &self,
         content: String,
@@ -195,6 +245,17 @@ pub trait Chat {
     ) -> Result<(), Box<dyn Error + Send + Sync>>;
     /// Start the main loop that listen to [ChatEvent](enum.ChatEvent.html)
     async fn start_loop(&self) -> Result<(), Box<dyn Error + Send + Sync>>;
+    /// Export the history of a channel to a file and return its path.
+    ///
+    /// The channel is looked up by name. Backends that can't export
+    /// return an error.
+    async fn export(
+        &self,
+        _channel_name: String,
+        _options: ExportOptions,
+    ) -> Result<PathBuf, Box<dyn Error + Send + Sync>> {
+        Err("Export is not supported by this chat".into())
+    }
 }
 
 /// User Interface trait.
@@ -204,6 +265,7 @@ pub trait UI {
     fn update_messages(&self, content: String) -> Result<(), Box<dyn Error + Send + Sync>>;
     fn update_channels(
         &self,
+        account: AccountId,
         channels: Vec<(String, Channel)>,
     ) -> Result<(), Box<dyn Error + Send + Sync>>;
     fn update_users_in_room(
@@ -211,12 +273,127 @@ pub trait UI {
         users: Vec<(String, String)>,
     ) -> Result<(), Box<dyn Error + Send + Sync>>;
     fn add_message(&self, message: Message) -> Result<(), Box<dyn Error + Send + Sync>>;
-    fn select_channel(&self, channel: Channel) -> Result<(), Box<dyn Error + Send + Sync>>;
+    fn select_channel(
+        &self,
+        account: AccountId,
+        channel: Channel,
+    ) -> Result<(), Box<dyn Error + Send + Sync>>;
     fn show_fatal_error(&self, content: String) -> Result<(), Box<dyn Error + Send + Sync>>;
+    fn show_info(&self, content: String) -> Result<(), Box<dyn Error + Send + Sync>>;
     /// Start the main loop that listen to [UIEvent](enum.UIEvent.html)
+    /// tagged with their [AccountId](type.AccountId.html)
     fn start_loop(&self) -> Result<(), Box<dyn Error + Send + Sync>>;
 }
 
+/// Which messages of a channel are notified.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum NotificationLevel {
+    All,
+    /// Messages containing our username or a keyword.
+    #[default]
+    Mentions,
+    None,
+}
+
+impl fmt::Display for NotificationLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NotificationLevel::All => write!(f, "all"),
+            NotificationLevel::Mentions => write!(f, "mentions"),
+            NotificationLevel::None => write!(f, "none"),
+        }
+    }
+}
+
+impl std::str::FromStr for NotificationLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(NotificationLevel::All),
+            "mentions" => Ok(NotificationLevel::Mentions),
+            "none" => Ok(NotificationLevel::None),
+            _ => Err(format!(
+                "Invalid notification level {}, expected all, mentions or none",
+                s
+            )),
+        }
+    }
+}
+
+/// A received message which may be notified.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Alert {
+    pub channel: Channel,
+    /// The channel name, or its id when the name is unknown.
+    pub channel_name: String,
+    pub author: String,
+    pub content: String,
+    pub datetime: DateTime<Utc>,
+    /// Whether the message contains our username.
+    pub mention: bool,
+    /// Whether a keyword of the notification rules matched.
+    pub highlight: bool,
+    /// The level chosen on the server for the channel, if any.
+    pub server_level: Option<NotificationLevel>,
+}
+
+impl Alert {
+    pub fn new(channel: Channel, channel_name: &str, message: &Message, username: &str) -> Self {
+        Alert {
+            channel,
+            channel_name: channel_name.to_string(),
+            author: message.author.clone(),
+            content: message.content.clone(),
+            datetime: message.datetime,
+            mention: !username.is_empty() && message.content.contains(username),
+            highlight: false,
+            server_level: None,
+        }
+    }
+}
+
 pub trait Notification {
     fn notify(&self, title: &str, content: &str) -> Result<(), Box<dyn Error + Send + Sync>>;
+
+    /// Notify a message received from someone else.
+    ///
+    /// Every message is notified by default, wrap the notifier in a
+    /// [RuleNotifier](../notifications/struct.RuleNotifier.html) to filter them.
+    fn notify_message(&self, alert: &Alert) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.notify(&alert.author, &alert.content)
+    }
+
+    /// Run a `/notify` command typed in a channel, the answer is shown to the user.
+    fn configure(
+        &self,
+        _channel: &Channel,
+        _channel_name: &str,
+        _args: &str,
+    ) -> Result<String, String> {
+        Err("Notification rules aren't enabled".into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_channel() {
+        let channels = vec![
+            ("general".to_string(), Channel::Group("GENERAL".into())),
+            ("support".to_string(), Channel::Group("a1b2".into())),
+        ];
+        assert_eq!(
+            Channel::find(&channels, "#support"),
+            Some(&Channel::Group("a1b2".into()))
+        );
+        assert_eq!(
+            Channel::find(&channels, "GENERAL"),
+            Some(&Channel::Group("GENERAL".into()))
+        );
+        assert_eq!(Channel::find(&channels, "random"), None);
+    }
 }
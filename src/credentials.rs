@@ -0,0 +1,325 @@
+//! Credentials module.
+//!
+//! This module contains the ways to get a password without writing it in
+//! the configuration file: a command printing it, like a password manager,
+//! or a credentials file encrypted with a passphrase.
+use chacha20poly1305::aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const MAGIC: &[u8] = b"TALKOXID-CREDENTIALS-1";
+const SALT_SIZE: usize = 16;
+const NONCE_SIZE: usize = 12;
+/// PBKDF2 rounds of the files we write, the count is stored in the file.
+#[cfg(not(test))]
+pub const ITERATIONS: u32 = 600_000;
+/// Unit tests don't need a slow key derivation.
+#[cfg(test)]
+pub const ITERATIONS: u32 = 1_000;
+/// Most PBKDF2 rounds a file can ask for, a corrupted count would hang.
+const MAX_ITERATIONS: u32 = 10_000_000;
+
+/// Run a password command with `sh -c`, the password is its output without
+/// the trailing line breaks.
+pub fn run_password_command(command: &str) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .output()
+        .map_err(|err| format!("Can't run password_command `{}`: {}", command, err))?;
+    if !output.status.success() {
+        return Err(format!(
+            "password_command `{}` failed with {}: {}",
+            command,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    let password = String::from_utf8(output.stdout)
+        .map_err(|_| format!("password_command `{}` printed invalid UTF-8", command))?;
+    let password = password.trim_end_matches('\n');
+    if password.is_empty() {
+        return Err(format!("password_command `{}` printed nothing", command).into());
+    }
+    Ok(password.to_string())
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], iterations: u32) -> Key {
+    let mut key = Key::default();
+    pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, iterations, &mut key);
+    key
+}
+
+/// Encrypt `plaintext` with a key derived from `passphrase`.
+///
+/// The file starts with a magic string, the PBKDF2 rounds, the salt and
+/// the nonce, the header is authenticated along with the ChaCha20-Poly1305
+/// ciphertext.
+pub fn encrypt(
+    plaintext: &[u8],
+    passphrase: &str,
+    iterations: u32,
+) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let mut salt = [0u8; SALT_SIZE];
+    OsRng.fill_bytes(&mut salt);
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let mut data = MAGIC.to_vec();
+    data.extend_from_slice(&iterations.to_be_bytes());
+    data.extend_from_slice(&salt);
+    let cipher = ChaCha20Poly1305::new(&derive_key(passphrase, &salt, iterations));
+    let ciphertext = cipher
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: plaintext,
+                aad: &data,
+            },
+        )
+        .map_err(|_| "Can't encrypt the credentials")?;
+    data.extend_from_slice(&nonce);
+    data.extend_from_slice(&ciphertext);
+    Ok(data)
+}
+
+/// Decrypt the output of [encrypt](fn.encrypt.html).
+pub fn decrypt(data: &[u8], passphrase: &str) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let header_size = MAGIC.len() + 4 + SALT_SIZE;
+    if !data.starts_with(MAGIC) || data.len() < header_size + NONCE_SIZE {
+        return Err("Not a talkoxid credentials file".into());
+    }
+    let (header, rest) = data.split_at(header_size);
+    let mut iterations = [0u8; 4];
+    iterations.copy_from_slice(&header[MAGIC.len()..MAGIC.len() + 4]);
+    let iterations = u32::from_be_bytes(iterations);
+    if iterations == 0 || iterations > MAX_ITERATIONS {
+        return Err(format!(
+            "Invalid credentials file: {} PBKDF2 rounds, expected at most {}",
+            iterations, MAX_ITERATIONS
+        )
+        .into());
+    }
+    let salt = &header[MAGIC.len() + 4..];
+    let (nonce, ciphertext) = rest.split_at(NONCE_SIZE);
+    let cipher = ChaCha20Poly1305::new(&derive_key(passphrase, salt, iterations));
+    cipher
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: header,
+            },
+        )
+        .map_err(|_| "Wrong passphrase or corrupted credentials file".into())
+}
+
+/// Write a file only the user can read.
+///
+/// The content goes to a temporary file of the same directory, renamed over
+/// `path` once on disk, so that a crash leaves the old file or the new one.
+pub fn write_private(path: &Path, content: &[u8]) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    let tmp_path = path.with_file_name(name);
+    let write = || -> std::io::Result<()> {
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&tmp_path)?;
+        // The mode only applies to a new file
+        #[cfg(unix)]
+        file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+        file.write_all(content)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, path)
+    };
+    write().map_err(|err| {
+        std::fs::remove_file(&tmp_path).ok();
+        format!("Can't write {}: {}", path.display(), err).into()
+    })
+}
+
+/// The default credentials file, `~/.config/talkoxid/credentials.enc`.
+pub fn default_path() -> PathBuf {
+    let mut path = dirs_next::config_dir().unwrap_or_else(|| PathBuf::from("."));
+    path.push("talkoxid");
+    path.push("credentials.enc");
+    path
+}
+
+/// Ask a passphrase on the terminal.
+pub fn prompt_passphrase(prompt: &str) -> std::io::Result<String> {
+    rpassword::prompt_password(prompt)
+}
+
+/// Encrypted credentials file.
+///
+/// This type maps account names to passwords. The file is decrypted the
+/// first time a password is needed, the passphrase comes from `prompt`.
+pub struct Credentials<'a> {
+    path: PathBuf,
+    prompt: &'a dyn Fn(&Path) -> std::io::Result<String>,
+    passwords: Option<BTreeMap<String, String>>,
+}
+
+impl<'a> Credentials<'a> {
+    pub fn new(path: PathBuf, prompt: &'a dyn Fn(&Path) -> std::io::Result<String>) -> Self {
+        Credentials {
+            path,
+            prompt,
+            passwords: None,
+        }
+    }
+
+    /// Decrypt the file, a missing file has no passwords.
+    fn unlock(&mut self) -> Result<&mut BTreeMap<String, String>, Box<dyn Error + Send + Sync>> {
+        if self.passwords.is_none() {
+            let passwords = if self.path.exists() {
+                let data = std::fs::read(&self.path)
+                    .map_err(|err| format!("Can't read {}: {}", self.path.display(), err))?;
+                let passphrase = (self.prompt)(&self.path)?;
+                let plaintext = decrypt(&data, &passphrase)
+                    .map_err(|err| format!("{}: {}", self.path.display(), err))?;
+                toml::from_slice(&plaintext)?
+            } else {
+                BTreeMap::new()
+            };
+            self.passwords = Some(passwords);
+        }
+        Ok(self.passwords.as_mut().unwrap())
+    }
+
+    /// The password of an account.
+    pub fn password(
+        &mut self,
+        account: &str,
+    ) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        if self.passwords.is_none() && !self.path.exists() {
+            return Ok(None);
+        }
+        Ok(self.unlock()?.get(account).cloned())
+    }
+
+    /// Store the password of an account, the file is encrypted with `passphrase`.
+    pub fn set_password(
+        &mut self,
+        account: &str,
+        password: &str,
+        passphrase: &str,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let passwords = self.unlock()?;
+        passwords.insert(account.to_string(), password.to_string());
+        let plaintext = toml::to_string(passwords)?;
+        if let Some(directory) = self.path.parent() {
+            std::fs::create_dir_all(directory)?;
+        }
+        write_private(
+            &self.path,
+            &encrypt(plaintext.as_bytes(), passphrase, ITERATIONS)?,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_password_command() {
+        assert_eq!(
+            run_password_command("printf '  hunter2 \\n\\n'").unwrap(),
+            "  hunter2 "
+        );
+        let err = run_password_command("echo locked >&2; exit 3")
+            .err()
+            .unwrap();
+        assert!(format!("{}", err).ends_with(": locked"));
+        let err = run_password_command("true").err().unwrap();
+        assert_eq!(
+            format!("{}", err),
+            "password_command `true` printed nothing"
+        );
+    }
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let data = encrypt(b"lou = \"secret\"", "correct horse", 10).unwrap();
+        assert!(data.starts_with(MAGIC));
+        assert!(!data.windows(6).any(|x| x == b"secret"));
+        assert_eq!(
+            decrypt(&data, "correct horse").unwrap(),
+            b"lou = \"secret\"".to_vec()
+        );
+        assert!(decrypt(&data, "wrong horse").is_err());
+        let mut tampered = data.clone();
+        tampered[MAGIC.len() + 3] ^= 1;
+        assert!(decrypt(&tampered, "correct horse").is_err());
+        assert!(decrypt(b"lou = \"secret\"", "correct horse").is_err());
+        let mut slow = data;
+        slow[MAGIC.len()..MAGIC.len() + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+        let err = decrypt(&slow, "correct horse").err().unwrap();
+        assert_eq!(
+            format!("{}", err),
+            "Invalid credentials file: 4294967295 PBKDF2 rounds, expected at most 10000000"
+        );
+    }
+
+    #[test]
+    fn test_write_private() {
+        let path = std::env::temp_dir().join("talkoxid_test_write_private");
+        std::fs::write(&path, "old").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        }
+        write_private(&path, b"new").unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::metadata(&path).unwrap().permissions().mode() & 0o777
+        };
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(content, "new");
+        assert!(!path
+            .with_file_name("talkoxid_test_write_private.tmp")
+            .exists());
+        #[cfg(unix)]
+        assert_eq!(mode, 0o600);
+    }
+
+    #[test]
+    fn test_credentials_file() {
+        let path = std::env::temp_dir().join("talkoxid_test_credentials_file.enc");
+        let passwords = "community = \"secret\"\n\"lou@chat.test\" = \"hunter2\"\n";
+        write_private(
+            &path,
+            &encrypt(passwords.as_bytes(), "passphrase", 10).unwrap(),
+        )
+        .unwrap();
+        let prompt = |_: &Path| Ok("passphrase".to_string());
+        let mut credentials = Credentials::new(path.clone(), &prompt);
+        assert_eq!(
+            credentials.password("lou@chat.test").unwrap(),
+            Some("hunter2".into())
+        );
+        assert_eq!(credentials.password("other").unwrap(), None);
+        let wrong = |_: &Path| Ok("wrong".to_string());
+        let err = Credentials::new(path.clone(), &wrong)
+            .password("community")
+            .err()
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(format!("{}", err).ends_with("Wrong passphrase or corrupted credentials file"));
+        let missing = |_: &Path| -> std::io::Result<String> { panic!("No file to unlock") };
+        let mut credentials = Credentials::new(path, &missing);
+        assert_eq!(credentials.password("community").unwrap(), None);
+    }
+}
@@ -0,0 +1,446 @@
+//! Export module.
+//!
+//! This module contains the logic to render a channel history
+//! to Markdown, JSON or HTML files.
+use super::core::Message;
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use serde::Serialize;
+use url::Url;
+
+use std::error::Error;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Export file format.
+#[derive(Eq, PartialEq, PartialOrd, Clone, Copy, Debug)]
+pub enum ExportFormat {
+    Markdown,
+    Json,
+    Html,
+}
+
+impl ExportFormat {
+    /// The file extension matching the format.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Markdown => "md",
+            ExportFormat::Json => "json",
+            ExportFormat::Html => "html",
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "md" | "markdown" => Ok(ExportFormat::Markdown),
+            "json" => Ok(ExportFormat::Json),
+            "html" => Ok(ExportFormat::Html),
+            _ => Err(format!("Unknown export format: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for ExportFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.extension())
+    }
+}
+
+/// Export parameters.
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub struct ExportOptions {
+    /// The format of the exported file.
+    pub format: ExportFormat,
+    /// Only messages sent after this date are exported.
+    pub since: Option<DateTime<Utc>>,
+    /// Where to write the file, a name is derived from the channel if missing.
+    pub output: Option<PathBuf>,
+}
+
+impl ExportOptions {
+    /// Resolve the path of the exported file.
+    pub fn path(&self, channel_name: &str) -> PathBuf {
+        self.output.clone().unwrap_or_else(|| {
+            let name: String = channel_name
+                .chars()
+                .map(|c| {
+                    if c.is_alphanumeric() || c == '-' {
+                        c
+                    } else {
+                        '_'
+                    }
+                })
+                .collect();
+            PathBuf::from(format!(
+                "{}-{}.{}",
+                name,
+                Utc::now().format("%Y%m%d%H%M%S"),
+                self.format.extension()
+            ))
+        })
+    }
+}
+
+/// Parse a `--since` date, either `YYYY-MM-DD` or RFC 3339.
+pub fn parse_since(date: &str) -> Result<DateTime<Utc>, Box<dyn Error + Send + Sync>> {
+    if let Ok(datetime) = DateTime::parse_from_rfc3339(date) {
+        return Ok(datetime.with_timezone(&Utc));
+    }
+    let day = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .map_err(|err| format!("Bad date {}: {}", date, err))?;
+    Ok(Utc.from_utc_datetime(&day.and_hms(0, 0, 0)))
+}
+
+#[derive(Serialize)]
+struct JsonAttachment<'a> {
+    title: &'a str,
+    link: &'a str,
+}
+
+#[derive(Serialize)]
+struct JsonMessage<'a> {
+    id: &'a str,
+    author: &'a str,
+    timestamp: String,
+    content: &'a str,
+    thread: Option<&'a str>,
+    attachments: Vec<JsonAttachment<'a>>,
+}
+
+#[derive(Serialize)]
+struct JsonExport<'a> {
+    channel: &'a str,
+    messages: Vec<JsonMessage<'a>>,
+}
+
+/// Order messages by date with the replies right after their thread.
+///
+/// The boolean tells if the message is displayed as a reply.
+fn thread_order(messages: &[Message]) -> Vec<(&Message, bool)> {
+    let mut sorted: Vec<&Message> = messages.iter().collect();
+    sorted.sort_by_key(|m| m.datetime);
+    let is_known = |id: &str| sorted.iter().any(|m| m.id == id);
+    let mut ordered = vec![];
+    for message in sorted.iter() {
+        match &message.thread {
+            Some(thread) if is_known(thread) => continue,
+            _ => ordered.push((*message, message.thread.is_some())),
+        }
+        ordered.extend(
+            sorted
+                .iter()
+                .filter(|m| m.thread.as_deref() == Some(&message.id[..]))
+                .map(|m| (*m, true)),
+        );
+    }
+    ordered
+}
+
+fn timestamp(message: &Message) -> String {
+    message.datetime.format("%Y-%m-%d %H:%M:%S UTC").to_string()
+}
+
+fn escape_html(content: &str) -> String {
+    content
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Escape the characters with a Markdown meaning, line breaks would end
+/// the list item.
+fn escape_markdown(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' | '`' | '*' | '_' | '[' | ']' | '(' | ')' | '<' | '>' | '#' | '!' | '|' => {
+                out.push('\\');
+                out.push(c);
+            }
+            '\r' | '\n' => out.push(' '),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Percent-encode the characters ending a Markdown link destination.
+fn escape_markdown_link(link: &str) -> String {
+    link.replace(' ', "%20")
+        .replace('(', "%28")
+        .replace(')', "%29")
+        .replace('<', "%3C")
+        .replace('>', "%3E")
+}
+
+/// Links of the HTML export, other schemes like `javascript:` could run
+/// code when the file is opened in a browser.
+fn is_safe_link(link: &str) -> bool {
+    Url::parse(link)
+        .map(|x| matches!(x.scheme(), "http" | "https" | "mailto"))
+        .unwrap_or(false)
+}
+
+fn render_markdown(channel_name: &str, messages: &[Message]) -> String {
+    let mut out = format!("# {}\n\n", escape_markdown(channel_name));
+    for (message, reply) in thread_order(messages) {
+        let indent = if reply { "    " } else { "" };
+        out.push_str(&format!(
+            "{}- **{}** _{}_: {}\n",
+            indent,
+            message.author,
+            timestamp(message),
+            message.content.replace('\n', &format!("\n{}  ", indent))
+        ));
+        for attachment in message.attachments.iter() {
+            out.push_str(&format!(
+                "{}    - [{}]({})\n",
+                indent,
+                escape_markdown(&attachment.title),
+                escape_markdown_link(&attachment.link)
+            ));
+        }
+    }
+    out
+}
+
+fn render_html(channel_name: &str, messages: &[Message]) -> String {
+    let mut out = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{0}</title></head>\n<body>\n<h1>{0}</h1>\n<ul>\n",
+        escape_html(channel_name)
+    );
+    for (message, reply) in thread_order(messages) {
+        let class = if reply { " class=\"reply\"" } else { "" };
+        out.push_str(&format!(
+            "<li id=\"{}\"{}><b>{}</b> <time datetime=\"{}\">{}</time>: {}",
+            escape_html(&message.id),
+            class,
+            escape_html(&message.author),
+            message.datetime.to_rfc3339(),
+            timestamp(message),
+            escape_html(&message.content).replace('\n', "<br>")
+        ));
+        for attachment in message.attachments.iter() {
+            if is_safe_link(&attachment.link) {
+                out.push_str(&format!(
+                    " <a href=\"{}\">{}</a>",
+                    escape_html(&attachment.link),
+                    escape_html(&attachment.title)
+                ));
+            } else {
+                out.push_str(&format!(" {}", escape_html(&attachment.title)));
+            }
+        }
+        out.push_str("</li>\n");
+    }
+    out.push_str("</ul>\n</body>\n</html>\n");
+    out
+}
+
+fn render_json(
+    channel_name: &str,
+    messages: &[Message],
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let export = JsonExport {
+        channel: channel_name,
+        messages: thread_order(messages)
+            .into_iter()
+            .map(|(message, _)| JsonMessage {
+                id: &message.id,
+                author: &message.author,
+                timestamp: message.datetime.to_rfc3339(),
+                content: &message.content,
+                thread: message.thread.as_deref(),
+                attachments: message
+                    .attachments
+                    .iter()
+                    .map(|a| JsonAttachment {
+                        title: &a.title,
+                        link: &a.link,
+                    })
+                    .collect(),
+            })
+            .collect(),
+    };
+    Ok(serde_json::to_string_pretty(&export)?)
+}
+
+/// Render messages of a channel in the given format.
+pub fn render(
+    channel_name: &str,
+    messages: &[Message],
+    format: ExportFormat,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    match format {
+        ExportFormat::Markdown => Ok(render_markdown(channel_name, messages)),
+        ExportFormat::Json => render_json(channel_name, messages),
+        ExportFormat::Html => Ok(render_html(channel_name, messages)),
+    }
+}
+
+/// Render messages of a channel and write them to the export file.
+pub fn write(
+    channel_name: &str,
+    messages: &[Message],
+    options: &ExportOptions,
+) -> Result<PathBuf, Box<dyn Error + Send + Sync>> {
+    let messages = messages
+        .iter()
+        .filter(|m| {
+            options
+                .since
+                .map(|since| m.datetime >= since)
+                .unwrap_or(true)
+        })
+        .cloned()
+        .collect::<Vec<Message>>();
+    let path = options.path(channel_name);
+    std::fs::write(&path, render(channel_name, &messages, options.format)?)?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::Attachment;
+
+    fn message(id: &str, thread: Option<&str>, millis: i64) -> Message {
+        Message {
+            id: id.into(),
+            author: "lou".into(),
+            content: format!("content {}", id),
+            datetime: Utc.timestamp_millis(millis),
+            thread: thread.map(|x| x.into()),
+            attachments: vec![],
+        }
+    }
+
+    fn messages() -> Vec<Message> {
+        let mut with_file = message("c", None, 1593589760000);
+        with_file.attachments.push(Attachment {
+            title: "logs.txt".into(),
+            link: "https://chat.test/file-upload/id/logs.txt".into(),
+        });
+        vec![
+            with_file,
+            message("b", Some("a"), 1593589755000),
+            message("a", None, 1593589750000),
+        ]
+    }
+
+    #[test]
+    fn test_parse_format() {
+        assert_eq!("md".parse::<ExportFormat>(), Ok(ExportFormat::Markdown));
+        assert_eq!("json".parse::<ExportFormat>(), Ok(ExportFormat::Json));
+        assert_eq!("html".parse::<ExportFormat>(), Ok(ExportFormat::Html));
+        assert!("pdf".parse::<ExportFormat>().is_err());
+    }
+
+    #[test]
+    fn test_parse_since() {
+        assert_eq!(
+            parse_since("2020-07-01").unwrap(),
+            Utc.timestamp_millis(1593561600000)
+        );
+        assert_eq!(
+            parse_since("2020-07-01T02:00:00+02:00").unwrap(),
+            Utc.timestamp_millis(1593561600000)
+        );
+        assert!(parse_since("yesterday").is_err());
+    }
+
+    #[test]
+    fn test_thread_order() {
+        let messages = messages();
+        let ordered = thread_order(&messages)
+            .iter()
+            .map(|(m, reply)| (m.id.clone(), *reply))
+            .collect::<Vec<(String, bool)>>();
+        assert_eq!(
+            ordered,
+            vec![
+                ("a".to_string(), false),
+                ("b".to_string(), true),
+                ("c".to_string(), false)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_render_markdown() {
+        assert_eq!(
+            render("general", &messages(), ExportFormat::Markdown).unwrap(),
+            "# general\n\n\
+             - **lou** _2020-07-01 07:49:10 UTC_: content a\n    \
+             - **lou** _2020-07-01 07:49:15 UTC_: content b\n\
+             - **lou** _2020-07-01 07:49:20 UTC_: content c\n    \
+             - [logs.txt](https://chat.test/file-upload/id/logs.txt)\n"
+        );
+    }
+
+    #[test]
+    fn test_render_json() {
+        let json: serde_json::Value =
+            serde_json::from_str(&render("general", &messages(), ExportFormat::Json).unwrap())
+                .unwrap();
+        assert_eq!(json["channel"], "general");
+        assert_eq!(json["messages"][1]["id"], "b");
+        assert_eq!(json["messages"][1]["thread"], "a");
+        assert_eq!(
+            json["messages"][0]["timestamp"],
+            "2020-07-01T07:49:10+00:00"
+        );
+        assert_eq!(
+            json["messages"][2]["attachments"][0]["link"],
+            "https://chat.test/file-upload/id/logs.txt"
+        );
+    }
+
+    #[test]
+    fn test_render_html_escapes() {
+        let mut message = message("a", None, 1593589750000);
+        message.content = "<script>".into();
+        let html = render("general", &[message], ExportFormat::Html).unwrap();
+        assert!(html.contains("&lt;script&gt;"));
+        assert!(!html.contains("<script>"));
+    }
+
+    #[test]
+    fn test_render_html_links() {
+        let mut message = message("a", None, 1593589750000);
+        message.attachments = vec![
+            Attachment {
+                title: "logs".into(),
+                link: "javascript:alert(1)".into(),
+            },
+            Attachment {
+                title: "mail".into(),
+                link: "mailto:lou@chat.test".into(),
+            },
+        ];
+        let html = render("general", &[message], ExportFormat::Html).unwrap();
+        assert!(!html.contains("javascript:"));
+        assert!(html.contains(" logs"));
+        assert!(html.contains("<a href=\"mailto:lou@chat.test\">mail</a>"));
+    }
+
+    #[test]
+    fn test_render_markdown_escapes() {
+        let mut message = message("a", None, 1593589750000);
+        message.attachments = vec![Attachment {
+            title: "a](javascript:alert(1)) *b*".into(),
+            link: "https://chat.test/my file (1).txt".into(),
+        }];
+        assert_eq!(
+            render("general", &[message], ExportFormat::Markdown).unwrap(),
+            "# general\n\n\
+             - **lou** _2020-07-01 07:49:10 UTC_: content a\n    \
+             - [a\\]\\(javascript:alert\\(1\\)\\) \\*b\\*](https://chat.test/my%20file%20%281%29.txt)\n"
+        );
+    }
+}
@@ -1,5 +1,11 @@
+pub mod chatlog;
 pub mod chats;
 pub mod config;
 pub mod core;
+pub mod credentials;
+pub mod export;
+pub mod logging;
 pub mod notifications;
+pub mod session;
+pub mod setup;
 pub mod ui;
@@ -0,0 +1,322 @@
+//! Logging module.
+//!
+//! This module sets up log4rs from the command line, the configuration
+//! file or a `log4rs.yaml` file. The log files, the ones of a `log4rs.yaml`
+//! file too, are only readable by the user. The log file lives in the XDG
+//! state directory by default.
+use super::config::state_dir;
+use log::LevelFilter;
+use log4rs::append::file::FileAppender;
+use log4rs::append::Append;
+use log4rs::config::{Appender, Config, Logger, Root};
+use log4rs::encode::EncoderConfig;
+use log4rs::file::Deserializers;
+use serde::Deserialize;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Level used when none is given.
+const DEFAULT_LEVEL: &str = "info";
+
+/// Logging settings.
+///
+/// This type contains the `log_file`, `log_level` and `log_config` keys
+/// of the configuration file, or the matching command line options.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct LogConfig {
+    #[serde(rename = "log_file")]
+    pub file: Option<PathBuf>,
+    /// A level, then `module=level` pairs, separated by commas.
+    #[serde(rename = "log_level")]
+    pub level: Option<String>,
+    /// A log4rs YAML file replacing the other settings.
+    #[serde(rename = "log_config")]
+    pub config: Option<PathBuf>,
+}
+
+impl LogConfig {
+    /// Read the logging keys of a configuration file.
+    ///
+    /// A missing or invalid file has no settings, its errors are reported
+    /// when the accounts are loaded.
+    pub fn from_file(path: &Path) -> Self {
+        std::fs::read_to_string(path)
+            .ok()
+            .and_then(|x| toml::from_str(&x).ok())
+            .unwrap_or_default()
+    }
+
+    /// Keep these values and take the missing ones from `other`.
+    pub fn or(self, other: LogConfig) -> Self {
+        LogConfig {
+            file: self.file.or(other.file),
+            level: self.level.or(other.level),
+            config: self.config.or(other.config),
+        }
+    }
+}
+
+/// The levels of a `log_level` value, like `warn,talkoxid::chats::rocketchat=trace`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Levels {
+    pub root: LevelFilter,
+    pub modules: Vec<(String, LevelFilter)>,
+}
+
+impl FromStr for Levels {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let level = |x: &str| {
+            LevelFilter::from_str(x.trim()).map_err(|_| {
+                format!(
+                    "Invalid log level {}, expected off, error, warn, info, debug or trace",
+                    x.trim()
+                )
+            })
+        };
+        let mut levels = Levels {
+            root: LevelFilter::Info,
+            modules: vec![],
+        };
+        for directive in spec.split(',').filter(|x| !x.trim().is_empty()) {
+            match directive.split_once('=') {
+                Some((module, module_level)) if !module.trim().is_empty() => levels
+                    .modules
+                    .push((module.trim().to_string(), level(module_level)?)),
+                Some(_) => return Err(format!("Invalid log directive {}", directive)),
+                None => levels.root = level(directive)?,
+            }
+        }
+        Ok(levels)
+    }
+}
+
+/// The default log file, `$XDG_STATE_HOME/talkoxid/talkoxid.log`.
+pub fn default_log_file() -> PathBuf {
+    state_dir().join("talkoxid.log")
+}
+
+/// The default log4rs file, `log4rs.yaml` next to the configuration file.
+pub fn default_log4rs_file() -> PathBuf {
+    let mut path = dirs_next::config_dir().unwrap_or_else(|| PathBuf::from("."));
+    path.push("talkoxid");
+    path.push("log4rs.yaml");
+    path
+}
+
+/// Create the log file and its directory, only the user can read them.
+fn create_private(path: &Path) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if let Some(directory) = path.parent().filter(|x| !x.as_os_str().is_empty()) {
+        let mut builder = std::fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        builder
+            .create(directory)
+            .map_err(|err| format!("Can't create {}: {}", directory.display(), err))?;
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.append(true).create(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let _file = options
+        .open(path)
+        .map_err(|err| format!("Can't open {}: {}", path.display(), err))?;
+    // The mode only applies to a new file
+    #[cfg(unix)]
+    _file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+    Ok(())
+}
+
+/// The `file` appender of a log4rs file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PrivateFileConfig {
+    path: String,
+    encoder: Option<EncoderConfig>,
+    append: Option<bool>,
+}
+
+/// Build the `file` appenders of log4rs files like the default log file,
+/// instead of creating their file under the umask.
+struct PrivateFileDeserializer;
+
+impl log4rs::file::Deserialize for PrivateFileDeserializer {
+    type Trait = dyn Append;
+
+    type Config = PrivateFileConfig;
+
+    fn deserialize(
+        &self,
+        config: PrivateFileConfig,
+        deserializers: &Deserializers,
+    ) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+        create_private(Path::new(&config.path))?;
+        let mut appender = FileAppender::builder();
+        if let Some(append) = config.append {
+            appender = appender.append(append);
+        }
+        if let Some(encoder) = config.encoder {
+            appender = appender.encoder(deserializers.deserialize(&encoder.kind, encoder.config)?);
+        }
+        Ok(Box::new(appender.build(&config.path)?))
+    }
+}
+
+/// The log4rs deserializers, with the private `file` appender.
+fn deserializers() -> Deserializers {
+    let mut deserializers = Deserializers::default();
+    deserializers.insert("file", PrivateFileDeserializer);
+    deserializers
+}
+
+/// The log4rs configuration writing `levels` to `path`.
+fn file_config(path: &Path, levels: &Levels) -> Result<Config, Box<dyn Error + Send + Sync>> {
+    create_private(path)?;
+    let file = FileAppender::builder()
+        .build(path)
+        .map_err(|err| format!("Can't open {}: {}", path.display(), err))?;
+    Ok(Config::builder()
+        .appender(Appender::builder().build("file", Box::new(file)))
+        .loggers(
+            levels
+                .modules
+                .iter()
+                .map(|(module, level)| Logger::builder().build(module, *level)),
+        )
+        .build(Root::builder().appender("file").build(levels.root))?)
+}
+
+/// Start logging.
+///
+/// The command line values in `cli` win over the ones of the configuration
+/// file in `file`. A log4rs file, given by `log_config` or at the
+/// [default path](fn.default_log4rs_file.html), is used unless the log file
+/// or the level are given on the command line.
+pub fn init(cli: LogConfig, file: LogConfig) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let log4rs_file = cli
+        .config
+        .clone()
+        .or_else(|| file.config.clone())
+        .or_else(|| Some(default_log4rs_file()).filter(|x| x.exists()));
+    if let Some(log4rs_file) = log4rs_file {
+        if cli.file.is_none() && cli.level.is_none() {
+            return log4rs::init_file(&log4rs_file, deserializers())
+                .map_err(|err| format!("Can't load {}: {}", log4rs_file.display(), err).into());
+        }
+    }
+    let settings = cli.or(file);
+    let levels: Levels = settings.level.as_deref().unwrap_or(DEFAULT_LEVEL).parse()?;
+    let path = settings.file.unwrap_or_else(default_log_file);
+    log4rs::init_config(file_config(&path, &levels)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_levels() {
+        let levels: Levels = "warn, talkoxid::chats::rocketchat=trace,talkoxid::ui=off"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            levels,
+            Levels {
+                root: LevelFilter::Warn,
+                modules: vec![
+                    ("talkoxid::chats::rocketchat".into(), LevelFilter::Trace),
+                    ("talkoxid::ui".into(), LevelFilter::Off),
+                ],
+            }
+        );
+        let levels: Levels = "talkoxid::chats=debug".parse().unwrap();
+        assert_eq!(levels.root, LevelFilter::Info);
+        assert_eq!(
+            "loud".parse::<Levels>(),
+            Err("Invalid log level loud, expected off, error, warn, info, debug or trace".into())
+        );
+        assert_eq!(
+            "=debug".parse::<Levels>(),
+            Err("Invalid log directive =debug".into())
+        );
+    }
+
+    #[test]
+    fn test_log_config() {
+        let file: LogConfig = toml::from_str(
+            r#"
+            username = "lou"
+            log_level = "debug"
+            log_file = "/var/log/talkoxid.log"
+            "#,
+        )
+        .unwrap();
+        let cli = LogConfig {
+            level: Some("trace".into()),
+            ..LogConfig::default()
+        };
+        assert_eq!(
+            cli.or(file),
+            LogConfig {
+                file: Some("/var/log/talkoxid.log".into()),
+                level: Some("trace".into()),
+                config: None,
+            }
+        );
+        assert_eq!(
+            LogConfig::from_file(Path::new("/nonexistent/talkoxid.toml")),
+            LogConfig::default()
+        );
+    }
+
+    #[test]
+    fn test_file_config() {
+        let directory = std::env::temp_dir().join("talkoxid_test_file_config");
+        std::fs::remove_dir_all(&directory).ok();
+        let path = directory.join("state").join("talkoxid.log");
+        let levels: Levels = "warn,talkoxid::chats::rocketchat=trace".parse().unwrap();
+        let config = file_config(&path, &levels).unwrap();
+        assert_eq!(config.root().level(), LevelFilter::Warn);
+        assert_eq!(config.loggers()[0].name(), "talkoxid::chats::rocketchat");
+        assert_eq!(config.loggers()[0].level(), LevelFilter::Trace);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode(&path), 0o600);
+            assert_eq!(mode(path.parent().unwrap()), 0o700);
+        }
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_log4rs_file() {
+        let directory = std::env::temp_dir().join("talkoxid_test_log4rs_file");
+        std::fs::remove_dir_all(&directory).ok();
+        std::fs::create_dir(&directory).unwrap();
+        let path = directory.join("state").join("talkoxid.log");
+        let log4rs_file = directory.join("log4rs.yaml");
+        std::fs::write(
+            &log4rs_file,
+            format!(
+                "appenders:\n  file:\n    kind: file\n    path: \"{}\"\nroot:\n  appenders:\n    - file\n",
+                path.display()
+            ),
+        )
+        .unwrap();
+        let config = log4rs::load_config_file(&log4rs_file, deserializers()).unwrap();
+        assert_eq!(config.appenders()[0].name(), "file");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode(&path), 0o600);
+            assert_eq!(mode(path.parent().unwrap()), 0o700);
+        }
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,437 @@
+//! Notification coalescing.
+//!
+//! This module keeps a busy channel or a reconnection from showing dozens of
+//! notifications. The first message of a channel is notified at once, the
+//! next ones within a time window are summed up in a single notification,
+//! and no more notifications than a maximum rate are shown.
+use super::super::core::{AccountId, Alert, Channel, Notification};
+use log::{error, warn};
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The period of the maximum rate.
+const RATE_PERIOD: Duration = Duration::from_secs(60);
+
+/// The messages of a channel received within a window.
+struct Window {
+    start: Instant,
+    /// Messages not notified yet.
+    count: usize,
+    /// The last message not notified, mentioning us if any of them did.
+    last: Option<Alert>,
+}
+
+impl Window {
+    fn new(start: Instant) -> Self {
+        Window {
+            start,
+            count: 0,
+            last: None,
+        }
+    }
+
+    fn add(&mut self, alert: Alert) {
+        let (mention, highlight) = match self.last.as_ref() {
+            Some(last) => (last.mention, last.highlight),
+            None => (false, false),
+        };
+        self.count += 1;
+        self.last = Some(Alert {
+            mention: alert.mention || mention,
+            highlight: alert.highlight || highlight,
+            ..alert
+        });
+    }
+
+    /// The notification of the pending messages.
+    fn summary(self) -> Option<Alert> {
+        let count = self.count;
+        self.last.map(|last| match count {
+            1 => last,
+            _ => Alert {
+                content: match last.channel {
+                    Channel::User(_) => {
+                        format!("{} new messages from {}", count, last.channel_name)
+                    }
+                    _ => format!("{} new messages in #{}", count, last.channel_name),
+                },
+                ..last
+            },
+        })
+    }
+}
+
+/// Coalescing state, with the time given by the caller.
+struct Coalescer {
+    /// No window when zero.
+    window: Duration,
+    /// Notifications per minute, unlimited when zero.
+    max_rate: usize,
+    rooms: HashMap<(AccountId, Channel), Window>,
+    /// When the notifications of the last minute were shown, for all accounts.
+    sent: VecDeque<Instant>,
+}
+
+impl Coalescer {
+    fn new(window: Duration, max_rate: usize) -> Self {
+        Coalescer {
+            window,
+            max_rate,
+            rooms: HashMap::new(),
+            sent: VecDeque::new(),
+        }
+    }
+
+    /// When the rate allows the next notification.
+    fn rate_free(&self, now: Instant) -> Instant {
+        match self.sent.front() {
+            Some(oldest) if self.max_rate > 0 && self.sent.len() >= self.max_rate => {
+                *oldest + RATE_PERIOD
+            }
+            _ => now,
+        }
+    }
+
+    /// Record a notification if the rate allows it.
+    fn take_rate(&mut self, now: Instant) -> bool {
+        while self
+            .sent
+            .front()
+            .is_some_and(|x| now.saturating_duration_since(*x) >= RATE_PERIOD)
+        {
+            self.sent.pop_front();
+        }
+        if self.rate_free(now) > now {
+            return false;
+        }
+        if self.max_rate > 0 {
+            self.sent.push_back(now);
+        }
+        true
+    }
+
+    /// The notification of a message received by `account`, if shown now.
+    fn push(&mut self, account: &str, alert: Alert, now: Instant) -> Option<Alert> {
+        let room = (account.to_string(), alert.channel.clone());
+        if let Some(window) = self.rooms.get_mut(&room) {
+            window.add(alert);
+            return None;
+        }
+        if self.take_rate(now) {
+            if !self.window.is_zero() {
+                self.rooms.insert(room, Window::new(now));
+            }
+            return Some(alert);
+        }
+        let mut window = Window::new(now);
+        window.add(alert);
+        self.rooms.insert(room, window);
+        None
+    }
+
+    /// The summaries of the windows ended by `now`.
+    fn due(&mut self, now: Instant) -> Vec<Alert> {
+        let mut ended: Vec<(Instant, (AccountId, Channel))> = self
+            .rooms
+            .iter()
+            .filter(|(_, x)| x.start + self.window <= now)
+            .map(|(room, x)| (x.start, room.clone()))
+            .collect();
+        ended.sort_by_key(|(start, _)| *start);
+        let mut summaries = vec![];
+        for (_, room) in ended {
+            let pending = self.rooms.get(&room).map_or(0, |x| x.count);
+            if pending == 0 {
+                self.rooms.remove(&room);
+            } else if self.take_rate(now) {
+                let window = self.rooms.remove(&room).unwrap();
+                summaries.extend(window.summary());
+                // Keep summing up a busy channel
+                if !self.window.is_zero() {
+                    self.rooms.insert(room, Window::new(now));
+                }
+            }
+        }
+        summaries
+    }
+
+    /// When the next window ends, if any.
+    fn deadline(&self, now: Instant) -> Option<Instant> {
+        let rate_free = self.rate_free(now);
+        self.rooms
+            .values()
+            .map(|x| match x.count {
+                0 => x.start + self.window,
+                _ => (x.start + self.window).max(rate_free),
+            })
+            .min()
+    }
+}
+
+/// Notify the coalesced messages until the notifier is dropped.
+fn coalesce_worker(
+    rx: Receiver<(AccountId, Alert)>,
+    mut coalescer: Coalescer,
+    notifier: Arc<dyn Notification + Send + Sync>,
+) {
+    let notify = |alert: Alert| {
+        if let Err(err) = notifier.notify_message(&alert) {
+            error!("Can't notify: {}", err);
+        }
+    };
+    loop {
+        let received = match coalescer.deadline(Instant::now()) {
+            Some(deadline) => rx.recv_timeout(deadline.saturating_duration_since(Instant::now())),
+            None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok((account, alert)) => coalescer
+                .push(&account, alert, Instant::now())
+                .into_iter()
+                .for_each(notify),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+        coalescer.due(Instant::now()).into_iter().for_each(notify);
+    }
+}
+
+/// Coalescing notifier.
+///
+/// This type notifies the messages on a worker thread, grouped by account
+/// and channel within `window` and at most `max_rate` per minute for all
+/// the accounts sharing it. Other notifications go straight to the next
+/// notifier.
+pub struct CoalescingNotifier {
+    notifier: Arc<dyn Notification + Send + Sync>,
+    tx: Mutex<Option<Sender<(AccountId, Alert)>>>,
+}
+
+impl CoalescingNotifier {
+    /// No worker is started when both `window` and `max_rate` are zero.
+    pub fn new(
+        window: Duration,
+        max_rate: usize,
+        notifier: Box<dyn Notification + Send + Sync>,
+    ) -> Self {
+        let notifier: Arc<dyn Notification + Send + Sync> = Arc::from(notifier);
+        if window.is_zero() && max_rate == 0 {
+            return CoalescingNotifier {
+                notifier,
+                tx: Mutex::new(None),
+            };
+        }
+        let (tx, rx) = channel();
+        let coalescer = Coalescer::new(window, max_rate);
+        let worker = notifier.clone();
+        let tx = match std::thread::Builder::new()
+            .name("coalesce".into())
+            .spawn(move || coalesce_worker(rx, coalescer, worker))
+        {
+            Ok(_) => Some(tx),
+            Err(err) => {
+                warn!("Can't start the notification worker: {}", err);
+                None
+            }
+        };
+        CoalescingNotifier {
+            notifier,
+            tx: Mutex::new(tx),
+        }
+    }
+
+    /// The notifier of an account.
+    pub fn account(self: &Arc<Self>, account: &str) -> Box<dyn Notification + Send + Sync> {
+        Box::new(AccountNotifier {
+            account: account.to_string(),
+            coalescer: self.clone(),
+        })
+    }
+}
+
+/// The messages of an account sent to a shared coalescing notifier.
+struct AccountNotifier {
+    account: AccountId,
+    coalescer: Arc<CoalescingNotifier>,
+}
+
+impl Notification for AccountNotifier {
+    fn notify(&self, title: &str, content: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.coalescer.notifier.notify(title, content)
+    }
+
+    fn notify_message(&self, alert: &Alert) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match self.coalescer.tx.lock().unwrap().as_ref() {
+            Some(tx) => tx
+                .send((self.account.clone(), alert.clone()))
+                .map_err(|_| "The notification worker stopped".into()),
+            None => self.coalescer.notifier.notify_message(alert),
+        }
+    }
+
+    fn configure(
+        &self,
+        channel: &Channel,
+        channel_name: &str,
+        args: &str,
+    ) -> Result<String, String> {
+        self.coalescer
+            .notifier
+            .configure(channel, channel_name, args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn alert(channel: Channel, content: &str, mention: bool) -> Alert {
+        Alert {
+            channel_name: format!("{}", channel).to_lowercase(),
+            channel,
+            author: "lou".into(),
+            content: content.into(),
+            datetime: Utc::now(),
+            mention,
+            highlight: false,
+            server_level: None,
+        }
+    }
+
+    fn contents(alerts: &[Alert]) -> Vec<&str> {
+        alerts.iter().map(|x| x.content.as_str()).collect()
+    }
+
+    #[test]
+    fn test_coalesce_window() {
+        let ops = Channel::Group("OPS".into());
+        let lou = Channel::User("LOU".into());
+        let start = Instant::now();
+        let at = |x: u64| start + Duration::from_secs(x);
+        let mut coalescer = Coalescer::new(Duration::from_secs(5), 0);
+        assert!(coalescer
+            .push("work", alert(ops.clone(), "a", false), at(0))
+            .is_some());
+        assert!(coalescer
+            .push("work", alert(lou.clone(), "hi", false), at(0))
+            .is_some());
+        for (i, content) in ["b", "c", "d", "e", "f"].iter().enumerate() {
+            let mention = i == 1;
+            assert!(coalescer
+                .push("work", alert(ops.clone(), content, mention), at(1))
+                .is_none());
+        }
+        assert!(coalescer
+            .push("work", alert(lou, "there", false), at(2))
+            .is_none());
+        assert_eq!(coalescer.deadline(at(2)), Some(at(5)));
+        assert!(coalescer.due(at(4)).is_empty());
+
+        let mut summaries = coalescer.due(at(5));
+        summaries.sort_by_key(|x| x.channel.clone());
+        assert_eq!(
+            contents(&summaries),
+            vec!["there", "5 new messages in #ops"]
+        );
+        assert!(summaries[1].mention);
+        // The busy channel stays summed up until a quiet window
+        assert!(coalescer
+            .push("work", alert(ops.clone(), "g", false), at(6))
+            .is_none());
+        assert_eq!(contents(&coalescer.due(at(10))), vec!["g"]);
+        assert!(coalescer.due(at(15)).is_empty());
+        assert_eq!(coalescer.deadline(at(15)), None);
+        assert!(coalescer
+            .push("work", alert(ops, "h", false), at(16))
+            .is_some());
+    }
+
+    #[test]
+    fn test_coalesce_rate() {
+        let start = Instant::now();
+        let at = |x: u64| start + Duration::from_secs(x);
+        let mut coalescer = Coalescer::new(Duration::from_secs(0), 2);
+        let room = |x: &str| Channel::Group(x.into());
+        assert!(coalescer
+            .push("work", alert(room("A"), "a", false), at(0))
+            .is_some());
+        assert!(coalescer
+            .push("home", alert(room("B"), "b", false), at(1))
+            .is_some());
+        assert!(coalescer
+            .push("work", alert(room("C"), "c", false), at(2))
+            .is_none());
+        assert!(coalescer
+            .push("work", alert(room("C"), "d", false), at(3))
+            .is_none());
+        assert!(coalescer
+            .push("work", alert(room("A"), "e", false), at(4))
+            .is_none());
+        // The channels of another account have their own windows
+        assert!(coalescer
+            .push("home", alert(room("C"), "f", false), at(5))
+            .is_none());
+        assert!(coalescer.due(at(30)).is_empty());
+        assert_eq!(coalescer.deadline(at(30)), Some(at(60)));
+        assert_eq!(
+            contents(&coalescer.due(at(60))),
+            vec!["2 new messages in #c"]
+        );
+        assert_eq!(contents(&coalescer.due(at(61))), vec!["e"]);
+        assert_eq!(contents(&coalescer.due(at(120))), vec!["f"]);
+        assert_eq!(coalescer.deadline(at(120)), None);
+    }
+
+    /// Notifier sending its notifications to a channel.
+    struct ChannelNotifier(Mutex<Sender<String>>);
+
+    impl Notification for ChannelNotifier {
+        fn notify(&self, _: &str, content: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+            self.0.lock().unwrap().send(content.to_string())?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_coalescing_notifier() {
+        let (tx, rx) = channel();
+        let coalescer = Arc::new(CoalescingNotifier::new(
+            Duration::from_millis(100),
+            0,
+            Box::new(ChannelNotifier(Mutex::new(tx))),
+        ));
+        let notifier = coalescer.account("work");
+        let ops = Channel::Group("OPS".into());
+        for content in ["a", "b", "c"].iter() {
+            notifier
+                .notify_message(&alert(ops.clone(), content, false))
+                .unwrap();
+        }
+        let timeout = Duration::from_secs(5);
+        assert_eq!(rx.recv_timeout(timeout).unwrap(), "a");
+        assert_eq!(rx.recv_timeout(timeout).unwrap(), "2 new messages in #ops");
+    }
+
+    #[test]
+    fn test_coalescing_accounts() {
+        let (tx, rx) = channel();
+        let coalescer = Arc::new(CoalescingNotifier::new(
+            Duration::from_secs(0),
+            1,
+            Box::new(ChannelNotifier(Mutex::new(tx))),
+        ));
+        let (work, home) = (coalescer.account("work"), coalescer.account("home"));
+        let general = Channel::Group("GENERAL".into());
+        work.notify_message(&alert(general.clone(), "a", false))
+            .unwrap();
+        home.notify_message(&alert(general, "b", false)).unwrap();
+        let timeout = Duration::from_millis(300);
+        assert_eq!(rx.recv_timeout(timeout).unwrap(), "a");
+        // The limit is shared by both accounts
+        assert!(rx.recv_timeout(timeout).is_err());
+    }
+}
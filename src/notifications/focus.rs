@@ -0,0 +1,147 @@
+//! Terminal focus.
+//!
+//! This module shares the focus of the terminal, reported by the UI, with
+//! the notifiers, so that the channel being read isn't notified.
+use super::super::core::{AccountId, Alert, Channel, Notification};
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+
+#[derive(Default)]
+struct FocusState {
+    /// Unknown until the terminal reports a focus change.
+    focused: Option<bool>,
+    /// The channel displayed by the UI.
+    active: Option<(AccountId, Channel)>,
+}
+
+/// Focus of the terminal and the channel it displays.
+#[derive(Default)]
+pub struct TerminalFocus {
+    state: Mutex<FocusState>,
+}
+
+impl TerminalFocus {
+    pub fn new() -> Self {
+        TerminalFocus::default()
+    }
+
+    pub fn set_focused(&self, focused: bool) {
+        self.state.lock().unwrap().focused = Some(focused);
+    }
+
+    pub fn set_active(&self, account: &str, channel: &Channel) {
+        self.state.lock().unwrap().active = Some((account.to_string(), channel.clone()));
+    }
+
+    /// Whether the user is reading a channel, none when the terminal
+    /// doesn't report its focus.
+    pub fn watching(&self, account: &str, channel: &Channel) -> Option<bool> {
+        let state = self.state.lock().unwrap();
+        let active = state
+            .active
+            .as_ref()
+            .is_some_and(|(x, y)| x == account && y == channel);
+        state.focused.map(|focused| focused && active)
+    }
+}
+
+/// Focus-aware notifier.
+///
+/// This type doesn't notify the messages of the channel the user is
+/// reading in the focused terminal.
+pub struct FocusNotifier {
+    account: AccountId,
+    focus: Arc<TerminalFocus>,
+    notifier: Box<dyn Notification + Send + Sync>,
+}
+
+impl FocusNotifier {
+    pub fn new(
+        account: &str,
+        focus: Arc<TerminalFocus>,
+        notifier: Box<dyn Notification + Send + Sync>,
+    ) -> Self {
+        FocusNotifier {
+            account: account.to_string(),
+            focus,
+            notifier,
+        }
+    }
+}
+
+impl Notification for FocusNotifier {
+    fn notify(&self, title: &str, content: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.notifier.notify(title, content)
+    }
+
+    fn notify_message(&self, alert: &Alert) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if self.focus.watching(&self.account, &alert.channel) == Some(true) {
+            return Ok(());
+        }
+        self.notifier.notify_message(alert)
+    }
+
+    fn configure(
+        &self,
+        channel: &Channel,
+        channel_name: &str,
+        args: &str,
+    ) -> Result<String, String> {
+        self.notifier.configure(channel, channel_name, args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Notifier counting its notifications.
+    struct CountingNotifier(Arc<AtomicUsize>);
+
+    impl Notification for CountingNotifier {
+        fn notify(&self, _: &str, _: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_focus_notifier() {
+        let focus = Arc::new(TerminalFocus::new());
+        let count = Arc::new(AtomicUsize::new(0));
+        let notifier = FocusNotifier::new(
+            "work",
+            focus.clone(),
+            Box::new(CountingNotifier(count.clone())),
+        );
+        let ops = Channel::Group("OPS".into());
+        let alert = |channel: &Channel| Alert {
+            channel: channel.clone(),
+            channel_name: "ops".into(),
+            author: "lou".into(),
+            content: "hi".into(),
+            datetime: Utc::now(),
+            mention: false,
+            highlight: false,
+            server_level: None,
+        };
+        let notified = |channel: &Channel| {
+            let before = count.load(Ordering::Relaxed);
+            notifier.notify_message(&alert(channel)).unwrap();
+            count.load(Ordering::Relaxed) > before
+        };
+
+        focus.set_active("work", &ops);
+        // The focus isn't known yet
+        assert_eq!(focus.watching("work", &ops), None);
+        assert!(notified(&ops));
+        focus.set_focused(true);
+        assert!(!notified(&ops));
+        assert!(notified(&Channel::Group("RANDOM".into())));
+        assert_eq!(focus.watching("home", &ops), Some(false));
+        focus.set_focused(false);
+        assert!(notified(&ops));
+    }
+}
@@ -0,0 +1,284 @@
+//! Hook notifications.
+//!
+//! This module runs a user command for each notification, with the message
+//! as JSON on its standard input, to forward it to a pager or a script.
+//! Commands run in the background and are killed after a timeout.
+use super::super::core::{Alert, Channel, Notification};
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::io::Write;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How often a running command is checked.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Hook settings.
+///
+/// This type contains the `[notifier.hook]` table of the configuration.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct HookConfig {
+    /// The program and its arguments, no shell is involved.
+    pub command: Vec<String>,
+    /// Seconds before the command is killed.
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    /// Commands running at the same time for all the accounts, the next
+    /// notifications are dropped.
+    #[serde(default = "default_max_running")]
+    pub max_running: usize,
+}
+
+fn default_timeout() -> u64 {
+    10
+}
+
+fn default_max_running() -> usize {
+    4
+}
+
+/// The JSON document written to the command.
+#[derive(Serialize, Debug, PartialEq)]
+struct HookMessage<'a> {
+    /// The channel name, none for notifications without a channel.
+    room: Option<&'a str>,
+    /// Whether the channel is a direct conversation.
+    direct: bool,
+    author: &'a str,
+    content: &'a str,
+    timestamp: DateTime<Utc>,
+    mention: bool,
+}
+
+impl<'a> From<&'a Alert> for HookMessage<'a> {
+    fn from(alert: &'a Alert) -> Self {
+        HookMessage {
+            room: Some(&alert.channel_name),
+            direct: matches!(alert.channel, Channel::User(_)),
+            author: &alert.author,
+            content: &alert.content,
+            timestamp: alert.datetime,
+            mention: alert.mention,
+        }
+    }
+}
+
+/// Decrement the number of running commands when dropped.
+struct RunningGuard(Arc<AtomicUsize>);
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Wait for a command, killing it after `timeout`.
+fn wait(mut child: Child, program: &str, timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) if !status.success() => {
+                warn!("The notification hook {} failed: {}", program, status);
+                return;
+            }
+            Ok(Some(_)) => return,
+            Ok(None) if Instant::now() >= deadline => {
+                warn!(
+                    "The notification hook {} timed out after {:?}",
+                    program, timeout
+                );
+                child.kill().ok();
+                child.wait().ok();
+                return;
+            }
+            Ok(None) => std::thread::sleep(POLL_INTERVAL),
+            Err(err) => {
+                warn!("Can't wait for the notification hook {}: {}", program, err);
+                return;
+            }
+        }
+    }
+}
+
+/// Hook notifier.
+///
+/// This type runs the command of a [HookConfig](struct.HookConfig.html)
+/// for each notification without waiting for it.
+pub struct HookNotifier {
+    command: Vec<String>,
+    timeout: Duration,
+    max_running: usize,
+    running: Arc<AtomicUsize>,
+}
+
+impl HookNotifier {
+    pub fn new(command: Vec<String>, timeout: Duration, max_running: usize) -> Self {
+        HookNotifier {
+            command,
+            timeout,
+            max_running,
+            running: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn from_config(config: &HookConfig) -> Self {
+        Self::new(
+            config.command.clone(),
+            Duration::from_secs(config.timeout),
+            config.max_running,
+        )
+    }
+
+    /// Start the command with `message` on its input.
+    fn run(&self, message: &HookMessage) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let (program, args) = self
+            .command
+            .split_first()
+            .ok_or("The notification hook has no command")?;
+        let max_running = self.max_running;
+        self.running
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| {
+                Some(x + 1).filter(|x| *x <= max_running)
+            })
+            .map_err(|_| {
+                format!(
+                    "Too many notification hooks running, dropping the notification of {}",
+                    message.author
+                )
+            })?;
+        let guard = RunningGuard(self.running.clone());
+        let mut input = serde_json::to_vec(message)?;
+        input.push(b'\n');
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|err| format!("Can't run the notification hook {}: {}", program, err))?;
+        let stdin = child.stdin.take();
+        let program = program.clone();
+        let timeout = self.timeout;
+        std::thread::Builder::new()
+            .name("hook".into())
+            .spawn(move || {
+                let _guard = guard;
+                // The command may not read its input, don't wait for it
+                if let Some(mut stdin) = stdin {
+                    std::thread::spawn(move || stdin.write_all(&input));
+                }
+                wait(child, &program, timeout);
+            })?;
+        Ok(())
+    }
+}
+
+impl Notification for HookNotifier {
+    fn notify(&self, title: &str, content: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.run(&HookMessage {
+            room: None,
+            direct: false,
+            author: title,
+            content,
+            timestamp: Utc::now(),
+            mention: false,
+        })
+    }
+
+    fn notify_message(&self, alert: &Alert) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.run(&HookMessage::from(alert))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn alert() -> Alert {
+        Alert {
+            channel: Channel::Group("OPS".into()),
+            channel_name: "ops".into(),
+            author: "lou".into(),
+            content: "@here the \"db\" is down".into(),
+            datetime: Utc.ymd(2021, 3, 4).and_hms(5, 6, 7),
+            mention: true,
+            highlight: false,
+            server_level: None,
+        }
+    }
+
+    /// Wait for the commands of a notifier to end.
+    fn wait_idle(notifier: &HookNotifier) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while notifier.running.load(Ordering::Relaxed) > 0 {
+            assert!(Instant::now() < deadline, "The hooks are still running");
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    #[test]
+    fn test_hook_config() {
+        let config: HookConfig =
+            toml::from_str(r#"command = ["notify-pager", "--team", "ops"]"#).unwrap();
+        assert_eq!(config.timeout, 10);
+        assert_eq!(config.max_running, 4);
+        assert!(toml::from_str::<HookConfig>("timeout = 3").is_err());
+    }
+
+    #[test]
+    fn test_hook_message() {
+        let alert = alert();
+        assert_eq!(
+            serde_json::to_string(&HookMessage::from(&alert)).unwrap(),
+            r#"{"room":"ops","direct":false,"author":"lou","content":"@here the \"db\" is down","timestamp":"2021-03-04T05:06:07Z","mention":true}"#
+        );
+    }
+
+    #[test]
+    fn test_hook_notifier() {
+        let path = std::env::temp_dir().join("talkoxid_test_hook_notifier.json");
+        std::fs::remove_file(&path).ok();
+        let notifier = HookNotifier::new(
+            vec![
+                "sh".into(),
+                "-c".into(),
+                format!("cat > {}", path.display()),
+            ],
+            Duration::from_secs(5),
+            1,
+        );
+        notifier.notify_message(&alert()).unwrap();
+        wait_idle(&notifier);
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert!(written.starts_with(r#"{"room":"ops","#));
+        assert!(written.ends_with("}\n"));
+        std::fs::remove_file(&path).unwrap();
+
+        let missing = HookNotifier::new(vec!["/nonexistent/hook".into()], POLL_INTERVAL, 1);
+        assert!(missing.notify("lou", "hi").is_err());
+        assert_eq!(missing.running.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_hook_limits() {
+        let notifier = HookNotifier::new(
+            vec!["sleep".into(), "30".into()],
+            Duration::from_millis(200),
+            1,
+        );
+        let start = Instant::now();
+        notifier.notify("lou", "hi").unwrap();
+        // The first command is still running
+        assert!(notifier.notify("lou", "hi again").is_err());
+        wait_idle(&notifier);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        notifier.notify("lou", "hi at last").unwrap();
+        wait_idle(&notifier);
+    }
+}
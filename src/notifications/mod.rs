@@ -1,28 +1,446 @@
-use super::core::Notification;
-use rodio::Source;
+mod coalesce;
+mod focus;
+mod hook;
+mod rules;
+mod sound;
+mod terminal;
+
+pub use coalesce::CoalescingNotifier;
+pub use focus::{FocusNotifier, TerminalFocus};
+pub use hook::{HookConfig, HookNotifier};
+pub use rules::{NotificationRules, QuietHours, RuleNotifier};
+pub use sound::{SoundConfig, SoundEvent, SoundPlayer};
+pub use terminal::{TerminalNotifier, TerminalStyle};
+
+use super::core::{Alert, Channel, Notification};
+use log::warn;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
 use std::error::Error;
-use std::fs::File;
-use std::io::BufReader;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
-pub struct DesktopNotifier;
+/// Read a table of a configuration file.
+///
+/// A missing file or table gives the default value, an invalid table is
+/// reported and ignored. Invalid files are reported when the accounts are
+/// loaded.
+fn read_table<T: DeserializeOwned + Default>(path: &Path, name: &str) -> T {
+    let table = std::fs::read_to_string(path)
+        .ok()
+        .and_then(|x| toml::from_str::<toml::Value>(&x).ok())
+        .and_then(|x| x.get(name).cloned());
+    match table {
+        Some(table) => table.try_into().unwrap_or_else(|err| {
+            warn!(
+                "Ignoring the [{}] table of {}: {}",
+                name,
+                path.display(),
+                err
+            );
+            T::default()
+        }),
+        None => T::default(),
+    }
+}
 
-impl Notification for DesktopNotifier {
+/// Where notifications are shown.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum NotifierBackend {
+    /// The desktop, then the terminal when there is no notification daemon.
+    #[default]
+    Auto,
+    Desktop,
+    /// The terminal, with the style it supports.
+    Terminal,
+    Bell,
+    Osc9,
+    Osc777,
+    Tmux,
+    /// The command of the `[notifier.hook]` table.
+    Hook,
+}
+
+/// Notifier settings.
+///
+/// This type contains the `[notifier]` table of the configuration.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct NotifierConfig {
+    #[serde(default)]
+    pub backend: NotifierBackend,
+    /// Seconds during which the messages of a channel are summed up in one
+    /// notification, none when zero.
+    #[serde(default = "default_coalesce_window")]
+    pub coalesce_window: u64,
+    /// Notifications shown per minute, unlimited when zero.
+    #[serde(default = "default_max_per_minute")]
+    pub max_per_minute: usize,
+    pub hook: Option<HookConfig>,
+}
+
+fn default_coalesce_window() -> u64 {
+    5
+}
+
+fn default_max_per_minute() -> usize {
+    12
+}
+
+impl Default for NotifierConfig {
+    fn default() -> Self {
+        NotifierConfig {
+            backend: NotifierBackend::default(),
+            coalesce_window: default_coalesce_window(),
+            max_per_minute: default_max_per_minute(),
+            hook: None,
+        }
+    }
+}
+
+impl NotifierConfig {
+    /// Read the `[notifier]` table of a configuration file.
+    ///
+    /// The hook backend needs a command, the default backend is used
+    /// without one.
+    pub fn from_file(path: &Path) -> Self {
+        let config: NotifierConfig = read_table(path, "notifier");
+        let command = config.hook.as_ref().map_or(0, |x| x.command.len());
+        if config.backend == NotifierBackend::Hook && command == 0 {
+            warn!(
+                "The hook backend of {} has no command, expected a [notifier.hook] table",
+                path.display()
+            );
+            return NotifierConfig {
+                backend: NotifierBackend::default(),
+                ..config
+            };
+        }
+        config
+    }
+}
+
+/// The notifier of a configuration, sounds are played with `sounds`.
+///
+/// It is built once and shared by the accounts, so that they share the
+/// notification rate and the running hooks.
+pub fn build_notifier(
+    config: &NotifierConfig,
+    sounds: Arc<SoundPlayer>,
+) -> Arc<CoalescingNotifier> {
+    Arc::new(CoalescingNotifier::new(
+        Duration::from_secs(config.coalesce_window),
+        config.max_per_minute,
+        build_backend(config, sounds),
+    ))
+}
+
+fn build_backend(
+    config: &NotifierConfig,
+    sounds: Arc<SoundPlayer>,
+) -> Box<dyn Notification + Send + Sync> {
+    let terminal = |style| Box::new(TerminalNotifier::new(style));
+    match config.backend {
+        NotifierBackend::Auto => Box::new(FallbackNotifier::new(vec![
+            Box::new(DesktopNotifier::new(sounds)),
+            terminal(TerminalStyle::detect()),
+        ])),
+        NotifierBackend::Desktop => Box::new(DesktopNotifier::new(sounds)),
+        NotifierBackend::Terminal => terminal(TerminalStyle::detect()),
+        NotifierBackend::Bell => terminal(TerminalStyle::Bell),
+        NotifierBackend::Osc9 => terminal(TerminalStyle::Osc9),
+        NotifierBackend::Osc777 => terminal(TerminalStyle::Osc777),
+        NotifierBackend::Tmux => terminal(TerminalStyle::Tmux),
+        NotifierBackend::Hook => match config.hook.as_ref() {
+            Some(hook) => Box::new(HookNotifier::from_config(hook)),
+            None => build_backend(&NotifierConfig::default(), sounds),
+        },
+    }
+}
+
+/// Notifier with fallbacks.
+///
+/// This type notifies with the first of its notifiers. Once a notifier
+/// fails, the next one is used from then on.
+pub struct FallbackNotifier {
+    notifiers: Vec<Box<dyn Notification + Send + Sync>>,
+    /// The notifier in use.
+    current: AtomicUsize,
+}
+
+impl FallbackNotifier {
+    pub fn new(notifiers: Vec<Box<dyn Notification + Send + Sync>>) -> Self {
+        FallbackNotifier {
+            notifiers,
+            current: AtomicUsize::new(0),
+        }
+    }
+
+    fn send<F>(&self, send: F) -> Result<(), Box<dyn Error + Send + Sync>>
+    where
+        F: Fn(&dyn Notification) -> Result<(), Box<dyn Error + Send + Sync>>,
+    {
+        let mut index = self.current.load(Ordering::Relaxed);
+        loop {
+            let notifier = self.notifiers.get(index).ok_or("No notifier")?;
+            match send(notifier.as_ref()) {
+                Err(err) if index + 1 < self.notifiers.len() => {
+                    warn!("Notification failed, using the next notifier: {}", err);
+                    index += 1;
+                    self.current.store(index, Ordering::Relaxed);
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl Notification for FallbackNotifier {
     fn notify(&self, title: &str, content: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.send(|x| x.notify(title, content))
+    }
+
+    fn notify_message(&self, alert: &Alert) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.send(|x| x.notify_message(alert))
+    }
+
+    /// The answer of the first notifier knowing the command.
+    fn configure(
+        &self,
+        channel: &Channel,
+        channel_name: &str,
+        args: &str,
+    ) -> Result<String, String> {
+        let mut answer = Err("No notifier".to_string());
+        for notifier in self.notifiers.iter() {
+            answer = notifier.configure(channel, channel_name, args);
+            if answer.is_ok() {
+                break;
+            }
+        }
+        answer
+    }
+}
+
+/// The sound of an alert.
+fn sound_event(alert: &Alert) -> SoundEvent {
+    match alert.channel {
+        Channel::User(_) => SoundEvent::Direct,
+        _ if alert.mention => SoundEvent::Mention,
+        _ if alert.highlight => SoundEvent::Highlight,
+        _ => SoundEvent::Message,
+    }
+}
+
+/// Desktop notifier.
+///
+/// This type shows notifications through D-Bus and plays their sounds
+/// with a player shared by every account.
+pub struct DesktopNotifier {
+    sounds: Arc<SoundPlayer>,
+}
+
+impl DesktopNotifier {
+    pub fn new(sounds: Arc<SoundPlayer>) -> Self {
+        DesktopNotifier { sounds }
+    }
+
+    fn show(&self, title: &str, content: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
         notify_rust::Notification::new()
-            .summary(&title[..])
-            .body(&content[..])
+            .summary(title)
+            .body(content)
             .timeout(20000)
             .show()?;
-        let (_stream, stream_handle) = rodio::OutputStream::try_default()?;
-        let maybe_file =
-            File::open("/usr/share/sounds/freedesktop/stereo/message-new-instant.ogaa");
-        if let Ok(file) = maybe_file {
-            let maybe_source = rodio::Decoder::new(BufReader::new(file));
-            if let Ok(source) = maybe_source {
-                stream_handle.play_raw(source.convert_samples())?;
+        Ok(())
+    }
+}
+
+impl Notification for DesktopNotifier {
+    fn notify(&self, title: &str, content: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.sounds.play(SoundEvent::Message);
+        self.show(title, content)
+    }
+
+    fn notify_message(&self, alert: &Alert) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.sounds.play(sound_event(alert));
+        self.show(&alert.author, &alert.content)
+    }
+
+    /// Mute or unmute the sounds of every account.
+    fn configure(
+        &self,
+        _channel: &Channel,
+        _channel_name: &str,
+        args: &str,
+    ) -> Result<String, String> {
+        let muted = match args.split_whitespace().collect::<Vec<&str>>()[..] {
+            ["sound"] => !self.sounds.is_muted(),
+            ["sound", "on"] => false,
+            ["sound", "off"] => true,
+            _ => return Err("Usage: /notify sound [on|off]".into()),
+        };
+        self.sounds.set_muted(muted);
+        match muted {
+            true => Ok("Sounds muted".into()),
+            false => Ok("Sounds enabled".into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    #[test]
+    fn test_sound_event() {
+        let alert = Alert {
+            channel: Channel::Group("GENERAL".into()),
+            channel_name: "general".into(),
+            author: "lou".into(),
+            content: "deploy done".into(),
+            datetime: Utc::now(),
+            mention: false,
+            highlight: true,
+            server_level: None,
+        };
+        assert_eq!(sound_event(&alert), SoundEvent::Highlight);
+        let mention = Alert {
+            mention: true,
+            ..alert.clone()
+        };
+        assert_eq!(sound_event(&mention), SoundEvent::Mention);
+        let direct = Alert {
+            channel: Channel::User("lou".into()),
+            ..mention
+        };
+        assert_eq!(sound_event(&direct), SoundEvent::Direct);
+    }
+
+    #[test]
+    fn test_sound_command() {
+        let sounds = Arc::new(SoundPlayer::new(SoundConfig::default()));
+        let notifier = RuleNotifier::new(
+            NotificationRules::default(),
+            Box::new(DesktopNotifier::new(sounds.clone())),
+        );
+        let general = Channel::Group("GENERAL".into());
+        assert_eq!(
+            notifier.configure(&general, "general", "sound off"),
+            Ok("Sounds muted".into())
+        );
+        assert!(sounds.is_muted());
+        assert_eq!(
+            notifier.configure(&general, "general", "sound"),
+            Ok("Sounds enabled".into())
+        );
+        assert!(!sounds.is_muted());
+        assert!(notifier
+            .configure(&general, "general", "sound loud")
+            .is_err());
+    }
+
+    /// Notifier counting its notifications, failing if `fail` is set.
+    struct CountingNotifier {
+        fail: bool,
+        count: Arc<AtomicUsize>,
+    }
+
+    impl Notification for CountingNotifier {
+        fn notify(&self, _: &str, _: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+            self.count.fetch_add(1, Ordering::Relaxed);
+            if self.fail {
+                return Err("No notification daemon".into());
             }
+            Ok(())
         }
+    }
 
-        Ok(())
+    #[test]
+    fn test_fallback_notifier() {
+        let desktop = Arc::new(AtomicUsize::new(0));
+        let terminal = Arc::new(AtomicUsize::new(0));
+        let notifier = FallbackNotifier::new(vec![
+            Box::new(CountingNotifier {
+                fail: true,
+                count: desktop.clone(),
+            }),
+            Box::new(CountingNotifier {
+                fail: false,
+                count: terminal.clone(),
+            }),
+        ]);
+        notifier.notify("lou", "hi").unwrap();
+        notifier.notify("lou", "hi again").unwrap();
+        // The failing notifier isn't tried again
+        assert_eq!(desktop.load(Ordering::Relaxed), 1);
+        assert_eq!(terminal.load(Ordering::Relaxed), 2);
+
+        let failing = FallbackNotifier::new(vec![Box::new(CountingNotifier {
+            fail: true,
+            count: desktop,
+        })]);
+        assert!(failing.notify("lou", "hi").is_err());
+    }
+
+    #[test]
+    fn test_notifier_config() {
+        let config: NotifierConfig = toml::from_str(r#"backend = "osc777""#).unwrap();
+        assert_eq!(config.backend, NotifierBackend::Osc777);
+        assert_eq!(config.coalesce_window, 5);
+        assert_eq!(config.max_per_minute, 12);
+        assert!(toml::from_str::<NotifierConfig>(r#"backend = "pager""#).is_err());
+        let config: NotifierConfig = toml::from_str(
+            r#"
+            backend = "hook"
+
+            [hook]
+            command = ["notify-pager", "--team", "ops"]
+            max_running = 2
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.backend, NotifierBackend::Hook);
+        assert_eq!(config.hook.unwrap().max_running, 2);
+        assert_eq!(
+            NotifierConfig::from_file(Path::new("/nonexistent/talkoxid.toml")),
+            NotifierConfig::default()
+        );
+    }
+
+    #[test]
+    fn test_shared_hook_limit() {
+        let config = NotifierConfig {
+            backend: NotifierBackend::Hook,
+            coalesce_window: 0,
+            max_per_minute: 0,
+            hook: Some(HookConfig {
+                command: vec!["sleep".into(), "1".into()],
+                timeout: 5,
+                max_running: 1,
+            }),
+        };
+        let sounds = Arc::new(SoundPlayer::new(SoundConfig {
+            mute: true,
+            ..SoundConfig::default()
+        }));
+        let notifier = build_notifier(&config, sounds);
+        let (work, home) = (notifier.account("work"), notifier.account("home"));
+        let alert = Alert {
+            channel: Channel::Group("GENERAL".into()),
+            channel_name: "general".into(),
+            author: "lou".into(),
+            content: "deploy done".into(),
+            datetime: Utc::now(),
+            mention: false,
+            highlight: false,
+            server_level: None,
+        };
+        work.notify_message(&alert).unwrap();
+        // The hook of the first account is still running
+        assert!(home.notify_message(&alert).is_err());
     }
 }
@@ -0,0 +1,382 @@
+//! Notification rules.
+//!
+//! This module decides which received messages are notified, from the
+//! `[notifications]` table of the configuration, the `/notify` commands and
+//! the levels chosen on the server.
+use super::super::core::{Alert, Channel, Notification, NotificationLevel};
+use chrono::{Local, NaiveTime, Timelike};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// A daily time range without notifications, like `22:00-07:30`.
+///
+/// The range ends the next day when it ends before it starts.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub struct QuietHours {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl QuietHours {
+    /// Whether `time` is in the range.
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+}
+
+impl FromStr for QuietHours {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid quiet hours {}, expected HH:MM-HH:MM", s);
+        let (start, end) = s.split_once('-').ok_or_else(invalid)?;
+        let time = |x: &str| NaiveTime::parse_from_str(x.trim(), "%H:%M").map_err(|_| invalid());
+        Ok(QuietHours {
+            start: time(start)?,
+            end: time(end)?,
+        })
+    }
+}
+
+impl TryFrom<String> for QuietHours {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for QuietHours {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start.hour(),
+            self.start.minute(),
+            self.end.hour(),
+            self.end.minute()
+        )
+    }
+}
+
+impl From<QuietHours> for String {
+    fn from(quiet_hours: QuietHours) -> Self {
+        quiet_hours.to_string()
+    }
+}
+
+/// Notification rules.
+///
+/// This type contains the `[notifications]` table of the configuration.
+/// Direct messages are notified unless their channel has a level.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct NotificationRules {
+    /// The level of the channels without one.
+    #[serde(default)]
+    pub level: NotificationLevel,
+    /// Levels by channel name or id.
+    #[serde(default)]
+    pub channels: BTreeMap<String, NotificationLevel>,
+    /// Words notified like mentions, whatever their case.
+    #[serde(default)]
+    pub keywords: Vec<String>,
+    /// Users never notified.
+    #[serde(default)]
+    pub ignored_users: Vec<String>,
+    pub quiet_hours: Option<QuietHours>,
+}
+
+impl NotificationRules {
+    /// The key of `channels` matching a channel, with or without `#`.
+    fn channel_key(&self, channel: &Channel, channel_name: &str) -> Option<&String> {
+        let id = format!("{}", channel);
+        let name = channel_name.trim_start_matches('#');
+        self.channels.keys().find(|x| {
+            let key = x.trim_start_matches('#');
+            key == name || key == id
+        })
+    }
+
+    /// The level of the channel of an alert.
+    ///
+    /// The configured level comes first, then the one of the server.
+    pub fn level(&self, alert: &Alert) -> NotificationLevel {
+        if let Some(key) = self.channel_key(&alert.channel, &alert.channel_name) {
+            return self.channels[key];
+        }
+        match (alert.server_level, &alert.channel) {
+            (Some(level), _) => level,
+            (None, Channel::User(_)) => NotificationLevel::All,
+            (None, _) => self.level,
+        }
+    }
+
+    /// Whether a keyword is in the content of an alert.
+    pub fn highlights(&self, alert: &Alert) -> bool {
+        let content = alert.content.to_lowercase();
+        self.keywords
+            .iter()
+            .any(|x| content.contains(&x.to_lowercase()))
+    }
+
+    /// Whether an alert received at `time` is notified.
+    pub fn allows(&self, alert: &Alert, time: NaiveTime) -> bool {
+        if self.ignored_users.iter().any(|x| x == &alert.author) {
+            return false;
+        }
+        if self.quiet_hours.is_some_and(|x| x.contains(time)) {
+            return false;
+        }
+        match self.level(alert) {
+            NotificationLevel::All => true,
+            NotificationLevel::Mentions => alert.mention || self.highlights(alert),
+            NotificationLevel::None => false,
+        }
+    }
+
+    /// Describe the rules applying to a channel.
+    fn describe(&self, channel: &Channel, channel_name: &str) -> String {
+        let level = match self.channel_key(channel, channel_name) {
+            Some(key) => format!("{}", self.channels[key]),
+            None => format!("{} (default)", self.level),
+        };
+        let list = |x: &[String]| match x.is_empty() {
+            true => "none".to_string(),
+            false => x.join(", "),
+        };
+        let quiet_hours = self
+            .quiet_hours
+            .map(|x| x.to_string())
+            .unwrap_or_else(|| "none".into());
+        format!(
+            "Notifications in {}: {}. Keywords: {}. Ignored users: {}. Quiet hours: {}.",
+            channel_name,
+            level,
+            list(&self.keywords),
+            list(&self.ignored_users),
+            quiet_hours
+        )
+    }
+
+    /// Change the rules with the arguments of a `/notify` command typed in
+    /// a channel, the answer describes the new rules.
+    pub fn apply(
+        &mut self,
+        channel: &Channel,
+        channel_name: &str,
+        args: &str,
+    ) -> Result<String, String> {
+        let args = args.split_whitespace().collect::<Vec<&str>>();
+        match args[..] {
+            [] => {}
+            ["default"] => {
+                if let Some(key) = self.channel_key(channel, channel_name).cloned() {
+                    self.channels.remove(&key);
+                }
+            }
+            [level] if level.parse::<NotificationLevel>().is_ok() => {
+                if let Some(key) = self.channel_key(channel, channel_name).cloned() {
+                    self.channels.remove(&key);
+                }
+                self.channels
+                    .insert(channel_name.to_string(), level.parse()?);
+            }
+            ["keyword", "add", keyword] => {
+                if !self.keywords.iter().any(|x| x == keyword) {
+                    self.keywords.push(keyword.to_string());
+                }
+            }
+            ["keyword", "remove", keyword] => self.keywords.retain(|x| x != keyword),
+            ["ignore", user] => {
+                if !self.ignored_users.iter().any(|x| x == user) {
+                    self.ignored_users.push(user.to_string());
+                }
+            }
+            ["unignore", user] => self.ignored_users.retain(|x| x != user),
+            ["quiet", "off"] => self.quiet_hours = None,
+            ["quiet", range] => self.quiet_hours = Some(range.parse()?),
+            _ => {
+                return Err(
+                    "Usage: /notify [all|mentions|none|default], /notify keyword add|remove WORD, \
+                     /notify ignore|unignore USER, /notify quiet HH:MM-HH:MM|off, \
+                     /notify sound [on|off]"
+                        .into(),
+                )
+            }
+        }
+        Ok(self.describe(channel, channel_name))
+    }
+}
+
+/// Notifier applying rules.
+///
+/// This type forwards the alerts allowed by its rules to another notifier.
+/// The rules can be changed with `/notify` until the program exits.
+pub struct RuleNotifier {
+    rules: Mutex<NotificationRules>,
+    notifier: Box<dyn Notification + Send + Sync>,
+}
+
+impl RuleNotifier {
+    pub fn new(rules: NotificationRules, notifier: Box<dyn Notification + Send + Sync>) -> Self {
+        RuleNotifier {
+            rules: Mutex::new(rules),
+            notifier,
+        }
+    }
+}
+
+impl Notification for RuleNotifier {
+    fn notify(&self, title: &str, content: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.notifier.notify(title, content)
+    }
+
+    fn notify_message(&self, alert: &Alert) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let alert = {
+            let rules = self.rules.lock().unwrap();
+            if !rules.allows(alert, Local::now().time()) {
+                return Ok(());
+            }
+            Alert {
+                highlight: rules.highlights(alert),
+                ..alert.clone()
+            }
+        };
+        self.notifier.notify_message(&alert)
+    }
+
+    /// Change the rules, `sound` commands go to the next notifier.
+    fn configure(
+        &self,
+        channel: &Channel,
+        channel_name: &str,
+        args: &str,
+    ) -> Result<String, String> {
+        match args.split_whitespace().next() {
+            Some("sound") => self.notifier.configure(channel, channel_name, args),
+            _ => self
+                .rules
+                .lock()
+                .unwrap()
+                .apply(channel, channel_name, args),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn alert(channel: Channel, author: &str, content: &str, mention: bool) -> Alert {
+        Alert {
+            channel_name: format!("{}", channel).to_lowercase(),
+            channel,
+            author: author.into(),
+            content: content.into(),
+            datetime: Utc::now(),
+            mention,
+            highlight: false,
+            server_level: None,
+        }
+    }
+
+    fn time(s: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(s, "%H:%M").unwrap()
+    }
+
+    #[test]
+    fn test_quiet_hours() {
+        let night: QuietHours = "22:00-07:30".parse().unwrap();
+        assert!(night.contains(time("23:15")));
+        assert!(night.contains(time("03:00")));
+        assert!(!night.contains(time("07:30")));
+        assert!(!night.contains(time("12:00")));
+        let lunch: QuietHours = "12:00-13:00".parse().unwrap();
+        assert!(lunch.contains(time("12:30")));
+        assert!(!lunch.contains(time("13:30")));
+        assert_eq!(night.to_string(), "22:00-07:30");
+        assert_eq!(
+            "22h-7h".parse::<QuietHours>(),
+            Err("Invalid quiet hours 22h-7h, expected HH:MM-HH:MM".into())
+        );
+    }
+
+    #[test]
+    fn test_allows() {
+        let rules: NotificationRules = toml::from_str(
+            r##"
+            level = "mentions"
+            keywords = ["Deploy"]
+            ignored_users = ["bot"]
+            quiet_hours = "22:00-07:00"
+
+            [channels]
+            "#random" = "none"
+            ops = "all"
+            "##,
+        )
+        .unwrap();
+        let noon = time("12:00");
+        let general = Channel::Group("GENERAL".into());
+        assert!(!rules.allows(&alert(general.clone(), "lou", "hello", false), noon));
+        assert!(rules.allows(&alert(general.clone(), "lou", "hello me", true), noon));
+        assert!(rules.allows(&alert(general.clone(), "lou", "deploy done", false), noon));
+        assert!(!rules.allows(&alert(general, "bot", "hello me", true), noon));
+        let random = Channel::Group("RANDOM".into());
+        assert!(!rules.allows(&alert(random, "lou", "hello me", true), noon));
+        assert!(rules.allows(
+            &alert(Channel::Group("ops".into()), "lou", "hi", false),
+            noon
+        ));
+        let direct = alert(Channel::User("lou".into()), "lou", "hi", false);
+        assert!(rules.allows(&direct, noon));
+        assert!(!rules.allows(&direct, time("23:00")));
+        let muted = Alert {
+            server_level: Some(NotificationLevel::None),
+            ..direct
+        };
+        assert!(!rules.allows(&muted, noon));
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut rules = NotificationRules::default();
+        let general = Channel::Group("GENERAL".into());
+        assert_eq!(
+            rules.apply(&general, "general", "all"),
+            Ok("Notifications in general: all. Keywords: none. Ignored users: none. Quiet hours: none.".into())
+        );
+        rules
+            .apply(&general, "general", "keyword add deploy")
+            .unwrap();
+        rules.apply(&general, "general", "ignore bot").unwrap();
+        rules
+            .apply(&general, "general", "quiet 22:00-07:00")
+            .unwrap();
+        assert_eq!(
+            rules.apply(&general, "general", ""),
+            Ok("Notifications in general: all. Keywords: deploy. Ignored users: bot. Quiet hours: 22:00-07:00.".into())
+        );
+        rules.apply(&general, "general", "default").unwrap();
+        rules.apply(&general, "general", "unignore bot").unwrap();
+        rules.apply(&general, "general", "quiet off").unwrap();
+        assert_eq!(
+            rules.apply(&general, "general", ""),
+            Ok("Notifications in general: mentions (default). Keywords: deploy. Ignored users: none. Quiet hours: none.".into())
+        );
+        assert!(rules.apply(&general, "general", "loud").is_err());
+        assert!(rules.apply(&general, "general", "quiet later").is_err());
+    }
+}
@@ -0,0 +1,256 @@
+//! Notification sounds.
+//!
+//! This module plays the sounds of the notifications on a worker thread
+//! which keeps the audio output open, so that sounds aren't cut off and
+//! never block the chats.
+use super::read_table;
+use log::{error, warn};
+use serde::Deserialize;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::Mutex;
+
+/// Sound of the freedesktop theme used when none is configured.
+const DEFAULT_SOUND: &str = "/usr/share/sounds/freedesktop/stereo/message-new-instant.oga";
+
+/// Sounds waiting to be played, the next ones are dropped.
+const QUEUE_SIZE: usize = 8;
+
+/// What a notified message is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SoundEvent {
+    Direct,
+    Mention,
+    /// A keyword of the notification rules matched.
+    Highlight,
+    /// Any other notified message.
+    Message,
+}
+
+/// Sound settings.
+///
+/// This type contains the `[sounds]` table of the configuration. A sound
+/// set to an empty string is disabled.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SoundConfig {
+    #[serde(default)]
+    pub mute: bool,
+    /// From 0 to 1.
+    #[serde(default = "default_volume")]
+    pub volume: f32,
+    pub direct: Option<PathBuf>,
+    pub mention: Option<PathBuf>,
+    pub highlight: Option<PathBuf>,
+    pub message: Option<PathBuf>,
+}
+
+fn default_volume() -> f32 {
+    1.0
+}
+
+impl Default for SoundConfig {
+    fn default() -> Self {
+        SoundConfig {
+            mute: false,
+            volume: default_volume(),
+            direct: None,
+            mention: None,
+            highlight: None,
+            message: None,
+        }
+    }
+}
+
+impl SoundConfig {
+    /// Read the `[sounds]` table of a configuration file.
+    ///
+    /// A missing file or table gives the default sounds, an invalid table
+    /// is reported and ignored.
+    pub fn from_file(path: &Path) -> Self {
+        let config: SoundConfig = read_table(path, "sounds");
+        if !(0.0..=1.0).contains(&config.volume) {
+            warn!(
+                "Invalid volume {} in {}, expected a number between 0 and 1",
+                config.volume,
+                path.display()
+            );
+            return SoundConfig {
+                volume: default_volume(),
+                ..config
+            };
+        }
+        config
+    }
+
+    /// The file played for an event, if any.
+    pub fn sound(&self, event: SoundEvent) -> Option<PathBuf> {
+        let sound = match event {
+            SoundEvent::Direct => &self.direct,
+            SoundEvent::Mention => &self.mention,
+            SoundEvent::Highlight => &self.highlight,
+            SoundEvent::Message => &self.message,
+        };
+        match sound {
+            Some(path) if path.as_os_str().is_empty() => None,
+            Some(path) => Some(path.clone()),
+            None => Some(PathBuf::from(DEFAULT_SOUND)),
+        }
+    }
+}
+
+/// Play sounds until every sender is dropped.
+///
+/// The output is opened with the first playable sound, and again after a
+/// failure.
+fn audio_worker(rx: Receiver<(PathBuf, f32)>) {
+    let mut output: Option<(rodio::OutputStream, rodio::OutputStreamHandle)> = None;
+    let mut warned = false;
+    for (path, volume) in rx {
+        let source = File::open(&path)
+            .map_err(|err| err.to_string())
+            .and_then(|x| rodio::Decoder::new(BufReader::new(x)).map_err(|err| err.to_string()));
+        let source = match source {
+            Ok(source) => source,
+            Err(err) => {
+                warn!("Can't play {}: {}", path.display(), err);
+                continue;
+            }
+        };
+        if output.is_none() {
+            match rodio::OutputStream::try_default() {
+                Ok(stream) => output = Some(stream),
+                Err(err) => {
+                    if !warned {
+                        warn!("Can't open the audio output: {}", err);
+                        warned = true;
+                    }
+                    continue;
+                }
+            }
+        }
+        let handle = match output.as_ref() {
+            Some((_, handle)) => handle,
+            None => continue,
+        };
+        match rodio::Sink::try_new(handle) {
+            Ok(sink) => {
+                sink.set_volume(volume);
+                sink.append(source);
+                sink.detach();
+            }
+            Err(err) => {
+                error!("Can't play {}: {}", path.display(), err);
+                output = None;
+            }
+        }
+    }
+}
+
+/// Sound player.
+///
+/// This type sends the sounds to an audio worker thread, sounds are dropped
+/// when the worker is busy.
+pub struct SoundPlayer {
+    config: SoundConfig,
+    muted: AtomicBool,
+    tx: Mutex<Option<SyncSender<(PathBuf, f32)>>>,
+}
+
+impl SoundPlayer {
+    pub fn new(config: SoundConfig) -> Self {
+        let (tx, rx) = sync_channel(QUEUE_SIZE);
+        let tx = match std::thread::Builder::new()
+            .name("audio".into())
+            .spawn(move || audio_worker(rx))
+        {
+            Ok(_) => Some(tx),
+            Err(err) => {
+                error!("Can't start the audio worker: {}", err);
+                None
+            }
+        };
+        SoundPlayer {
+            muted: AtomicBool::new(config.mute),
+            config,
+            tx: Mutex::new(tx),
+        }
+    }
+
+    /// Play the sound of an event, unless muted.
+    pub fn play(&self, event: SoundEvent) {
+        if self.is_muted() {
+            return;
+        }
+        let path = match self.config.sound(event) {
+            Some(path) => path,
+            None => return,
+        };
+        let mut tx = self.tx.lock().unwrap();
+        let sent = match tx.as_ref() {
+            Some(tx) => tx.try_send((path, self.config.volume)),
+            None => return,
+        };
+        match sent {
+            Ok(_) | Err(TrySendError::Full(_)) => {}
+            Err(TrySendError::Disconnected(_)) => {
+                error!("The audio worker stopped");
+                *tx = None;
+            }
+        }
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.muted.load(Ordering::Relaxed)
+    }
+
+    pub fn set_muted(&self, muted: bool) {
+        self.muted.store(muted, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sound_config() {
+        let config: SoundConfig = toml::from_str(
+            r#"
+            volume = 0.5
+            direct = "/home/lou/sounds/direct.ogg"
+            highlight = ""
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.volume, 0.5);
+        assert!(!config.mute);
+        assert_eq!(
+            config.sound(SoundEvent::Direct),
+            Some("/home/lou/sounds/direct.ogg".into())
+        );
+        assert_eq!(
+            config.sound(SoundEvent::Mention),
+            Some(DEFAULT_SOUND.into())
+        );
+        assert_eq!(config.sound(SoundEvent::Highlight), None);
+    }
+
+    #[test]
+    fn test_muted_player() {
+        let player = SoundPlayer::new(SoundConfig {
+            mute: true,
+            message: Some("/nonexistent/message.oga".into()),
+            ..SoundConfig::default()
+        });
+        assert!(player.is_muted());
+        player.play(SoundEvent::Direct);
+        player.set_muted(false);
+        // A missing file is only logged by the worker
+        player.play(SoundEvent::Message);
+        assert!(!player.is_muted());
+    }
+}
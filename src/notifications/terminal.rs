@@ -0,0 +1,164 @@
+//! Terminal notifications.
+//!
+//! This module notifies through the terminal when there is no desktop
+//! notification daemon, like over SSH: a bell, an OSC 9 or OSC 777
+//! notification, or a tmux message.
+use super::super::core::Notification;
+use std::error::Error;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// Longest notification text sent to the terminal.
+const MAX_LENGTH: usize = 200;
+
+/// How the terminal is notified.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TerminalStyle {
+    Bell,
+    /// `ESC ] 9 ; text BEL`, understood by iTerm2, WezTerm and Windows Terminal.
+    Osc9,
+    /// `ESC ] 777 ; notify ; title ; body BEL`, understood by VTE terminals,
+    /// foot and urxvt.
+    Osc777,
+    /// `tmux display-message`.
+    Tmux,
+}
+
+impl TerminalStyle {
+    /// The style supported by the current terminal.
+    pub fn detect() -> Self {
+        Self::from_vars(|x| std::env::var(x).ok())
+    }
+
+    fn from_vars<F: Fn(&str) -> Option<String>>(var: F) -> Self {
+        let term = var("TERM").unwrap_or_default();
+        if var("TMUX").is_some() {
+            TerminalStyle::Tmux
+        } else if matches!(
+            var("TERM_PROGRAM").as_deref(),
+            Some("iTerm.app") | Some("WezTerm")
+        ) || var("WT_SESSION").is_some()
+        {
+            TerminalStyle::Osc9
+        } else if var("VTE_VERSION").is_some()
+            || term.starts_with("foot")
+            || term.starts_with("rxvt")
+        {
+            TerminalStyle::Osc777
+        } else {
+            TerminalStyle::Bell
+        }
+    }
+
+    /// The escape sequence of a notification, tmux isn't notified by one.
+    fn sequence(&self, title: &str, content: &str) -> Option<String> {
+        match self {
+            TerminalStyle::Bell => Some("\x07".into()),
+            TerminalStyle::Osc9 => Some(format!(
+                "\x1b]9;{}\x07",
+                sanitize(&format!("{}: {}", title, content))
+            )),
+            TerminalStyle::Osc777 => Some(format!(
+                "\x1b]777;notify;{};{}\x07",
+                sanitize(title).replace(';', ","),
+                sanitize(content)
+            )),
+            TerminalStyle::Tmux => None,
+        }
+    }
+}
+
+/// The text without control characters, which could end the escape
+/// sequence and write to the terminal, and shortened.
+fn sanitize(text: &str) -> String {
+    text.chars()
+        .map(|x| if x.is_control() { ' ' } else { x })
+        .take(MAX_LENGTH)
+        .collect()
+}
+
+/// Terminal notifier.
+///
+/// This type writes the notifications to the controlling terminal, or
+/// asks tmux to show them.
+pub struct TerminalNotifier {
+    style: TerminalStyle,
+}
+
+impl TerminalNotifier {
+    pub fn new(style: TerminalStyle) -> Self {
+        TerminalNotifier { style }
+    }
+}
+
+impl Notification for TerminalNotifier {
+    fn notify(&self, title: &str, content: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match self.style.sequence(title, content) {
+            Some(sequence) => {
+                let mut tty = OpenOptions::new().write(true).open("/dev/tty")?;
+                tty.write_all(sequence.as_bytes())?;
+                tty.flush()?;
+            }
+            None => {
+                let status = Command::new("tmux")
+                    .arg("display-message")
+                    .arg("--")
+                    .arg(sanitize(&format!("{}: {}", title, content)).replace('#', "##"))
+                    .stdin(Stdio::null())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null())
+                    .status()?;
+                if !status.success() {
+                    return Err(format!("tmux display-message failed: {}", status).into());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_detect() {
+        let detect = |vars: &[(&str, &str)]| {
+            let vars = vars
+                .iter()
+                .map(|(x, y)| (x.to_string(), y.to_string()))
+                .collect::<HashMap<String, String>>();
+            TerminalStyle::from_vars(|x| vars.get(x).cloned())
+        };
+        assert_eq!(
+            detect(&[
+                ("TMUX", "/tmp/tmux-1000/default,1,0"),
+                ("VTE_VERSION", "7200")
+            ]),
+            TerminalStyle::Tmux
+        );
+        assert_eq!(detect(&[("TERM_PROGRAM", "WezTerm")]), TerminalStyle::Osc9);
+        assert_eq!(detect(&[("TERM", "foot")]), TerminalStyle::Osc777);
+        assert_eq!(detect(&[("TERM", "xterm-256color")]), TerminalStyle::Bell);
+    }
+
+    #[test]
+    fn test_sequence() {
+        assert_eq!(
+            TerminalStyle::Osc9.sequence("lou", "hi\x1b]0;pwned\x07"),
+            Some("\x1b]9;lou: hi ]0;pwned \x07".into())
+        );
+        assert_eq!(
+            TerminalStyle::Osc777.sequence("lou;x", "hi; there"),
+            Some("\x1b]777;notify;lou,x;hi; there\x07".into())
+        );
+        assert_eq!(
+            TerminalStyle::Bell.sequence("lou", "hi"),
+            Some("\x07".into())
+        );
+        assert_eq!(TerminalStyle::Tmux.sequence("lou", "hi"), None);
+        assert_eq!(sanitize(&"a".repeat(500)).len(), MAX_LENGTH);
+    }
+}
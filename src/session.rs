@@ -0,0 +1,198 @@
+//! Session module.
+//!
+//! This module routes events between the User Interface
+//! and the chat system of each account, and keeps what is
+//! restored on the next start.
+use super::config::state_dir;
+use super::core::{AccountId, Channel, ChatEvent, UIEvent};
+use super::credentials::write_private;
+use async_channel::{Receiver, Sender};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+/// A message typed but not sent yet.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Draft {
+    pub channel: Channel,
+    pub content: String,
+}
+
+/// What is restored for an account.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default)]
+pub struct AccountState {
+    /// The last channel displayed.
+    pub channel: Option<Channel>,
+    pub drafts: Vec<Draft>,
+}
+
+/// Session state.
+///
+/// This type contains the layout of the UI and the state of each account
+/// when talkoxid quits, it is saved in the state directory.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default)]
+pub struct SessionState {
+    /// The account of the displayed channel.
+    pub active_account: Option<AccountId>,
+    /// The first visible line of the channel list.
+    pub channel_list_scroll: usize,
+    pub channels_collapsed: bool,
+    pub users_collapsed: bool,
+    pub accounts: BTreeMap<AccountId, AccountState>,
+}
+
+impl SessionState {
+    /// The session file, `$XDG_STATE_HOME/talkoxid/session.toml`.
+    pub fn default_path() -> PathBuf {
+        state_dir().join("session.toml")
+    }
+
+    /// Read a saved session, a missing or invalid file is an empty session.
+    pub fn load(path: &Path) -> Self {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(_) => return SessionState::default(),
+        };
+        toml::from_str(&content).unwrap_or_else(|err| {
+            warn!("Ignoring the session in {}: {}", path.display(), err);
+            SessionState::default()
+        })
+    }
+
+    /// Write the session, only the user can read it since drafts are private.
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if let Some(directory) = path.parent() {
+            std::fs::create_dir_all(directory)?;
+        }
+        // Going through a value puts the tables after the plain values
+        let content = toml::to_string(&toml::Value::try_from(self)?)?;
+        write_private(path, content.as_bytes())
+    }
+
+    /// The last channel of an account.
+    pub fn channel(&self, account: &str) -> Option<&Channel> {
+        self.accounts.get(account).and_then(|x| x.channel.as_ref())
+    }
+}
+
+/// Forward the events of an account's chat system to the UI.
+///
+/// Events are tagged with the account so the UI knows where they come from.
+pub async fn forward_ui_events(
+    account: AccountId,
+    rx_ui: Receiver<UIEvent>,
+    tx_ui: Sender<(AccountId, UIEvent)>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    loop {
+        let event = rx_ui.recv().await?;
+        tx_ui.send((account.clone(), event)).await?;
+    }
+}
+
+/// Route the events of the UI to the chat system of their account.
+pub async fn route_chat_events(
+    rx_chat: Receiver<(AccountId, ChatEvent)>,
+    mut routes: HashMap<AccountId, Sender<ChatEvent>>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    loop {
+        let (account, event) = rx_chat.recv().await?;
+        match routes.get(&account) {
+            Some(tx_chat) => {
+                // The chat of the account ended, the others keep running
+                if let Err(err) = tx_chat.send(event).await {
+                    error!("Can't route to the chat of {}: {}", account, err);
+                    routes.remove(&account);
+                }
+            }
+            None => error!("No chat for account {}", account),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::Channel;
+    use async_channel::unbounded;
+
+    #[tokio::test]
+    async fn test_forward_ui_events() {
+        let (tx_account, rx_account) = unbounded();
+        let (tx_ui, rx_ui) = unbounded();
+        tx_account
+            .send(UIEvent::ShowInfo("hello".into()))
+            .await
+            .unwrap();
+        drop(tx_account);
+        assert!(forward_ui_events("work".into(), rx_account, tx_ui)
+            .await
+            .is_err());
+        assert_eq!(
+            rx_ui.recv().await.unwrap(),
+            ("work".to_string(), UIEvent::ShowInfo("hello".into()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_route_chat_events() {
+        let (tx_work, rx_work) = unbounded();
+        let (tx_community, rx_community) = unbounded();
+        let (tx_chat, rx_chat) = unbounded();
+        let mut routes = HashMap::new();
+        routes.insert("work".to_string(), tx_work);
+        routes.insert("community".to_string(), tx_community);
+        let (tx_ended, rx_ended) = unbounded();
+        routes.insert("ended".to_string(), tx_ended);
+        drop(rx_ended);
+        let event = ChatEvent::Init(Channel::Group("GENERAL".into()));
+        tx_chat.send(("ended".into(), event.clone())).await.unwrap();
+        tx_chat
+            .send(("unknown".into(), event.clone()))
+            .await
+            .unwrap();
+        tx_chat
+            .send(("community".into(), event.clone()))
+            .await
+            .unwrap();
+        drop(tx_chat);
+        assert!(route_chat_events(rx_chat, routes).await.is_err());
+        assert_eq!(rx_community.recv().await.unwrap(), event);
+        assert!(rx_work.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_session_state() {
+        let path = std::env::temp_dir().join("talkoxid_test_session_state.toml");
+        std::fs::remove_file(&path).ok();
+        assert_eq!(SessionState::load(&path), SessionState::default());
+        let mut state = SessionState {
+            active_account: Some("work".into()),
+            channel_list_scroll: 3,
+            users_collapsed: true,
+            ..SessionState::default()
+        };
+        state.accounts.insert(
+            "work".into(),
+            AccountState {
+                channel: Some(Channel::Private("ops".into())),
+                drafts: vec![Draft {
+                    channel: Channel::User("lou".into()),
+                    content: "see you \"tomorrow\"\nbye".into(),
+                }],
+            },
+        );
+        state.save(&path).unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(content.contains("kind = \"private\""));
+        assert_eq!(SessionState::load(&path), state);
+        assert_eq!(state.channel("work"), Some(&Channel::Private("ops".into())));
+        assert_eq!(state.channel("community"), None);
+        std::fs::write(&path, "channel_list_scroll = \"top\"").unwrap();
+        assert_eq!(SessionState::load(&path), SessionState::default());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,327 @@
+//! Setup module.
+//!
+//! This module contains the first run setup: the answers of the wizard,
+//! the connection test and the configuration file they give.
+use super::chats::{build_chat, ChatParams, TlsConfig};
+use super::config::{account_name, check_url};
+use super::core::Notification;
+use super::credentials::{self, run_password_command, Credentials};
+use async_channel::unbounded;
+use serde::Serialize;
+use std::error::Error;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use url::Url;
+
+/// Time given to the server to accept the login.
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How the password is given.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Auth {
+    /// Written in the configuration file.
+    Password(String),
+    /// Printed by a command, like a password manager.
+    Command(String),
+    /// Stored in the encrypted credentials file.
+    Credentials {
+        password: String,
+        passphrase: String,
+    },
+}
+
+/// The answers of the setup wizard.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Setup {
+    pub hostname: String,
+    pub backend: String,
+    pub username: String,
+    pub auth: Auth,
+    /// The certificate checks, used to test the login and saved.
+    pub tls: TlsConfig,
+}
+
+/// The configuration file written by the setup.
+#[derive(Serialize)]
+struct SetupConfig<'a> {
+    backend: &'a str,
+    username: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    password: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    password_command: Option<&'a str>,
+    hostname: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    credentials_file: Option<&'a Path>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ssl_verify: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tls: Option<&'a TlsConfig>,
+}
+
+/// Nothing is notified while testing the connection.
+struct NoNotifier;
+
+impl Notification for NoNotifier {
+    fn notify(&self, _title: &str, _content: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        Ok(())
+    }
+}
+
+impl Setup {
+    /// Check the answers before connecting.
+    pub fn validate(&self) -> Result<(), String> {
+        check_url(&self.hostname).map_err(|err| format!("Invalid server URL: {}", err))?;
+        if self.username.is_empty() {
+            return Err("The username is missing".into());
+        }
+        match &self.auth {
+            Auth::Password(password) if password.is_empty() => {
+                Err("The password is missing".into())
+            }
+            Auth::Command(command) if command.is_empty() => {
+                Err("The password command is missing".into())
+            }
+            Auth::Credentials { password, .. } if password.is_empty() => {
+                Err("The password is missing".into())
+            }
+            Auth::Credentials { passphrase, .. } if passphrase.is_empty() => {
+                Err("The passphrase is missing".into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn password(&self) -> Result<String, Box<dyn Error + Send + Sync>> {
+        match &self.auth {
+            Auth::Password(password) | Auth::Credentials { password, .. } => Ok(password.clone()),
+            Auth::Command(command) => run_password_command(command),
+        }
+    }
+
+    /// Connect and log in like talkoxid does on startup.
+    pub async fn test_connection(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let (_tx_chat, rx_chat) = unbounded();
+        let (tx_ui, _rx_ui) = unbounded();
+        let params = ChatParams {
+            host: Url::parse(&self.hostname)?,
+            username: self.username.clone(),
+            password: self.password()?,
+            tls: self.tls.clone(),
+            proxy: None,
+            chat_log: None,
+            record: None,
+            options: toml::value::Table::new(),
+            tx_ui,
+            rx_chat,
+            notifier: Box::new(NoNotifier {}),
+        };
+        tokio::time::timeout(CONNECTION_TIMEOUT, build_chat(&self.backend, params))
+            .await
+            .map_err(|_| format!("No answer from {}", self.hostname))??;
+        Ok(())
+    }
+
+    /// The credentials file next to the configuration file.
+    fn credentials_path(config_path: &Path) -> PathBuf {
+        config_path.with_file_name("credentials.enc")
+    }
+
+    /// The content of the configuration file.
+    pub fn to_toml(&self, config_path: &Path) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let credentials_path = Self::credentials_path(config_path);
+        let config = SetupConfig {
+            backend: &self.backend,
+            username: &self.username,
+            password: match &self.auth {
+                Auth::Password(password) => Some(password),
+                _ => None,
+            },
+            password_command: match &self.auth {
+                Auth::Command(command) => Some(command),
+                _ => None,
+            },
+            hostname: &self.hostname,
+            credentials_file: match &self.auth {
+                Auth::Credentials { .. } if credentials_path != credentials::default_path() => {
+                    Some(&credentials_path)
+                }
+                _ => None,
+            },
+            ssl_verify: Some(false).filter(|_| !self.tls.verify),
+            tls: Some(&self.tls).filter(|x| x.ca_file.is_some()),
+        };
+        Ok(toml::to_string(&config)?)
+    }
+
+    /// Write the configuration file, and the credentials file if needed.
+    ///
+    /// Both are only readable by the user, an existing configuration file
+    /// is never replaced.
+    pub fn save(&self, config_path: &Path) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if let Some(directory) = config_path.parent() {
+            let mut builder = std::fs::DirBuilder::new();
+            builder.recursive(true);
+            #[cfg(unix)]
+            std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+            builder
+                .create(directory)
+                .map_err(|err| format!("Can't create {}: {}", directory.display(), err))?;
+        }
+        // Created only if missing, so that another file is never replaced
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(config_path).map_err(|err| match err.kind() {
+            std::io::ErrorKind::AlreadyExists => {
+                format!("{} already exists", config_path.display())
+            }
+            _ => format!("Can't write {}: {}", config_path.display(), err),
+        })?;
+        let written = self.write_config(config_path, &mut file);
+        if written.is_err() {
+            std::fs::remove_file(config_path).ok();
+        }
+        written
+    }
+
+    /// Write the credentials file if needed, then the configuration to `file`.
+    fn write_config(
+        &self,
+        config_path: &Path,
+        file: &mut std::fs::File,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if let Auth::Credentials {
+            password,
+            passphrase,
+        } = &self.auth
+        {
+            let prompt = |_: &Path| Ok(passphrase.clone());
+            Credentials::new(Self::credentials_path(config_path), &prompt).set_password(
+                &account_name(&self.username, &self.hostname),
+                password,
+                passphrase,
+            )?;
+        }
+        file.write_all(self.to_toml(config_path)?.as_bytes())?;
+        file.sync_all()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::chats::fake_server::FakeDdpServer;
+    use super::*;
+
+    fn setup(hostname: &str, auth: Auth) -> Setup {
+        Setup {
+            hostname: hostname.into(),
+            backend: "rocketchat".into(),
+            username: "lou".into(),
+            auth,
+            tls: TlsConfig::default(),
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let password = Auth::Password("secret".into());
+        assert!(setup("https://chat.test", password.clone())
+            .validate()
+            .is_ok());
+        assert!(setup("chat.test", password.clone())
+            .validate()
+            .unwrap_err()
+            .starts_with("Invalid server URL"));
+        assert_eq!(
+            setup("https://chat.test", Auth::Command("".into())).validate(),
+            Err("The password command is missing".into())
+        );
+        let credentials = Auth::Credentials {
+            password: "secret".into(),
+            passphrase: "".into(),
+        };
+        assert_eq!(
+            setup("https://chat.test", credentials).validate(),
+            Err("The passphrase is missing".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connection() {
+        let server = FakeDdpServer::start("lou", "secret", vec![]).await;
+        let command = Auth::Command("echo secret".into());
+        setup(server.url.as_str(), command)
+            .test_connection()
+            .await
+            .unwrap();
+        let server = FakeDdpServer::start("lou", "secret", vec![]).await;
+        let err = setup(server.url.as_str(), Auth::Password("wrong".into()))
+            .test_connection()
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(format!("{}", err), "Login failed: User not found");
+    }
+
+    #[test]
+    fn test_save() {
+        let directory = std::env::temp_dir().join("talkoxid_test_setup_save");
+        std::fs::remove_dir_all(&directory).ok();
+        let config_path = directory.join("talkoxid.toml");
+        let command = setup("https://chat.test", Auth::Command("pass show chat".into()));
+        command.save(&config_path).unwrap();
+        let config = std::fs::read_to_string(&config_path).unwrap();
+        assert_eq!(
+            config,
+            "backend = \"rocketchat\"\nusername = \"lou\"\npassword_command = \"pass show chat\"\nhostname = \"https://chat.test\"\n"
+        );
+        assert_eq!(
+            format!("{}", command.save(&config_path).unwrap_err()),
+            format!("{} already exists", config_path.display())
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&config_path)
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        std::fs::remove_file(&config_path).unwrap();
+        let credentials = Auth::Credentials {
+            password: "secret".into(),
+            passphrase: "passphrase".into(),
+        };
+        setup("https://chat.test", credentials)
+            .save(&config_path)
+            .unwrap();
+        let config = std::fs::read_to_string(&config_path).unwrap();
+        assert!(!config.contains("secret"));
+        assert!(config.contains("credentials_file = "));
+        let prompt = |_: &Path| Ok("passphrase".to_string());
+        let mut credentials = Credentials::new(directory.join("credentials.enc"), &prompt);
+        assert_eq!(
+            credentials.password("lou@chat.test").unwrap(),
+            Some("secret".into())
+        );
+        std::fs::remove_file(&config_path).unwrap();
+        let insecure = Setup {
+            tls: TlsConfig {
+                verify: false,
+                ca_file: Some("/etc/ssl/company.pem".into()),
+                ..TlsConfig::default()
+            },
+            ..setup("https://chat.test", Auth::Password("secret".into()))
+        };
+        insecure.save(&config_path).unwrap();
+        let config = std::fs::read_to_string(&config_path).unwrap();
+        assert!(config.contains("ssl_verify = false\n"));
+        assert!(config.contains("[tls]\nca_file = \"/etc/ssl/company.pem\"\n"));
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -1,33 +1,26 @@
 pub mod views;
-use super::super::core::{Channel, ChatEvent, Message, UIEvent, UI};
+mod wizard;
+use super::super::core::{AccountId, Channel, ChatEvent, Message, UIError, UIEvent, UI};
+use super::super::notifications::TerminalFocus;
+use super::super::session::{Draft, SessionState};
 use async_channel::{Receiver, Sender};
+use cursive::event::{Event, Key};
 use cursive::traits::*;
 use cursive::view::ScrollStrategy;
-use cursive::views::{LinearLayout, Panel, SelectView, TextView};
+use cursive::views::{HideableView, LinearLayout, Panel, ResizedView, SelectView, TextView};
 use cursive::views::{NamedView, ScrollView};
 use cursive::{CbSink, Cursive, CursiveRunnable, CursiveRunner};
 
 use log::error;
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::error::Error;
-use std::fmt;
+use std::io::Write;
 use std::rc::Rc;
+use std::sync::Arc;
 
-use views::{BufferView, ChannelView, MessageBoxView};
-
-#[derive(Debug)]
-struct UIError {
-    source: String,
-}
-
-impl fmt::Display for UIError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "There was an UI Error: {}", self.source)
-    }
-}
-
-impl Error for UIError {}
+use views::{BufferView, ChannelEntry, ChannelView, MessageBoxView};
+pub use wizard::setup_wizard;
 
 fn format_channel(channels: Vec<(String, Channel)>) -> Vec<(String, Channel)> {
     let mut chats: Vec<(String, Channel)> = channels
@@ -43,29 +36,83 @@ fn format_channel(channels: Vec<(String, Channel)>) -> Vec<(String, Channel)> {
     chats
 }
 
-fn on_channel_changed(tx_chat: Sender<ChatEvent>) -> impl Fn(&mut Cursive, &Channel) -> () {
-    move |siv: &mut Cursive, item: &Channel| {
-        tx_chat.try_send(ChatEvent::Init(item.clone())).unwrap();
-        siv.focus_name("input").unwrap();
+/// A side pane which can be collapsed.
+type Pane = HideableView<ResizedView<LinearLayout>>;
+
+/// Window title without unread messages.
+const WINDOW_TITLE: &str = "talkoxid";
+
+/// Sent by the terminal when focus reporting is enabled.
+const FOCUS_IN: &[u8] = b"\x1b[I";
+const FOCUS_OUT: &[u8] = b"\x1b[O";
+
+/// Write an escape sequence to the terminal, bypassing cursive.
+fn write_terminal(sequence: &str) {
+    let written = std::fs::OpenOptions::new()
+        .write(true)
+        .open("/dev/tty")
+        .and_then(|mut tty| tty.write_all(sequence.as_bytes()));
+    if let Err(err) = written {
+        error!("Can't write to the terminal: {}", err);
+    }
+}
+
+fn toggle_pane(siv: &mut Cursive, name: &str) {
+    siv.call_on_name(name, |view: &mut Pane| view.set_visible(!view.is_visible()));
+}
+
+fn on_channel_changed(
+    tx_chat: Sender<(AccountId, ChatEvent)>,
+    active_account: Rc<RefCell<Option<AccountId>>>,
+) -> impl Fn(&mut Cursive, &ChannelEntry) {
+    move |siv: &mut Cursive, item: &ChannelEntry| {
+        if let ChannelEntry::Channel(account, channel) = item {
+            *active_account.borrow_mut() = Some(account.clone());
+            tx_chat
+                .try_send((account.clone(), ChatEvent::Init(channel.clone())))
+                .unwrap();
+            siv.focus_name("input").unwrap();
+        }
     }
 }
 
 /// Cursive UI.
 ///
 /// This type is a terminal user interface using the cursive library.
+///
+/// Only the events of the active account change the displayed channel,
+/// the channel list shows the channels of every account.
 pub struct CursiveUI {
     cb_sink: CbSink,
     siv: RefCell<CursiveRunner<CursiveRunnable>>,
-    rx_ui: Receiver<UIEvent>,
+    rx_ui: Receiver<(AccountId, UIEvent)>,
+    active_account: Rc<RefCell<Option<AccountId>>>,
+    /// The state saved on quit, updated as channels are selected.
+    session: RefCell<SessionState>,
+    /// The account displayed last time, it becomes active when it selects a channel.
+    restore_account: RefCell<Option<AccountId>>,
+    /// The channel list scroll to restore once it is long enough.
+    restore_scroll: RefCell<Option<usize>>,
+    /// The focus shared with the notifiers, if reported.
+    focus: Option<Arc<TerminalFocus>>,
+    /// Messages received in the active channel since the terminal lost focus.
+    unread: Rc<Cell<usize>>,
 }
 
 impl CursiveUI {
-    pub fn new(tx_chat: Sender<ChatEvent>, rx_ui: Receiver<UIEvent>) -> Self {
+    pub fn new(
+        tx_chat: Sender<(AccountId, ChatEvent)>,
+        rx_ui: Receiver<(AccountId, UIEvent)>,
+    ) -> Self {
         let mut siv = cursive::default();
         let tx_chat2 = tx_chat.clone();
+        let active_account = Rc::new(RefCell::new(None));
+        let active_account2 = active_account.clone();
 
         let cb_sink = siv.cb_sink().clone();
         siv.add_global_callback('q', |s| s.quit());
+        siv.add_global_callback(Key::F2, |s| toggle_pane(s, "channels_pane"));
+        siv.add_global_callback(Key::F3, |s| toggle_pane(s, "users_pane"));
         siv.load_toml(include_str!("../../../assets/style.toml"))
             .unwrap();
         let buffer = BufferView::new(cb_sink.clone())
@@ -76,25 +123,34 @@ impl CursiveUI {
         let message_input_box = MessageBoxView::new(None, tx_chat.clone()).with_name("input");
 
         let channel_list = ChannelView::new()
-            .on_submit(on_channel_changed(tx_chat))
+            .on_submit(on_channel_changed(tx_chat, active_account.clone()))
             .with_name("channel_list")
-            .scrollable();
+            .scrollable()
+            .with_name("channel_scroll");
         let users_list = SelectView::<String>::new()
             .on_submit(move |_: &mut Cursive, item: &String| {
-                tx_chat2
-                    .try_send(ChatEvent::DirectChat(item.clone()))
-                    .unwrap();
+                if let Some(account) = active_account2.borrow().clone() {
+                    tx_chat2
+                        .try_send((account, ChatEvent::DirectChat(item.clone())))
+                        .unwrap();
+                }
             })
             .with_name("users_list")
             .scrollable();
-        let channels = LinearLayout::vertical()
-            .child(TextView::new("CHANNELS:"))
-            .child(channel_list)
-            .min_width(20);
-        let users = LinearLayout::vertical()
-            .child(TextView::new("USERS:"))
-            .child(users_list)
-            .min_width(20);
+        let channels = HideableView::new(
+            LinearLayout::vertical()
+                .child(TextView::new("CHANNELS:"))
+                .child(channel_list)
+                .min_width(20),
+        )
+        .with_name("channels_pane");
+        let users = HideableView::new(
+            LinearLayout::vertical()
+                .child(TextView::new("USERS:"))
+                .child(users_list)
+                .min_width(20),
+        )
+        .with_name("users_pane");
         let chat_layout = LinearLayout::vertical()
             .child(Panel::new(buffer).full_height())
             .child(Panel::new(message_input_box))
@@ -110,6 +166,170 @@ impl CursiveUI {
             cb_sink,
             siv: RefCell::new(siv.into_runner()),
             rx_ui,
+            active_account,
+            session: RefCell::new(SessionState::default()),
+            restore_account: RefCell::new(None),
+            restore_scroll: RefCell::new(None),
+            focus: None,
+            unread: Rc::new(Cell::new(0)),
+        }
+    }
+
+    /// Track the focus of the terminal.
+    ///
+    /// This enables focus reporting. While the terminal is unfocused, new
+    /// messages of the active channel are counted in the window title and
+    /// the first one rings the bell.
+    pub fn with_focus(mut self, focus: Arc<TerminalFocus>) -> Self {
+        {
+            let mut siv = self.siv.borrow_mut();
+            let focus_in = focus.clone();
+            let unread = self.unread.clone();
+            siv.set_on_pre_event(Event::Unknown(FOCUS_IN.to_vec()), move |s| {
+                focus_in.set_focused(true);
+                if unread.replace(0) > 0 {
+                    s.set_window_title(WINDOW_TITLE);
+                }
+            });
+            let focus_out = focus.clone();
+            siv.set_on_pre_event(Event::Unknown(FOCUS_OUT.to_vec()), move |_| {
+                focus_out.set_focused(false)
+            });
+        }
+        write_terminal("\x1b[?1004h");
+        self.focus = Some(focus);
+        self
+    }
+
+    /// Restore a saved session.
+    pub fn with_session(self, session: SessionState) -> Self {
+        {
+            let mut siv = self.siv.borrow_mut();
+            siv.call_on_name("channels_pane", |view: &mut Pane| {
+                view.set_visible(!session.channels_collapsed)
+            });
+            siv.call_on_name("users_pane", |view: &mut Pane| {
+                view.set_visible(!session.users_collapsed)
+            });
+            siv.call_on_name("input", |view: &mut MessageBoxView| {
+                for (account, state) in session.accounts.iter() {
+                    for draft in state.drafts.iter() {
+                        view.add_draft(
+                            account.clone(),
+                            draft.channel.clone(),
+                            draft.content.clone(),
+                        );
+                    }
+                }
+            });
+        }
+        *self.restore_account.borrow_mut() = session.active_account.clone();
+        *self.restore_scroll.borrow_mut() = Some(session.channel_list_scroll).filter(|x| *x > 0);
+        *self.session.borrow_mut() = session;
+        self
+    }
+
+    /// The session to save: the layout, the last channels and the drafts.
+    pub fn session(&self) -> SessionState {
+        let mut session = self.session.borrow().clone();
+        let mut siv = self.siv.borrow_mut();
+        if let Some(collapsed) =
+            siv.call_on_name("channels_pane", |view: &mut Pane| !view.is_visible())
+        {
+            session.channels_collapsed = collapsed;
+        }
+        if let Some(collapsed) =
+            siv.call_on_name("users_pane", |view: &mut Pane| !view.is_visible())
+        {
+            session.users_collapsed = collapsed;
+        }
+        if let Some(scroll) = siv.call_on_name(
+            "channel_scroll",
+            |view: &mut ScrollView<NamedView<ChannelView>>| view.content_viewport().top(),
+        ) {
+            session.channel_list_scroll = self.restore_scroll.borrow().unwrap_or(scroll);
+        }
+        if let Some(mut drafts) =
+            siv.call_on_name("input", |view: &mut MessageBoxView| view.drafts())
+        {
+            drafts.sort_by(|a, b| (&a.0, &a.1).cmp(&(&b.0, &b.1)));
+            for state in session.accounts.values_mut() {
+                state.drafts.clear();
+            }
+            for (account, channel, content) in drafts {
+                session
+                    .accounts
+                    .entry(account)
+                    .or_default()
+                    .drafts
+                    .push(Draft { channel, content });
+            }
+        }
+        session
+    }
+
+    /// Scroll the channel list like last time once it is long enough.
+    fn restore_channel_scroll(&self, siv: &mut Cursive) {
+        let offset = match *self.restore_scroll.borrow() {
+            Some(offset) => offset,
+            None => return,
+        };
+        let restored = siv.call_on_name(
+            "channel_scroll",
+            |view: &mut ScrollView<NamedView<ChannelView>>| {
+                if view.get_inner_mut().get_mut().view.len() <= offset {
+                    return false;
+                }
+                view.set_offset((0, offset));
+                true
+            },
+        );
+        if restored == Some(true) {
+            *self.restore_scroll.borrow_mut() = None;
+        }
+    }
+
+    /// Show that a message arrived in the active channel of an unfocused
+    /// terminal.
+    fn hint_unread(&self, siv: &mut Cursive, account: &str) {
+        let (focus, channel) = match (&self.focus, self.session.borrow().channel(account)) {
+            (Some(focus), Some(channel)) => (focus.clone(), channel.clone()),
+            _ => return,
+        };
+        if focus.watching(account, &channel) != Some(false) {
+            return;
+        }
+        let unread = self.unread.get() + 1;
+        self.unread.set(unread);
+        siv.set_window_title(format!("({}) {}", unread, WINDOW_TITLE));
+        if unread == 1 {
+            write_terminal("\x07");
+        }
+    }
+
+    /// Whether the event comes from the active account.
+    ///
+    /// The first account selecting a channel becomes the active one, until
+    /// the account displayed last time selects one.
+    fn is_active(&self, account: &str, event: &UIEvent) -> bool {
+        let mut active_account = self.active_account.borrow_mut();
+        if let UIEvent::SelectChannel(_) = event {
+            let mut restore_account = self.restore_account.borrow_mut();
+            if active_account.is_none() || restore_account.as_deref() == Some(account) {
+                *active_account = Some(account.to_string());
+            }
+            if restore_account.as_deref() == Some(account) {
+                *restore_account = None;
+            }
+        }
+        active_account.as_deref() == Some(account)
+    }
+}
+
+impl Drop for CursiveUI {
+    fn drop(&mut self) {
+        if self.focus.is_some() {
+            write_terminal("\x1b[?1004l");
         }
     }
 }
@@ -119,15 +339,27 @@ impl UI for CursiveUI {
         let mut siv = self.siv.borrow_mut();
         while siv.is_running() {
             siv.step();
-            match self.rx_ui.try_recv() {
-                Ok(UIEvent::AddMessages(msg)) => self.add_message(msg)?,
-                Ok(UIEvent::UpdateChannels(channels)) => self.update_channels(channels)?,
-                Ok(UIEvent::UpdateMessages(messages)) => self.update_messages(messages)?,
-                Ok(UIEvent::UpdateUsersInRoom(users)) => self.update_users_in_room(users)?,
-                Ok(UIEvent::SelectChannel(channel)) => self.select_channel(channel)?,
-                Ok(UIEvent::ShowFatalError(content)) => self.show_fatal_error(content)?,
+            self.restore_channel_scroll(&mut siv);
+            let (account, event) = match self.rx_ui.try_recv() {
+                Ok(received) => received,
                 _ => continue,
             };
+            let is_active = self.is_active(&account, &event);
+            match event {
+                UIEvent::UpdateChannels(channels) => self.update_channels(account, channels)?,
+                UIEvent::ShowFatalError(content) => {
+                    self.show_fatal_error(format!("{}: {}", account, content))?
+                }
+                UIEvent::ShowInfo(content) => self.show_info(content)?,
+                _ if !is_active => continue,
+                UIEvent::AddMessages(msg) => {
+                    self.hint_unread(&mut siv, &account);
+                    self.add_message(msg)?
+                }
+                UIEvent::UpdateMessages(messages) => self.update_messages(messages)?,
+                UIEvent::UpdateUsersInRoom(users) => self.update_users_in_room(users)?,
+                UIEvent::SelectChannel(channel) => self.select_channel(account, channel)?,
+            };
         }
         Ok(())
     }
@@ -144,23 +376,14 @@ impl UI for CursiveUI {
 
     fn update_channels(
         &self,
+        account: AccountId,
         channels: Vec<(String, Channel)>,
     ) -> Result<(), Box<dyn Error + Send + Sync>> {
         let chats = format_channel(channels);
         self.cb_sink
             .send(Box::new(|siv: &mut Cursive| {
                 siv.call_on_name("channel_list", move |view: &mut ChannelView| {
-                    let selected = view
-                        .view
-                        .selection()
-                        .unwrap_or_else(|| Rc::new(Channel::Group("GENERAL".into())));
-                    let index = chats
-                        .iter()
-                        .position(|x| &x.1 == selected.as_ref())
-                        .unwrap_or_default();
-                    view.view.clear();
-                    view.view.add_all(chats);
-                    view.view.set_selection(index);
+                    view.set_channels(account, chats);
                 });
             }))
             .map_err(|err| UIError {
@@ -208,19 +431,26 @@ impl UI for CursiveUI {
         Ok(())
     }
 
-    fn select_channel(&self, channel: Channel) -> Result<(), Box<dyn Error + Send + Sync>> {
+    fn select_channel(
+        &self,
+        account: AccountId,
+        channel: Channel,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        {
+            let mut session = self.session.borrow_mut();
+            session.active_account = Some(account.clone());
+            session.accounts.entry(account.clone()).or_default().channel = Some(channel.clone());
+        }
+        if let Some(focus) = self.focus.as_ref() {
+            focus.set_active(&account, &channel);
+        }
         self.cb_sink
             .send(Box::new(|siv: &mut Cursive| {
                 siv.call_on_name("input", |view: &mut MessageBoxView| {
-                    view.channel = Some(channel.clone());
+                    view.switch_channel(account.clone(), channel.clone());
                 });
                 siv.call_on_name("channel_list", move |view: &mut ChannelView| {
-                    let index = view
-                        .view
-                        .iter()
-                        .position(|x| &x.1 == &&channel)
-                        .unwrap_or_default();
-                    view.view.set_selection(index);
+                    view.select(&account, &channel);
                 });
             }))
             .map_err(|err| UIError {
@@ -245,6 +475,29 @@ impl UI for CursiveUI {
 
         Ok(())
     }
+
+    fn show_info(&self, content: String) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.cb_sink
+            .send(Box::new(move |siv: &mut Cursive| {
+                siv.add_layer(
+                    cursive::views::Dialog::new()
+                        .title("Info")
+                        .content(TextView::new(content))
+                        .button("Ok", |s| {
+                            s.pop_layer();
+                            s.focus_name("input").unwrap_or_else(|err| {
+                                error!("Can't focus input: {}", err);
+                                cursive::event::EventResult::Ignored
+                            });
+                        }),
+                );
+            }))
+            .map_err(|err| UIError {
+                source: format!("{}", err),
+            })?;
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -278,4 +531,24 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_drafts() {
+        use cursive::view::ViewWrapper;
+        let (tx, _rx) = async_channel::unbounded();
+        let mut view = MessageBoxView::new(None, tx);
+        let general = Channel::Group("GENERAL".into());
+        let support = Channel::Group("a1b2".into());
+        view.add_draft("work".into(), support.clone(), "see you".into());
+        view.switch_channel("work".into(), general.clone());
+        view.with_view_mut(|x| x.set_content("hello"));
+        view.switch_channel("work".into(), support.clone());
+        assert_eq!(
+            view.with_view(|x| x.get_content().to_string()),
+            Some("see you".into())
+        );
+        view.with_view_mut(|x| x.set_content(""));
+        let drafts = view.drafts();
+        assert_eq!(drafts, vec![("work".into(), general, "hello".into())]);
+    }
 }
@@ -8,27 +8,80 @@ use cursive::views::{NamedView, ScrollView, SelectView, TextArea, TextView};
 use cursive::wrap_impl;
 use cursive::{CbSink, Cursive, Printer};
 
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
 
-use super::super::super::core::{Channel, ChatEvent};
+use super::super::super::core::{AccountId, Channel, ChatEvent};
 
 pub struct MessageBoxView {
     view: TextArea,
+    pub account: Option<AccountId>,
     pub channel: Option<Channel>,
     multiline: bool,
-    tx: Sender<ChatEvent>,
+    tx: Sender<(AccountId, ChatEvent)>,
+    /// The unsent text of the other channels.
+    drafts: HashMap<(AccountId, Channel), String>,
 }
 
 impl MessageBoxView {
-    pub fn new(channel: Option<Channel>, tx: Sender<ChatEvent>) -> Self {
+    pub fn new(channel: Option<Channel>, tx: Sender<(AccountId, ChatEvent)>) -> Self {
         let view = TextArea::new();
         MessageBoxView {
+            account: None,
             channel,
             tx,
             view,
             multiline: false,
+            drafts: HashMap::new(),
         }
     }
+
+    /// Keep the typed text as the draft of the current channel, then show
+    /// the draft of the new one.
+    pub fn switch_channel(&mut self, account: AccountId, channel: Channel) {
+        if self.account.as_ref() == Some(&account) && self.channel.as_ref() == Some(&channel) {
+            return;
+        }
+        if let (Some(old_account), Some(old_channel)) = (self.account.take(), self.channel.take()) {
+            let content = self.view.get_content().to_string();
+            if !content.is_empty() {
+                self.drafts.insert((old_account, old_channel), content);
+            }
+        }
+        let draft = self
+            .drafts
+            .remove(&(account.clone(), channel.clone()))
+            .unwrap_or_default();
+        self.view.set_content(draft);
+        self.account = Some(account);
+        self.channel = Some(channel);
+    }
+
+    /// The drafts of every channel, with the text being typed.
+    pub fn drafts(&self) -> Vec<(AccountId, Channel, String)> {
+        let mut drafts = self
+            .drafts
+            .iter()
+            .map(|((account, channel), content)| {
+                (account.clone(), channel.clone(), content.clone())
+            })
+            .collect::<Vec<_>>();
+        if let (Some(account), Some(channel)) = (&self.account, &self.channel) {
+            if !self.view.get_content().is_empty() {
+                drafts.push((
+                    account.clone(),
+                    channel.clone(),
+                    self.view.get_content().to_string(),
+                ));
+            }
+        }
+        drafts
+    }
+
+    /// Restore saved drafts.
+    pub fn add_draft(&mut self, account: AccountId, channel: Channel, content: String) {
+        self.drafts.insert((account, channel), content);
+    }
 }
 
 impl ViewWrapper for MessageBoxView {
@@ -36,10 +89,15 @@ impl ViewWrapper for MessageBoxView {
     fn wrap_on_event<'r>(&mut self, event: Event) -> EventResult {
         match event {
             Event::Key(Key::Enter) if !self.multiline => {
+                // No channel to send to yet, the text is kept
+                let (account, channel) = match (&self.account, &self.channel) {
+                    (Some(account), Some(channel)) => (account.clone(), channel.clone()),
+                    _ => return EventResult::Consumed(None),
+                };
                 self.tx
-                    .try_send(ChatEvent::SendMessage(
-                        String::from(self.view.get_content()),
-                        self.channel.clone().unwrap(),
+                    .try_send((
+                        account,
+                        ChatEvent::SendMessage(String::from(self.view.get_content()), channel),
                     ))
                     .unwrap();
                 self.view.set_content("");
@@ -95,7 +153,7 @@ impl BufferView {
     }
 }
 
-impl<'a> ViewWrapper for BufferView {
+impl ViewWrapper for BufferView {
     wrap_impl!(self.view: TextView);
 
     fn wrap_required_size(&mut self, size: cursive::Vec2) -> cursive::Vec2 {
@@ -105,22 +163,77 @@ impl<'a> ViewWrapper for BufferView {
     }
 }
 
+/// An entry of the channel list.
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub enum ChannelEntry {
+    /// The header grouping the channels of a server.
+    Server(AccountId),
+    /// A channel of a server.
+    Channel(AccountId, Channel),
+}
+
 #[derive(Default)]
 pub struct ChannelView {
-    pub view: SelectView<Channel>,
+    pub view: SelectView<ChannelEntry>,
+    channels: BTreeMap<AccountId, Vec<(String, Channel)>>,
 }
 
 impl ChannelView {
     pub fn new() -> Self {
         let view = SelectView::new();
-        ChannelView { view }
+        ChannelView {
+            view,
+            channels: BTreeMap::new(),
+        }
     }
-    pub fn on_submit(mut self, func: impl Fn(&mut Cursive, &Channel) + 'static) -> Self {
+    pub fn on_submit(mut self, func: impl Fn(&mut Cursive, &ChannelEntry) + 'static) -> Self {
         self.view.set_on_submit(func);
         self
     }
+
+    /// Replace the channels of an account, keeping the current selection.
+    pub fn set_channels(&mut self, account: AccountId, channels: Vec<(String, Channel)>) {
+        let selected = self.view.selection();
+        self.channels.insert(account, channels);
+        let grouped = self.channels.len() > 1;
+        self.view.clear();
+        for (account, channels) in self.channels.iter() {
+            if grouped {
+                self.view.add_item(
+                    format!("[{}]", account),
+                    ChannelEntry::Server(account.clone()),
+                );
+            }
+            for (name, channel) in channels.iter() {
+                let label = if grouped {
+                    format!(" {}", name)
+                } else {
+                    name.clone()
+                };
+                self.view.add_item(
+                    label,
+                    ChannelEntry::Channel(account.clone(), channel.clone()),
+                );
+            }
+        }
+        let index = selected
+            .and_then(|x| self.view.iter().position(|y| y.1 == x.as_ref()))
+            .unwrap_or_default();
+        self.view.set_selection(index);
+    }
+
+    /// Move the selection to a channel of an account.
+    pub fn select(&mut self, account: &str, channel: &Channel) {
+        let index = self.view.iter().position(|x| match x.1 {
+            ChannelEntry::Channel(a, c) => a == account && c == channel,
+            _ => false,
+        });
+        if let Some(index) = index {
+            self.view.set_selection(index);
+        }
+    }
 }
 
-impl<'a> ViewWrapper for ChannelView {
-    wrap_impl!(self.view: SelectView<Channel>);
+impl ViewWrapper for ChannelView {
+    wrap_impl!(self.view: SelectView<ChannelEntry>);
 }
@@ -0,0 +1,200 @@
+use super::super::super::chats::{backends, TlsConfig};
+use super::super::super::setup::{Auth, Setup};
+use cursive::traits::*;
+use cursive::views::{Checkbox, Dialog, EditView, ListView, SelectView};
+use cursive::Cursive;
+use std::path::{Path, PathBuf};
+use tokio::runtime::Handle;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum AuthKind {
+    Password,
+    Command,
+    Credentials,
+}
+
+/// The fields used by each authentication method.
+const AUTH_FIELDS: &[(&str, &[AuthKind])] = &[
+    ("password", &[AuthKind::Password, AuthKind::Credentials]),
+    ("password_command", &[AuthKind::Command]),
+    ("passphrase", &[AuthKind::Credentials]),
+    ("passphrase_repeat", &[AuthKind::Credentials]),
+];
+
+fn content(siv: &mut Cursive, name: &str) -> String {
+    siv.call_on_name(name, |view: &mut EditView| {
+        view.get_content().trim().to_string()
+    })
+    .unwrap_or_default()
+}
+
+fn on_auth_changed(siv: &mut Cursive, kind: &AuthKind) {
+    for (name, kinds) in AUTH_FIELDS {
+        siv.call_on_name(name, |view: &mut EditView| {
+            view.set_enabled(kinds.contains(kind))
+        });
+    }
+}
+
+fn show_error(siv: &mut Cursive, content: String) {
+    siv.add_layer(Dialog::info(content).title("Error"));
+}
+
+/// Read the answers from the form.
+fn read_setup(siv: &mut Cursive) -> Result<Setup, String> {
+    let backend = siv
+        .call_on_name("backend", |view: &mut SelectView<&'static str>| {
+            view.selection()
+        })
+        .flatten()
+        .map(|x| x.to_string())
+        .unwrap_or_default();
+    let kind = siv
+        .call_on_name("auth", |view: &mut SelectView<AuthKind>| view.selection())
+        .flatten()
+        .map(|x| *x)
+        .unwrap_or(AuthKind::Password);
+    let password = siv
+        .call_on_name("password", |view: &mut EditView| {
+            view.get_content().to_string()
+        })
+        .unwrap_or_default();
+    let auth = match kind {
+        AuthKind::Password => Auth::Password(password),
+        AuthKind::Command => Auth::Command(content(siv, "password_command")),
+        AuthKind::Credentials => {
+            let passphrase = content(siv, "passphrase");
+            if passphrase != content(siv, "passphrase_repeat") {
+                return Err("The passphrases don't match".into());
+            }
+            Auth::Credentials {
+                password,
+                passphrase,
+            }
+        }
+    };
+    let verify = siv
+        .call_on_name("ssl_verify", |view: &mut Checkbox| view.is_checked())
+        .unwrap_or(true);
+    let ca_file = Some(content(siv, "ca_file"))
+        .filter(|x| !x.is_empty())
+        .map(PathBuf::from);
+    let setup = Setup {
+        hostname: content(siv, "hostname"),
+        backend,
+        username: content(siv, "username"),
+        auth,
+        tls: TlsConfig {
+            verify,
+            ca_file,
+            ..TlsConfig::default()
+        },
+    };
+    setup.validate()?;
+    Ok(setup)
+}
+
+/// Test the connection in the background, then save the configuration.
+fn submit(siv: &mut Cursive, handle: &Handle, config_path: &Path) {
+    let setup = match read_setup(siv) {
+        Ok(setup) => setup,
+        Err(err) => return show_error(siv, err),
+    };
+    siv.add_layer(
+        Dialog::text(format!(
+            "Logging in to {} as {}",
+            setup.hostname, setup.username
+        ))
+        .title("Testing the connection"),
+    );
+    let cb_sink = siv.cb_sink().clone();
+    let config_path = config_path.to_path_buf();
+    handle.spawn(async move {
+        let saved = match setup.test_connection().await {
+            Ok(()) => setup.save(&config_path).map_err(|err| err.to_string()),
+            Err(err) => Err(format!("Can't log in: {}", err)),
+        };
+        cb_sink
+            .send(Box::new(move |siv: &mut Cursive| {
+                siv.pop_layer();
+                match saved {
+                    Ok(()) => {
+                        siv.set_user_data(true);
+                        siv.add_layer(
+                            Dialog::text(format!(
+                                "Configuration saved to {}",
+                                config_path.display()
+                            ))
+                            .title("Done")
+                            .button("Start", |s| s.quit()),
+                        );
+                    }
+                    Err(err) => show_error(siv, err),
+                }
+            }))
+            .ok();
+    });
+}
+
+/// First run setup wizard.
+///
+/// Ask the server, the backend, the username, how the password is given
+/// and how the server certificate is checked. The login is tested on the runtime of `handle` before the
+/// configuration is written to `config_path`. Return whether it was.
+pub fn setup_wizard(handle: Handle, config_path: PathBuf) -> bool {
+    let mut siv = cursive::default();
+    siv.load_toml(include_str!("../../../assets/style.toml"))
+        .unwrap();
+    let backend = SelectView::new()
+        .popup()
+        .with_all(
+            backends()
+                .iter()
+                .map(|x| (format!("{} ({})", x.name, x.description), x.name)),
+        )
+        .with_name("backend");
+    let auth = SelectView::new()
+        .popup()
+        .item("Password in the config file", AuthKind::Password)
+        .item("Password command", AuthKind::Command)
+        .item("Encrypted credentials file", AuthKind::Credentials)
+        .on_submit(on_auth_changed)
+        .with_name("auth");
+    let form = ListView::new()
+        .child("Server URL", EditView::new().with_name("hostname"))
+        .child("Backend", backend)
+        .child("Username", EditView::new().with_name("username"))
+        .child("Authentication", auth)
+        .child("Password", EditView::new().secret().with_name("password"))
+        .child(
+            "Password command",
+            EditView::new().disabled().with_name("password_command"),
+        )
+        .child(
+            "Passphrase",
+            EditView::new().secret().disabled().with_name("passphrase"),
+        )
+        .child(
+            "Repeat passphrase",
+            EditView::new()
+                .secret()
+                .disabled()
+                .with_name("passphrase_repeat"),
+        )
+        .child(
+            "Verify the certificate",
+            Checkbox::new().checked().with_name("ssl_verify"),
+        )
+        .child("CA certificates file", EditView::new().with_name("ca_file"))
+        .min_width(60);
+    siv.add_layer(
+        Dialog::around(form)
+            .title("Welcome to talkoxid")
+            .button("Test and save", move |siv| {
+                submit(siv, &handle, &config_path)
+            })
+            .button("Quit", |s| s.quit()),
+    );
+    siv.run();
+    siv.take_user_data::<bool>().unwrap_or(false)
+}
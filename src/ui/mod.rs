@@ -2,4 +2,4 @@
 //!
 //! This module contains the various UI implementations.
 mod cursive_ui;
-pub use cursive_ui::CursiveUI;
+pub use cursive_ui::{setup_wizard, CursiveUI};